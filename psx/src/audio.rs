@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ BufWriter, Seek, SeekFrom, Write };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const CHANNELS: u16 = 2;
+
+// Seconds of audio between two updates of the WAV header
+const WAV_HEADER_INTERVAL: u32 = 5;
+
+// The RIFF size (data + 36 bytes of header) is a u32, the data stays made of whole frames
+const MAX_DATA_SIZE: u32 = (u32::MAX - 36) & !3;
+
+// Receives the sound played by the console.
+// Frames are interleaved stereo samples: left, right, left, right...
+pub trait AudioSink
+{
+    fn push(&mut self, frames: &[i16]);
+}
+
+// Keeps the latest samples around for the host to consume at its own pace.
+// When full, the oldest samples are dropped.
+// Clones share the same buffer, so the host can keep one to read from
+// (e.g. in its audio thread) while the emulator pushes into another.
+#[derive(Clone)]
+pub struct RingBufferSink
+{
+    buffer: Arc<Mutex<VecDeque<i16>>>,
+    capacity: usize
+}
+
+impl RingBufferSink
+{
+    // The capacity is a number of stereo frames
+    pub fn new(capacity: usize) -> Self
+    {
+        RingBufferSink
+        {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity * CHANNELS as usize))),
+            capacity: capacity * CHANNELS as usize
+        }
+    }
+
+    // Number of buffered samples (not frames)
+    pub fn len(&self) -> usize
+    {
+        self.buffer.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.buffer.lock().unwrap().is_empty()
+    }
+
+    // Moves buffered samples to the output, returns the number of samples written
+    pub fn pop(&self, output: &mut [i16]) -> usize
+    {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        let count = output.len().min(buffer.len());
+
+        for (sample, value) in output.iter_mut().zip(buffer.drain(.. count))
+        {
+            *sample = value;
+        }
+
+        count
+    }
+}
+
+impl AudioSink for RingBufferSink
+{
+    fn push(&mut self, frames: &[i16])
+    {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        for &sample in frames
+        {
+            if buffer.len() == self.capacity
+            {
+                buffer.pop_front();
+            }
+
+            buffer.push_back(sample);
+        }
+    }
+}
+
+// Records everything to a 16-bit stereo WAV file.
+// The header sizes are patched every few seconds and when the sink is dropped,
+// so that the file stays mostly valid even if the host exits without dropping it.
+pub struct WavFileSink
+{
+    writer: BufWriter<File>,
+    data_size: u32,
+    header_data_size: u32, // Data size when the header was last written
+    full: bool // The sizes in the header can't go further
+}
+
+impl WavFileSink
+{
+    pub fn new(path: PathBuf) -> std::io::Result<Self>
+    {
+        let mut sink = WavFileSink
+        {
+            writer: BufWriter::new(File::create(path)?),
+            data_size: 0,
+            header_data_size: 0,
+            full: false
+        };
+
+        sink.write_header()?;
+
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()>
+    {
+        let block_align = CHANNELS * 2;
+        let byte_rate = SAMPLE_RATE * block_align as u32;
+
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + self.data_size).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+
+        w.write_all(b"fmt ")?;
+        w.write_all(&16u32.to_le_bytes())?; // Chunk size
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&CHANNELS.to_le_bytes())?;
+        w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        w.write_all(&byte_rate.to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?; // Bits per sample
+
+        w.write_all(b"data")?;
+        w.write_all(&self.data_size.to_le_bytes())?;
+
+        self.header_data_size = self.data_size;
+
+        Ok(())
+    }
+
+    // Writes the final sizes in the header
+    pub fn finish(&mut self) -> std::io::Result<()>
+    {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl AudioSink for WavFileSink
+{
+    fn push(&mut self, frames: &[i16])
+    {
+        if frames.is_empty()
+        {
+            return;
+        }
+
+        for sample in frames
+        {
+            let data_size = match self.data_size.checked_add(2).filter(|size| *size <= MAX_DATA_SIZE)
+            {
+                Some(data_size) => data_size,
+                None =>
+                {
+                    if !self.full
+                    {
+                        error!("the WAV file reached the 4 GiB limit, the following samples are dropped");
+                        self.full = true;
+                    }
+
+                    break;
+                }
+            };
+
+            if let Err(e) = self.writer.write_all(&sample.to_le_bytes())
+            {
+                error!("cannot write WAV samples: {}", e);
+                return;
+            }
+
+            self.data_size = data_size;
+        }
+
+        let interval = WAV_HEADER_INTERVAL * SAMPLE_RATE * CHANNELS as u32 * 2;
+
+        if self.data_size - self.header_data_size >= interval
+        {
+            if let Err(e) = self.finish()
+            {
+                error!("cannot finalize WAV file: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for WavFileSink
+{
+    fn drop(&mut self)
+    {
+        if let Err(e) = self.finish()
+        {
+            error!("cannot finalize WAV file: {}", e);
+        }
+    }
+}
@@ -1,36 +1,227 @@
 // TODO load as u32? misaligned rw possible?
 
+use crate::error::Error;
 use crate::memory::Addressable;
 use crate::memory_segment::MemorySegment;
 
+use serde::{ Serialize, Deserialize };
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 
+const SIZE: usize = 512 * 1024;
+
+pub(crate) const SHELL_ADDRESS: u32 = 0x8003_0000; // Entry point of the shell, where the EXE files are hot-loaded
+
+// Version string of the BIOS 3.0 and later, e.g. "System ROM Version 4.1 12/16/97 A"
+const VERSION_PREFIX: &[u8] = b"System ROM Version ";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Region
+{
+    Japan, // NTSC-J
+    NorthAmerica, // NTSC-U
+    Europe // PAL
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BIOSVersion
+{
+    pub model: &'static str,
+    pub version: &'static str,
+    pub date: &'static str,
+    pub region: Region
+}
+
+impl fmt::Display for BIOSVersion
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} v{} ({}, {:?})", self.model, self.version, self.date, self.region)
+    }
+}
+
+// CRC32 of the known good dumps
+const KNOWN_DUMPS: [(u32, BIOSVersion); 10] =
+[
+    (0x3B60_1FC8, BIOSVersion { model: "SCPH-1000", version: "1.0", date: "09/22/94", region: Region::Japan }),
+    (0x9BB8_7C4B, BIOSVersion { model: "SCPH-1002", version: "2.0", date: "05/10/95", region: Region::Europe }),
+    (0x3715_7331, BIOSVersion { model: "SCPH-1001", version: "2.2", date: "12/04/95", region: Region::NorthAmerica }),
+    (0xFF3E_EB8C, BIOSVersion { model: "SCPH-5500", version: "3.0", date: "09/09/96", region: Region::Japan }),
+    (0x8D8C_B7E4, BIOSVersion { model: "SCPH-5501", version: "3.0", date: "11/18/96", region: Region::NorthAmerica }),
+    (0x4D9E_7C86, BIOSVersion { model: "SCPH-5502", version: "3.0", date: "01/06/97", region: Region::Europe }),
+    (0xEC54_1CD0, BIOSVersion { model: "SCPH-7000", version: "4.0", date: "08/18/97", region: Region::Japan }),
+    (0x5022_24B6, BIOSVersion { model: "SCPH-7001", version: "4.1", date: "12/16/97", region: Region::NorthAmerica }),
+    (0x3181_78BF, BIOSVersion { model: "SCPH-7502", version: "4.1", date: "12/16/97", region: Region::Europe }),
+    (0x171B_DCEC, BIOSVersion { model: "SCPH-101", version: "4.5", date: "05/25/00", region: Region::NorthAmerica })
+];
+
 pub struct BIOS
 {
-    data: MemorySegment
+    data: MemorySegment,
+    version: Option<BIOSVersion>, // None if the dump is unknown
+    region: Region,
+    crc: u32, // Identifies the image in the save states, 0 for the HLE kernel
+    hle: bool
 }
 
 impl BIOS
 {
-    pub fn new(path: PathBuf) -> Self
+    pub fn new(path: PathBuf) -> Result<Self, Error>
     {
         println!("Loading BIOS: \"{}\"", path.display());
 
         let mut buffer = Vec::new();
 
-        let mut file = File::open(path).unwrap(); //TODO err
-        file.read_to_end(&mut buffer).unwrap(); // TODO err
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut buffer))
+            .map_err(|error| Error::Io(path.clone(), error))?;
+
+        if buffer.len() != SIZE
+        {
+            return Err(Error::InvalidBIOS(path, format!("the image must be 512KB, got {} bytes", buffer.len())));
+        }
+
+        let crc = crc32fast::hash(&buffer);
+
+        let version = BIOS::known_version(crc);
+
+        let region = match version
+        {
+            Some(version) =>
+            {
+                println!("BIOS: {}", version);
+                version.region
+            },
+            None =>
+            {
+                let region = BIOS::find_region(&buffer);
+                warn!("Unknown BIOS dump (CRC32 {:08x}), region {:?}", crc, region);
+                region.unwrap_or(Region::NorthAmerica)
+            }
+        };
+
+        Ok(BIOS
+        {
+            data: MemorySegment::from_buffer(buffer),
+            version,
+            region,
+            crc,
+            hle: false
+        })
+    }
+
+    // Empty ROM, the kernel is emulated (see hle.rs)
+    pub fn hle() -> Self
+    {
+        println!("No BIOS, using the HLE kernel");
 
         BIOS
         {
-            data: MemorySegment::from_buffer(buffer)
+            data: MemorySegment::new(SIZE),
+            version: None,
+            region: Region::NorthAmerica,
+            crc: 0,
+            hle: true
         }
     }
 
+    // Looks up the CRC32 of the image in the known good dumps
+    fn known_version(crc: u32) -> Option<BIOSVersion>
+    {
+        KNOWN_DUMPS.iter().find(|(c, _)| *c == crc).map(|(_, version)| *version)
+    }
+
+    // Reads the region letter at the end of the version string, for the unknown dumps
+    fn find_region(data: &[u8]) -> Option<Region>
+    {
+        let start = data.windows(VERSION_PREFIX.len()).position(|w| w == VERSION_PREFIX)?;
+        let end = start + data[start ..].iter().position(|c| *c == 0)?;
+
+        match data[end - 1]
+        {
+            b'J' => Some(Region::Japan),
+            b'A' => Some(Region::NorthAmerica),
+            b'E' => Some(Region::Europe),
+            _    => None
+        }
+    }
+
+    // True for the empty ROM of the HLE kernel
+    pub fn is_hle(&self) -> bool
+    {
+        self.hle
+    }
+
+    pub fn crc(&self) -> u32
+    {
+        self.crc
+    }
+
+    pub fn version(&self) -> Option<BIOSVersion>
+    {
+        self.version
+    }
+
+    pub fn region(&self) -> Region
+    {
+        self.region
+    }
+
     pub fn read<T: Addressable>(&self, address: u32) -> T
     {
         self.data.read(address)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Zeros with the version string of a BIOS 3.0 or later
+    fn image(version: &[u8]) -> Vec<u8>
+    {
+        let mut data = vec![0; SIZE];
+        data[0x7FF00 .. 0x7FF00 + version.len()].copy_from_slice(version);
+        data
+    }
+
+    #[test]
+    fn known_dumps()
+    {
+        let version = BIOS::known_version(0x5022_24B6).unwrap();
+        assert_eq!((version.model, version.version, version.region), ("SCPH-7001", "4.1", Region::NorthAmerica));
+
+        assert_eq!(BIOS::known_version(0x9BB8_7C4B).unwrap().region, Region::Europe);
+        assert_eq!(BIOS::known_version(0), None);
+    }
+
+    #[test]
+    fn region_from_the_version_string()
+    {
+        assert_eq!(BIOS::find_region(&image(b"System ROM Version 4.0 08/18/97 J")), Some(Region::Japan));
+        assert_eq!(BIOS::find_region(&image(b"System ROM Version 4.1 12/16/97 A")), Some(Region::NorthAmerica));
+        assert_eq!(BIOS::find_region(&image(b"System ROM Version 4.1 12/16/97 E")), Some(Region::Europe));
+        assert_eq!(BIOS::find_region(&image(b"System ROM Version 4.1 12/16/97 X")), None);
+
+        // BIOS 2.2 and older don't have the string
+        assert_eq!(BIOS::find_region(&image(b"")), None);
+    }
+
+    #[test]
+    fn unknown_dump()
+    {
+        let path = std::env::temp_dir().join(format!("psx-bios-test-{}.bin", std::process::id()));
+
+        std::fs::write(&path, image(b"System ROM Version 4.1 12/16/97 E")).unwrap();
+        let bios = BIOS::new(path.clone()).unwrap();
+        assert_eq!((bios.version(), bios.region()), (None, Region::Europe));
+
+        std::fs::write(&path, vec![0; 1024]).unwrap();
+        assert!(BIOS::new(path.clone()).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use crate::cpu::Handler;
+
+use std::collections::HashMap;
+use std::hash::{ BuildHasherDefault, Hasher };
+use std::rc::Rc;
+
+// A run of instructions up to a branch and its delay slot, decoded once
+pub struct Block
+{
+    pub address: u32,
+    pub ram_page: Option<u32>, // None if the code cannot change (BIOS)
+    pub instructions: Vec<(Handler, u32)>, // Handler and instruction bits
+    pub interpreted: bool // Calls the kernel: the instructions go through the whole fetch
+}
+
+// The blocks are looked up after every branch, and their addresses don't need SipHash
+#[derive(Default)]
+pub(crate) struct AddressHasher
+{
+    hash: u64
+}
+
+impl Hasher for AddressHasher
+{
+    fn finish(&self) -> u64
+    {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8])
+    {
+        for &byte in bytes
+        {
+            self.write_u32(byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32)
+    {
+        // Fibonacci hashing, the instructions are word aligned
+        self.hash = (self.hash ^ (value >> 2) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+// Map from the code addresses
+pub(crate) type AddressMap<T> = HashMap<u32, T, BuildHasherDefault<AddressHasher>>;
+
+pub struct BlockCache
+{
+    blocks: AddressMap<Rc<Block>>
+}
+
+impl BlockCache
+{
+    pub fn new() -> Self
+    {
+        BlockCache
+        {
+            blocks: HashMap::default()
+        }
+    }
+
+    pub fn get(&self, address: u32) -> Option<Rc<Block>>
+    {
+        self.blocks.get(&address).cloned()
+    }
+
+    pub fn insert(&mut self, block: Block) -> Rc<Block>
+    {
+        let block = Rc::new(block);
+        self.blocks.insert(block.address, block.clone());
+        block
+    }
+
+    // Drops the blocks decoded from a RAM page that was written to
+    pub fn invalidate_page(&mut self, page: u32)
+    {
+        self.blocks.retain(|_, block| block.ram_page != Some(page));
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.blocks.clear();
+    }
+}
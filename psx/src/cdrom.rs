@@ -1,6 +1,8 @@
+use crate::bios::Region;
 use crate::interrupt_controller::{InterruptController, InterruptRequest};
 use crate::memory::{ Addressable, Width };
 
+use serde::{ Serialize, Deserialize };
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::rc::Rc;
@@ -8,12 +10,16 @@ use std::rc::Rc;
 enum Interrupt
 {
     _Int1 = 1,
-    _Int2 = 2,
+    Int2 = 2,
     Int3 = 3,
     _Int4 = 4,
     _Int5 = 5
 }
 
+// Status byte of the responses: the spindle motor is on, nothing else is happening
+const STAT_MOTOR_ON: u8 = 0x02;
+
+#[derive(Serialize, Deserialize)]
 pub struct CDROM
 {
     index: u8,
@@ -24,13 +30,22 @@ pub struct CDROM
     //current_command: Option<u8>,
     parameter_fifo: VecDeque<u8>,
     response_fifo: VecDeque<u8>,
+    second_response: VecDeque<u8>, // Sent with INT2 once the first response is acknowledged
+    data_fifo: VecDeque<u8>, // Read by the CPU or by the DMA
+
+    // Last sector read from the disc, copied to the data FIFO when the CPU asks for it.
+    // The read commands aren't emulated yet, so it stays empty.
+    sector_buffer: Vec<u8>,
+
+    region: Region, // Follows the BIOS
 
+    #[serde(skip)]
     interrupt_controller: Rc<RefCell<InterruptController>>
 }
 
 impl CDROM
 {
-    pub fn new(interrupt_controller: &Rc<RefCell<InterruptController>>) -> Self
+    pub fn new(interrupt_controller: &Rc<RefCell<InterruptController>>, region: Region) -> Self
     {
         CDROM
         {
@@ -42,11 +57,24 @@ impl CDROM
             //current_command: None,
             parameter_fifo: VecDeque::with_capacity(16),
             response_fifo: VecDeque::new(),
+            second_response: VecDeque::new(),
+            data_fifo: VecDeque::new(),
+
+            sector_buffer: Vec::new(),
+
+            region,
 
             interrupt_controller: interrupt_controller.clone()
         }
     }
 
+    // The interrupt controller is shared, it is restored separately
+    pub fn load_state(&mut self, mut state: CDROM)
+    {
+        state.interrupt_controller = self.interrupt_controller.clone();
+        *self = state;
+    }
+
     // TODO read is mut self which is weird, use some form of interior mutability?
     pub fn read<T: Addressable>(&mut self, offset: u32) -> T
     {
@@ -83,11 +111,8 @@ impl CDROM
                 }
             },
 
-            2 =>
-            {
-                error!("CDROM pop data FIFO: {:02X} NOT IMPLEMENTED", 0);
-                T::from_u8(0) // data FIFO
-            },
+            // Data FIFO
+            2 => T::from_u8(self.pop_data()),
 
             3 =>
             {
@@ -104,6 +129,24 @@ impl CDROM
         }
     }
 
+    // Reads a word from the data FIFO for the DMA
+    pub fn read_data_word(&mut self) -> u32
+    {
+        u32::from_le_bytes([self.pop_data(), self.pop_data(), self.pop_data(), self.pop_data()])
+    }
+
+    // Bytes left in the data FIFO, the DMA waits for a whole block
+    pub fn data_len(&self) -> usize
+    {
+        self.data_fifo.len()
+    }
+
+    // Reading past the end of the sector gives zeros
+    fn pop_data(&mut self) -> u8
+    {
+        self.data_fifo.pop_front().unwrap_or(0)
+    }
+
     pub fn write<T: Addressable>(&mut self, offset: u32, value: T)
     {
         error!("CDROM write {:?} {:08x} @ {} (index {})", T::width(), value.as_u32(), offset, self.index);
@@ -168,7 +211,21 @@ impl CDROM
             {
                 match self.index
                 {
-                    0 => {}, // request reg
+                    // Request Register, bit 7: load the sector buffer in the data FIFO, or clear it
+                    0 =>
+                    {
+                        if value & 0x80 != 0
+                        {
+                            if self.data_fifo.is_empty()
+                            {
+                                self.data_fifo.extend(self.sector_buffer.iter());
+                            }
+                        }
+                        else
+                        {
+                            self.data_fifo.clear();
+                        }
+                    },
 
                     //  Interrupt Flag Register
                     1 =>
@@ -176,6 +233,13 @@ impl CDROM
                         self.interrupt_flag &= !(value & 0x1F);
 
                         // TODO clear FIFO?
+
+                        if self.interrupt_flag == 0 && !self.second_response.is_empty()
+                        {
+                            self.response_fifo.clear();
+                            self.response_fifo.append(&mut self.second_response);
+                            self.interrupt(Interrupt::Int2);
+                        }
                     },
 
                     2 => {}, // Audio Volume for Left-CD-Out to Right-SPU-Input
@@ -191,7 +255,7 @@ impl CDROM
     fn status(&self) -> u8
     {
         (0 << 7) | // Command/Parameter transmission busy
-        (0 << 6) | // Data FIFO empty
+        (((!self.data_fifo.is_empty()) as u8) << 6) | // Data FIFO empty (0 = empty)
         (((self.response_fifo.len() != 0) as u8) << 5) | // Response FIFO empty (0 = empty)
         (((self.parameter_fifo.len() != 16) as u8) << 4) | // Parameter FIFO full (0 = full)
         (((self.parameter_fifo.len() == 0) as u8) << 3) | // Parameter FIFO empty (1 = empty)
@@ -234,12 +298,42 @@ impl CDROM
                         self.response_fifo.push_back(0x09);
                         self.response_fifo.push_back(0x19);
                         self.response_fifo.push_back(0xC0);
-                    }
+                    },
+
+                    // Drive region, checked against the license string of the discs
+                    0x22 =>
+                    {
+                        let region: &[u8] = match self.region
+                        {
+                            Region::Japan        => b"for Japan",
+                            Region::NorthAmerica => b"for U/C",
+                            Region::Europe       => b"for Europe"
+                        };
+
+                        self.response_fifo.extend(region.iter());
+                    },
 
                     x => panic!("unsupported subcommand {:02X}", x)
                 }
             },
 
+            // GetID
+            0x1A =>
+            {
+                self.response_fifo.push_back(STAT_MOTOR_ON);
+
+                // There is no disc model, the drive reports a licensed data disc of the console region
+                let license: &[u8] = match self.region
+                {
+                    Region::Japan        => b"SCEI",
+                    Region::NorthAmerica => b"SCEA",
+                    Region::Europe       => b"SCEE"
+                };
+
+                self.second_response.extend([STAT_MOTOR_ON, 0x00, 0x20, 0x00].iter());
+                self.second_response.extend(license.iter());
+            },
+
             x => panic!("unsupported command {:02X}", x)
         }
 
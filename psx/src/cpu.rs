@@ -1,23 +1,72 @@
+use crate::block_cache::{ Block, BlockCache };
 use crate::debugger::Debugger;
 use crate::exefile::ExeFile;
+use crate::hle::Kernel;
+use crate::icache::{ self, InstructionCache };
 use crate::interrupt_controller::InterruptController;
-use crate::memory::{ Addressable, Memory };
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+use crate::jit::{ self, Compiled, Cost, Entry, Offsets, JIT };
+use crate::kernel_calls;
+use crate::lockstep::MemoryWrite;
+use crate::memory::{ Addressable, BusErrorPolicy, Memory };
 use crate::opcode::Opcode;
+use crate::tracer::{ TraceEntry, Tracer };
 
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
 use std::rc::Rc;
 
 // TODO make sure R0 always 0
 
+// Executes a decoded instruction
+pub type Handler = fn(&mut CPU, &mut Memory, &Opcode);
+
+// Upper bound to keep the blocks small when there is no branch
+const MAX_BLOCK_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionMode
+{
+    Interpreter, // Decodes every instruction
+    CachedInterpreter, // Decodes blocks of instructions once, about 2.5 times faster (see `psxtest bench`)
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    Recompiler, // Translates blocks of instructions to x86-64 code
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    RecompilerLockstep // Same, but with one instruction per block, and the native ALU instructions checked against the interpreter (lockstep harness)
+}
+
+// COP0 status register bits
+const STATUS_USER_MODE: u32 = 1 << 1; // KUc
+const STATUS_COP0_ENABLE: u32 = 1 << 28; // CU0, COP1-3 are the next bits
+const STATUS_BEV: u32 = 1 << 22;
+
+// COP0 DCIC register bits
+// Documentation
+// https://problemkaputt.de/psx-spx.htm#cop0debugregisters
+const DCIC_ANY_BREAK: u32 = 1 << 0;
+const DCIC_CODE_BREAK: u32 = 1 << 1;
+const DCIC_DATA_BREAK: u32 = 1 << 2;
+const DCIC_DATA_READ_BREAK: u32 = 1 << 3;
+const DCIC_DATA_WRITE_BREAK: u32 = 1 << 4;
+const DCIC_MASTER_ENABLE: u32 = (1 << 23) | (1 << 30) | (1 << 31); // For the code and data breaks, bit 29 is for the any-jump break
+const DCIC_CODE_ENABLE: u32 = 1 << 24;
+const DCIC_DATA_ENABLE: u32 = 1 << 25;
+const DCIC_DATA_READ_ENABLE: u32 = 1 << 26;
+const DCIC_DATA_WRITE_ENABLE: u32 = 1 << 27;
+const DCIC_WRITABLE: u32 = 0xFF80_F03F;
+
+// Cycles taken by an instruction on top of its memory accesses
+const CYCLES_PER_INSTRUCTION: u32 = 1;
+
 #[derive(Clone, Debug)]
 enum Exception
 {
     ExternalInterrupt = 0,
     LoadAddress = 0x4,
     StoreAddress = 0x5,
+    InstructionBusError = 0x6,
+    DataBusError = 0x7,
     Syscall = 0x8,
     Break = 0x9,
     IllegalInstruction = 0xA,
@@ -47,28 +96,51 @@ pub struct CPU
     cop0_cause: u32,
     cop0_epc: u32,
 
+    // Debug breakpoints
+    cop0_bpc: u32, // Execution address
+    cop0_bpcm: u32, // Execution address mask
+    cop0_bda: u32, // Data address
+    cop0_bdam: u32, // Data address mask
+    cop0_dcic: u32, // Control
+
     //
     branching: bool,
     in_delay_slot: bool,
 
     pub counter: u32, // debug helper
+    pub cycles: u64, // Since the power on, including the time the DMA held the bus
+
+    tracer: Option<Tracer>,
+    kernel_call_log: bool, // Logs the BIOS function calls under the "kernel_calls" target
 
-    log_file: File,
-    logging: bool,
+    icache: InstructionCache,
+
+    pub execution_mode: ExecutionMode,
+    blocks: BlockCache,
+    cached_block: Option<Rc<Block>>, // Block being run by the cached interpreter
+    cached_index: usize, // Next instruction in that block
+    isolated_stores: bool, // Made while the cache is isolated, the blocks are dropped once it ends
+    deferred_cycles: u32, // Run by the cached interpreter or the recompiler but not ticked yet, see `tick_deferred`
+    tick_horizon: u32, // Cycles the devices can wait before something changes for the CPU
+    device_access: bool, // The instruction reached a device register
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    jit: JIT,
 
     pub debugger: Debugger, // TODO move out? or write memread/write function in cpu to wrap debugger registration
 
+    write_log: Option<Vec<MemoryWrite>>, // Stores recorded for the lockstep harness
+
     interrupt_controller: Rc<RefCell<InterruptController>>,
 
-    exe_path: Option<PathBuf>
+    hle: Option<Box<Kernel>>, // Runs the BIOS functions when there is no BIOS image
+
+    pending_exe: Option<(u32, ExeFile)> // Loaded once the CPU reaches the address
 }
 
 impl CPU
 {
-    pub fn new(
-        interrupt_controller: &Rc<RefCell<InterruptController>>,
-        exe_path: Option<PathBuf>)
-        -> Self
+    pub fn new(interrupt_controller: &Rc<RefCell<InterruptController>>) -> Self
     {
         CPU
         {
@@ -91,25 +163,77 @@ impl CPU
             cop0_cause: 0,
             cop0_epc: 0,
 
+            cop0_bpc: 0,
+            cop0_bpcm: 0,
+            cop0_bda: 0,
+            cop0_bdam: 0,
+            cop0_dcic: 0,
+
             branching: false,
             in_delay_slot: false,
 
             counter: 0,
+            cycles: 0,
+
+            tracer: None,
+            kernel_call_log: false,
 
-            log_file: File::create("custom_log_own.txt").unwrap(),
-            logging: false,
+            icache: InstructionCache::new(),
+
+            execution_mode: ExecutionMode::Interpreter,
+            blocks: BlockCache::new(),
+            cached_block: None,
+            cached_index: 0,
+            isolated_stores: false,
+            deferred_cycles: 0,
+            tick_horizon: 0,
+            device_access: false,
+
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            jit: JIT::new(Offsets
+            {
+                r: std::mem::offset_of!(CPU, r),
+                r_next: std::mem::offset_of!(CPU, r_next),
+                hi: std::mem::offset_of!(CPU, hi),
+                lo: std::mem::offset_of!(CPU, lo),
+                pc: std::mem::offset_of!(CPU, pc),
+                next_pc: std::mem::offset_of!(CPU, next_pc),
+                current_pc: std::mem::offset_of!(CPU, current_pc),
+                pending_load: std::mem::offset_of!(CPU, pending_load),
+                previous_pending_load: std::mem::offset_of!(CPU, previous_pending_load),
+                status: std::mem::offset_of!(CPU, status),
+                branching: std::mem::offset_of!(CPU, branching),
+                in_delay_slot: std::mem::offset_of!(CPU, in_delay_slot),
+                jit: std::mem::offset_of!(CPU, jit)
+            }),
 
             debugger: Debugger::new(),
 
+            write_log: None,
+
             interrupt_controller: interrupt_controller.clone(),
 
-            exe_path
+            hle: None,
+
+            pending_exe: None
         }
     }
 
     // Returns false if interrupted by a breakpoint
     pub fn run(&mut self, instructions: u32, mem: &mut Memory) -> bool
     {
+        match self.execution_mode
+        {
+            ExecutionMode::Interpreter        => (),
+            ExecutionMode::CachedInterpreter  => return self.run_cached(instructions, mem),
+
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            ExecutionMode::Recompiler         => return self.run_recompiled(instructions, mem, false),
+
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            ExecutionMode::RecompilerLockstep => return self.run_recompiled(instructions, mem, true)
+        }
+
         for _ in 0 .. instructions
         {
             if !self.step(mem)
@@ -122,8 +246,557 @@ impl CPU
     }
 
     pub fn step(&mut self, mem: &mut Memory) -> bool
+    {
+        self.begin_instruction(mem);
+        self.finish_instruction(mem)
+    }
+
+    // Interprets the instruction at PC once it has begun
+    fn finish_instruction(&mut self, mem: &mut Memory) -> bool
+    {
+        let opcode = match self.fetch_instruction(mem)
+        {
+            Some(opcode) => opcode,
+            None         => return self.end_instruction(mem) // Bus error
+        };
+
+        self.log_instruction(&opcode);
+
+        (CPU::decode(&opcode))(self, mem, &opcode);
+
+        self.end_instruction(mem)
+    }
+
+    // Same as the interpreter, but the instructions come decoded from the block cache.
+    // The code isn't read again, the blocks are dropped when their RAM page is written to.
+    // Returns false if interrupted by a breakpoint
+    fn run_cached(&mut self, instructions: u32, mem: &mut Memory) -> bool
+    {
+        // The block is kept between two runs
+        let mut block = self.cached_block.take();
+        let mut index = self.cached_index;
+        let mut running = true;
+
+        // The devices may have changed since the previous run
+        self.tick_horizon = 0;
+
+        for _ in 0 .. instructions
+        {
+            self.begin_instruction(mem);
+
+            if mem.has_invalidated_code_pages()
+            {
+                for page in mem.take_invalidated_code_pages()
+                {
+                    self.blocks.invalidate_page(page);
+                }
+
+                block = None;
+            }
+
+            // Keep going through the current block unless we jumped out of it
+
+            let in_block = match &block
+            {
+                Some(b) => index < b.instructions.len() && b.address.wrapping_add(index as u32 * 4) == self.pc,
+                None    => false
+            };
+
+            if !in_block
+            {
+                block = self.find_block(mem);
+                index = 0;
+            }
+
+            // The kernel calls and the code breakpoints still go through the whole fetch
+
+            let decoded = match &block
+            {
+                Some(b) if !b.interpreted && self.cop0_dcic & DCIC_CODE_ENABLE == 0 => Some(b.instructions[index]),
+                _                                                                 => None
+            };
+
+            index += 1;
+
+            let finished = match decoded
+            {
+                Some((handler, bits)) =>
+                {
+                    self.current_pc = self.pc;
+
+                    let fetched = self.fetch_decoded(mem, bits);
+
+                    self.pc = self.next_pc;
+                    self.next_pc = self.pc.wrapping_add(4);
+
+                    let opcode = Opcode(fetched);
+
+                    self.log_instruction(&opcode);
+
+                    // The instruction cache may hold older code
+                    if fetched == bits
+                    {
+                        handler(self, mem, &opcode);
+                    }
+                    else
+                    {
+                        (CPU::decode(&opcode))(self, mem, &opcode);
+                    }
+
+                    self.end_cached_instruction(mem)
+                },
+                None =>
+                {
+                    // The kernel may access the devices
+                    self.tick_deferred(mem);
+
+                    let finished = self.finish_instruction(mem);
+
+                    self.tick_horizon = 0;
+
+                    finished
+                }
+            };
+
+            if !finished
+            {
+                running = false;
+                break;
+            }
+        }
+
+        self.tick_deferred(mem);
+
+        self.cached_block = block;
+        self.cached_index = index;
+
+        running
+    }
+
+    // Same as `end_instruction`, but the devices are only ticked once they have something to do.
+    // They are up to date whenever the CPU can see them, so the result is the same.
+    fn end_cached_instruction(&mut self, mem: &mut Memory) -> bool
+    {
+        self.r = self.r_next;
+
+        self.deferred_cycles += CYCLES_PER_INSTRUCTION + mem.take_access_cycles();
+
+        if self.deferred_cycles >= self.tick_horizon || self.device_access
+        {
+            self.device_access = false;
+            self.tick_deferred(mem);
+        }
+
+        let stop = self.debugger.is_breakpoint(self.next_pc, self) || self.debugger.has_data_breakpoint();
+
+        !stop
+    }
+
+    // Lets the devices catch up with the instructions run by the cached interpreter or the recompiler
+    fn tick_deferred(&mut self, mem: &mut Memory)
+    {
+        let cycles = std::mem::replace(&mut self.deferred_cycles, 0);
+
+        if cycles != 0
+        {
+            let stall_cycles = mem.tick(cycles);
+            self.cycles += (cycles + stall_cycles) as u64;
+        }
+
+        self.tick_horizon = mem.cycles_until_event();
+    }
+
+    // Returns the block starting at PC, decodes it if needed
+    fn find_block(&mut self, mem: &mut Memory) -> Option<Rc<Block>>
+    {
+        if let Some(block) = self.blocks.get(self.pc)
+        {
+            return Some(block);
+        }
+
+        let block = self.decode_block(mem)?;
+
+        Some(self.blocks.insert(block))
+    }
+
+    // Decodes the instructions from PC up to a branch and its delay slot
+    fn decode_block(&self, mem: &mut Memory) -> Option<Block>
+    {
+        let ram_page = mem.code_page(self.pc);
+
+        let mut instructions = Vec::new();
+        let mut address = self.pc;
+        let mut delay_slot = false;
+
+        while instructions.len() < MAX_BLOCK_SIZE && mem.code_page(address) == ram_page
+        {
+            let bits = match mem.peek_code(address)
+            {
+                Some(bits) => bits,
+                None       => break
+            };
+
+            let opcode = Opcode(bits);
+            instructions.push((CPU::decode(&opcode), bits));
+
+            // Stop after the delay slot of a branch
+            if delay_slot
+            {
+                break;
+            }
+
+            delay_slot = CPU::is_branch(&opcode);
+            address = address.wrapping_add(4);
+        }
+
+        if instructions.is_empty()
+        {
+            return None;
+        }
+
+        if let Some(page) = ram_page
+        {
+            mem.watch_code_page(page);
+        }
+
+        // The BIOS function calls, the HLE kernel and the pending EXE are only seen by the interpreter
+        let interpreted = (0 .. instructions.len() as u32)
+            .map(|index| self.pc.wrapping_add(index * 4))
+            .any(|address| matches!(address & 0x1FFF_FFFF, 0xA0 | 0xB0 | 0xC0) ||
+                (self.hle.is_some() && Kernel::is_hook(address)) ||
+                self.pending_exe.as_ref().is_some_and(|(pending, _)| *pending == address));
+
+        Some(Block { address: self.pc, ram_page, instructions, interpreted })
+    }
+
+    // Jumps, branches and the instructions that always raise an exception
+    fn is_branch(opcode: &Opcode) -> bool
+    {
+        match opcode.instr()
+        {
+            0b000000 => matches!(opcode.sub(), 0b001000 | 0b001001 | 0b001100 | 0b001101), // JR, JALR, SYSCALL, BREAK
+            0b000001 ..= 0b000111 => true,
+            _ => false
+        }
+    }
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn run_recompiled(&mut self, instructions: u32, mem: &mut Memory, lockstep: bool) -> bool
+    {
+        self.jit.budget = instructions;
+        self.jit.stopped = false;
+
+        // The devices may have changed since the previous run
+        self.tick_horizon = 0;
+
+        while self.jit.budget > 0 && !self.jit.stopped
+        {
+            self.run_recompiled_block(mem, lockstep);
+        }
+
+        self.tick_deferred(mem);
+
+        !self.jit.stopped
+    }
+
+    // Runs the block at PC, or a single instruction if it must be interpreted
+    // Nothing is run if an interrupt is taken first
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn run_recompiled_block(&mut self, mem: &mut Memory, lockstep: bool)
+    {
+        // The blocks can only be dropped when no machine code is running
+
+        if mem.has_invalidated_code_pages()
+        {
+            for page in mem.take_invalidated_code_pages()
+            {
+                self.jit.invalidate_page(page);
+            }
+        }
+
+        if self.jit.flush_requested
+        {
+            self.jit.clear();
+        }
+
+        let compiled = if self.needs_interpreter() { None } else { self.find_compiled_block(mem, lockstep) };
+
+        match compiled
+        {
+            Some(Compiled::Native(function, cost)) if cost.length <= self.jit.budget && self.jit_fits(mem, &cost) =>
+            {
+                if !self.jit_begin_block(mem)
+                {
+                    return; // Interrupted, the handler runs next
+                }
+
+                self.jit.set_memory(mem.jit_pointers(), self.write_log.is_none());
+
+                let executed = unsafe { function(self, mem) };
+
+                self.jit_end_block(mem, executed);
+            },
+            _ =>
+            {
+                // Not in RAM or in the BIOS, calling the kernel, or not enough instructions or cycles left for the block
+                self.tick_deferred(mem);
+
+                self.jit.budget -= 1;
+                self.jit.stopped = !self.step(mem);
+
+                self.tick_horizon = 0;
+            }
+        }
+    }
+
+    // The tracer, the breakpoints, the pending EXE and the delay slot of a jump that ended a block
+    // need to see each instruction
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn needs_interpreter(&self) -> bool
+    {
+        self.branching ||
+        self.tracer.is_some() ||
+        self.debugger.has_breakpoints() ||
+        self.cop0_dcic & (DCIC_CODE_ENABLE | DCIC_DATA_ENABLE) != 0 ||
+        self.reaches_pending_exe()
+    }
+
+    // Returns the block starting at PC, compiles it if needed
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn find_compiled_block(&mut self, mem: &mut Memory, lockstep: bool) -> Option<Compiled>
+    {
+        if let Some(compiled) = self.jit.get(self.pc)
+        {
+            return Some(compiled);
+        }
+
+        let block = self.decode_block(mem)?;
+
+        if block.interpreted
+        {
+            self.jit.insert_interpreted(&block);
+            return Some(Compiled::Interpreted);
+        }
+
+        match self.jit.compile(&block, lockstep)
+        {
+            Ok(compiled) => Some(compiled),
+            Err(error) =>
+            {
+                error!("cannot allocate recompiled code: {}", error);
+                None
+            }
+        }
+    }
+
+    // True if the block can't reach the next device event, even with every fetch and access at its slowest.
+    // A refill reads the rest of its cache line: the block fetches at most 3 words more than its length.
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn jit_fits(&mut self, mem: &mut Memory, cost: &Cost) -> bool
+    {
+        let cycles = cost.length * CYCLES_PER_INSTRUCTION +
+            (cost.length + 3) * mem.code_fetch_cycles(self.pc) +
+            cost.loads * mem.max_access_cycles(false) +
+            cost.stores * mem.max_access_cycles(true);
+
+        if self.deferred_cycles + cycles >= self.tick_horizon
+        {
+            self.tick_deferred(mem);
+        }
+
+        cycles < self.tick_horizon
+    }
+
+    // Same as the start of an interpreted instruction, the pending load is applied by the block
+    // Returns false if an interrupt was taken instead
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn jit_begin_block(&mut self, mem: &mut Memory) -> bool
     {
         self.debugger.clear_data_access();
+        self.device_access = false;
+
+        // Don't charge the accesses made outside of the CPU (debugger...)
+        mem.take_access_cycles();
+
+        self.in_delay_slot = false;
+        self.current_pc = self.pc;
+
+        if self.update_pending_interrupt()
+        {
+            self.exception(Exception::ExternalInterrupt);
+            return false;
+        }
+
+        self.jit.block_address = self.pc;
+        self.jit.ticked = 0;
+
+        true
+    }
+
+    // Times the whole block
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn jit_end_block(&mut self, mem: &mut Memory, executed: u32)
+    {
+        self.jit_tick(mem, executed);
+    }
+
+    // Times the first instructions of the block
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn jit_tick(&mut self, mem: &mut Memory, count: u32)
+    {
+        if count == self.jit.ticked
+        {
+            return;
+        }
+
+        let executed = count - self.jit.ticked;
+        let address = self.jit.block_address.wrapping_add(self.jit.ticked * 4);
+
+        self.charge_fetches(mem, address, executed);
+
+        // Ticked with the next device access or event
+        self.deferred_cycles += executed * CYCLES_PER_INSTRUCTION + self.jit.take_cycles() + mem.take_access_cycles();
+
+        self.counter += executed;
+        self.jit.budget -= executed;
+        self.jit.ticked = count;
+    }
+
+
+    // Times the fetches of instructions that were decoded before, without reading them again:
+    // the uncached code pays a memory read per instruction, the cached code the refill of the missed lines
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    fn charge_fetches(&mut self, mem: &mut Memory, address: u32, count: u32)
+    {
+        if !InstructionCache::is_cached(address) || mem.cache_control() & icache::CONTROL_ENABLE == 0
+        {
+            mem.charge_code_fetches(address, count);
+            return;
+        }
+
+        for index in 0 .. count
+        {
+            let pc = address.wrapping_add(index * 4);
+
+            if self.icache.fetch(pc).is_none()
+            {
+                // The code has been decoded from there, the refill can't fail
+                let _ = self.refill_icache_line(mem, pc);
+            }
+        }
+    }
+
+    // Runs an instruction of a recompiled block with its handler
+    // Returns false if the block must be left: exception, breakpoint, COP0 change, or new code
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub fn jit_execute(&mut self, mem: &mut Memory, entry: &Entry) -> bool
+    {
+        self.debugger.clear_data_access();
+
+        self.in_delay_slot = entry.delay_slot;
+        self.branching = false;
+        self.current_pc = entry.address;
+
+        // In a delay slot, NEXT_PC already holds the target of the jump
+        let pc = entry.address.wrapping_add(4);
+
+        self.pc = pc;
+
+        if !entry.delay_slot
+        {
+            self.next_pc = pc.wrapping_add(4);
+        }
+
+        // A device register may be accessed, its cycles must be known
+        if jit::is_load(&entry.opcode) || jit::is_store(&entry.opcode)
+        {
+            self.jit_tick(mem, entry.index);
+        }
+
+        (entry.handler)(self, mem, &entry.opcode);
+
+        self.r = self.r_next;
+
+        self.jit.stopped = self.debugger.has_data_breakpoint();
+
+        // Exception
+        if self.pc != pc
+        {
+            return false;
+        }
+
+        // The device may have raised an interrupt or started a transfer
+        let device_access = std::mem::take(&mut self.device_access);
+
+        if device_access
+        {
+            self.tick_horizon = 0;
+        }
+
+        let leave = self.jit.stopped ||
+            device_access ||
+            entry.opcode.instr() == 0b010000 ||
+            mem.has_invalidated_code_pages() ||
+            self.jit.flush_requested;
+
+        if leave && entry.delay_slot
+        {
+            self.pc = self.next_pc;
+            self.next_pc = self.pc.wrapping_add(4);
+        }
+
+        !leave
+    }
+
+    // Runs the interpreter on the side before a native instruction
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub fn jit_check_begin(&mut self, mem: &mut Memory, handler: Handler, opcode: &Opcode)
+    {
+        let registers = self.r_next;
+
+        handler(self, mem, opcode);
+
+        self.jit.expected_registers = self.r_next;
+        self.r_next = registers;
+    }
+
+    // Returns false if the native instruction didn't give the same result as the interpreter
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub fn jit_check_end(&mut self, mem: &mut Memory, address: u32) -> bool
+    {
+        if self.r_next == self.jit.expected_registers
+        {
+            return true;
+        }
+
+        // Stop after the instruction
+        self.r = self.r_next;
+        self.current_pc = address;
+        self.jump(address.wrapping_add(4));
+
+        let disassembly = self.debugger.disassemble(address, self, mem);
+        error!("Recompiler divergence @ {:08X}: {:08X} {}", address, disassembly.bits, disassembly.mnemonics);
+
+        for (index, (actual, expected)) in self.r_next.iter().zip(self.jit.expected_registers.iter()).enumerate()
+        {
+            if actual != expected
+            {
+                error!("  R{} = {:08X}, interpreter: {:08X}", index, actual, expected);
+            }
+        }
+
+        self.jit.stopped = true;
+
+        false
+    }
+
+    // Everything that happens before an instruction is fetched
+    fn begin_instruction(&mut self, mem: &mut Memory)
+    {
+        self.debugger.clear_data_access();
+
+        // Don't charge the accesses made outside of the CPU (debugger...)
+        mem.take_access_cycles();
 
         // Apply any pending load
 
@@ -137,7 +810,8 @@ impl CPU
         self.in_delay_slot = self.branching;
         self.branching = false;
 
-        //
+        // The exception returns to this instruction
+        self.current_pc = self.pc;
 
         if self.update_pending_interrupt()
         {
@@ -147,14 +821,17 @@ impl CPU
 
         //
 
-        if self.pc == 0x8003_0000 && self.exe_path.is_some()
+        if self.reaches_pending_exe()
         {
-            let exe = ExeFile::new_from_file(self.exe_path.take().unwrap());
+            let (_, exe) = self.pending_exe.take().unwrap();
             exe.load(self, mem);
         }
+    }
 
-        // Fetch the next instruction
-
+    // Fetches the instruction at PC and moves to the next one
+    // Returns None on a bus error, an execution breakpoint, or when the HLE kernel ran the code at PC
+    fn fetch_instruction(&mut self, mem: &mut Memory) -> Option<Opcode>
+    {
         self.current_pc = self.pc;
 
         // TODO remove? dealt with in the jump instructions now
@@ -163,145 +840,366 @@ impl CPU
             panic!("unexpected unaligned address {:08X}", self.current_pc);
         }
 
-        let opcode = Opcode(mem.read(self.pc));
+        if self.is_code_breakpoint(self.current_pc)
+        {
+            self.debug_break(DCIC_CODE_BREAK);
+            return None;
+        }
+
+        if matches!(self.current_pc & 0x1FFF_FFFF, 0xA0 | 0xB0 | 0xC0)
+        {
+            self.kernel_call(mem);
+        }
+
+        if self.hle.is_some() && Kernel::is_hook(self.current_pc)
+        {
+            let mut kernel = self.hle.take().unwrap();
+            let handled = kernel.call(self, mem);
+            self.hle = Some(kernel);
+
+            if handled
+            {
+                return None;
+            }
+        }
+
+        let fetched = self.fetch(mem);
 
         self.pc = self.next_pc;
         self.next_pc = self.pc.wrapping_add(4);
 
-        let debug_addr = 129925482 - 10000;
-        let mut debug = false;
-        if self.counter >= debug_addr && self.counter < debug_addr + 400000
+        match fetched
         {
-            debug = true;
+            Some(bits) => Some(Opcode(bits)),
+            None =>
+            {
+                self.bus_error(mem, Exception::InstructionBusError, self.current_pc);
+                None
+            }
+        }
+    }
 
-            // debug logging
+    fn log_instruction(&mut self, opcode: &Opcode)
+    {
+        if let Some(tracer) = &mut self.tracer
+        {
+            if tracer.is_tracing(self.counter, self.current_pc)
+            {
+                tracer.write(&TraceEntry
+                {
+                    pc: self.current_pc,
+                    opcode: opcode.0,
+                    r: &self.r,
+                    hi: self.hi,
+                    lo: self.lo,
+                    cop0: [self.status, self.cop0_cause, self.cop0_epc, self.cop0_badaddr]
+                });
+            }
+        }
 
-            //write!(&mut self.log_file, "{} {:08x} {:08x} \n", self.counter, self.current_pc, opcode).unwrap();
+        self.counter += 1;
+    }
 
-            let mut s = String::new();
+    // Logs a BIOS function call, and captures its TTY output when the real BIOS runs it
+    fn kernel_call(&mut self, mem: &mut Memory)
+    {
+        let table = self.current_pc & 0x1FFF_FFFF;
+        let function = self.r_next[9];
+        let args = [self.r_next[4], self.r_next[5], self.r_next[6], self.r_next[7]];
 
-            //s += &format!("{:08x} {:08x} \n", self.current_pc, opcode);
-            s += &format!("{:08x} {:08x} ", self.current_pc, opcode);
-            //s += &format!("{} {:08x} {:08x} ", self.counter, self.current_pc, opcode);
+        if self.kernel_call_log
+        {
+            info!(target: "kernel_calls", "{}", kernel_calls::describe(mem, table, function, args, self.r_next[31]));
+        }
+
+        // The HLE kernel writes to the TTY itself
+        if self.hle.is_some()
+        {
+            return;
+        }
 
-            for i in 0 .. 32
+        match (table, function)
+        {
+            (0xA0, 0x3C) | (0xB0, 0x3D) => mem.tty.putchar(args[0] as u8), // std_out_putchar
+            (0xA0, 0x03) | (0xB0, 0x35) if args[0] == 1 => // FileWrite to stdout
             {
-               s += &format!("R{}={:08x} ", i, self.r[i]);
-            }
-            s += &format!("HI {:08x} ", self.hi);
-            s += &format!("LO {:08x} ", self.lo);
-            s += &format!("S {:08x} ", self.status);
-            s += &format!("\n");
+                let bytes = kernel_calls::read_bytes(mem, args[1], args[2]);
+                mem.tty.write(&bytes);
+            },
+            _ => ()
+        }
+    }
+
+    pub fn set_kernel_call_log(&mut self, enabled: bool)
+    {
+        self.kernel_call_log = enabled;
+    }
+
+    // Back to the power-on state, the BIOS starts again
+    pub fn reset(&mut self)
+    {
+        self.pc = 0xBFC0_0000;
+        self.next_pc = 0xBFC0_0004;
+
+        self.r = [0; 32];
+        self.r_next = [0; 32];
+        self.hi = 0;
+        self.lo = 0;
+
+        self.pending_load = (0, 0);
+        self.previous_pending_load = (0, 0);
 
-            write!(&mut self.log_file, "{}", s).unwrap();
+        self.status = 0;
+        self.current_pc = 0;
+        self.cop0_badaddr = 0;
+        self.cop0_cause = 0;
+        self.cop0_epc = 0;
 
-            /*for i in 0 .. 32
+        self.cop0_bpc = 0;
+        self.cop0_bpcm = 0;
+        self.cop0_bda = 0;
+        self.cop0_bdam = 0;
+        self.cop0_dcic = 0;
+
+        self.branching = false;
+        self.in_delay_slot = false;
+
+        self.flush_instruction_cache();
+
+        if let Some(kernel) = &mut self.hle
+        {
+            kernel.restart();
+        }
+    }
+
+    // Saves the registers, the pipeline, the caches and the HLE kernel.
+    // The settings (execution mode, tracer...) and the debugger are not part of the state.
+    pub(crate) fn save_state(&self, writer: &mut Vec<u8>) -> bincode::Result<()>
+    {
+        let registers = (self.pc, self.next_pc, self.current_pc, self.r, self.r_next, self.hi, self.lo);
+        let pipeline = (self.pending_load, self.previous_pending_load, self.branching, self.in_delay_slot);
+        let cop0 = (self.status, self.cop0_badaddr, self.cop0_cause, self.cop0_epc, self.cop0_bpc, self.cop0_bpcm, self.cop0_bda, self.cop0_bdam, self.cop0_dcic);
+
+        bincode::serialize_into(writer, &(registers, pipeline, cop0, (self.counter, self.cycles), &self.icache, &self.hle, &self.pending_exe))
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut &[u8]) -> bincode::Result<()>
+    {
+        type Registers = (u32, u32, u32, [u32; 32], [u32; 32], u32, u32);
+        type Pipeline = ((u32, u32), (u32, u32), bool, bool);
+        type COP0 = (u32, u32, u32, u32, u32, u32, u32, u32, u32);
+        type Counters = (u32, u64);
+        type PendingExe = Option<(u32, ExeFile)>;
+
+        let (registers, pipeline, cop0, (counter, cycles), icache, hle, pending_exe):
+            (Registers, Pipeline, COP0, Counters, InstructionCache, Option<Box<Kernel>>, PendingExe)
+            = bincode::deserialize_from(reader)?;
+
+        // The directories of the HLE kernel are settings, the current ones are kept
+        let hle = match hle
+        {
+            Some(mut kernel) =>
             {
-                debug!("\tR{} = {:08x}", i, self.r[i]);
-            }*/
+                let current = self.hle.as_deref().ok_or_else(|| Box::new(bincode::ErrorKind::Custom(String::from("the state needs the HLE kernel"))))?;
+                kernel.restore(current).map_err(|reason| Box::new(bincode::ErrorKind::Custom(reason)))?;
+                Some(kernel)
+            },
+            None => None
+        };
 
-            self.logging = true;
+        // The compiled blocks may not match the new memory contents
+        self.flush_instruction_cache();
+
+        (self.pc, self.next_pc, self.current_pc, self.r, self.r_next, self.hi, self.lo) = registers;
+        (self.pending_load, self.previous_pending_load, self.branching, self.in_delay_slot) = pipeline;
+        (self.status, self.cop0_badaddr, self.cop0_cause, self.cop0_epc, self.cop0_bpc, self.cop0_bpcm, self.cop0_bda, self.cop0_bdam, self.cop0_dcic) = cop0;
+        self.counter = counter;
+        self.cycles = cycles;
+        self.icache = icache;
+        self.hle = hle;
+        self.pending_exe = pending_exe;
+
+        Ok(())
+    }
+
+    // The EXE file is loaded once the CPU reaches the address, e.g. the shell after the kernel initialization
+    pub(crate) fn set_pending_exe(&mut self, address: u32, exe: ExeFile)
+    {
+        self.pending_exe = Some((address, exe));
+
+        // The blocks decoded before may run past the address
+        self.blocks.clear();
+        self.cached_block = None;
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        {
+            self.jit.flush_requested = true;
         }
+    }
 
-        self.counter += 1;
+    fn reaches_pending_exe(&self) -> bool
+    {
+        self.pending_exe.as_ref().is_some_and(|(address, _)| *address == self.pc)
+    }
 
-        /*if self.logging
+    // Runs an EXE file right away, from a fresh state
+    pub(crate) fn run_exe(&mut self, exe: &ExeFile, mem: &mut Memory)
+    {
+        self.reset();
+
+        // The function tables are still needed without the kernel initialization
+        if let Some(kernel) = &mut self.hle
         {
-            debug!("\nopcode {:08x} @ {:08x} | {:b} | {}", opcode, self.current_pc, self.status, self.counter);
-        }*/
+            kernel.install(mem);
+        }
+
+        // The program may rely on the BIOS stack
+        self.set_reg(29, 0x801F_FFF0);
+        self.set_reg(30, 0x801F_FFF0);
+
+        exe.load(self, mem);
+
+        self.jump(exe.pc());
+        self.r = self.r_next;
+    }
+
+    // Emulates the BIOS kernel instead of running the BIOS code
+    pub fn set_hle(&mut self, kernel: Option<Kernel>)
+    {
+        self.hle = kernel.map(Box::new);
+
+        // The hooks are found when the blocks are decoded
+        self.blocks.clear();
+        self.cached_block = None;
+    }
+
+    pub fn set_execution_mode(&mut self, mode: ExecutionMode)
+    {
+        // The lockstep mode compiles the blocks differently
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        {
+            if mode != self.execution_mode
+            {
+                self.jit.flush_requested = true;
+            }
+        }
+
+        self.execution_mode = mode;
+    }
+
+    // Starts writing an execution trace, or stops it
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>)
+    {
+        self.tracer = tracer;
+    }
 
+    // Returns the function executing the instruction
+    fn decode(opcode: &Opcode) -> Handler
+    {
         match opcode.instr()
         {
             0b000000 =>
             {
                 match opcode.sub()
                 {
-                    0b000000 => self.sll(&opcode),
-                    0b000010 => self.srl(&opcode),
-                    0b000011 => self.sra(&opcode),
-                    0b000100 => self.sllv(&opcode),
-                    0b000110 => self.srlv(&opcode),
-                    0b000111 => self.srav(&opcode),
-                    0b001000 => self.jr(&opcode),
-                    0b001001 => self.jalr(&opcode),
-                    0b001100 => self.syscall(),
-                    0b001101 => self.break_(),
-                    0b010000 => self.mfhi(&opcode),
-                    0b010001 => self.mthi(&opcode),
-                    0b010010 => self.mflo(&opcode),
-                    0b010011 => self.mtlo(&opcode),
-                    0b011000 => self.mult(&opcode),
-                    0b011001 => self.multu(&opcode),
-                    0b011010 => self.div(&opcode),
-                    0b011011 => self.divu(&opcode),
-                    0b100000 => self.add(&opcode),
-                    0b100001 => self.addu(&opcode),
-                    0b100010 => self.sub(&opcode),
-                    0b100011 => self.subu(&opcode),
-                    0b100100 => self.and(&opcode),
-                    0b100101 => self.or(&opcode),
-                    0b100110 => self.xor(&opcode),
-                    0b100111 => self.nor(&opcode),
-                    0b101010 => self.slt(&opcode),
-                    0b101011 => self.sltu(&opcode),
-                    _        => self.illegal(&opcode),
+                    0b000000 => |cpu, _, opcode| cpu.sll(opcode),
+                    0b000010 => |cpu, _, opcode| cpu.srl(opcode),
+                    0b000011 => |cpu, _, opcode| cpu.sra(opcode),
+                    0b000100 => |cpu, _, opcode| cpu.sllv(opcode),
+                    0b000110 => |cpu, _, opcode| cpu.srlv(opcode),
+                    0b000111 => |cpu, _, opcode| cpu.srav(opcode),
+                    0b001000 => |cpu, _, opcode| cpu.jr(opcode),
+                    0b001001 => |cpu, _, opcode| cpu.jalr(opcode),
+                    0b001100 => |cpu, _, _| cpu.syscall(),
+                    0b001101 => |cpu, _, _| cpu.break_(),
+                    0b010000 => |cpu, _, opcode| cpu.mfhi(opcode),
+                    0b010001 => |cpu, _, opcode| cpu.mthi(opcode),
+                    0b010010 => |cpu, _, opcode| cpu.mflo(opcode),
+                    0b010011 => |cpu, _, opcode| cpu.mtlo(opcode),
+                    0b011000 => |cpu, _, opcode| cpu.mult(opcode),
+                    0b011001 => |cpu, _, opcode| cpu.multu(opcode),
+                    0b011010 => |cpu, _, opcode| cpu.div(opcode),
+                    0b011011 => |cpu, _, opcode| cpu.divu(opcode),
+                    0b100000 => |cpu, _, opcode| cpu.add(opcode),
+                    0b100001 => |cpu, _, opcode| cpu.addu(opcode),
+                    0b100010 => |cpu, _, opcode| cpu.sub(opcode),
+                    0b100011 => |cpu, _, opcode| cpu.subu(opcode),
+                    0b100100 => |cpu, _, opcode| cpu.and(opcode),
+                    0b100101 => |cpu, _, opcode| cpu.or(opcode),
+                    0b100110 => |cpu, _, opcode| cpu.xor(opcode),
+                    0b100111 => |cpu, _, opcode| cpu.nor(opcode),
+                    0b101010 => |cpu, _, opcode| cpu.slt(opcode),
+                    0b101011 => |cpu, _, opcode| cpu.sltu(opcode),
+                    _        => |cpu, _, opcode| cpu.illegal(opcode),
                 }
             },
-            0b000001 => self.bcond(&opcode),
-            0b000010 => self.j(&opcode),
-            0b000011 => self.jal(&opcode),
-            0b000100 => self.beq(&opcode),
-            0b000101 => self.bne(&opcode),
-            0b000110 => self.blez(&opcode),
-            0b000111 => self.bgtz(&opcode),
-            0b001000 => self.addi(&opcode),
-            0b001001 => self.addiu(&opcode),
-            0b001010 => self.slti(&opcode),
-            0b001011 => self.sltiu(&opcode),
-            0b001100 => self.andi(&opcode),
-            0b001101 => self.ori(&opcode),
-            0b001110 => self.xori(&opcode),
-            0b001111 => self.lui(&opcode),
+            0b000001 => |cpu, _, opcode| cpu.bcond(opcode),
+            0b000010 => |cpu, _, opcode| cpu.j(opcode),
+            0b000011 => |cpu, _, opcode| cpu.jal(opcode),
+            0b000100 => |cpu, _, opcode| cpu.beq(opcode),
+            0b000101 => |cpu, _, opcode| cpu.bne(opcode),
+            0b000110 => |cpu, _, opcode| cpu.blez(opcode),
+            0b000111 => |cpu, _, opcode| cpu.bgtz(opcode),
+            0b001000 => |cpu, _, opcode| cpu.addi(opcode),
+            0b001001 => |cpu, _, opcode| cpu.addiu(opcode),
+            0b001010 => |cpu, _, opcode| cpu.slti(opcode),
+            0b001011 => |cpu, _, opcode| cpu.sltiu(opcode),
+            0b001100 => |cpu, _, opcode| cpu.andi(opcode),
+            0b001101 => |cpu, _, opcode| cpu.ori(opcode),
+            0b001110 => |cpu, _, opcode| cpu.xori(opcode),
+            0b001111 => |cpu, _, opcode| cpu.lui(opcode),
             0b010000 =>
             {
                 match opcode.rs()
                 {
-                    0b00000 => self.cop0_mfc(&opcode),
-                    0b00100 => self.cop0_mtc(&opcode),
-                    0b10000 => self.cop0_rfe(),
-                    _       => self.illegal(&opcode)
+                    0b00000 => |cpu, _, opcode| cpu.cop0_mfc(opcode),
+                    0b00100 => |cpu, _, opcode| cpu.cop0_mtc(opcode),
+                    0b10000 => |cpu, _, _| cpu.cop0_rfe(),
+                    _       => |cpu, _, opcode| cpu.illegal(opcode)
                 }
             },
-            0b010001 => self.cop1(),
-            0b010010 => self.cop2(),
-            0b010011 => self.cop3(),
-            0b100000 => self.lb(mem, &opcode),
-            0b100001 => self.lh(mem, &opcode),
-            0b100010 => self.lwl(mem, &opcode),
-            0b100011 => self.lw(mem, &opcode),
-            0b100100 => self.lbu(mem, &opcode),
-            0b100101 => self.lhu(mem, &opcode),
-            0b100110 => self.lwr(mem, &opcode),
-            0b101000 => self.sb(mem, &opcode),
-            0b101001 => self.sh(mem, &opcode),
-            0b101010 => self.swl(mem, &opcode),
-            0b101011 => self.sw(mem, &opcode),
-            0b101110 => self.swr(mem, &opcode),
-            0b110000 => self.cop0_lwc(),
-            0b110001 => self.cop1_lwc(),
-            0b110010 => self.cop2_lwc(),
-            0b110011 => self.cop3_lwc(),
-            0b111000 => self.cop0_swc(),
-            0b111001 => self.cop1_swc(),
-            0b111010 => self.cop2_swc(),
-            0b111011 => self.cop3_swc(),
-            _        => self.illegal(&opcode)
+            0b010001 => |cpu, _, _| cpu.cop1(),
+            0b010010 => |cpu, _, _| cpu.cop2(),
+            0b010011 => |cpu, _, _| cpu.cop3(),
+            0b100000 => |cpu, mem, opcode| cpu.lb(mem, opcode),
+            0b100001 => |cpu, mem, opcode| cpu.lh(mem, opcode),
+            0b100010 => |cpu, mem, opcode| cpu.lwl(mem, opcode),
+            0b100011 => |cpu, mem, opcode| cpu.lw(mem, opcode),
+            0b100100 => |cpu, mem, opcode| cpu.lbu(mem, opcode),
+            0b100101 => |cpu, mem, opcode| cpu.lhu(mem, opcode),
+            0b100110 => |cpu, mem, opcode| cpu.lwr(mem, opcode),
+            0b101000 => |cpu, mem, opcode| cpu.sb(mem, opcode),
+            0b101001 => |cpu, mem, opcode| cpu.sh(mem, opcode),
+            0b101010 => |cpu, mem, opcode| cpu.swl(mem, opcode),
+            0b101011 => |cpu, mem, opcode| cpu.sw(mem, opcode),
+            0b101110 => |cpu, mem, opcode| cpu.swr(mem, opcode),
+            0b110000 => |cpu, _, _| cpu.cop0_lwc(),
+            0b110001 => |cpu, _, _| cpu.cop1_lwc(),
+            0b110010 => |cpu, _, _| cpu.cop2_lwc(),
+            0b110011 => |cpu, _, _| cpu.cop3_lwc(),
+            0b111000 => |cpu, _, _| cpu.cop0_swc(),
+            0b111001 => |cpu, _, _| cpu.cop1_swc(),
+            0b111010 => |cpu, _, _| cpu.cop2_swc(),
+            0b111011 => |cpu, _, _| cpu.cop3_swc(),
+            _        => |cpu, _, opcode| cpu.illegal(opcode)
         }
+    }
 
+    // Everything that happens after an instruction is executed
+    // Returns false if a breakpoint was hit
+    fn end_instruction(&mut self, mem: &mut Memory) -> bool
+    {
         // Update the registers to account for the load-delay slot
 
         self.r = self.r_next;
 
+        // Let the other components catch up
+
+        let cycles = CYCLES_PER_INSTRUCTION + mem.take_access_cycles();
+        let stall_cycles = mem.tick(cycles);
+
+        self.cycles += (cycles + stall_cycles) as u64;
+
         // Check breakpoints
 
         let stop = self.debugger.is_breakpoint(self.next_pc, self) || self.debugger.has_data_breakpoint();
@@ -314,6 +1212,90 @@ impl CPU
         self.r[index as usize]
     }
 
+    // COP0 registers as (index, value), for comparisons between two CPUs
+    pub fn cop0_registers(&self) -> Vec<(u32, u32)>
+    {
+        vec![
+            (3, self.cop0_bpc),
+            (5, self.cop0_bda),
+            (7, self.cop0_dcic),
+            (8, self.cop0_badaddr),
+            (9, self.cop0_bdam),
+            (11, self.cop0_bpcm),
+            (12, self.status),
+            (13, self.cop0_cause),
+            (14, self.cop0_epc)
+        ]
+    }
+
+    // Registers as seen by the current instruction
+    pub(crate) fn registers(&self) -> [u32; 32]
+    {
+        self.r_next
+    }
+
+    // Continues at this address, without a delay slot
+    pub(crate) fn jump(&mut self, address: u32)
+    {
+        self.pc = address;
+        self.next_pc = address.wrapping_add(4);
+    }
+
+    pub(crate) fn cop0_status(&self) -> u32
+    {
+        self.status
+    }
+
+    pub(crate) fn set_cop0_status(&mut self, status: u32)
+    {
+        self.status = status;
+    }
+
+    pub(crate) fn cop0_cause(&self) -> u32
+    {
+        self.cop0_cause
+    }
+
+    pub(crate) fn cop0_epc(&self) -> u32
+    {
+        self.cop0_epc
+    }
+
+    // Drops the cached instructions, after code has been loaded
+    pub(crate) fn flush_instruction_cache(&mut self)
+    {
+        self.icache = InstructionCache::new();
+        self.blocks.clear();
+        self.cached_block = None;
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        {
+            self.jit.flush_requested = true;
+        }
+    }
+
+    // True until the EXE file given on the command line has been loaded
+    pub(crate) fn has_pending_exe(&self) -> bool
+    {
+        self.pending_exe.is_some()
+    }
+
+    // Starts or stops recording the stores that reach the memory
+    pub fn record_writes(&mut self, enabled: bool)
+    {
+        self.write_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // Returns the stores recorded since the latest call
+    pub fn take_writes(&mut self) -> Vec<MemoryWrite>
+    {
+        match &mut self.write_log
+        {
+            Some(log) => std::mem::take(log),
+            None      => Vec::new()
+        }
+    }
+
     // Instructions must use this function to set registers so that
     // load-delay slots are properly emulated.
     pub fn set_reg(&mut self, index: u32, value: u32)
@@ -347,16 +1329,152 @@ impl CPU
         (((self.cop0_cause & self.status) >> 8) & 0xFF) != 0 // pending (cause) & mask (status)
     }
 
-    fn read<T: Addressable>(&mut self, mem: &mut Memory, address: u32) -> T// TODO mut because of debuffer??
+    // Reads the instruction at PC, through the instruction cache if it's enabled
+    // Returns None if the fetch caused a bus error
+    fn fetch(&mut self, mem: &mut Memory) -> Option<u32>
+    {
+        let pc = self.pc;
+
+        if !InstructionCache::is_cached(pc) || mem.cache_control() & icache::CONTROL_ENABLE == 0
+        {
+            let bits = mem.read(pc);
+            return if mem.has_bus_error() { None } else { Some(bits) };
+        }
+
+        if let Some(bits) = self.icache.fetch(pc)
+        {
+            return Some(bits); // Cache hit: no memory access
+        }
+
+        self.refill_icache_line(mem, pc)
+    }
+
+    // Cache miss: refills the line from the address, the memory accesses are charged by the memory
+    // Returns the instruction at the address, or None on a bus error
+    fn refill_icache_line(&mut self, mem: &mut Memory, pc: u32) -> Option<u32>
+    {
+        let count = 4 - ((pc >> 2) & 3) as usize;
+        let mut words = [0; 4];
+
+        for (index, word) in words[.. count].iter_mut().enumerate()
+        {
+            *word = mem.read(pc + index as u32 * 4);
+
+            if mem.has_bus_error()
+            {
+                return None;
+            }
+        }
+
+        self.icache.refill(pc, &words[.. count]);
+
+        Some(words[0])
+    }
+
+    // Fetches an instruction decoded before, the memory is only read again on an instruction cache miss
+    // Returns the instruction to run
+    fn fetch_decoded(&mut self, mem: &mut Memory, bits: u32) -> u32
+    {
+        let pc = self.pc;
+
+        if !InstructionCache::is_cached(pc) || mem.cache_control() & icache::CONTROL_ENABLE == 0
+        {
+            mem.charge_code_fetches(pc, 1);
+            return bits;
+        }
+
+        // The code has been decoded from there, the refill can't fail
+        self.icache.fetch(pc).or_else(|| self.refill_icache_line(mem, pc)).unwrap_or(bits)
+    }
+
+    // Returns None if the read caused a bus error or a data breakpoint
+    fn read<T: Addressable>(&mut self, mem: &mut Memory, address: u32) -> Option<T> // TODO mut because of debuffer??
     {
         self.debugger.register_data_access(address, true);
-        mem.read::<T>(address)
+
+        if self.is_data_breakpoint(address, DCIC_DATA_READ_ENABLE)
+        {
+            self.debug_break(DCIC_DATA_BREAK | DCIC_DATA_READ_BREAK);
+            return None;
+        }
+
+        if mem.is_device(address)
+        {
+            self.catch_up_devices(mem);
+        }
+
+        let value = mem.read::<T>(address);
+
+        if mem.has_bus_error()
+        {
+            self.bus_error(mem, Exception::DataBusError, address);
+            return None;
+        }
+
+        Some(value)
     }
 
     fn write<T: Addressable>(&mut self, mem: &mut Memory, address: u32, value: T)
     {
         self.debugger.register_data_access(address, false);
-        mem.write::<T>(address, value)
+
+        if self.is_data_breakpoint(address, DCIC_DATA_WRITE_ENABLE)
+        {
+            self.debug_break(DCIC_DATA_BREAK | DCIC_DATA_WRITE_BREAK);
+            return;
+        }
+
+        // While the cache is isolated, the stores don't reach the memory
+        if self.status & 0x10000 != 0
+        {
+            let cache_control = mem.cache_control();
+
+            if cache_control & icache::CONTROL_ENABLE != 0
+            {
+                self.icache.store(address, value.as_u32(), cache_control & icache::CONTROL_TAG_TEST != 0);
+            }
+
+            self.isolated_stores = true;
+
+            return;
+        }
+
+        if let Some(log) = &mut self.write_log
+        {
+            log.push(MemoryWrite { address, value: value.as_u32(), size: std::mem::size_of::<T>() as u8 });
+        }
+
+        if mem.is_device(address)
+        {
+            self.catch_up_devices(mem);
+        }
+
+        mem.write::<T>(address, value);
+
+        if mem.has_bus_error()
+        {
+            self.bus_error(mem, Exception::DataBusError, address);
+        }
+    }
+
+    // The devices must see all the instructions before this access
+    fn catch_up_devices(&mut self, mem: &mut Memory)
+    {
+        if self.deferred_cycles != 0
+        {
+            self.tick_deferred(mem);
+        }
+
+        self.device_access = true;
+    }
+
+    fn bus_error(&mut self, mem: &Memory, exception: Exception, address: u32)
+    {
+        match mem.bus_error_policy
+        {
+            BusErrorPolicy::Debugger => self.debugger.register_bus_error(address),
+            _                        => self.exception(exception)
+        }
     }
 
     fn illegal(&mut self, opcode: &Opcode)
@@ -607,20 +1725,30 @@ impl CPU
     {
         trace!("COP0 MFC | COP R{} -> R{}", opcode.rd(), opcode.rt());
 
+        if !self.is_coprocessor_usable(0)
+        {
+            self.coprocessor_unusable(0);
+            return;
+        }
+
         let value = match opcode.rd()
         {
+            3 => self.cop0_bpc,
+            5 => self.cop0_bda,
             6 => { warn!("COP JUMPDEST READ not implemented"); 0 },
-            7 => { warn!("COP DCIC READ not implemented"); 0 },
+            7 => self.cop0_dcic,
             8 => self.cop0_badaddr,
+            9 => self.cop0_bdam,
+            11 => self.cop0_bpcm,
             12 => self.status,
-            13 =>
-            {
-                //write!(&mut self.log_file, "CAUSE READ {} {:08x}\n", self.counter, self.cop0_cause).unwrap();
-                self.cop0_cause
-            },
+            13 => self.cop0_cause,
             14 => self.cop0_epc,
             15 => 0x00000002, // Processor ID
-            _  => panic!("Unsupported cop0 register: {:08x}", opcode.rd())
+            _  =>
+            {
+                warn!("Read from unknown COP0 register R{}", opcode.rd());
+                0
+            }
         };
 
         // Put in the load-delay slot
@@ -631,26 +1759,46 @@ impl CPU
     {
         trace!("COP0 MTC | R{} = {:08x} -> COP R{}", opcode.rt(), self.reg(opcode.rt()), opcode.rd());
 
+        if !self.is_coprocessor_usable(0)
+        {
+            self.coprocessor_unusable(0);
+            return;
+        }
+
+        let value = self.reg(opcode.rt());
+
         match opcode.rd()
         {
-            3 | 5 | 6 | 7| 9 | 11 => warn!("Ignoring write to CR{}", opcode.rd()),
+            3 => self.cop0_bpc = value,
+            5 => self.cop0_bda = value,
+            7 => self.cop0_dcic = value & DCIC_WRITABLE,
+            9 => self.cop0_bdam = value,
+            11 => self.cop0_bpcm = value,
+
+            12 =>
+            {
+                self.status = value;
+
+                // The cache has been flushed, new code is probably coming
+                if self.isolated_stores && value & 0x10000 == 0
+                {
+                    self.isolated_stores = false;
+                    self.blocks.clear();
 
-            12 => {
-                self.status = self.reg(opcode.rt());
-                //write!(&mut self.log_file, "write STATUS {:08x} {:08x} {:x}\n", self.status, self.current_pc, self.counter);
+                    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+                    {
+                        self.jit.flush_requested = true;
+                    }
+                }
             },
 
             13 =>
             {
-                //let v = self.reg(opcode.rt());
-                //write!(&mut self.log_file, "CAUSE WRITE {} {:08x}\n", self.counter, v).unwrap();
-
-                // Only SW (9-8) are writeable
-                error!("CAUSE {:08x}", opcode.rd());
-                self.cop0_cause = (self.cop0_cause & !0x300) | (self.reg(opcode.rt()) & 0x300);
+                // Only the software interrupts (9-8) are writeable
+                self.cop0_cause = (self.cop0_cause & !0x300) | (value & 0x300);
             },
 
-            _  => panic!("Unsupported cop0 register: {:08x}", opcode.rd())
+            _ => warn!("Ignoring write to COP0 register R{}", opcode.rd())
         };
     }
 
@@ -658,21 +1806,32 @@ impl CPU
     {
         trace!("COP0 RFE");
 
+        if !self.is_coprocessor_usable(0)
+        {
+            self.coprocessor_unusable(0);
+            return;
+        }
+
         // Shift the interrupt stack to the right (but the "old" values at the left remain unchanged)
         self.status = (self.status & !0b001111) | ((self.status >> 2) & 0b001111);
-        //write!(&mut self.log_file, "RFE STATUS {:08x} {:08x} {:x}\n", self.status, self.current_pc, self.counter);
     }
 
     fn cop1(&mut self)
     {
         trace!("COP1");
 
-        self.exception(Exception::CoprocessorError);
+        self.missing_coprocessor(1);
     }
 
     fn cop2(&mut self)
     {
-        trace!("COP1");
+        trace!("COP2");
+
+        if !self.is_coprocessor_usable(2)
+        {
+            self.coprocessor_unusable(2);
+            return;
+        }
 
         panic!("GTE not implemented");
     }
@@ -681,9 +1840,60 @@ impl CPU
     {
         trace!("COP3");
 
+        self.missing_coprocessor(3);
+    }
+
+    // COP0 is always usable in kernel mode, the others must be enabled in the status register
+    fn is_coprocessor_usable(&self, coprocessor: u32) -> bool
+    {
+        (coprocessor == 0 && self.status & STATUS_USER_MODE == 0) ||
+        self.status & (STATUS_COP0_ENABLE << coprocessor) != 0
+    }
+
+    fn coprocessor_unusable(&mut self, coprocessor: u32)
+    {
+        // CE field: number of the coprocessor
+        self.cop0_cause = (self.cop0_cause & !(3 << 28)) | (coprocessor << 28);
         self.exception(Exception::CoprocessorError);
     }
 
+    // COP1 and COP3 don't exist on the PSX
+    fn missing_coprocessor(&mut self, coprocessor: u32)
+    {
+        if self.is_coprocessor_usable(coprocessor)
+        {
+            warn!("Ignoring instruction for missing COP{}", coprocessor);
+        }
+        else
+        {
+            self.coprocessor_unusable(coprocessor);
+        }
+    }
+
+    fn is_code_breakpoint(&self, address: u32) -> bool
+    {
+        self.cop0_dcic & (DCIC_MASTER_ENABLE | DCIC_CODE_ENABLE) == DCIC_MASTER_ENABLE | DCIC_CODE_ENABLE &&
+        (address ^ self.cop0_bpc) & self.cop0_bpcm == 0
+    }
+
+    // `access` is either DCIC_DATA_READ_ENABLE or DCIC_DATA_WRITE_ENABLE
+    fn is_data_breakpoint(&self, address: u32, access: u32) -> bool
+    {
+        let enable = DCIC_MASTER_ENABLE | DCIC_DATA_ENABLE | access;
+
+        self.cop0_dcic & enable == enable &&
+        (address ^ self.cop0_bda) & self.cop0_bdam == 0
+    }
+
+    // Hardware breakpoint hit, used by cheat devices and debuggers
+    fn debug_break(&mut self, hit: u32)
+    {
+        info!("COP0 breakpoint @ {:08X}", self.current_pc);
+
+        self.cop0_dcic |= DCIC_ANY_BREAK | hit;
+        self.raise_exception_at(Exception::Break, self.current_pc, 0x40);
+    }
+
     fn j(&mut self, opcode: &Opcode)
     {
         trace!("J _ {:08x}", opcode.imm26());
@@ -751,7 +1961,11 @@ impl CPU
         }
 
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
-        let result = self.read::<u8>(mem, address) as i8;
+        let result = match self.read::<u8>(mem, address)
+        {
+            Some(value) => value as i8,
+            None        => return // Bus error
+        };
 
         let rt = opcode.rt();
 
@@ -777,7 +1991,11 @@ impl CPU
 
 
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
-        let result = self.read::<u8>(mem, address);
+        let result = match self.read::<u8>(mem, address)
+        {
+            Some(value) => value,
+            None        => return // Bus error
+        };
 
         let rt = opcode.rt();
 
@@ -804,7 +2022,11 @@ impl CPU
 
         if address % 2 == 0
         {
-            let result = self.read::<u16>(mem, address) as i16;
+            let result = match self.read::<u16>(mem, address)
+            {
+                Some(value) => value as i16,
+                None        => return // Bus error
+            };
 
             let rt = opcode.rt();
 
@@ -836,7 +2058,11 @@ impl CPU
 
         if address % 2 == 0
         {
-            let result = self.read::<u16>(mem, address);
+            let result = match self.read::<u16>(mem, address)
+            {
+                Some(value) => value,
+                None        => return // Bus error
+            };
 
             let rt = opcode.rt();
 
@@ -876,7 +2102,11 @@ impl CPU
                 self.r_next[rt as usize] = self.r[rt as usize];
             }
 
-            let value = self.read::<u32>(mem, address);
+            let value = match self.read::<u32>(mem, address)
+            {
+                Some(value) => value,
+                None        => return // Bus error
+            };
             self.pending_load = (rt, value); // in the load-delay slot
         }
         else
@@ -894,7 +2124,11 @@ impl CPU
         // Bypass the load-delay slot
         let value = self.r_next[opcode.rt() as usize];
 
-        let aligned_value = self.read::<u32>(mem, address & !3);
+        let aligned_value = match self.read::<u32>(mem, address & !3)
+        {
+            Some(value) => value,
+            None        => return // Bus error
+        };
 
         let result = match address & 3
         {
@@ -925,7 +2159,11 @@ impl CPU
         // Load-delay slot
         let value = self.r_next[opcode.rt() as usize];
 
-        let aligned_value = self.read::<u32>(mem, address & !3);
+        let aligned_value = match self.read::<u32>(mem, address & !3)
+        {
+            Some(value) => value,
+            None        => return // Bus error
+        };
 
         let result = match address & 3
         {
@@ -1090,12 +2328,6 @@ impl CPU
     {
         trace!("SB _ R{}={:08x} -> {:08x}(R{}={:08x})={:08x}", opcode.rt(), self.reg(opcode.rt()), opcode.imm_se(), opcode.rs(), self.reg(opcode.rs()), self.reg(opcode.rs()).wrapping_add(opcode.imm_se()));
 
-        if self.status & 0x10000 != 0
-        {
-            debug!("Cache is isolated, ignoring");
-            return;
-        }
-
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
         let value = self.reg(opcode.rt()) as u8;
         self.write::<u8>(mem, address, value);
@@ -1105,12 +2337,6 @@ impl CPU
     {
         trace!("SH _ R{}={:08x} -> {:08x}(R{}={:08x})={:08x}", opcode.rt(), self.reg(opcode.rt()), opcode.imm_se(), opcode.rs(), self.reg(opcode.rs()), self.reg(opcode.rs()).wrapping_add(opcode.imm_se()));
 
-        if self.status & 0x10000 != 0
-        {
-            trace!("Cache is isolated, ignoring");
-            return;
-        }
-
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
 
         if address % 2 == 0
@@ -1128,12 +2354,6 @@ impl CPU
     {
         trace!("SW _ R{}={:08x} -> {:08x}(R{}={:08x})={:08x}", opcode.rt(), self.reg(opcode.rt()), opcode.imm_se(), opcode.rs(), self.reg(opcode.rs()), self.reg(opcode.rs()).wrapping_add(opcode.imm_se()));
 
-        if self.status & 0x10000 != 0
-        {
-            trace!("Cache is isolated, ignoring");
-            return;
-        }
-
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
 
         if address % 4 == 0
@@ -1153,7 +2373,11 @@ impl CPU
 
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
         let aligned_address = address & !3;
-        let aligned_value = self.read::<u32>(mem, aligned_address);
+        let aligned_value = match self.read::<u32>(mem, aligned_address)
+        {
+            Some(value) => value,
+            None        => return // Bus error
+        };
 
         let result = match address & 3
         {
@@ -1164,7 +2388,6 @@ impl CPU
             _ => unreachable!()
         };
 
-        //write!(&mut self.log_file, "SWL addr {:08x} = {:08x}, reg {:08x} -> {:08x}\n", address, aligned_value, value, result).unwrap();
 
         self.write::<u32>(mem, aligned_address, result);
     }
@@ -1175,7 +2398,11 @@ impl CPU
 
         let address = self.reg(opcode.rs()).wrapping_add(opcode.imm_se());
         let aligned_address = address & !3;
-        let aligned_value = self.read::<u32>(mem, aligned_address);
+        let aligned_value = match self.read::<u32>(mem, aligned_address)
+        {
+            Some(value) => value,
+            None        => return // Bus error
+        };
 
         let result = match address & 3
         {
@@ -1186,9 +2413,8 @@ impl CPU
             _ => unreachable!()
         };
 
-        //write!(&mut self.log_file, "SWR addr {:08x} = {:08x}, reg {:08x} -> {:08x}\n", address, aligned_value, value, result).unwrap();
 
-        mem.write(aligned_address, result);
+        self.write::<u32>(mem, aligned_address, result);
     }
 
     fn mflo(&mut self, opcode: &Opcode)
@@ -1233,7 +2459,12 @@ impl CPU
 
     fn raise_exception(&mut self, exception: Exception, epc: u32)
     {
-        //write!(&mut self.log_file, "EXCEPTION {}\n", exception.clone() as i32).unwrap();
+        self.raise_exception_at(exception, epc, 0x80);
+    }
+
+    // Jumps to the exception handler at `offset` in the vector area (0x40 for breakpoints, 0x80 otherwise)
+    fn raise_exception_at(&mut self, exception: Exception, epc: u32, offset: u32)
+    {
         error!("  EXCEPTION {:?}", &exception);
         //error!("  PC {:08X}", self.pc);
 
@@ -1242,10 +2473,6 @@ error!("EPC = {:08X}", self.cop0_epc);
         // Update the exception's cause in the CAUSE register
         self.cop0_cause = (self.cop0_cause & !0x7C) | ((exception as u32) << 2);
 
-        // TODO cause
-        // BD if we were in the branch delay slot (31)
-        // CE coprocessor number? (29-28)
-
         // Special case when branching:
         //   - the branch instruction is put in EPC instead of the current one
         //   - bit 31 of CAUSE is set
@@ -1254,13 +2481,16 @@ error!("EPC = {:08X}", self.cop0_epc);
             self.cop0_epc = self.cop0_epc.wrapping_sub(4);
             self.cop0_cause |= 1 << 31;
         }
+        else
+        {
+            self.cop0_cause &= !(1 << 31);
+        }
 
         // Stack the exception
         self.status = (self.status & !0x3F) | ((self.status << 2) & 0x3F);
-        //write!(&mut self.log_file, "exception STATUS {:08x} {:08x} {:x}\n", self.status, self.current_pc, self.counter);
 
-        // Two possible handler addresses depending on the status' BEV bit
-        let handler = if (self.status & (1 << 22)) != 0 { 0xBFC00180 } else { 0x80000080 };
+        // Two possible vector areas depending on the status' BEV bit
+        let handler = if (self.status & STATUS_BEV) != 0 { 0xBFC00100 + offset } else { 0x80000000 + offset };
 
         //error!("  NPC {:08X}", handler);
 
@@ -1284,41 +2514,131 @@ error!("EPC = {:08X}", self.cop0_epc);
 
     fn cop0_lwc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.coprocessor_unusable(0);
     }
 
     fn cop1_lwc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.missing_coprocessor(1);
     }
 
     fn cop2_lwc(&mut self)
     {
+        if !self.is_coprocessor_usable(2)
+        {
+            self.coprocessor_unusable(2);
+            return;
+        }
+
         panic!("unsupported cop2_lwc");
     }
 
     fn cop3_lwc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.missing_coprocessor(3);
     }
 
     fn cop0_swc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.coprocessor_unusable(0);
     }
 
     fn cop1_swc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.missing_coprocessor(1);
     }
 
     fn cop2_swc(&mut self)
     {
+        if !self.is_coprocessor_usable(2)
+        {
+            self.coprocessor_unusable(2);
+            return;
+        }
+
         panic!("unsupported cop2_swc");
     }
 
     fn cop3_swc(&mut self)
     {
-        self.exception(Exception::CoprocessorError);
+        self.missing_coprocessor(3);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bios::BIOS;
+    use crate::memory::RAM_READ_CYCLES;
+
+    const UNMAPPED: u32 = 0x1F90_0000;
+
+    // Runs `lw $8, 0($9)` at 0x80001000 with $9 pointing to nothing
+    fn load_from_unmapped(policy: BusErrorPolicy) -> (CPU, Memory)
+    {
+        let interrupt_controller = Rc::new(RefCell::new(InterruptController::new()));
+        let mut cpu = CPU::new(&interrupt_controller);
+        let mut mem = Memory::new(BIOS::hle(), None, &interrupt_controller);
+
+        mem.bus_error_policy = policy;
+        mem.write::<u32>(0x8000_1000, 0x8D28_0000);
+
+        cpu.status = 0;
+        cpu.pc = 0x8000_1000;
+        cpu.next_pc = 0x8000_1004;
+        cpu.r[9] = UNMAPPED;
+
+        cpu.step(&mut mem);
+
+        (cpu, mem)
+    }
+
+    #[test]
+    fn bus_error_policies()
+    {
+        let (cpu, _) = load_from_unmapped(BusErrorPolicy::Strict);
+        assert_eq!(cpu.pc, 0x8000_0080);
+        assert_eq!(cpu.cop0_epc, 0x8000_1000);
+        assert_eq!((cpu.cop0_cause >> 2) & 0x1F, Exception::DataBusError as u32);
+
+        let (cpu, _) = load_from_unmapped(BusErrorPolicy::Lenient);
+        assert_eq!(cpu.pc, 0x8000_1004);
+        assert_eq!(cpu.cop0_cause, 0);
+
+        let (cpu, _) = load_from_unmapped(BusErrorPolicy::Debugger);
+        assert_eq!(cpu.cop0_cause, 0);
+        assert_eq!(cpu.debugger.get_bus_error_hit(), Some(UNMAPPED));
+    }
+
+    #[test]
+    fn instruction_bus_error()
+    {
+        let (mut cpu, mut mem) = load_from_unmapped(BusErrorPolicy::Strict);
+
+        cpu.pc = UNMAPPED;
+        cpu.next_pc = UNMAPPED + 4;
+        cpu.step(&mut mem);
+
+        assert_eq!(cpu.pc, 0x8000_0080);
+        assert_eq!(cpu.cop0_epc, UNMAPPED);
+        assert_eq!((cpu.cop0_cause >> 2) & 0x1F, Exception::InstructionBusError as u32);
+    }
+
+    #[test]
+    fn load_charges_the_wait_states()
+    {
+        let (mut cpu, mut mem) = load_from_unmapped(BusErrorPolicy::Strict);
+
+        mem.write::<u32>(0x8000_2000, 0x1234_5678);
+        cpu.pc = 0x8000_1000;
+        cpu.next_pc = 0x8000_1004;
+        cpu.r[9] = 0x8000_2000;
+
+        let cycles = cpu.cycles;
+        cpu.step(&mut mem);
+
+        // The uncached fetch and the load both read the RAM
+        assert_eq!(cpu.cycles - cycles, (CYCLES_PER_INSTRUCTION + 2 * RAM_READ_CYCLES) as u64);
     }
-}
\ No newline at end of file
+}
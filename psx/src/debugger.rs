@@ -3,9 +3,12 @@ use crate::memory::Memory;
 use crate::opcode::Opcode;
 
 use serde::{ Serialize, Deserialize };
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{ Read, Write };
 
+const MAX_SYMBOL_OFFSET: u32 = 0x1_0000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegisterCondition
 {
@@ -73,7 +76,10 @@ pub struct Disassembly
     pub mnemonics: String,
 
     // Additional description such as the actual jump address
-    pub hint: String
+    pub hint: String,
+
+    // Closest symbol before the instruction, e.g. "main+0x10"
+    pub location: Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -84,7 +90,15 @@ pub struct Debugger
 
     // Data breakpoints hit since the latest check
     #[serde(skip)]
-    data_breakpoints_hit: Vec<u32>
+    data_breakpoints_hit: Vec<u32>,
+
+    // Address of the access that caused a bus error since the latest check
+    #[serde(skip)]
+    bus_error_hit: Option<u32>,
+
+    // Names of the functions and variables, loaded with the program
+    #[serde(skip)]
+    symbols: BTreeMap<u32, String>
 }
 
 impl Debugger
@@ -95,7 +109,9 @@ impl Debugger
         {
             breakpoints: Vec::new(),
             data_breakpoints: Vec::new(),
-            data_breakpoints_hit: Vec::new()
+            data_breakpoints_hit: Vec::new(),
+            bus_error_hit: None,
+            symbols: BTreeMap::new()
         }
     }
 
@@ -189,6 +205,12 @@ impl Debugger
             (b.register_conditions.is_empty() || b.register_conditions.iter().any(|c| c.is_matched(cpu))))
     }
 
+    // True if the instructions must be checked one by one
+    pub fn has_breakpoints(&self) -> bool
+    {
+        self.breakpoints.iter().any(|b| b.enabled) || !self.data_breakpoints.is_empty()
+    }
+
     pub fn get_breakpoints(&self) -> &[Breakpoint]
     {
         self.breakpoints.as_slice()
@@ -230,11 +252,22 @@ impl Debugger
     pub fn clear_data_access(&mut self)
     {
         self.data_breakpoints_hit.clear();
+        self.bus_error_hit = None;
     }
 
     pub fn has_data_breakpoint(&self) -> bool
     {
-        !self.data_breakpoints_hit.is_empty()
+        !self.data_breakpoints_hit.is_empty() || self.bus_error_hit.is_some()
+    }
+
+    pub fn register_bus_error(&mut self, address: u32)
+    {
+        self.bus_error_hit = Some(address);
+    }
+
+    pub fn get_bus_error_hit(&self) -> Option<u32>
+    {
+        self.bus_error_hit
     }
 
     pub fn is_data_breakpoint(&self, address: u32) -> bool
@@ -242,6 +275,40 @@ impl Debugger
         self.data_breakpoints_hit.iter().any(|a| *a == address)
     }
 
+    // Symbols
+
+    pub fn add_symbols(&mut self, symbols: &[(u32, String)])
+    {
+        for (address, name) in symbols
+        {
+            self.symbols.insert(*address, name.clone());
+        }
+    }
+
+    pub fn clear_symbols(&mut self)
+    {
+        self.symbols.clear();
+    }
+
+    pub fn find_symbol(&self, name: &str) -> Option<u32>
+    {
+        self.symbols.iter().find(|(_, n)| *n == name).map(|(address, _)| *address)
+    }
+
+    // Name of the closest symbol before the address, with the offset from it.
+    // The sizes aren't known for all the formats, the addresses too far from a symbol aren't named.
+    pub fn symbolize(&self, address: u32) -> Option<String>
+    {
+        let (symbol_address, name) = self.symbols.range(..= address).next_back()?;
+
+        match address - symbol_address
+        {
+            0                                     => Some(name.clone()),
+            offset if offset <= MAX_SYMBOL_OFFSET => Some(format!("{}+0x{:X}", name, offset)),
+            _                                     => None
+        }
+    }
+
     // Disassembly
 
     pub fn disassemble(&self, pc: u32, cpu: &CPU, mem: &mut Memory) -> Disassembly
@@ -375,7 +442,8 @@ impl Debugger
         {
             bits,
             mnemonics,
-            hint
+            hint,
+            location: self.symbolize(pc)
         }
     }
 }
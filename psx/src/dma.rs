@@ -1,15 +1,26 @@
+use crate::cdrom::CDROM;
 use crate::gpu::GPU;
-use crate::memory::{ Addressable, Width };
+use crate::interrupt_controller::{ InterruptController, InterruptRequest };
+use crate::mdec::MDEC;
+use crate::memory::{ Addressable, Width, CODE_PAGE_SHIFT };
 use crate::memory_segment::MemorySegment;
+use crate::spu::SPU;
 
-#[derive(Debug, Copy, Clone)]
+use serde::{ Serialize, Deserialize };
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Approximate bus time of a DMA transfer
+const CYCLES_PER_WORD: u32 = 1;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum TransferDirection
 {
     ToRAM = 0,
     FromRAM = 1
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum SyncMode
 {
     Manual = 0,
@@ -18,6 +29,7 @@ enum SyncMode
 }
 
 //#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
 pub struct Channel
 {
     // Base address
@@ -43,7 +55,15 @@ pub struct Channel
     sync_mode: SyncMode,
     chopping_enable: bool,
     increment: bool, // decrement if false
-    direction: TransferDirection
+    direction: TransferDirection,
+
+    // Progress of the current transfer
+
+    started: bool,
+    current_address: u32,
+    words_remaining: u32, // In the current block
+    blocks_remaining: u32,
+    cpu_window_cycles: u32 // Cycles left to the CPU before the next chopped burst
 }
 
 impl Channel
@@ -65,7 +85,13 @@ impl Channel
             sync_mode: SyncMode::Manual,
             chopping_enable: false,
             increment: true,
-            direction: TransferDirection::ToRAM
+            direction: TransferDirection::ToRAM,
+
+            started: false,
+            current_address: 0,
+            words_remaining: 0,
+            blocks_remaining: 0,
+            cpu_window_cycles: 0
         }
     }
 
@@ -111,6 +137,12 @@ impl Channel
         self.chopping_enable = ((value >> 8) & 1) != 0;
         self.increment = ((value >> 1) & 1) == 0;
         self.direction = if (value & 1) == 0 {TransferDirection::ToRAM} else {TransferDirection::FromRAM};
+
+        // Clearing the enable bit stops the transfer
+        if !self.enable
+        {
+            self.started = false;
+        }
     }
 
     pub fn is_active(&self) -> bool
@@ -124,6 +156,31 @@ impl Channel
 
         self.enable && trigger
     }
+
+    // Number of words in a block
+    fn block_size(&self) -> u32
+    {
+        match self.transfer_size
+        {
+            0 => 0x10000,
+            n => n as u32
+        }
+    }
+
+    fn start(&mut self)
+    {
+        self.started = true;
+        self.current_address = self.base_address;
+        self.words_remaining = self.block_size();
+        self.cpu_window_cycles = 0;
+
+        self.blocks_remaining = match (self.sync_mode, self.block_count)
+        {
+            (SyncMode::Request, 0) => 0x10000,
+            (SyncMode::Request, n) => n as u32,
+            _                      => 1
+        };
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -156,6 +213,83 @@ impl Port
     }
 }
 
+// The components that the DMA channels transfer data to and from
+pub struct Devices<'a>
+{
+    pub ram: &'a mut MemorySegment,
+    pub code_pages: &'a mut [bool], // Watched by the CPU, see Memory::watch_code_page
+    pub invalidated_code_pages: &'a mut Vec<u32>,
+    pub mdec: &'a mut MDEC,
+    pub gpu: &'a mut GPU,
+    pub cdrom: &'a mut CDROM,
+    pub spu: &'a mut SPU
+}
+
+impl<'a> Devices<'a>
+{
+    // Stores a word in RAM, the CPU drops the code compiled from the page
+    fn write_ram(&mut self, address: u32, value: u32)
+    {
+        let page = (address >> CODE_PAGE_SHIFT) as usize;
+
+        if self.code_pages[page]
+        {
+            self.code_pages[page] = false;
+            self.invalidated_code_pages.push(page as u32);
+        }
+
+        self.ram.write::<u32>(address, value);
+    }
+
+    // Sends a word from RAM to a device
+    fn write(&mut self, port: Port, value: u32)
+    {
+        match port
+        {
+            Port::MDECin => self.mdec.write_command(value),
+            Port::GPU    => self.gpu.gp0(value),
+            Port::SPU    =>
+            {
+                self.spu.write_data(value as u16);
+                self.spu.write_data((value >> 16) as u16);
+            },
+            x => warn!("unsupported DMA transfer from RAM to {:?}", x)
+        }
+    }
+
+    // In Request mode, the devices ask for a new block when they can handle it
+    fn is_ready(&self, port: Port, words: u32) -> bool
+    {
+        match port
+        {
+            Port::MDECin  => self.mdec.input_ready(),
+            Port::MDECout => self.mdec.output_len() >= words as usize,
+            Port::CDROM   => self.cdrom.data_len() >= words as usize * 4,
+
+            // The GPU and the SPU FIFOs are drained faster than the DMA fills them
+            _             => true
+        }
+    }
+
+    // Reads a word from a device to be stored in RAM
+    fn read(&mut self, port: Port) -> u32
+    {
+        match port
+        {
+            Port::MDECout => self.mdec.read_data(),
+            Port::GPU     => self.gpu.read(),
+            Port::CDROM   => self.cdrom.read_data_word(),
+            Port::SPU     => self.spu.read_data() as u32 | (self.spu.read_data() as u32) << 16,
+            x =>
+            {
+                warn!("unsupported DMA transfer from {:?} to RAM", x);
+                0xFFFFFFFF
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct DMA
 {
     channels: [Channel; 7],
@@ -167,12 +301,16 @@ pub struct DMA
     irq_channel_enable: u8,
     irq_channel_status: u8,
     irq_force: bool,
-    irq_unknown: u8
+    irq_unknown: u8,
+    irq_master_flag: bool, // to request an interrupt when it gets set
+
+    #[serde(skip)]
+    interrupt_controller: Rc<RefCell<InterruptController>>
 }
 
 impl DMA
 {
-    pub fn new() -> DMA
+    pub fn new(interrupt_controller: &Rc<RefCell<InterruptController>>) -> DMA
     {
         DMA
         {
@@ -184,10 +322,20 @@ impl DMA
             irq_channel_enable: 0,
             irq_channel_status: 0,
             irq_force: false,
-            irq_unknown: 0
+            irq_unknown: 0,
+            irq_master_flag: false,
+
+            interrupt_controller: interrupt_controller.clone()
         }
     }
 
+    // The interrupt controller is shared, it is restored separately
+    pub fn load_state(&mut self, mut state: DMA)
+    {
+        state.interrupt_controller = self.interrupt_controller.clone();
+        *self = state;
+    }
+
     pub fn read<T: Addressable>(&self, offset: u32) -> T
     {
         if T::width() != Width::Word
@@ -222,7 +370,7 @@ impl DMA
         }
     }
 
-    pub fn write<T: Addressable>(&mut self, offset: u32, value: T, ram: &mut MemorySegment, gpu: &mut GPU)
+    pub fn write<T: Addressable>(&mut self, offset: u32, value: T)
     {
         if T::width() != Width::Word
         {
@@ -245,11 +393,6 @@ impl DMA
                     8 => channel.set_control_register(value),
                     _ => panic!("unsuppported DMA channel write {:08X} @ {:08X}", value, offset)
                 }
-
-                if channel.is_active()
-                {
-                    self.transfer(port, ram, gpu);
-                }
             },
 
             0x70 => self.control = value,
@@ -269,11 +412,22 @@ impl DMA
         &mut self.channels[port as usize]
     }
 
-    fn interrupt_register(&self) -> u32
+    // A transfer can start if the channel is active and enabled in the control register
+    fn is_ready(&self, port: Port) -> bool
     {
-        let master_flag = self.irq_force || (self.irq_enable && (self.irq_channel_enable & self.irq_channel_status) != 0);
+        let enabled = (self.control >> (port as u32 * 4 + 3)) & 1 != 0;
 
-        (master_flag as u32) << 31 |
+        enabled && self.channel(port).is_active()
+    }
+
+    fn interrupt_master_flag(&self) -> bool
+    {
+        self.irq_force || (self.irq_enable && (self.irq_channel_enable & self.irq_channel_status) != 0)
+    }
+
+    fn interrupt_register(&self) -> u32
+    {
+        (self.interrupt_master_flag() as u32) << 31 |
         (self.irq_channel_status as u32) << 24 |
         (self.irq_enable as u32) << 23 |
         (self.irq_channel_enable as u32) << 16 |
@@ -291,166 +445,396 @@ impl DMA
         // Write 1 to flag -> reset it
         let reset = ((value >> 24) & 0x7F) as u8;
         self.irq_channel_status &= !reset;
+
+        self.update_interrupt();
     }
 
-    fn transfer(&mut self, port: Port, ram: &mut MemorySegment, gpu: &mut GPU)
+    // Requests an interrupt when the master flag goes from 0 to 1
+    fn update_interrupt(&mut self)
     {
-        match self.channel(port).sync_mode
+        let master_flag = self.interrupt_master_flag();
+
+        if master_flag && !self.irq_master_flag
         {
-            SyncMode::LinkedList => self.transfer_linked_list(port, ram, gpu),
-            _                    => self.transfer_block(port, ram, gpu)
+            self.interrupt_controller.borrow_mut().request(InterruptRequest::DMA);
         }
+
+        self.irq_master_flag = master_flag;
     }
 
-    fn transfer_block(&mut self, port: Port, ram: &mut MemorySegment, gpu: &mut GPU)
+    // True while a channel is transferring or waiting for its device
+    pub fn is_running(&self) -> bool
     {
-        let channel = self.channel_mut(port);
+        (0 .. 7).any(|index| self.is_ready(Port::from_index(index)))
+    }
 
-        // For now, copy everything in one shot
+    // Runs the active channels alongside the CPU for the given number of cycles.
+    // Returns the number of cycles during which the DMA took the bus from the CPU.
+    pub fn tick(&mut self, cycles: u32, devices: &mut Devices) -> u32
+    {
+        let mut stall_cycles = 0;
 
-        let mut blocks = match channel.sync_mode
+        for index in 0 .. 7
         {
-            SyncMode::Manual  => channel.transfer_size,
-            SyncMode::Request => channel.transfer_size * channel.block_count,
-            _ => panic!("LinkedList not supported in block transfer")
-        };
+            let port = Port::from_index(index);
 
-        info!("DMA transfer {:?} {:?} {} {:X} {}", channel.sync_mode, channel.direction, channel.increment, channel.base_address, blocks);
+            if self.is_ready(port)
+            {
+                stall_cycles += self.run(port, cycles, devices);
+            }
+        }
 
-        let mut address = channel.base_address;
+        stall_cycles
+    }
 
-        match port
+    fn run(&mut self, port: Port, cycles: u32, devices: &mut Devices) -> u32
+    {
+        let channel = self.channel_mut(port);
+
+        if !channel.started
         {
-            Port::GPU =>
+            channel.start();
+            info!("DMA transfer {:?} {:?} {:?} {} {:X} {}x{}", port, channel.sync_mode, channel.direction, channel.increment, channel.base_address, channel.blocks_remaining, channel.words_remaining);
+        }
+
+        let (words, completed) = match channel.sync_mode
+        {
+            SyncMode::LinkedList => self.transfer_linked_list(port, devices),
+            _ =>
             {
-                match channel.direction
+                // With chopping, the DMA and the CPU take turns on the bus,
+                // otherwise the CPU is stopped until the end of the transfer:
+                // it is done at once, and its cycles are charged to the CPU
+                let max_words = if channel.chopping_enable
                 {
-                    TransferDirection::FromRAM =>
+                    if channel.cpu_window_cycles > cycles
                     {
-                        while blocks > 0
-                        {
-                            let actual_address = address & 0x1FFFFC; // The address must stay in RAM & aligned
+                        channel.cpu_window_cycles -= cycles;
+                        return 0;
+                    }
 
-                            // TODO other channels than OTC
-                            let value = ram.read::<u32>(actual_address);
+                    channel.cpu_window_cycles = 1 << channel.chopping_cpu_window;
+                    1 << channel.chopping_dma_window
+                }
+                else
+                {
+                    u32::MAX
+                };
 
-                            info!("GPU command {:08X}", value);
-                            gpu.gp0(value);
+                self.transfer_block(port, max_words, devices)
+            }
+        };
+
+        if completed
+        {
+            self.complete(port);
+        }
+
+        words * CYCLES_PER_WORD
+    }
+
+    // Resets the channel and flags the interrupt
+    fn complete(&mut self, port: Port)
+    {
+        let channel = self.channel_mut(port);
+        channel.enable = false;
+        channel.trigger = false;
+        channel.started = false;
+
+        let flag = 1 << (port as u8);
 
-                            //address = address.wrapping_add(step);
-                            address = if channel.increment { address.wrapping_add(4) } else { address.wrapping_sub(4) };
-                            blocks -= 1;
-                        }
-                    },
+        if self.irq_channel_enable & flag != 0
+        {
+            self.irq_channel_status |= flag;
+        }
+
+        self.update_interrupt();
+    }
+
+    // Copies at most max_words words.
+    // Returns the number of words copied and whether the transfer is complete.
+    fn transfer_block(&mut self, port: Port, max_words: u32, devices: &mut Devices) -> (u32, bool)
+    {
+        let channel = &mut self.channels[port as usize];
+        let block_size = channel.block_size();
 
-                    x => panic!("unsupported DMA transfer direction {:?}", x)
+        let mut words = 0;
+
+        while words < max_words && channel.blocks_remaining > 0
+        {
+            // Wait for the device to request the next block
+            if let SyncMode::Request = channel.sync_mode
+            {
+                if channel.words_remaining == block_size && !devices.is_ready(port, block_size)
+                {
+                    break;
                 }
-            },
+            }
 
-            Port::OTC =>
+            let actual_address = channel.current_address & 0x1FFFFC; // The address must stay in RAM & aligned
+
+            match (port, channel.direction)
             {
-                match channel.direction
+                // The OTC channel clears an ordering table: each entry points to the previous one
+                (Port::OTC, _) =>
                 {
-                    TransferDirection::ToRAM =>
+                    let value = match channel.words_remaining
                     {
-                        while blocks > 0
-                        {
-                            let actual_address = address & 0x1FFFFC; // The address must stay in RAM & aligned
+                        1 => 0xFFFFFF, // Last entry: end of the table
+                        _ => actual_address.wrapping_sub(4) & 0x1FFFFF // Pointer to the previous entry
+                    };
 
-                            // TODO other channels than OTC
-                            let value = match blocks
-                            {
-                                1 => 0xFFFFFF, // Last entry: end of the table
-                                _ => actual_address.wrapping_sub(4) & 0x1FFFFF // Pointer to the previous entry
-                            };
+                    devices.write_ram(actual_address, value);
+                },
 
-                            ram.write::<u32>(actual_address, value);
+                (_, TransferDirection::FromRAM) =>
+                {
+                    let value = devices.ram.read::<u32>(actual_address);
+                    devices.write(port, value);
+                },
 
-                            info!("{:0X} {:0X}", actual_address, value);
+                (_, TransferDirection::ToRAM) =>
+                {
+                    let value = devices.read(port);
+                    devices.write_ram(actual_address, value);
+                }
+            }
 
-                            //address = address.wrapping_add(step);
-                            address = if channel.increment { address.wrapping_add(4) } else { address.wrapping_sub(4) };
-                            blocks -= 1;
-                        }
-                    },
+            // The OTC channel always goes backward
+            channel.current_address = if channel.increment && !matches!(port, Port::OTC) { channel.current_address.wrapping_add(4) } else { channel.current_address.wrapping_sub(4) };
+            channel.words_remaining -= 1;
+            words += 1;
 
-                    x => panic!("unsupported DMA transfer direction {:?}", x)
-                }
-            },
+            if channel.words_remaining == 0
+            {
+                channel.blocks_remaining -= 1;
+                channel.words_remaining = block_size;
 
-            x => panic!("unsupported port {:?}", x)
+                // In Request mode, the registers follow the transfer
+                if let SyncMode::Request = channel.sync_mode
+                {
+                    channel.base_address = channel.current_address & 0xFFFFFF;
+                    channel.block_count = channel.blocks_remaining as u16;
+                }
+            }
         }
 
-        // Reset the state
-
-        channel.enable = false;
-        channel.trigger = false;
+        (words, channel.blocks_remaining == 0)
     }
 
-    fn transfer_linked_list(&mut self, port: Port, ram: &mut MemorySegment, gpu: &mut GPU)
+    // Returns the number of words copied (including the headers) and whether the transfer is complete
+    fn transfer_linked_list(&mut self, port: Port, devices: &mut Devices) -> (u32, bool)
     {
         let channel = self.channel_mut(port);
 
         // For now, copy everything in one shot
 
-        info!("DMA transfer {:?} {:?} {:X}", channel.sync_mode, channel.direction, channel.base_address,);
+        // Linked lists are only meant to send GPU commands
+        match (port, channel.direction)
+        {
+            (Port::GPU, TransferDirection::FromRAM) => (),
+            (port, direction) =>
+            {
+                warn!("unsupported DMA linked list transfer {:?} {:?}", port, direction);
+                return (0, true);
+            }
+        }
 
         let mut address = channel.base_address & 0x1FFFFC;
+        let mut words = 0;
 
-        match port
+        // Guard against malformed lists that loop forever
+        let mut remaining_entries = 0x10000;
+
+        loop
         {
-            Port::GPU =>
+            let header = devices.ram.read::<u32>(address);
+            info!("header {:08X}", header);
+            words += 1;
+
+            // Send the commands to the GPU
+
+            let mut word_count = header >> 24;
+            let next_address = header & 0x1FFFFC;
+            info!("word count {}, next {:08X}", word_count, next_address);
+
+            while word_count > 0
             {
-                match channel.direction
-                {
-                    TransferDirection::FromRAM =>
-                    {
-                        loop
-                        {
-                            let header = ram.read::<u32>(address);
-                            info!("header {:08X}", header);
+                address = address.wrapping_add(4) & 0x1FFFFC;
+                let value = devices.ram.read::<u32>(address);
 
-                            // Send the commands to the GPU
+                info!("GPU command {:08X}", value);
+                devices.gpu.gp0(value);
 
-                            let mut word_count = header >> 24;
-                            let next_address = header & 0x1FFFFF; // TODO align?
-                            info!("word count {}, next {:08X}", word_count, next_address);
+                word_count -= 1;
+                words += 1;
+            }
 
-                            while word_count > 0
-                            {
-                                address = address.wrapping_add(4) & 0x1FFFFC;
-                                let value = ram.read::<u32>(address);
+            // Check if we hit the end of the linked list
 
-                                info!("GPU command {:08X}", value);
-                                gpu.gp0(value);
+            remaining_entries -= 1;
 
-                                word_count -= 1;
-                            }
+            if header & 0x800000 != 0 || remaining_entries == 0 // Only the MSB of the pointer is checked (psx-spx, mednafen)
+            {
+                info!("STOP");
+                break;
+            }
 
-                            // Check if we hit the end of the linked list
+            // Go to the next entry in the linked list
 
-                            if (header & 0xFFFFFF) == 0xFFFFFF // the psx guide & mednafen check only the msb though
-                            {
-                                info!("STOP");
-                                break;
-                            }
+            address = next_address;
+        }
 
-                            // Go to the next entry in the linked list
+        channel.base_address = 0xFFFFFF;
 
-                            address = next_address;
-                        }
-                    },
+        (words, true)
+    }
+}
 
-                    x => panic!("unsupported DMA transfer direction {:?}", x)
-                }
-            },
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bios::Region;
+    use crate::memory::RAM_SIZE;
+    use crate::timers::VideoStandard;
 
-            x => panic!("unsupported port {:?}", x)
+    struct Bus
+    {
+        interrupt_controller: Rc<RefCell<InterruptController>>,
+        ram: MemorySegment,
+        code_pages: Vec<bool>,
+        invalidated_code_pages: Vec<u32>,
+        mdec: MDEC,
+        gpu: GPU,
+        cdrom: CDROM,
+        spu: SPU
+    }
+
+    impl Bus
+    {
+        fn new() -> Self
+        {
+            let interrupt_controller = Rc::new(RefCell::new(InterruptController::new()));
+
+            Bus
+            {
+                ram: MemorySegment::new(RAM_SIZE as usize),
+                code_pages: vec![false; (RAM_SIZE >> CODE_PAGE_SHIFT) as usize],
+                invalidated_code_pages: Vec::new(),
+                mdec: MDEC::new(),
+                gpu: GPU::new(None, VideoStandard::NTSC),
+                cdrom: CDROM::new(&interrupt_controller, Region::NorthAmerica),
+                spu: SPU::new(),
+                interrupt_controller
+            }
+        }
+
+        fn devices(&mut self) -> Devices<'_>
+        {
+            Devices
+            {
+                ram: &mut self.ram,
+                code_pages: &mut self.code_pages,
+                invalidated_code_pages: &mut self.invalidated_code_pages,
+                mdec: &mut self.mdec,
+                gpu: &mut self.gpu,
+                cdrom: &mut self.cdrom,
+                spu: &mut self.spu
+            }
         }
+    }
 
-        // Reset the state
+    // Enables the port in the control register and starts a transfer
+    fn start(dma: &mut DMA, port: Port, base_address: u32, block_control: u32, control: u32)
+    {
+        let offset = port as u32 * 0x10;
 
-        channel.enable = false;
-        channel.trigger = false;
+        dma.write::<u32>(0x70, 1 << (port as u32 * 4 + 3));
+        dma.write::<u32>(offset, base_address);
+        dma.write::<u32>(offset + 4, block_control);
+        dma.write::<u32>(offset + 8, control);
+    }
+
+    #[test]
+    fn linked_list_sends_the_commands()
+    {
+        let mut bus = Bus::new();
+        let mut dma = DMA::new(&bus.interrupt_controller);
+
+        // Two entries with a draw mode command each, the second one ends the list
+        bus.ram.write::<u32>(0x100, 0x01000200);
+        bus.ram.write::<u32>(0x104, 0xE1000001);
+        bus.ram.write::<u32>(0x200, 0x01FFFFFF);
+        bus.ram.write::<u32>(0x204, 0xE1000005);
+
+        start(&mut dma, Port::GPU, 0x100, 0, 0x01000401);
+
+        assert_eq!(dma.tick(1, &mut bus.devices()), 4 * CYCLES_PER_WORD);
+        assert_eq!(bus.gpu.status() & 0xF, 5);
+        assert_eq!(dma.read::<u32>(0x20), 0xFFFFFF);
+        assert_eq!(dma.read::<u32>(0x28) & (1 << 24), 0);
+        assert!(!dma.is_running());
+    }
+
+    #[test]
+    fn otc_clears_the_ordering_table()
+    {
+        let mut bus = Bus::new();
+        let mut dma = DMA::new(&bus.interrupt_controller);
+
+        start(&mut dma, Port::OTC, 0x10C, 4, 0x11000002);
+
+        assert_eq!(dma.tick(1, &mut bus.devices()), 4 * CYCLES_PER_WORD);
+        assert_eq!(bus.ram.read::<u32>(0x10C), 0x108);
+        assert_eq!(bus.ram.read::<u32>(0x108), 0x104);
+        assert_eq!(bus.ram.read::<u32>(0x104), 0x100);
+        assert_eq!(bus.ram.read::<u32>(0x100), 0xFFFFFF);
+        assert_eq!(bus.ram.read::<u32>(0xFC), 0);
+    }
+
+    #[test]
+    fn master_flag_requests_the_interrupt()
+    {
+        let mut bus = Bus::new();
+        let mut dma = DMA::new(&bus.interrupt_controller);
+        let dma_interrupt = 1 << InterruptRequest::DMA as u16;
+
+        // Interrupts enabled for the OTC channel
+        dma.write::<u32>(0x74, 1 << 23 | 1 << 22);
+        start(&mut dma, Port::OTC, 0x10C, 4, 0x11000002);
+        dma.tick(1, &mut bus.devices());
+
+        assert_eq!(dma.read::<u32>(0x74) >> 24, 0x80 | 0x40);
+        assert_eq!(bus.interrupt_controller.borrow().read_status() & dma_interrupt, dma_interrupt);
+
+        // Acknowledged
+        bus.interrupt_controller.borrow_mut().write_status(0);
+        dma.write::<u32>(0x74, 1 << 23 | 1 << 22 | 1 << 30);
+        assert_eq!(dma.read::<u32>(0x74) >> 24, 0);
+
+        // Forced
+        dma.write::<u32>(0x74, 1 << 15);
+        assert_eq!(dma.read::<u32>(0x74) >> 31, 1);
+        assert_eq!(bus.interrupt_controller.borrow().read_status() & dma_interrupt, dma_interrupt);
+    }
+
+    #[test]
+    fn chopping_shares_the_bus()
+    {
+        let mut bus = Bus::new();
+        let mut dma = DMA::new(&bus.interrupt_controller);
+
+        // 8 NOPs to the GPU, 2 words at a time and 4 cycles to the CPU in between
+        start(&mut dma, Port::GPU, 0x100, 8, 0x11210101);
+
+        let stalls: Vec<u32> = (0 .. 13).map(|_| dma.tick(1, &mut bus.devices())).collect();
+
+        assert_eq!(stalls, [2, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 2]);
+        assert!(!dma.is_running());
+
+        // Without chopping, the CPU waits for the whole transfer
+        start(&mut dma, Port::GPU, 0x100, 8, 0x11000001);
+        assert_eq!(dma.tick(1, &mut bus.devices()), 8);
     }
 }
@@ -0,0 +1,47 @@
+// Errors reported when a system cannot be created from the given files
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error
+{
+    Io(PathBuf, io::Error), // The file cannot be read
+    InvalidBIOS(PathBuf, String),
+    InvalidExe(PathBuf, String),
+    InvalidDisc(PathBuf, String),
+    InvalidPSF(PathBuf, String),
+    InvalidState(String), // The save state is corrupted, or was made with another BIOS or program
+    CannotSaveState(String)
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Error::Io(path, error)           => write!(f, "cannot read \"{}\": {}", path.display(), error),
+            Error::InvalidBIOS(path, reason) => write!(f, "invalid BIOS \"{}\": {}", path.display(), reason),
+            Error::InvalidExe(path, reason)  => write!(f, "invalid EXE \"{}\": {}", path.display(), reason),
+            Error::InvalidDisc(path, reason) => write!(f, "invalid disc \"{}\": {}", path.display(), reason),
+            Error::InvalidPSF(path, reason)  => write!(f, "invalid PSF \"{}\": {}", path.display(), reason),
+            Error::InvalidState(reason)      => write!(f, "invalid save state: {}", reason),
+            Error::CannotSaveState(reason)   => write!(f, "cannot save the state: {}", reason)
+        }
+    }
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+        match self
+        {
+            Error::Io(_, error) => Some(error),
+            _                   => None
+        }
+    }
+}
@@ -1,104 +1,656 @@
+use crate::bios::Region;
 use crate::cpu::CPU;
+use crate::error::Error;
 use crate::memory::Memory;
 
+use serde::{ Serialize, Deserialize };
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 
+// Executables in the "PS-X EXE" format of the discs, in the ELF format of the
+// homebrew toolchains, or in the CPE format of the old SDK tools
+//
 // Documentation
 //
 // https://problemkaputt.de/psx-spx.htm#cdromfileformats
 // http://www.emulatronia.com/doctec/consolas/psx/exeheader.txt
+// https://refspecs.linuxfoundation.org/elf/elf.pdf
 
-pub struct ExeFile
+const HEADER_SIZE: usize = 0x800;
+const RAM_SIZE: u32 = 2 * 1024 * 1024;
+
+// Start of the ASCII marker of the licensed programs, e.g. "Sony Computer Entertainment Inc. for North America area"
+const MARKER_OFFSET: usize = 0x4C;
+const MARKER_PREFIX: &str = "Sony Computer Entertainment Inc.";
+
+const EXE_MAGIC: &[u8] = b"PS-X EXE";
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const CPE_MAGIC: &[u8] = b"CPE\x01";
+
+// ELF constants
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_MACHINE_MIPS: u16 = 8;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SYMBOL_SIZE: usize = 16;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+// CPE register number of the PC
+const CPE_PC: u16 = 0x90;
+
+// Code or data copied to RAM
+#[derive(Clone, Serialize, Deserialize)]
+struct Segment
 {
+    address: u32,
     data: Vec<u8>
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExeFile
+{
+    segments: Vec<Segment>,
+    pc: u32,
+    gp: u32,
+    memfill: (u32, u32), // (address, size), zeroed before the segments are copied
+    stack: (u32, u32), // (address, size), the BIOS stack is kept if the address is 0
+    marker: String,
+    symbols: Vec<(u32, String)>,
+    hash: u32 // CRC32 of the file, the save states are only loaded with the same program
+}
+
 impl ExeFile
 {
-    pub fn new_from_file(path: PathBuf) -> Self
+    pub fn new_from_file(path: PathBuf) -> Result<Self, Error>
     {
         println!("Loading EXE \"{}\"", path.display());
 
         let mut data = Vec::new();
-        let mut file = File::open(path).unwrap(); //TODO err
-        file.read_to_end(&mut data).unwrap(); // TODO err
 
-        let exe = ExeFile { data };
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|error| Error::Io(path.clone(), error))?;
+
+        let exe = if data.starts_with(EXE_MAGIC)
+        {
+            ExeFile::parse_exe(&data)
+        }
+        else if data.starts_with(ELF_MAGIC)
+        {
+            ExeFile::parse_elf(&data)
+        }
+        else if data.starts_with(CPE_MAGIC)
+        {
+            ExeFile::parse_cpe(&data)
+        }
+        else
+        {
+            Err(String::from("the file does not start with \"PS-X EXE\" and is not an ELF or CPE file"))
+        };
+
+        let exe = exe.map_err(|reason| Error::InvalidExe(path.clone(), reason))?;
 
-        //
+        exe.validate().map_err(|reason| Error::InvalidExe(path.clone(), reason))?;
 
-        if exe.id() != [0x50, 0x53, 0x2D, 0x58, 0x20, 0x45, 0x58, 0x45] // "PS-X EXE"
+        if data.starts_with(EXE_MAGIC) && !exe.marker.starts_with(MARKER_PREFIX)
         {
-            panic!("EXE header does not start with \"PSX-EXE\"");
+            warn!("EXE \"{}\" has no license marker: \"{}\"", path.display(), exe.marker);
         }
 
-        exe
+        Ok(exe)
     }
 
-    pub fn load(&self, cpu: &mut CPU, mem: &mut Memory)
+    // PS-X EXE: 2KB header, followed by the code
+    pub(crate) fn parse_exe(data: &[u8]) -> Result<Self, String>
+    {
+        if data.len() < HEADER_SIZE
+        {
+            return Err(format!("the file is too small for the header ({} bytes)", data.len()));
+        }
+
+        let destination_size = word(data, 0x1C);
+
+        if HEADER_SIZE + destination_size as usize > data.len()
+        {
+            return Err(format!("the header announces {} bytes of code but the file is truncated", destination_size));
+        }
+
+        let marker = data[MARKER_OFFSET .. HEADER_SIZE].iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        Ok(ExeFile
+        {
+            segments: vec![Segment { address: word(data, 0x18), data: data[HEADER_SIZE .. HEADER_SIZE + destination_size as usize].to_vec() }],
+            pc: word(data, 0x10),
+            gp: word(data, 0x14),
+            memfill: (word(data, 0x28), word(data, 0x2C)),
+            stack: (word(data, 0x30), word(data, 0x34)),
+            marker,
+            symbols: Vec::new(),
+            hash: crc32fast::hash(data)
+        })
+    }
+
+    // ELF32 little-endian MIPS: the PT_LOAD segments, with the symbols of .symtab
+    fn parse_elf(data: &[u8]) -> Result<Self, String>
+    {
+        let truncated = || String::from("the ELF file is truncated");
+
+        if data.len() < 0x34
+        {
+            return Err(truncated());
+        }
+
+        if data[4] != ELF_CLASS_32 || data[5] != ELF_DATA_LITTLE_ENDIAN || half(data, 0x12) != ELF_MACHINE_MIPS
+        {
+            return Err(String::from("only the 32-bit little-endian MIPS ELF files can be loaded"));
+        }
+
+        let entry = word(data, 0x18);
+        let program_headers = word(data, 0x1C) as usize;
+        let section_headers = word(data, 0x20) as usize;
+        let program_header_size = half(data, 0x2A) as usize;
+        let program_header_count = half(data, 0x2C) as usize;
+        let section_header_size = half(data, 0x2E) as usize;
+        let section_header_count = half(data, 0x30) as usize;
+
+        // Segments
+
+        let mut segments = Vec::new();
+
+        for index in 0 .. program_header_count
+        {
+            let header = program_headers + index * program_header_size;
+
+            if header + 0x20 > data.len()
+            {
+                return Err(truncated());
+            }
+
+            if word(data, header) != PT_LOAD
+            {
+                continue;
+            }
+
+            let offset = word(data, header + 0x04) as usize;
+            let address = word(data, header + 0x08);
+            let file_size = word(data, header + 0x10) as usize;
+            let memory_size = word(data, header + 0x14) as usize;
+
+            // Checked before allocating the BSS
+            if memory_size.max(file_size) > RAM_SIZE as usize
+            {
+                return Err(format!("the segment at {:08X} is larger than the RAM (size {:08X})", address, memory_size.max(file_size)));
+            }
+
+            let mut segment = data.get(offset .. offset + file_size).ok_or_else(truncated)?.to_vec();
+
+            // The rest (BSS) is zeroed
+            segment.resize(memory_size.max(file_size), 0);
+
+            if !segment.is_empty()
+            {
+                segments.push(Segment { address, data: segment });
+            }
+        }
+
+        if segments.is_empty()
+        {
+            return Err(String::from("the ELF file has no loadable segment"));
+        }
+
+        // Symbols
+
+        let mut symbols = Vec::new();
+
+        for index in 0 .. section_header_count
+        {
+            let header = section_headers + index * section_header_size;
+
+            if header + 0x28 > data.len()
+            {
+                return Err(truncated());
+            }
+
+            if word(data, header + 0x04) != SHT_SYMTAB
+            {
+                continue;
+            }
+
+            let offset = word(data, header + 0x10) as usize;
+            let size = word(data, header + 0x14) as usize;
+            let strings_header = section_headers + word(data, header + 0x18) as usize * section_header_size;
+
+            if strings_header + 0x28 > data.len()
+            {
+                return Err(truncated());
+            }
+
+            let strings_offset = word(data, strings_header + 0x10) as usize;
+            let strings_size = word(data, strings_header + 0x14) as usize;
+            let strings = data.get(strings_offset .. strings_offset + strings_size).ok_or_else(truncated)?;
+
+            for symbol in data.get(offset .. offset + size).ok_or_else(truncated)?.chunks_exact(SYMBOL_SIZE)
+            {
+                let name = word(symbol, 0) as usize;
+                let value = word(symbol, 4);
+                let kind = symbol[12] & 0xF;
+                let section = half(symbol, 14);
+
+                // Undefined symbols, and the names of the source files and sections
+                if name == 0 || section == 0 || kind == STT_SECTION || kind == STT_FILE
+                {
+                    continue;
+                }
+
+                let name: String = strings.get(name ..).unwrap_or(&[]).iter()
+                    .take_while(|c| **c != 0)
+                    .map(|c| *c as char)
+                    .collect();
+
+                if !name.is_empty()
+                {
+                    symbols.push((value, name));
+                }
+            }
+        }
+
+        let gp = symbols.iter().find(|(_, name)| name == "_gp").map_or(0, |(address, _)| *address);
+
+        Ok(ExeFile
+        {
+            segments,
+            pc: entry,
+            gp,
+            memfill: (0, 0),
+            stack: (0, 0),
+            marker: String::new(),
+            symbols,
+            hash: crc32fast::hash(data)
+        })
+    }
+
+    // CPE: a list of chunks, each starting with its type
+    fn parse_cpe(data: &[u8]) -> Result<Self, String>
+    {
+        let mut exe = ExeFile
+        {
+            segments: Vec::new(),
+            pc: 0,
+            gp: 0,
+            memfill: (0, 0),
+            stack: (0, 0),
+            marker: String::new(),
+            symbols: Vec::new(),
+            hash: crc32fast::hash(data)
+        };
+
+        let mut offset = CPE_MAGIC.len();
+
+        // Reads the next bytes of the chunk
+        let mut take = |size: usize| -> Result<&[u8], String>
+        {
+            let bytes = data.get(offset .. offset + size).ok_or_else(|| String::from("the CPE file is truncated"))?;
+            offset += size;
+            Ok(bytes)
+        };
+
+        loop
+        {
+            match take(1)?[0]
+            {
+                0x00 => break, // End of file
+                0x01 => // Load data
+                {
+                    let header = take(8)?;
+                    let (address, size) = (word(header, 0), word(header, 4));
+                    exe.segments.push(Segment { address, data: take(size as usize)?.to_vec() });
+                },
+                0x02 => exe.pc = word(take(4)?, 0), // Run address
+                chunk @ 0x03 ..= 0x06 => // Set register to a 32, 16, 8 or 24-bit value
+                {
+                    let register = half(take(2)?, 0);
+                    let bytes = take([4, 2, 1, 3][chunk as usize - 3])?;
+                    let value = bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32);
+
+                    match register
+                    {
+                        CPE_PC => exe.pc = value,
+                        28     => exe.gp = value,
+                        29     => exe.stack = (value, 0),
+                        _      => warn!("Ignoring the CPE initial value {:08X} of register {:X}", value, register)
+                    }
+                },
+                0x07 => // Select file name
+                {
+                    while take(1)?[0] != 0 {}
+                },
+                0x08 => { take(1)?; }, // Select unit
+                chunk => return Err(format!("unsupported CPE chunk {:02X}", chunk))
+            }
+        }
+
+        if exe.segments.is_empty()
+        {
+            return Err(String::from("the CPE file has no data to load"));
+        }
+
+        Ok(exe)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String>
     {
-        println!("Zeroing @ {:08X}, size = {:08X}", self.memfill_address(), self.memfill_size());
+        for segment in &self.segments
+        {
+            if !ExeFile::is_in_ram(segment.address, segment.data.len() as u32)
+            {
+                return Err(format!("the code does not fit in RAM ({:08X}, size {:08X})", segment.address, segment.data.len()));
+            }
+        }
+
+        if !ExeFile::is_in_ram(self.memfill.0, self.memfill.1)
+        {
+            return Err(format!("the memory fill does not fit in RAM ({:08X}, size {:08X})", self.memfill.0, self.memfill.1));
+        }
+
+        if !ExeFile::is_in_ram(self.pc, 4)
+        {
+            return Err(format!("the entry point {:08X} is not in RAM", self.pc));
+        }
+
+        Ok(())
+    }
 
-        if self.memfill_size() > 0
+    // Loads a library before the program, e.g. the sound driver of a MiniPSF.
+    // The initial registers are taken from the library if `registers` is set.
+    pub(crate) fn add_library(&mut self, library: ExeFile, registers: bool)
+    {
+        if registers
         {
-            panic!("EXE memfill not implemented");
+            self.pc = library.pc;
+            self.gp = library.gp;
+            self.stack = library.stack;
         }
 
-        // TODO make sure it's aligned?
-        /*
-        for address in self.memfill_address() .. self.memfill_address() + self.memfill_size()
+        let segments = std::mem::take(&mut self.segments);
+        self.segments = library.segments;
+        self.segments.extend(segments);
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&library.hash.to_le_bytes());
+        hasher.update(&self.hash.to_le_bytes());
+        self.hash = hasher.finalize();
+    }
+
+    // In KUSEG, KSEG0 or KSEG1, without the RAM mirrors
+    fn is_in_ram(address: u32, size: u32) -> bool
+    {
+        let physical = address & 0x1FFF_FFFF;
+
+        matches!(address >> 29, 0 | 4 | 5) && physical.checked_add(size).is_some_and(|end| end <= RAM_SIZE)
+    }
+
+    // Region the program is licensed for, None if it has no marker
+    pub fn region(&self) -> Option<Region>
+    {
+        if !self.marker.starts_with(MARKER_PREFIX)
+        {
+            None
+        }
+        else if self.marker.contains("North America")
+        {
+            Some(Region::NorthAmerica)
+        }
+        else if self.marker.contains("Japan")
+        {
+            Some(Region::Japan)
+        }
+        else if self.marker.contains("Europe")
+        {
+            Some(Region::Europe)
+        }
+        else
         {
-            mem.write8(address, self.data[address as usize]);
+            None
         }
-        */
+    }
+
+    pub fn load(&self, cpu: &mut CPU, mem: &mut Memory)
+    {
+        let (memfill_address, memfill_size) = self.memfill;
 
-        let destination = self.destination_address();
+        println!("Zeroing @ {:08X}, size = {:08X}", memfill_address, memfill_size);
 
-        println!("Copying data @ {:08X}, size = {:08X}", self.destination_address(), self.destination_size());
+        for address in memfill_address .. memfill_address + memfill_size
+        {
+            mem.write::<u8>(address, 0);
+        }
 
-        // TODO make sure it's aligned?
-        for offset in 0 .. self.destination_size()
+        for segment in &self.segments
         {
-            mem.write::<u8>(destination + offset, self.data[0x800 + offset as usize]);
+            println!("Copying data @ {:08X}, size = {:08X}", segment.address, segment.data.len());
+
+            // TODO make sure it's aligned?
+            for (offset, byte) in segment.data.iter().enumerate()
+            {
+                mem.write::<u8>(segment.address + offset as u32, *byte);
+            }
         }
 
-        cpu.next_pc = self.pc();
+        cpu.next_pc = self.pc;
 
-        println!("new PC @ {:08X}", cpu.pc);
+        println!("new PC @ {:08X}", self.pc);
 
-        cpu.set_reg(28, self.gp());
+        cpu.set_reg(28, self.gp);
 
-        if self.sp_address() != 0
+        if self.stack.0 != 0
         {
-            let sp = self.sp_address() + self.sp_size();
+            let sp = self.stack.0 + self.stack.1;
             cpu.set_reg(29, sp);
             cpu.set_reg(30, sp);
         }
     }
 
-    fn word(&self, address: u32) -> u32
+    pub fn pc(&self) -> u32 { self.pc }
+
+    pub fn hash(&self) -> u32 { self.hash }
+
+    // (address, name), only the ELF files have symbols
+    pub fn symbols(&self) -> &[(u32, String)] { &self.symbols }
+}
+
+fn word(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn half(data: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // PS-X EXE with `size` bytes of code announced and `code` bytes present
+    fn image(pc: u32, address: u32, size: u32, code: usize, memfill: (u32, u32), marker: &str) -> Vec<u8>
+    {
+        let mut data = vec![0; HEADER_SIZE + code];
+
+        data[0 .. 8].copy_from_slice(EXE_MAGIC);
+
+        for (offset, value) in [(0x10, pc), (0x18, address), (0x1C, size), (0x28, memfill.0), (0x2C, memfill.1), (0x30, 0x801F_FF00)]
+        {
+            data[offset .. offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+
+        data[MARKER_OFFSET .. MARKER_OFFSET + marker.len()].copy_from_slice(marker.as_bytes());
+
+        data
+    }
+
+    fn validate(data: &[u8]) -> Result<ExeFile, String>
+    {
+        let exe = ExeFile::parse_exe(data)?;
+        exe.validate()?;
+        Ok(exe)
+    }
+
+    #[test]
+    fn valid_exe()
+    {
+        let exe = validate(&image(0x8001_0000, 0x8001_0000, 0x800, 0x800, (0x8010_0000, 0x100), "Sony Computer Entertainment Inc. for Europe area")).unwrap();
+
+        assert_eq!(exe.pc(), 0x8001_0000);
+        assert_eq!((exe.segments[0].address, exe.segments[0].data.len()), (0x8001_0000, 0x800));
+        assert_eq!(exe.stack, (0x801F_FF00, 0));
+        assert_eq!(exe.region(), Some(Region::Europe));
+
+        // The end of the RAM, in KUSEG and KSEG1
+        assert!(validate(&image(0x001F_F800, 0x001F_F800, 0x800, 0x800, (0, 0), "")).is_ok());
+        assert!(validate(&image(0xA01F_F800, 0xA01F_F800, 0x800, 0x800, (0, 0), "")).is_ok());
+    }
+
+    #[test]
+    fn bad_sizes()
+    {
+        // Header cut short
+        assert!(ExeFile::parse_exe(&image(0x8001_0000, 0x8001_0000, 0, 0, (0, 0), "")[.. 0x100]).is_err());
+
+        // Less code than announced
+        assert!(ExeFile::parse_exe(&image(0x8001_0000, 0x8001_0000, 0x1000, 0x800, (0, 0), "")).is_err());
+
+        // Past the end of the RAM, or wrapping around the address space
+        assert!(validate(&image(0x8001_0000, 0x801F_FC00, 0x800, 0x800, (0, 0), "")).is_err());
+        assert!(validate(&image(0x8001_0000, 0x8001_0000, 0x800, 0x800, (0x8010_0000, 0xFFFF_FF00), "")).is_err());
+    }
+
+    #[test]
+    fn bad_addresses()
+    {
+        // Entry point in the BIOS, code in a RAM mirror or in KSEG2, memory fill in the scratchpad
+        assert!(validate(&image(0xBFC0_0000, 0x8001_0000, 0x800, 0x800, (0, 0), "")).is_err());
+        assert!(validate(&image(0x8001_0000, 0x8020_0000, 0x800, 0x800, (0, 0), "")).is_err());
+        assert!(validate(&image(0x8001_0000, 0xC000_0000, 0x800, 0x800, (0, 0), "")).is_err());
+        assert!(validate(&image(0x8001_0000, 0x8001_0000, 0x800, 0x800, (0x1F80_0000, 0x100), "")).is_err());
+    }
+
+    fn put(data: &mut [u8], offset: usize, bytes: &[u8])
+    {
+        data[offset .. offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // ELF with one segment of 16 bytes followed by 16 bytes of BSS, and a symbol table
+    fn elf(address: u32, memory_size: u32) -> Vec<u8>
+    {
+        let mut data = vec![0; 0x150 + 3 * 0x28];
+
+        put(&mut data, 0, ELF_MAGIC);
+        data[4] = ELF_CLASS_32;
+        data[5] = ELF_DATA_LITTLE_ENDIAN;
+        put(&mut data, 0x12, &ELF_MACHINE_MIPS.to_le_bytes());
+        put(&mut data, 0x18, &address.to_le_bytes());
+        put(&mut data, 0x1C, &0x34u32.to_le_bytes());
+        put(&mut data, 0x20, &0x150u32.to_le_bytes());
+        put(&mut data, 0x2A, &[0x20, 0, 1, 0, 0x28, 0, 3, 0]);
+
+        // Program header
+        for (offset, value) in [(0x34, PT_LOAD), (0x38, 0x100), (0x3C, address), (0x44, 0x10), (0x48, memory_size)]
+        {
+            put(&mut data, offset, &value.to_le_bytes());
+        }
+
+        put(&mut data, 0x100, &[0xFF; 0x10]);
+
+        // Strings, then the symbols after the null one: _gp and main
+        put(&mut data, 0x110, b"\0_gp\0main\0");
+        put(&mut data, 0x130, &[1, 0, 0, 0, 0x00, 0x80, 0x01, 0x80, 0, 0, 0, 0, 0x00, 0, 1, 0]);
+        put(&mut data, 0x140, &[5, 0, 0, 0, 0x00, 0x00, 0x01, 0x80, 0, 0, 0, 0, 0x12, 0, 1, 0]);
+
+        // Section headers after the null one: the symbols and their strings
+        for (offset, value) in [(0x178 + 0x04, SHT_SYMTAB), (0x178 + 0x10, 0x120), (0x178 + 0x14, 0x30), (0x178 + 0x18, 2), (0x1A0 + 0x04, 3), (0x1A0 + 0x10, 0x110), (0x1A0 + 0x14, 10)]
+        {
+            put(&mut data, offset, &value.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn elf_segments_and_symbols()
+    {
+        let exe = ExeFile::parse_elf(&elf(0x8001_0000, 0x20)).unwrap();
+        exe.validate().unwrap();
+
+        assert_eq!((exe.pc(), exe.gp), (0x8001_0000, 0x8001_8000));
+        assert_eq!(exe.segments[0].data, [[0xFF; 0x10], [0; 0x10]].concat());
+        assert_eq!(exe.symbols(), [(0x8001_8000, String::from("_gp")), (0x8001_0000, String::from("main"))]);
+    }
+
+    #[test]
+    fn bad_elf()
+    {
+        let mut big_endian = elf(0x8001_0000, 0x20);
+        big_endian[5] = 2;
+        assert!(ExeFile::parse_elf(&big_endian).is_err());
+
+        assert!(ExeFile::parse_elf(&elf(0x8001_0000, 0x20)[.. 0x120]).is_err());
+        assert!(ExeFile::parse_elf(&elf(0x8001_0000, RAM_SIZE + 1)).is_err());
+        assert!(ExeFile::parse_elf(&elf(0x801F_FFF0, 0x20)).unwrap().validate().is_err());
+        assert!(ExeFile::parse_elf(&elf(0x1F80_0000, 0x20)).unwrap().validate().is_err());
+    }
+
+    // CPE with a unit, 4 bytes of data and the PC
+    fn cpe(address: u32) -> Vec<u8>
+    {
+        let mut data = CPE_MAGIC.to_vec();
+
+        data.extend([0x08, 0]);
+        data.push(0x01);
+        data.extend(address.to_le_bytes());
+        data.extend(4u32.to_le_bytes());
+        data.extend([1, 2, 3, 4]);
+        data.push(0x03);
+        data.extend(CPE_PC.to_le_bytes());
+        data.extend(address.to_le_bytes());
+        data.push(0x00);
+
+        data
+    }
+
+    #[test]
+    fn cpe_chunks()
     {
-        let offset = address as usize;
+        let exe = ExeFile::parse_cpe(&cpe(0x8001_0000)).unwrap();
+        exe.validate().unwrap();
 
-        (self.data[offset + 0] as u32) |
-        (self.data[offset + 1] as u32) << 8 |
-        (self.data[offset + 2] as u32) << 16 |
-        (self.data[offset + 3] as u32) << 24
+        assert_eq!(exe.pc(), 0x8001_0000);
+        assert_eq!((exe.segments[0].address, &exe.segments[0].data[..]), (0x8001_0000, &[1, 2, 3, 4][..]));
     }
 
-    pub fn id(&self) -> &[u8] { &self.data[0..8] }
+    #[test]
+    fn bad_cpe()
+    {
+        let data = cpe(0x8001_0000);
 
-    pub fn pc(&self) -> u32 { self.word(0x10) }
-    pub fn gp(&self) -> u32 { self.word(0x14) }
+        // Truncated, or without the end chunk
+        assert!(ExeFile::parse_cpe(&data[.. 12]).is_err());
+        assert!(ExeFile::parse_cpe(&data[.. data.len() - 1]).is_err());
 
-    pub fn destination_address(&self) -> u32 { self.word(0x18) }
-    pub fn destination_size(&self) -> u32 { self.word(0x1C) }
+        let mut unknown_chunk = data.clone();
+        unknown_chunk[4] = 0x09;
+        assert!(ExeFile::parse_cpe(&unknown_chunk).is_err());
 
-    pub fn memfill_address(&self) -> u32 { self.word(0x28) }
-    pub fn memfill_size(&self) -> u32 { self.word(0x2C) }
+        // Nothing to load
+        assert!(ExeFile::parse_cpe(&[CPE_MAGIC, &[0x00]].concat()).is_err());
 
-    pub fn sp_address(&self) -> u32 { self.word(0x30) }
-    pub fn sp_size(&self) -> u32 { self.word(0x34) }
+        assert!(ExeFile::parse_cpe(&cpe(0xBFC0_0000)).unwrap().validate().is_err());
+    }
 }
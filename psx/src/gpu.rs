@@ -1,7 +1,10 @@
 use crate::renderer::{ Color, Position, Renderer };
+use crate::timers::VideoStandard;
+
+use serde::{ Serialize, Deserialize };
 use std::collections::VecDeque;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum DMADirection
 {
     Off = 0,
@@ -10,7 +13,7 @@ enum DMADirection
     GPUToCPU = 3
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum TextureDepth
 {
     Bits4 = 0,
@@ -18,7 +21,7 @@ enum TextureDepth
     Bits15 = 2
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 struct HorizontalResolution(u8);
 
 impl HorizontalResolution
@@ -36,42 +39,42 @@ impl HorizontalResolution
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum VerticalResolution
 {
     V240 = 0,
     V480 = 1
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum VideoMode
 {
     NTSC = 0,
     PAL = 1
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum DisplayDepth
 {
     Bits15 = 0,
     Bits24 = 1
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum Field
 {
     _Bottom = 0,
     Top = 1
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Port
 {
     GP0 = 0,
     GP1 = 1
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandBuffer
 {
     data: [u32; 12], // 12 is the longest command size
@@ -119,6 +122,7 @@ impl ::std::ops::Index<usize> for CommandBuffer
 #[derive(Debug)]
 pub struct CommandRecord(pub Port, pub CommandBuffer);
 
+#[derive(Serialize, Deserialize)]
 enum GP0Mode
 {
     Command,
@@ -128,6 +132,7 @@ enum GP0Mode
 const VRAM_WIDTH: usize = 1024;
 const VRAM_HEIGHT: usize = 512;
 
+#[derive(Serialize, Deserialize)]
 pub struct GPU
 {
     // Status
@@ -138,6 +143,7 @@ pub struct GPU
     interlace: bool,
     display_depth: DisplayDepth,
     video_mode: VideoMode,
+    region_video_mode: VideoMode, // Given by the console region, set at power on and by the reset
     resolution_vertical: VerticalResolution,
     resolution_horizontal: HorizontalResolution,
     texture_disable: bool,
@@ -176,7 +182,8 @@ pub struct GPU
     // Command buffering
 
     gp0_command_buffer: CommandBuffer,
-    gp0_command_method: fn(&mut GPU),
+    #[serde(skip, default = "GPU::default_command_method")]
+    gp0_command_method: fn(&mut GPU), // Restored from the buffered command, see `load_state`
     gp0_words_remaining: u32,
     gp0_mode: GP0Mode,
 
@@ -197,17 +204,28 @@ pub struct GPU
 
     // Debugging
 
+    #[serde(skip)]
     pub previous_commands: VecDeque<CommandRecord>,
 
-    renderer: Renderer
+    #[serde(skip)]
+    renderer: Option<Renderer> // None when running headless
 }
 
 const MAX_COMMAND_RECORD_SIZE: usize = 1000;
 
+// Method and word count
+type GP0Command = (fn(&mut GPU), u32);
+
 impl GPU
 {
-    pub fn new(display: &glium::Display) -> GPU
+    pub fn new(display: Option<&glium::Display>, video_standard: VideoStandard) -> GPU
     {
+        let video_mode = match video_standard
+        {
+            VideoStandard::NTSC => VideoMode::NTSC,
+            VideoStandard::PAL  => VideoMode::PAL
+        };
+
         GPU
         {
             dma_direction: DMADirection::Off,
@@ -215,7 +233,8 @@ impl GPU
             display_disable: false,
             interlace: false,
             display_depth: DisplayDepth::Bits15,
-            video_mode: VideoMode::NTSC,
+            video_mode,
+            region_video_mode: video_mode,
             resolution_vertical: VerticalResolution::V240,
             resolution_horizontal: HorizontalResolution::from_bytes(0, 0),
             texture_disable: false,
@@ -268,13 +287,16 @@ impl GPU
 
             read_response: 0,
 
-            renderer: Renderer::new(display)
+            renderer: display.map(Renderer::new)
         }
     }
 
     pub fn render(&mut self, target: &mut glium::Frame)
     {
-        self.renderer.render(target);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.render(target);
+        }
     }
 
     fn save_command(&mut self, port: Port, command: CommandBuffer)
@@ -399,34 +421,79 @@ impl GPU
         self.read_response
     }
 
+    // Method and word count of a GP0 command
+    fn gp0_command(opcode: u32) -> Option<GP0Command>
+    {
+        let command = match opcode
+        {
+            0x00 => (GPU::gp0_nop as fn(&mut GPU), 1),
+            0x01 => (GPU::gp0_clear_cache as fn(&mut GPU), 1),
+            0x28 => (GPU::gp0_draw_quad_mono_opaque as fn(&mut GPU), 5),
+            0x2C => (GPU::gp0_draw_quad_textured_opaque as fn(&mut GPU), 9),
+            0x30 => (GPU::gp0_draw_triangle_shaded_opaque as fn(&mut GPU), 6),
+            0x38 => (GPU::gp0_draw_quad_shaded_opaque as fn(&mut GPU), 8),
+            0x68 => (GPU::gp0_draw_dot_mono_opaque as fn(&mut GPU), 2),
+            0xA0 => (GPU::gp0_load_image as fn(&mut GPU), 3),
+            0xC0 => (GPU::gp0_store_image as fn(&mut GPU), 3),
+            0xE1 => (GPU::gp0_draw_mode as fn(&mut GPU), 1),
+            0xE2 => (GPU::gp0_texture_window as fn(&mut GPU), 1),
+            0xE3 => (GPU::gp0_drawing_area_top_left as fn(&mut GPU), 1),
+            0xE4 => (GPU::gp0_drawing_area_bottom_right as fn(&mut GPU), 1),
+            0xE5 => (GPU::gp0_drawing_offset as fn(&mut GPU), 1),
+            0xE6 => (GPU::gp0_mask_bit_setting as fn(&mut GPU), 1),
+
+            _ => return None
+        };
+
+        Some(command)
+    }
+
+    fn default_command_method() -> fn(&mut GPU)
+    {
+        GPU::gp0_nop
+    }
+
+    // The method of the command being buffered can't be saved
+    pub fn load_state(&mut self, mut state: GPU) -> Result<(), String>
+    {
+        if state.vram.len() != VRAM_WIDTH * VRAM_HEIGHT || state.gp0_command_buffer.current_length > state.gp0_command_buffer.data.len()
+        {
+            return Err(String::from("the GPU buffers have the wrong size"));
+        }
+
+        if let (GP0Mode::Command, 1 ..) = (&state.gp0_mode, state.gp0_words_remaining)
+        {
+            let opcode = state.gp0_command_buffer[0] >> 24;
+            state.gp0_command_method = GPU::gp0_command(opcode).ok_or_else(|| format!("unsupported GP0 opcode {:02X} being buffered", opcode))?.0;
+        }
+
+        state.renderer = self.renderer.take();
+        *self = state;
+
+        // The renderer still shows the VRAM from before the load
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.upload_vram(&self.vram);
+        }
+
+        Ok(())
+    }
+
+    // Back to the power-on state, the renderer is kept
+    pub fn reset(&mut self, video_standard: VideoStandard)
+    {
+        let renderer = self.renderer.take();
+        *self = GPU::new(None, video_standard);
+        self.renderer = renderer;
+    }
+
     pub fn gp0(&mut self, command: u32)
     {
         // No command being buffered, we start a new one
 
         if self.gp0_words_remaining == 0
         {
-            let opcode = command >> 24;
-
-            let (method, word_count) = match opcode
-            {
-                0x00 => (GPU::gp0_nop as fn(&mut GPU), 1),
-                0x01 => (GPU::gp0_clear_cache as fn(&mut GPU), 1),
-                0x28 => (GPU::gp0_draw_quad_mono_opaque as fn(&mut GPU), 5),
-                0x2C => (GPU::gp0_draw_quad_textured_opaque as fn(&mut GPU), 9),
-                0x30 => (GPU::gp0_draw_triangle_shaded_opaque as fn(&mut GPU), 6),
-                0x38 => (GPU::gp0_draw_quad_shaded_opaque as fn(&mut GPU), 8),
-                0x68 => (GPU::gp0_draw_dot_mono_opaque as fn(&mut GPU), 2),
-                0xA0 => (GPU::gp0_load_image as fn(&mut GPU), 3),
-                0xC0 => (GPU::gp0_store_image as fn(&mut GPU), 3),
-                0xE1 => (GPU::gp0_draw_mode as fn(&mut GPU), 1),
-                0xE2 => (GPU::gp0_texture_window as fn(&mut GPU), 1),
-                0xE3 => (GPU::gp0_drawing_area_top_left as fn(&mut GPU), 1),
-                0xE4 => (GPU::gp0_drawing_area_bottom_right as fn(&mut GPU), 1),
-                0xE5 => (GPU::gp0_drawing_offset as fn(&mut GPU), 1),
-                0xE6 => (GPU::gp0_mask_bit_setting as fn(&mut GPU), 1),
-
-                _ => panic!("unsupported GP0 opcode {:0X}", opcode)
-            };
+            let (method, word_count) = GPU::gp0_command(command >> 24).unwrap_or_else(|| panic!("unsupported GP0 opcode {:0X}", command >> 24));
 
             self.gp0_command_method = method;
             self.gp0_command_buffer.clear();
@@ -521,7 +588,10 @@ impl GPU
 
         let colors = [Color::from_command(self.gp0_command_buffer[0]); 4];
 
-        self.renderer.push_quad(positions, colors);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.push_quad(positions, colors);
+        }
     }
 
     fn gp0_draw_quad_textured_opaque(&mut self)
@@ -536,7 +606,10 @@ impl GPU
 
         let colors = [Color(255, 20, 147); 4]; // TEMP FAKE COLOR
 
-        self.renderer.push_quad(positions, colors);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.push_quad(positions, colors);
+        }
     }
 
     fn gp0_draw_triangle_shaded_opaque(&mut self)
@@ -555,7 +628,10 @@ impl GPU
             Color::from_command(self.gp0_command_buffer[4])
         ];
 
-        self.renderer.push_triangle(positions, colors);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.push_triangle(positions, colors);
+        }
     }
 
     fn gp0_draw_quad_shaded_opaque(&mut self)
@@ -576,7 +652,10 @@ impl GPU
             Color::from_command(self.gp0_command_buffer[6])
         ];
 
-        self.renderer.push_quad(positions, colors);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.push_quad(positions, colors);
+        }
     }
 
     fn gp0_draw_dot_mono_opaque(&mut self)
@@ -601,7 +680,10 @@ impl GPU
             color
         ];
 
-        self.renderer.push_quad(positions, colors);
+        if let Some(renderer) = &mut self.renderer
+        {
+            renderer.push_quad(positions, colors);
+        }
     }
 
     fn gp0_load_image(&mut self)
@@ -750,7 +832,7 @@ impl GPU
         self.display_disable = true;
         self.interlace = false;
         self.display_depth = DisplayDepth::Bits15;
-        self.video_mode = VideoMode::NTSC;
+        self.video_mode = self.region_video_mode;
         self.resolution_vertical = VerticalResolution::V240;
         self.resolution_horizontal = HorizontalResolution::from_bytes(0, 0);
         self.texture_disable = false;
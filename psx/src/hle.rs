@@ -0,0 +1,1986 @@
+// High-level emulation of the BIOS kernel, to run programs without a BIOS image
+//
+// The kernel functions are run natively when the CPU reaches the A0h/B0h/C0h
+// entry points, and the exceptions are handled natively when it reaches the
+// exception vector. Guest callbacks (events, interrupt handlers) are run as
+// regular code, with their return address pointing back to the kernel.
+//
+// File I/O is done on the host: "cdrom:" maps to the directory of the program
+// (or to the extracted disc contents), "bu00:" and "bu10:" to directories in
+// the memory card folder.
+//
+// Documentation
+// https://problemkaputt.de/psx-spx.htm#biosfunctionsummary
+// https://problemkaputt.de/psx-spx.htm#biosinterrupthandling
+
+use crate::bios::SHELL_ADDRESS;
+use crate::cpu::CPU;
+use crate::memory::{ Memory, RAM_SIZE };
+
+use serde::{ ser, Deserialize, Deserializer, Serialize, Serializer };
+use std::collections::{ HashMap, VecDeque };
+use std::fs::{ self, File };
+use std::io::{ Read, Seek, SeekFrom, Write };
+use std::path::{ Component, Path, PathBuf };
+
+// Physical addresses handled by the kernel
+const RESET_VECTOR: u32 = 0x1FC0_0000;
+const EXCEPTION_VECTOR: u32 = 0x0000_0080;
+const A_VECTOR: u32 = 0x0000_00A0;
+const B_VECTOR: u32 = 0x0000_00B0;
+const C_VECTOR: u32 = 0x0000_00C0;
+const CALLBACK_RETURN: u32 = 0x1FC0_E000; // Return address given to the guest callbacks
+const FUNCTION_ENTRIES: u32 = 0x1FC0_F000; // Targets of the function tables, see `install`
+
+// Function tables in RAM, games may patch them
+const A_TABLE: u32 = 0x200;
+const B_TABLE: u32 = 0x874;
+const C_TABLE: u32 = 0x674;
+const A_TABLE_SIZE: u32 = 0xC0;
+const B_TABLE_SIZE: u32 = 0x60;
+const C_TABLE_SIZE: u32 = 0x20;
+
+// Same exception vector code as the BIOS, replaced by the games that install their own handler
+const EXCEPTION_VECTOR_CODE: [u32; 4] = [0x3C1A_0000, 0x275A_0C80, 0x0340_0008, 0x0000_0000];
+
+const KERNEL_STACK: u32 = 0x8000_E000; // Used by the callbacks run from exceptions
+const KERNEL_HEAP: (u32, u32) = (0x8000_8000, 0x4000);
+const DEFAULT_STACK: u32 = 0x801F_FF00;
+
+const I_STAT: u32 = 0x1F80_1070;
+const I_MASK: u32 = 0x1F80_1074;
+const GP0: u32 = 0x1F80_1810;
+const GP1: u32 = 0x1F80_1814;
+
+// Events
+const EVENT_FREE: u32 = 0x0000;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+const EVENT_MODE_CALLBACK: u32 = 0x1000;
+const EVENT_ROOT_COUNTER: u32 = 0xF200_0000; // Class of the root counter events, counter 3 is the VBlank
+const EVENT_SW_CARD: u32 = 0xF400_0001;
+const EVENT_SPEC_INTERRUPT: u32 = 0x0002;
+const EVENT_SPEC_IO_END: u32 = 0x0004;
+
+// Error codes
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+const EINVAL: u32 = 22;
+
+const FIRST_FILE: u32 = 2; // 0 and 1 are the TTY
+const MAX_FILES: u32 = 16;
+const DIRENT_SIZE: u32 = 0x28;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Context
+{
+    r: [u32; 32],
+    hi: u32,
+    lo: u32,
+    status: u32,
+    pc: u32
+}
+
+impl Context
+{
+    fn save(cpu: &CPU) -> Self
+    {
+        Context
+        {
+            r: cpu.registers(),
+            hi: cpu.hi,
+            lo: cpu.lo,
+            status: cpu.cop0_status(),
+            pc: cpu.pc
+        }
+    }
+
+    // `rfe` pops the interrupt enable stack, to return from an exception
+    fn restore(&self, cpu: &mut CPU, rfe: bool)
+    {
+        for index in 1 .. 32
+        {
+            cpu.set_reg(index, self.r[index as usize]);
+        }
+
+        cpu.hi = self.hi;
+        cpu.lo = self.lo;
+
+        let status = if rfe { (self.status & !0xF) | ((self.status >> 2) & 0xF) } else { self.status };
+        cpu.set_cop0_status(status);
+
+        cpu.jump(self.pc);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Event
+{
+    class: u32,
+    spec: u32,
+    mode: u32,
+    function: u32,
+    status: u32
+}
+
+#[derive(Serialize, Deserialize)]
+enum Call
+{
+    Function(u32),
+    Handler(u32) // Interrupt handler structure: next, second function, first function
+}
+
+// Where to go once all the callbacks have returned
+#[derive(Serialize, Deserialize)]
+enum Exit
+{
+    Exception,
+    Return { ra: u32, v0: u32 }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Frame
+{
+    calls: VecDeque<Call>,
+    current: Option<Call>,
+    exit: Exit
+}
+
+// What to do once a function is done
+enum Flow
+{
+    Return(u32), // Returns to the caller with this value
+    Jump, // The function has already set PC
+    Retry // Blocked, the call will be made again
+}
+
+#[derive(Clone, Copy)]
+enum Table
+{
+    A,
+    B,
+    C
+}
+
+impl Table
+{
+    fn name(self) -> &'static str
+    {
+        match self
+        {
+            Table::A => "A0",
+            Table::B => "B0",
+            Table::C => "C0"
+        }
+    }
+
+    fn address(self) -> u32
+    {
+        match self
+        {
+            Table::A => A_TABLE,
+            Table::B => B_TABLE,
+            Table::C => C_TABLE
+        }
+    }
+
+    fn size(self) -> u32
+    {
+        match self
+        {
+            Table::A => A_TABLE_SIZE,
+            Table::B => B_TABLE_SIZE,
+            Table::C => C_TABLE_SIZE
+        }
+    }
+
+    // Each table entry points to its own address in the function entries area,
+    // so that the calls made through a saved entry are still handled
+    fn entry(self, function: u32) -> u32
+    {
+        0xA000_0000 | (FUNCTION_ENTRIES + ((self as u32) << 10) + function * 4)
+    }
+
+    fn from_entry(physical: u32) -> Option<(Table, u32)>
+    {
+        let offset = physical.checked_sub(FUNCTION_ENTRIES)?;
+        let function = (offset & 0x3FF) / 4;
+
+        let table = match offset >> 10
+        {
+            0 => Table::A,
+            1 => Table::B,
+            2 => Table::C,
+            _ => return None
+        };
+
+        if function < table.size() { Some((table, function)) } else { None }
+    }
+}
+
+// Host file opened by the guest
+struct OpenFile
+{
+    file: Option<File>, // None until a loaded state opens it again
+    name: String, // As given by the guest
+    position: u64, // In the loaded state
+    writable: bool,
+    port: Option<u32> // Memory card port
+}
+
+// The save states keep the guest name and the position, not the host path:
+// the file is resolved again in the current directories when the state is loaded
+impl Serialize for OpenFile
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        let position = match &self.file
+        {
+            Some(file) => (&*file).stream_position().map_err(ser::Error::custom)?,
+            None       => self.position
+        };
+
+        (&self.name, position, self.writable).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OpenFile
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let (name, position, writable): (String, u64, bool) = Deserialize::deserialize(deserializer)?;
+
+        Ok(OpenFile { file: None, name, position, writable, port: None })
+    }
+}
+
+// Simple first-fit allocator for the guest heaps
+#[derive(Serialize, Deserialize)]
+struct Heap
+{
+    free: Vec<(u32, u32)>, // (address, size), sorted
+    allocated: HashMap<u32, u32>
+}
+
+impl Heap
+{
+    fn new(address: u32, size: u32) -> Self
+    {
+        Heap
+        {
+            free: vec![(address, size)],
+            allocated: HashMap::new()
+        }
+    }
+
+    fn allocate(&mut self, size: u32) -> Option<u32>
+    {
+        let size = size.max(1).checked_add(7)? & !7;
+        let index = self.free.iter().position(|(_, s)| *s >= size)?;
+        let (address, block_size) = self.free[index];
+
+        if block_size == size
+        {
+            self.free.remove(index);
+        }
+        else
+        {
+            self.free[index] = (address.wrapping_add(size), block_size - size);
+        }
+
+        self.allocated.insert(address, size);
+
+        Some(address)
+    }
+
+    fn release(&mut self, address: u32)
+    {
+        let size = match self.allocated.remove(&address)
+        {
+            Some(size) => size,
+            None => return
+        };
+
+        let index = self.free.iter().position(|(a, _)| *a > address).unwrap_or(self.free.len());
+        self.free.insert(index, (address, size));
+
+        // Merge with the neighbours
+        if index + 1 < self.free.len() && address.wrapping_add(size) == self.free[index + 1].0
+        {
+            self.free[index].1 += self.free[index + 1].1;
+            self.free.remove(index + 1);
+        }
+
+        if index > 0 && self.free[index - 1].0.wrapping_add(self.free[index - 1].1) == address
+        {
+            self.free[index - 1].1 += self.free[index].1;
+            self.free.remove(index);
+        }
+    }
+
+    fn size_of(&self, address: u32) -> Option<u32>
+    {
+        self.allocated.get(&address).cloned()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Kernel
+{
+    // Settings, the save states don't change them
+    #[serde(skip)]
+    cdrom_root: Option<PathBuf>,
+    #[serde(skip)]
+    memory_card_root: PathBuf,
+
+    events: Vec<Event>,
+    threads: Vec<Option<Context>>,
+    current_thread: usize,
+
+    contexts: Vec<Context>, // Saved by the exceptions, restored by ReturnFromException
+    frames: Vec<Frame>, // Callbacks being run
+    pending_calls: VecDeque<Call>, // Callbacks requested by the current function
+    interrupt_handlers: [u32; 4], // First handler structure of each priority
+    custom_exit: Option<u32>,
+    clear_counters: [bool; 4],
+
+    pad_buffers: [(u32, u32); 2],
+    pad_buttons: u32,
+    pads_started: bool,
+
+    files: HashMap<u32, OpenFile>,
+    last_error: u32,
+    file_errors: HashMap<u32, u32>,
+    search: Vec<(String, u32)>, // Remaining (name, size) of firstfile/nextfile
+
+    heap: Option<Heap>,
+    kernel_heap: Heap,
+    random_seed: u32,
+
+    halted: bool
+}
+
+impl Kernel
+{
+    pub fn new(cdrom_root: Option<PathBuf>, memory_card_root: PathBuf) -> Self
+    {
+        Kernel
+        {
+            cdrom_root,
+            memory_card_root,
+
+            events: Vec::new(),
+            threads: Vec::new(),
+            current_thread: 0,
+
+            contexts: Vec::new(),
+            frames: Vec::new(),
+            pending_calls: VecDeque::new(),
+            interrupt_handlers: [0; 4],
+            custom_exit: None,
+            clear_counters: [true; 4],
+
+            pad_buffers: [(0, 0); 2],
+            pad_buttons: 0,
+            pads_started: false,
+
+            files: HashMap::new(),
+            last_error: 0,
+            file_errors: HashMap::new(),
+            search: Vec::new(),
+
+            heap: None,
+            kernel_heap: Heap::new(KERNEL_HEAP.0, KERNEL_HEAP.1),
+            random_seed: 0,
+
+            halted: false
+        }
+    }
+
+    // Forgets the previous boot, when the CPU is reset
+    pub fn restart(&mut self)
+    {
+        let cdrom_root = self.cdrom_root.take();
+        let memory_card_root = std::mem::take(&mut self.memory_card_root);
+
+        *self = Kernel::new(cdrom_root, memory_card_root);
+    }
+
+    // Takes over the directories of the current kernel, and opens the files of the loaded state again
+    pub(crate) fn restore(&mut self, current: &Kernel) -> Result<(), String>
+    {
+        self.cdrom_root = current.cdrom_root.clone();
+        self.memory_card_root = current.memory_card_root.clone();
+
+        let mut files = std::mem::take(&mut self.files);
+
+        for open in files.values_mut()
+        {
+            let (path, port) = self.resolve(&open.name).ok_or_else(|| format!("cannot find \"{}\" again", open.name))?;
+
+            // Only the memory cards can be written to
+            let writable = open.writable && port.is_some();
+
+            let mut file = fs::OpenOptions::new().read(true).write(writable).open(&path)
+                .map_err(|error| format!("cannot open \"{}\" again: {}", path.display(), error))?;
+
+            file.seek(SeekFrom::Start(open.position)).map_err(|error| error.to_string())?;
+
+            open.file = Some(file);
+            open.writable = writable;
+            open.port = port;
+        }
+
+        self.files = files;
+
+        Ok(())
+    }
+
+    pub fn is_hook(address: u32) -> bool
+    {
+        let physical = address & 0x1FFF_FFFF;
+
+        matches!(physical, RESET_VECTOR | EXCEPTION_VECTOR | A_VECTOR | B_VECTOR | C_VECTOR | CALLBACK_RETURN) ||
+        Table::from_entry(physical).is_some()
+    }
+
+    // Runs the kernel code at PC
+    // Returns false if the CPU must execute the instruction there instead
+    pub fn call(&mut self, cpu: &mut CPU, mem: &mut Memory) -> bool
+    {
+        let physical = cpu.pc & 0x1FFF_FFFF;
+
+        match physical
+        {
+            RESET_VECTOR => self.reset(cpu, mem),
+
+            EXCEPTION_VECTOR =>
+            {
+                if (0 .. 4).any(|i| mem.read::<u32>(EXCEPTION_VECTOR + i * 4) != EXCEPTION_VECTOR_CODE[i as usize])
+                {
+                    return false;
+                }
+
+                self.exception(cpu, mem);
+            },
+
+            A_VECTOR => self.table_call(cpu, mem, Table::A),
+            B_VECTOR => self.table_call(cpu, mem, Table::B),
+            C_VECTOR => self.table_call(cpu, mem, Table::C),
+
+            CALLBACK_RETURN => self.callback_returned(cpu, mem),
+
+            _ => match Table::from_entry(physical)
+            {
+                Some((table, function)) => self.function(cpu, mem, table, function),
+                None => return false
+            }
+        }
+
+        true
+    }
+
+    // Boot
+
+    fn reset(&mut self, cpu: &mut CPU, mem: &mut Memory)
+    {
+        if self.halted
+        {
+            return;
+        }
+
+        info!("HLE kernel boot");
+
+        self.install(mem);
+
+        // Kernel mode, interrupts disabled, RAM exception vectors
+        cpu.set_cop0_status(0);
+
+        self.threads = vec![Some(Context::save(cpu))];
+        self.current_thread = 0;
+
+        cpu.set_reg(29, DEFAULT_STACK);
+        cpu.set_reg(30, DEFAULT_STACK);
+
+        // The CPU loads the EXE file given on the command line when it reaches the shell
+        if cpu.has_pending_exe()
+        {
+            cpu.jump(SHELL_ADDRESS);
+            return;
+        }
+
+        let (boot, stack) = self.read_system_cnf();
+
+        info!("Booting {}", boot);
+
+        if !self.load_exec(cpu, mem, &boot, stack, 0)
+        {
+            error!("HLE kernel: nothing to boot");
+            self.halted = true;
+        }
+    }
+
+    // Sets up the RAM like the BIOS does
+    pub fn install(&mut self, mem: &mut Memory)
+    {
+        for (index, word) in EXCEPTION_VECTOR_CODE.iter().enumerate()
+        {
+            mem.write::<u32>(EXCEPTION_VECTOR + index as u32 * 4, *word);
+        }
+
+        for table in [Table::A, Table::B, Table::C].iter()
+        {
+            for function in 0 .. table.size()
+            {
+                mem.write::<u32>(table.address() + function * 4, table.entry(function));
+            }
+        }
+    }
+
+    // The files an extracted disc boots from: SYSTEM.CNF and the executable it names, if they exist
+    pub(crate) fn boot_files(cdrom_root: &Path) -> Vec<PathBuf>
+    {
+        let kernel = Kernel::new(Some(cdrom_root.to_path_buf()), PathBuf::new());
+        let (boot, _) = kernel.read_system_cnf();
+
+        ["cdrom:SYSTEM.CNF;1", &boot].iter()
+            .filter_map(|name| kernel.resolve(name))
+            .map(|(path, _)| path)
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    // Returns the boot file and the stack address
+    fn read_system_cnf(&self) -> (String, u32)
+    {
+        let mut boot = String::from("cdrom:PSX.EXE;1");
+        let mut stack = DEFAULT_STACK;
+
+        let contents = self.resolve("cdrom:SYSTEM.CNF;1")
+            .and_then(|(path, _)| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+
+        for line in contents.lines()
+        {
+            if let Some((key, value)) = line.split_once('=')
+            {
+                match key.trim()
+                {
+                    "BOOT"  => boot = value.trim().to_string(),
+                    "STACK" => stack = u32::from_str_radix(value.trim(), 16).unwrap_or(DEFAULT_STACK),
+                    _       => ()
+                }
+            }
+        }
+
+        (boot, stack)
+    }
+
+    // Function tables
+
+    fn table_call(&mut self, cpu: &mut CPU, mem: &mut Memory, table: Table)
+    {
+        let function = cpu.registers()[9]; // T1
+
+        if function >= table.size()
+        {
+            warn!("HLE kernel: unknown function {}({:02X})", table.name(), function);
+            cpu.set_reg(2, 0);
+            cpu.jump(cpu.registers()[31]);
+            return;
+        }
+
+        // Patched entry
+        let entry = mem.read::<u32>(table.address() + function * 4);
+
+        if entry != table.entry(function)
+        {
+            cpu.jump(entry);
+            return;
+        }
+
+        self.function(cpu, mem, table, function);
+    }
+
+    fn function(&mut self, cpu: &mut CPU, mem: &mut Memory, table: Table, function: u32)
+    {
+        let r = cpu.registers();
+        let ra = r[31];
+        let args = [r[4], r[5], r[6], r[7]];
+
+        trace!("HLE kernel: {}({:02X}) {:08x} {:08x} {:08x} {:08x}", table.name(), function, args[0], args[1], args[2], args[3]);
+
+        let flow = match table
+        {
+            Table::A => self.a_function(cpu, mem, function, args),
+            Table::B => self.b_function(cpu, mem, function, args),
+            Table::C => self.c_function(cpu, mem, function, args)
+        };
+
+        match flow
+        {
+            Flow::Return(v0) if !self.pending_calls.is_empty() =>
+            {
+                // Run the callbacks before returning
+                let calls = std::mem::take(&mut self.pending_calls);
+                self.frames.push(Frame { calls, current: None, exit: Exit::Return { ra, v0 } });
+                self.next_call(cpu, mem);
+            },
+            Flow::Return(v0) =>
+            {
+                cpu.set_reg(2, v0);
+                cpu.jump(ra);
+            },
+            Flow::Jump | Flow::Retry => ()
+        }
+    }
+
+    fn a_function(&mut self, cpu: &mut CPU, mem: &mut Memory, function: u32, args: [u32; 4]) -> Flow
+    {
+        let [a0, a1, a2, a3] = args;
+
+        let v0 = match function
+        {
+            0x00 => self.open(mem, a0, a1),
+            0x01 => self.seek(a0, a1, a2),
+            0x02 => self.read(mem, a0, a1, a2),
+            0x03 => self.write(mem, a0, a1, a2),
+            0x04 => self.close(a0),
+            0x06 | 0x3A => return self.exit(cpu, a0),
+            0x07 => (a0 < FIRST_FILE) as u32, // isatty
+            0x09 | 0x3C => { mem.tty.putchar(a0 as u8); a0 },
+            0x0A => match a0 as u8 { c @ b'0' ..= b'9' => (c - b'0') as u32, c @ b'a' ..= b'z' => (c - b'a' + 10) as u32, c @ b'A' ..= b'Z' => (c - b'A' + 10) as u32, _ => 9_999_999 }, // todigit
+            0x0E | 0x0F => (a0 as i32).wrapping_abs() as u32,
+            0x10 | 0x11 => Kernel::read_string(mem, a0).trim().parse::<i32>().unwrap_or(0) as u32, // atoi, atol
+            0x13 => { self.setjmp(cpu, mem, a0); 0 },
+            0x14 => { Kernel::longjmp(cpu, mem, a0, a1); return Flow::Jump },
+            0x15 => { let s = Kernel::read_string(mem, a0) + &Kernel::read_string(mem, a1); Kernel::write_string(mem, a0, &s); a0 }, // strcat
+            0x17 => Kernel::compare(&Kernel::read_string(mem, a0), &Kernel::read_string(mem, a1), usize::MAX), // strcmp
+            0x18 => Kernel::compare(&Kernel::read_string(mem, a0), &Kernel::read_string(mem, a1), a2 as usize), // strncmp
+            0x19 => { let s = Kernel::read_string(mem, a1); Kernel::write_string(mem, a0, &s); a0 }, // strcpy
+            0x1A => // strncpy
+            {
+                let s = Kernel::read_string(mem, a1);
+                let bytes = s.as_bytes();
+
+                for index in 0 .. a2
+                {
+                    mem.write::<u8>(a0.wrapping_add(index), bytes.get(index as usize).cloned().unwrap_or(0));
+                }
+
+                a0
+            },
+            0x1B => Kernel::read_string(mem, a0).len() as u32, // strlen
+            0x1C | 0x1E => Kernel::read_string(mem, a0).bytes().position(|c| c == a1 as u8).map_or(0, |i| a0 + i as u32), // strchr
+            0x1D | 0x1F => Kernel::read_string(mem, a0).bytes().rposition(|c| c == a1 as u8).map_or(0, |i| a0 + i as u32), // strrchr
+            0x25 => (a0 as u8).to_ascii_uppercase() as u32,
+            0x26 => (a0 as u8).to_ascii_lowercase() as u32,
+            0x27 => { Kernel::copy(mem, a1, a0, a2); a1 }, // bcopy
+            0x28 => { Kernel::fill(mem, a0, 0, a1); a0 }, // bzero
+            0x29 | 0x2D => // bcmp, memcmp
+            {
+                let a = Kernel::read_bytes(mem, a0, a2);
+                let b = Kernel::read_bytes(mem, a1, a2);
+                a.iter().zip(b.iter()).find(|(x, y)| x != y).map_or(0, |(x, y)| (*x as i32 - *y as i32) as u32)
+            },
+            0x2A | 0x2C => { Kernel::copy(mem, a0, a1, a2); a0 }, // memcpy, memmove
+            0x2B => { Kernel::fill(mem, a0, a1 as u8, a2); a0 }, // memset
+            0x2E => Kernel::read_bytes(mem, a0, a2).iter().position(|c| *c == a1 as u8).map_or(0, |i| a0 + i as u32), // memchr
+            0x2F => // rand
+            {
+                self.random_seed = self.random_seed.wrapping_mul(0x41C6_4E6D).wrapping_add(0x3039);
+                (self.random_seed >> 16) & 0x7FFF
+            },
+            0x30 => { self.random_seed = a0; 0 }, // srand
+            0x33 => self.heap.as_mut().and_then(|h| h.allocate(a0)).unwrap_or(0), // malloc
+            0x34 => { if let Some(heap) = &mut self.heap { heap.release(a0) }; 0 }, // free
+            0x37 => // calloc
+            {
+                let size = a0.wrapping_mul(a1);
+                let address = self.heap.as_mut().and_then(|h| h.allocate(size)).unwrap_or(0);
+
+                if address != 0
+                {
+                    Kernel::fill(mem, address, 0, size);
+                }
+
+                address
+            },
+            0x38 => // realloc
+            {
+                let heap = match &mut self.heap { Some(heap) => heap, None => return Flow::Return(0) };
+                let old_size = heap.size_of(a0).unwrap_or(0);
+                let address = heap.allocate(a1).unwrap_or(0);
+
+                if address != 0 && a0 != 0
+                {
+                    Kernel::copy(mem, address, a0, old_size.min(a1));
+                    heap.release(a0);
+                }
+
+                address
+            },
+            0x39 => { self.heap = Some(Heap::new(a0, a1)); 0 }, // InitHeap
+            0x3E => { let s = Kernel::read_string(mem, a0); mem.tty.write(s.as_bytes()); mem.tty.putchar(b'\n'); 1 }, // puts
+            0x3F => { let s = Kernel::printf(cpu, mem, a0); mem.tty.write(s.as_bytes()); s.len() as u32 }, // printf
+            0x42 => { let name = Kernel::read_string(mem, a0); self.load(cpu, mem, &name, a1) as u32 },
+            0x43 => { Kernel::exec(cpu, mem, a0, a1, a2); return Flow::Jump },
+            0x44 => { cpu.flush_instruction_cache(); 0 }, // FlushCache
+            0x45 => { self.install(mem); 0 }, // init_a0_b0_c0_vectors
+            0x46 | 0x47 => // GPU_dw, gpu_send_dma
+            {
+                let source = mem.read::<u32>(cpu.registers()[29].wrapping_add(0x10));
+                mem.write::<u32>(GP0, 0xA000_0000);
+                mem.write::<u32>(GP0, (a1 << 16) | (a0 & 0xFFFF));
+                mem.write::<u32>(GP0, (a3 << 16) | (a2 & 0xFFFF));
+
+                // Same size as the GPU sees it, at most the whole VRAM
+                let width = (a2.wrapping_sub(1) & 0x3FF) + 1;
+                let height = (a3.wrapping_sub(1) & 0x1FF) + 1;
+
+                for index in 0 .. (width * height).div_ceil(2)
+                {
+                    let word = mem.read::<u32>(source.wrapping_add(index * 4));
+                    mem.write::<u32>(GP0, word);
+                }
+
+                0
+            },
+            0x48 => { mem.write::<u32>(GP1, a0); 0 }, // SendGP1Command
+            0x49 => { mem.write::<u32>(GP0, a0); 1 }, // GPU_cw
+            0x4A => // GPU_cwp
+            {
+                for index in 0 .. a1
+                {
+                    let word = mem.read::<u32>(a0.wrapping_add(index.wrapping_mul(4)));
+                    mem.write::<u32>(GP0, word);
+                }
+
+                1
+            },
+            0x4D => mem.read::<u32>(GP1), // GetGPUStatus
+            0x4E => 0, // gpu_sync
+            0x51 => // LoadExec
+            {
+                let name = Kernel::read_string(mem, a0);
+                return if self.load_exec(cpu, mem, &name, a1, a2) { Flow::Jump } else { Flow::Return(0) };
+            },
+            0x54 | 0x55 | 0x56 | 0x70 | 0x71 | 0x72 => 1, // CD and memory card init/remove
+            0x96 | 0x97 | 0x99 | 0x9F => 1, // Device installation, SetMemSize
+            0xA0 => { cpu.jump(RESET_VECTOR | 0xA000_0000); self.halted = false; return Flow::Jump }, // WarmBoot
+            0xAB | 0xAC => // _card_info, _card_async_load_directory
+            {
+                self.deliver_event(EVENT_SW_CARD, EVENT_SPEC_IO_END);
+                1
+            },
+            0xB4 => if a0 == 0 { 0x1995_1204 } else { 0 }, // GetSystemInfo (BIOS date)
+            _ =>
+            {
+                warn!("HLE kernel: unimplemented function A0({:02X})", function);
+                0
+            }
+        };
+
+        Flow::Return(v0)
+    }
+
+    fn b_function(&mut self, cpu: &mut CPU, mem: &mut Memory, function: u32, args: [u32; 4]) -> Flow
+    {
+        let [a0, a1, a2, a3] = args;
+
+        let v0 = match function
+        {
+            0x00 => self.kernel_heap.allocate(a0).unwrap_or(0),
+            0x01 => { self.kernel_heap.release(a0); 0 },
+            0x02 => // init_timer
+            {
+                if a0 < 3
+                {
+                    let mut mode = if a2 & 0x10 == 0 { 0x48 } else { 0x49 };
+
+                    if a2 & 1 == 0 { mode |= 0x100 }
+                    if a2 & 0x1000 != 0 { mode |= 0x10 }
+
+                    mem.write::<u32>(0x1F80_1104 + a0 * 0x10, 0);
+                    mem.write::<u32>(0x1F80_1108 + a0 * 0x10, a1);
+                    mem.write::<u32>(0x1F80_1104 + a0 * 0x10, mode);
+                }
+
+                1
+            },
+            0x03 => if a0 < 3 { mem.read::<u32>(0x1F80_1100 + a0 * 0x10) & 0xFFFF } else { 0 }, // get_timer
+            0x04 | 0x05 => // enable_timer_irq, disable_timer_irq
+            {
+                let bit = if a0 < 3 { 1 << (4 + a0) } else { 1 };
+                let mask = mem.read::<u32>(I_MASK);
+                mem.write::<u32>(I_MASK, if function == 0x04 { mask | bit } else { mask & !bit });
+                1
+            },
+            0x06 => { if a0 < 3 { mem.write::<u32>(0x1F80_1100 + a0 * 0x10, 0) }; 1 }, // restart_timer
+            0x07 => { self.deliver_event(a0, a1); 0 },
+            0x08 => self.open_event(a0, a1, a2, a3),
+            0x09 => self.set_event_status(a0, EVENT_FREE),
+            0x0A => // WaitEvent
+            {
+                match self.event(a0).map(|e| e.status)
+                {
+                    Some(EVENT_READY)   => { self.set_event_status(a0, EVENT_ENABLED); 1 },
+                    Some(EVENT_ENABLED) => return Flow::Retry,
+                    _                   => 0
+                }
+            },
+            0x0B => // TestEvent
+            {
+                match self.event(a0).map(|e| e.status)
+                {
+                    Some(EVENT_READY) => { self.set_event_status(a0, EVENT_ENABLED); 1 },
+                    _                 => 0
+                }
+            },
+            0x0C => self.set_event_status(a0, EVENT_ENABLED),
+            0x0D => self.set_event_status(a0, EVENT_DISABLED),
+            0x0E => self.open_thread(cpu, a0, a1, a2),
+            0x0F => // CloseThread
+            {
+                match self.threads.get_mut((a0 & 0xFFFF) as usize)
+                {
+                    Some(thread) if a0 & 0xFFFF != 0 => { *thread = None; 1 },
+                    _ => 0
+                }
+            },
+            0x10 => return self.change_thread(cpu, a0),
+            0x12 => // InitPad
+            {
+                self.pad_buffers = [(a0, a1), (a2, a3)];
+                self.update_pads(mem);
+                2
+            },
+            0x13 => { self.start_pads(mem); 1 },
+            0x14 => { self.pads_started = false; 1 },
+            0x15 => { self.pad_buttons = a1; self.start_pads(mem); 2 }, // OutdatedPadInitAndStart
+            0x16 => 0xFFFF_FFFF, // OutdatedPadGetButtons, no button pressed
+            0x17 => { self.return_from_exception(cpu); return Flow::Jump },
+            0x18 => { self.custom_exit = None; 0 },
+            0x19 => { self.custom_exit = Some(a0); 0 },
+            0x20 => // UnDeliverEvent
+            {
+                for event in self.events.iter_mut().filter(|e| e.class == a0 && e.spec == a1 && e.status == EVENT_READY)
+                {
+                    event.status = EVENT_ENABLED;
+                }
+
+                0
+            },
+            0x32 => self.open(mem, a0, a1),
+            0x33 => self.seek(a0, a1, a2),
+            0x34 => self.read(mem, a0, a1, a2),
+            0x35 => self.write(mem, a0, a1, a2),
+            0x36 => self.close(a0),
+            0x38 => return self.exit(cpu, a0),
+            0x39 => (a0 < FIRST_FILE) as u32, // isatty
+            0x3B | 0x3D => { mem.tty.putchar(a0 as u8); a0 },
+            0x3F => { let s = Kernel::read_string(mem, a0); mem.tty.write(s.as_bytes()); mem.tty.putchar(b'\n'); 1 }, // puts
+            0x41 => self.format(mem, a0),
+            0x42 => self.first_file(mem, a0, a1),
+            0x43 => self.next_file(mem, a0),
+            0x44 => self.rename(mem, a0, a1),
+            0x45 => self.erase(mem, a0),
+            0x47 | 0x48 => 1, // AddDrv, DelDrv
+            0x4A | 0x4B | 0x4C | 0x50 => 1, // InitCard, StartCard, StopCard, allow_new_card
+            0x51 => 0xFFFF_FFFF, // Krom2RawAdd, no kanji font
+            0x54 => self.last_error,
+            0x55 => self.file_errors.get(&a0).cloned().unwrap_or(EBADF),
+            0x56 => C_TABLE,
+            0x57 => B_TABLE,
+            0x5B => 1, // ChangeClearPad
+            0x5C | 0x5D => 1, // get_card_status, wait_card_status: ready
+            _ =>
+            {
+                warn!("HLE kernel: unimplemented function B0({:02X})", function);
+                0
+            }
+        };
+
+        Flow::Return(v0)
+    }
+
+    fn c_function(&mut self, _cpu: &mut CPU, mem: &mut Memory, function: u32, args: [u32; 4]) -> Flow
+    {
+        let [a0, a1, _, _] = args;
+
+        let v0 = match function
+        {
+            0x02 => // SysEnqIntRP
+            {
+                if let Some(head) = self.interrupt_handlers.get_mut(a0 as usize)
+                {
+                    mem.write::<u32>(a1, *head);
+                    *head = a1;
+                }
+
+                0
+            },
+            0x03 => // SysDeqIntRP
+            {
+                if a0 < 4
+                {
+                    let next = mem.read::<u32>(a1);
+                    let mut current = self.interrupt_handlers[a0 as usize];
+
+                    if current == a1
+                    {
+                        self.interrupt_handlers[a0 as usize] = next;
+                    }
+
+                    while current != 0
+                    {
+                        let following = mem.read::<u32>(current);
+
+                        if following == a1
+                        {
+                            mem.write::<u32>(current, next);
+                            break;
+                        }
+
+                        current = following;
+                    }
+                }
+
+                0
+            },
+            0x0A => // ChangeClearRCnt
+            {
+                match self.clear_counters.get_mut(a0 as usize)
+                {
+                    Some(clear) => { let old = *clear; *clear = a1 != 0; old as u32 },
+                    None => 0
+                }
+            },
+            // Kernel initialization, already done
+            0x00 | 0x01 | 0x07 | 0x08 | 0x09 | 0x0C | 0x0D | 0x12 | 0x1C => 0,
+            _ =>
+            {
+                warn!("HLE kernel: unimplemented function C0({:02X})", function);
+                0
+            }
+        };
+
+        Flow::Return(v0)
+    }
+
+    // Exceptions
+
+    fn exception(&mut self, cpu: &mut CPU, mem: &mut Memory)
+    {
+        let code = (cpu.cop0_cause() >> 2) & 0x1F;
+
+        let mut context = Context::save(cpu);
+        context.pc = cpu.cop0_epc();
+
+        match code
+        {
+            0 =>
+            {
+                self.contexts.push(context);
+                self.interrupt(mem);
+
+                let calls = std::mem::take(&mut self.pending_calls);
+                self.frames.push(Frame { calls, current: None, exit: Exit::Exception });
+                self.next_call(cpu, mem);
+            },
+            8 =>
+            {
+                self.syscall(&mut context);
+                context.pc = context.pc.wrapping_add(4);
+                context.restore(cpu, true);
+            },
+            _ =>
+            {
+                error!("HLE kernel: unhandled exception {} @ {:08x}, skipping the instruction", code, context.pc);
+                context.pc = context.pc.wrapping_add(4);
+                context.restore(cpu, true);
+            }
+        }
+    }
+
+    fn syscall(&mut self, context: &mut Context)
+    {
+        match context.r[4]
+        {
+            0 => (),
+            1 => // EnterCriticalSection
+            {
+                context.r[2] = (context.status & 0x404 == 0x404) as u32;
+                context.status &= !0x404;
+            },
+            2 => context.status |= 0x404, // ExitCriticalSection
+            3 => // ChangeThreadSubFunction
+            {
+                let index = (context.r[5] & 0xFFFF) as usize;
+
+                if let Some(Some(thread)) = self.threads.get(index).cloned()
+                {
+                    let mut current = context.clone();
+                    current.pc = current.pc.wrapping_add(4);
+                    current.status = (current.status & !0xF) | ((current.status >> 2) & 0xF);
+                    self.threads[self.current_thread] = Some(current);
+                    self.current_thread = index;
+
+                    // Restored without popping the interrupt enable stack
+                    *context = thread;
+                    context.pc = context.pc.wrapping_sub(4);
+                    context.status = (context.status & !0x3F) | ((context.status << 2) & 0x3F);
+                }
+            },
+            function => warn!("HLE kernel: unknown syscall {}", function)
+        }
+    }
+
+    // Kernel interrupt handling, queues the callbacks to run
+    fn interrupt(&mut self, mem: &mut Memory)
+    {
+        let pending = mem.read::<u32>(I_STAT) & mem.read::<u32>(I_MASK);
+
+        // Root counters 0-2 and VBlank
+        for (counter, irq) in [(0, 4), (1, 5), (2, 6), (3, 0)].iter()
+        {
+            if pending & (1 << irq) != 0
+            {
+                self.deliver_event(EVENT_ROOT_COUNTER | counter, EVENT_SPEC_INTERRUPT);
+
+                if self.clear_counters[*counter as usize]
+                {
+                    mem.write::<u32>(I_STAT, !(1 << irq));
+                }
+            }
+        }
+
+        if pending & 1 != 0 && self.pads_started
+        {
+            self.update_pads(mem);
+        }
+
+        // Interrupt handlers installed by SysEnqIntRP
+        for priority in 0 .. 4
+        {
+            let mut handler = self.interrupt_handlers[priority];
+
+            while handler != 0
+            {
+                self.pending_calls.push_back(Call::Handler(handler));
+                handler = mem.read::<u32>(handler);
+            }
+        }
+    }
+
+    // Starts the next callback of the current frame, or leaves the frame
+    fn next_call(&mut self, cpu: &mut CPU, mem: &mut Memory)
+    {
+        let frame = match self.frames.last_mut()
+        {
+            Some(frame) => frame,
+            None => return
+        };
+
+        while let Some(call) = frame.calls.pop_front()
+        {
+            let function = match call
+            {
+                Call::Function(function) => function,
+                Call::Handler(handler) => mem.read::<u32>(handler + 8)
+            };
+
+            if function == 0
+            {
+                continue;
+            }
+
+            if let Exit::Exception = frame.exit
+            {
+                cpu.set_reg(29, KERNEL_STACK);
+            }
+
+            frame.current = Some(call);
+
+            cpu.set_reg(4, 0);
+            cpu.set_reg(31, CALLBACK_RETURN | 0xA000_0000);
+            cpu.jump(function);
+            return;
+        }
+
+        let frame = self.frames.pop().unwrap();
+
+        match frame.exit
+        {
+            Exit::Exception => match self.custom_exit
+            {
+                // The context stays saved until ReturnFromException
+                Some(buffer) => Kernel::longjmp(cpu, mem, buffer, 1),
+                None => self.return_from_exception(cpu)
+            },
+            Exit::Return { ra, v0 } =>
+            {
+                cpu.set_reg(2, v0);
+                cpu.jump(ra);
+            }
+        }
+    }
+
+    fn callback_returned(&mut self, cpu: &mut CPU, mem: &mut Memory)
+    {
+        let v0 = cpu.registers()[2];
+
+        let frame = match self.frames.last_mut()
+        {
+            Some(frame) => frame,
+            None =>
+            {
+                error!("HLE kernel: unexpected return from a callback");
+                return;
+            }
+        };
+
+        // An interrupt handler's second function is called with the result of the first one
+        if let Some(Call::Handler(handler)) = frame.current.take()
+        {
+            let second = mem.read::<u32>(handler + 4);
+
+            if v0 != 0 && second != 0
+            {
+                frame.current = Some(Call::Function(second));
+
+                cpu.set_reg(4, v0);
+                cpu.set_reg(31, CALLBACK_RETURN | 0xA000_0000);
+                cpu.jump(second);
+                return;
+            }
+        }
+
+        self.next_call(cpu, mem);
+    }
+
+    fn return_from_exception(&mut self, cpu: &mut CPU)
+    {
+        match self.contexts.pop()
+        {
+            Some(context) => context.restore(cpu, true),
+            None => error!("HLE kernel: ReturnFromException without an exception")
+        }
+    }
+
+    // Events
+
+    fn open_event(&mut self, class: u32, spec: u32, mode: u32, function: u32) -> u32
+    {
+        let event = Event { class, spec, mode, function, status: EVENT_DISABLED };
+
+        let index = match self.events.iter().position(|e| e.status == EVENT_FREE)
+        {
+            Some(index) => { self.events[index] = event; index },
+            None => { self.events.push(event); self.events.len() - 1 }
+        };
+
+        0xF100_0000 | index as u32
+    }
+
+    fn event(&self, handle: u32) -> Option<&Event>
+    {
+        self.events.get((handle & 0xFFFF) as usize).filter(|e| e.status != EVENT_FREE)
+    }
+
+    fn set_event_status(&mut self, handle: u32, status: u32) -> u32
+    {
+        match self.events.get_mut((handle & 0xFFFF) as usize)
+        {
+            Some(event) if event.status != EVENT_FREE => { event.status = status; 1 },
+            _ => 0
+        }
+    }
+
+    fn deliver_event(&mut self, class: u32, spec: u32)
+    {
+        for event in self.events.iter_mut().filter(|e| e.class == class && e.spec == spec && e.status == EVENT_ENABLED)
+        {
+            if event.mode == EVENT_MODE_CALLBACK
+            {
+                self.pending_calls.push_back(Call::Function(event.function));
+            }
+            else
+            {
+                event.status = EVENT_READY;
+            }
+        }
+    }
+
+    // Threads
+
+    fn open_thread(&mut self, cpu: &CPU, pc: u32, sp: u32, gp: u32) -> u32
+    {
+        let mut context = Context::save(cpu);
+        context.pc = pc;
+        context.r[28] = gp;
+        context.r[29] = sp;
+        context.r[30] = sp;
+
+        let index = match self.threads.iter().position(|t| t.is_none())
+        {
+            Some(index) => { self.threads[index] = Some(context); index },
+            None => { self.threads.push(Some(context)); self.threads.len() - 1 }
+        };
+
+        0xFF00_0000 | index as u32
+    }
+
+    fn change_thread(&mut self, cpu: &mut CPU, handle: u32) -> Flow
+    {
+        let index = (handle & 0xFFFF) as usize;
+
+        let thread = match self.threads.get(index).cloned().flatten()
+        {
+            Some(thread) => thread,
+            None => return Flow::Return(0)
+        };
+
+        let mut current = Context::save(cpu);
+        current.pc = current.r[31];
+        current.r[2] = 1;
+
+        self.threads[self.current_thread] = Some(current);
+        self.current_thread = index;
+
+        thread.restore(cpu, false);
+        cpu.set_reg(2, 1);
+
+        Flow::Jump
+    }
+
+    // setjmp/longjmp buffer: RA, SP, FP, S0-S7, GP
+
+    fn setjmp(&self, cpu: &CPU, mem: &mut Memory, buffer: u32)
+    {
+        let r = cpu.registers();
+        let registers = [31, 29, 30, 16, 17, 18, 19, 20, 21, 22, 23, 28];
+
+        for (index, register) in registers.iter().enumerate()
+        {
+            mem.write::<u32>(buffer + index as u32 * 4, r[*register]);
+        }
+    }
+
+    fn longjmp(cpu: &mut CPU, mem: &mut Memory, buffer: u32, value: u32)
+    {
+        let registers = [31, 29, 30, 16, 17, 18, 19, 20, 21, 22, 23, 28];
+
+        for (index, register) in registers.iter().enumerate()
+        {
+            let word = mem.read::<u32>(buffer + index as u32 * 4);
+            cpu.set_reg(*register, word);
+        }
+
+        cpu.set_reg(2, value);
+        cpu.jump(mem.read::<u32>(buffer));
+    }
+
+    // Pads, there is no controller connected
+
+    fn start_pads(&mut self, mem: &mut Memory)
+    {
+        self.pads_started = true;
+
+        let mask = mem.read::<u32>(I_MASK);
+        mem.write::<u32>(I_MASK, mask | 1);
+
+        self.update_pads(mem);
+    }
+
+    fn update_pads(&mut self, mem: &mut Memory)
+    {
+        for (buffer, size) in self.pad_buffers.iter()
+        {
+            if *buffer != 0 && *size > 0
+            {
+                mem.write::<u8>(*buffer, 0xFF); // No controller
+            }
+        }
+
+        if self.pad_buttons != 0
+        {
+            mem.write::<u32>(self.pad_buttons, 0xFFFF_FFFF);
+        }
+    }
+
+    // Programs
+
+    // Loads an EXE file and writes its header, returns false on failure
+    fn load(&mut self, cpu: &mut CPU, mem: &mut Memory, name: &str, header: u32) -> bool
+    {
+        let data = match self.resolve(name).and_then(|(path, _)| fs::read(path).ok())
+        {
+            Some(data) if data.len() >= 0x800 && &data[0 .. 8] == b"PS-X EXE" => data,
+            _ =>
+            {
+                error!("HLE kernel: cannot load \"{}\"", name);
+                self.last_error = ENOENT;
+                return false;
+            }
+        };
+
+        let word = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        let destination = word(0x18);
+        let size = word(0x1C).min(data.len() as u32 - 0x800);
+
+        for offset in 0 .. size
+        {
+            mem.write::<u8>(destination + offset, data[0x800 + offset as usize]);
+        }
+
+        // Execution header: PC, GP, text, data, BSS and stack addresses and sizes
+        for offset in (0 .. 0x3C).step_by(4)
+        {
+            mem.write::<u32>(header + offset, word(0x10 + offset as usize));
+        }
+
+        cpu.flush_instruction_cache();
+
+        true
+    }
+
+    fn exec(cpu: &mut CPU, mem: &mut Memory, header: u32, argument1: u32, argument2: u32)
+    {
+        let pc = mem.read::<u32>(header);
+        let gp = mem.read::<u32>(header + 0x04);
+        let bss_address = mem.read::<u32>(header + 0x18);
+        let bss_size = mem.read::<u32>(header + 0x1C);
+        let stack_address = mem.read::<u32>(header + 0x20);
+        let stack_size = mem.read::<u32>(header + 0x24);
+
+        Kernel::fill(mem, bss_address, 0, bss_size);
+
+        if stack_address != 0
+        {
+            cpu.set_reg(29, stack_address + stack_size);
+            cpu.set_reg(30, stack_address + stack_size);
+        }
+
+        cpu.set_reg(28, gp);
+        cpu.set_reg(4, argument1);
+        cpu.set_reg(5, argument2);
+        cpu.jump(pc);
+    }
+
+    fn load_exec(&mut self, cpu: &mut CPU, mem: &mut Memory, name: &str, stack_address: u32, stack_size: u32) -> bool
+    {
+        let header = KERNEL_HEAP.0 - 0x40;
+
+        if !self.load(cpu, mem, name, header)
+        {
+            return false;
+        }
+
+        mem.write::<u32>(header + 0x20, stack_address);
+        mem.write::<u32>(header + 0x24, stack_size);
+
+        Kernel::exec(cpu, mem, header, 1, 0);
+
+        true
+    }
+
+    fn exit(&mut self, cpu: &mut CPU, code: u32) -> Flow
+    {
+        info!("HLE kernel: program exited with code {}", code);
+
+        self.halted = true;
+        cpu.jump(RESET_VECTOR | 0xA000_0000);
+
+        Flow::Jump
+    }
+
+    // Files
+
+    // Returns the host path and if the file is on a memory card (and its port)
+    // The guest can't reach anything outside of the disc and memory card directories.
+    fn resolve(&self, name: &str) -> Option<(PathBuf, Option<u32>)>
+    {
+        let (device, path) = name.split_once(':')?;
+
+        match device.to_ascii_lowercase().as_str()
+        {
+            "cdrom" | "cdrom0" =>
+            {
+                let root = self.cdrom_root.as_ref()?;
+                let path = path.split(';').next().unwrap_or("").trim_start_matches(['\\', '/']);
+
+                let components: Vec<&str> = if path.is_empty() { Vec::new() } else { path.split(['\\', '/']).collect() };
+
+                if !components.iter().all(|component| Kernel::is_plain_name(component))
+                {
+                    return None;
+                }
+
+                let path = Kernel::find_case_insensitive(root, components.into_iter());
+
+                Some((Kernel::contain(root, &path)?, None))
+            },
+            "bu00" | "bu10" =>
+            {
+                if !Kernel::is_plain_name(path)
+                {
+                    return None;
+                }
+
+                let port = if device.starts_with("bu0") { 0 } else { 1 };
+                let path = self.memory_card_root.join(device).join(path);
+
+                Some((Kernel::contain(&self.memory_card_root, &path)?, Some(port)))
+            },
+            _ => None
+        }
+    }
+
+    // A single file or directory name: not empty, not "." or "..", no separator or drive
+    fn is_plain_name(name: &str) -> bool
+    {
+        let mut components = Path::new(name).components();
+
+        matches!((components.next(), components.next()), (Some(Component::Normal(normal)), None) if normal == name)
+    }
+
+    // Returns the real path if it is inside the root, the symbolic links are followed.
+    // The end of the path may not exist yet (a file being created).
+    fn contain(root: &Path, path: &Path) -> Option<PathBuf>
+    {
+        let root = Kernel::canonicalize(root)?;
+        let path = Kernel::canonicalize(path)?;
+
+        if path.starts_with(&root) { Some(path) } else { None }
+    }
+
+    fn canonicalize(path: &Path) -> Option<PathBuf>
+    {
+        let mut existing = path;
+        let mut missing = Vec::new();
+
+        let canonical = loop
+        {
+            // A relative path that doesn't exist at all
+            if existing.as_os_str().is_empty()
+            {
+                break std::env::current_dir().ok()?;
+            }
+
+            match existing.canonicalize()
+            {
+                Ok(canonical) => break canonical,
+                Err(_) =>
+                {
+                    missing.push(existing.file_name()?);
+                    existing = existing.parent()?;
+                }
+            }
+        };
+
+        Some(missing.iter().rev().fold(canonical, |path, name| path.join(name)))
+    }
+
+    // The disc file names are uppercase but the extracted files may not be
+    fn find_case_insensitive<'a>(root: &Path, components: impl Iterator<Item = &'a str>) -> PathBuf
+    {
+        let mut path = root.to_path_buf();
+
+        for component in components
+        {
+            let found = fs::read_dir(&path).ok().and_then(|entries|
+                entries.filter_map(|e| e.ok())
+                    .find(|e| e.file_name().to_string_lossy().eq_ignore_ascii_case(component))
+                    .map(|e| e.path())
+            );
+
+            path = found.unwrap_or_else(|| path.join(component));
+        }
+
+        path
+    }
+
+    fn open(&mut self, mem: &mut Memory, name: u32, mode: u32) -> u32
+    {
+        let name = Kernel::read_string(mem, name);
+
+        let fd = match (FIRST_FILE .. MAX_FILES).find(|fd| !self.files.contains_key(fd))
+        {
+            Some(fd) => fd,
+            None => { self.last_error = EBADF; return 0xFFFF_FFFF }
+        };
+
+        let (path, port) = match self.resolve(&name)
+        {
+            Some(resolved) => resolved,
+            None => { self.last_error = ENOENT; return 0xFFFF_FFFF }
+        };
+
+        let write = mode & 2 != 0 && port.is_some();
+        let create = mode & 0x200 != 0 && port.is_some();
+
+        if create
+        {
+            if let Some(parent) = path.parent()
+            {
+                let _ = fs::create_dir_all(parent);
+            }
+        }
+
+        let file = fs::OpenOptions::new().read(true).write(write || create).create(create).open(&path);
+
+        match file
+        {
+            Ok(file) =>
+            {
+                debug!("HLE kernel: open \"{}\" -> {}", name, fd);
+                self.files.insert(fd, OpenFile { file: Some(file), name, position: 0, writable: write || create, port });
+                self.file_errors.insert(fd, 0);
+                fd
+            },
+            Err(_) =>
+            {
+                warn!("HLE kernel: cannot open \"{}\" ({})", name, path.display());
+                self.last_error = ENOENT;
+                0xFFFF_FFFF
+            }
+        }
+    }
+
+    fn seek(&mut self, fd: u32, offset: u32, origin: u32) -> u32
+    {
+        let position = match origin
+        {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i32 as i64),
+            2 => SeekFrom::End(offset as i32 as i64),
+            _ => { self.file_errors.insert(fd, EINVAL); return 0xFFFF_FFFF }
+        };
+
+        match self.files.get_mut(&fd).and_then(|open| open.file.as_mut()).map(|file| file.seek(position))
+        {
+            Some(Ok(position)) => position as u32,
+            _ => { self.last_error = EBADF; 0xFFFF_FFFF }
+        }
+    }
+
+    fn read(&mut self, mem: &mut Memory, fd: u32, destination: u32, length: u32) -> u32
+    {
+        // Nothing bigger than the RAM can be read
+        let mut buffer = vec![0; length.min(RAM_SIZE) as usize];
+
+        let read = match self.files.get_mut(&fd).and_then(|open| open.file.as_mut()).map(|file| file.read(&mut buffer))
+        {
+            Some(Ok(read)) => read,
+            _ => { self.last_error = EBADF; return 0xFFFF_FFFF }
+        };
+
+        for (offset, byte) in buffer[.. read].iter().enumerate()
+        {
+            mem.write::<u8>(destination.wrapping_add(offset as u32), *byte);
+        }
+
+        // Memory card transfers signal their end with an event
+        if self.files.get(&fd).is_some_and(|open| open.port.is_some())
+        {
+            self.deliver_event(EVENT_SW_CARD, EVENT_SPEC_IO_END);
+        }
+
+        read as u32
+    }
+
+    fn write(&mut self, mem: &mut Memory, fd: u32, source: u32, length: u32) -> u32
+    {
+        let buffer = Kernel::read_bytes(mem, source, length);
+
+        if fd < FIRST_FILE
+        {
+            mem.tty.write(&buffer);
+            return length;
+        }
+
+        let written = match self.files.get_mut(&fd).and_then(|open| open.file.as_mut()).map(|file| file.write(&buffer))
+        {
+            Some(Ok(written)) => written,
+            _ => { self.last_error = EBADF; return 0xFFFF_FFFF }
+        };
+
+        if self.files.get(&fd).is_some_and(|open| open.port.is_some())
+        {
+            self.deliver_event(EVENT_SW_CARD, EVENT_SPEC_IO_END);
+        }
+
+        written as u32
+    }
+
+    fn close(&mut self, fd: u32) -> u32
+    {
+        match self.files.remove(&fd)
+        {
+            Some(_) => fd,
+            None => { self.last_error = EBADF; 0xFFFF_FFFF }
+        }
+    }
+
+    // Lists the files matching the pattern, and returns the first one
+    fn first_file(&mut self, mem: &mut Memory, pattern: u32, dirent: u32) -> u32
+    {
+        let pattern = Kernel::read_string(mem, pattern);
+
+        let (directory, _) = match self.resolve(&pattern)
+        {
+            Some(resolved) => resolved,
+            None => { self.last_error = ENOENT; return 0 }
+        };
+
+        let name_pattern = directory.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let name_pattern = name_pattern.split(';').next().unwrap_or("").to_string();
+
+        self.search = directory.parent()
+            .and_then(|parent| fs::read_dir(parent).ok())
+            .map(|entries| entries.filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file())
+                .map(|e| (e.file_name().to_string_lossy().to_string(), e.metadata().map(|m| m.len() as u32).unwrap_or(0)))
+                .filter(|(name, _)| Kernel::matches(&name_pattern, name))
+                .collect())
+            .unwrap_or_default();
+
+        self.search.sort();
+        self.search.reverse();
+
+        self.next_file(mem, dirent)
+    }
+
+    fn next_file(&mut self, mem: &mut Memory, dirent: u32) -> u32
+    {
+        let (name, size) = match self.search.pop()
+        {
+            Some(entry) => entry,
+            None => return 0
+        };
+
+        Kernel::fill(mem, dirent, 0, DIRENT_SIZE);
+
+        for (offset, byte) in name.bytes().take(19).enumerate()
+        {
+            mem.write::<u8>(dirent + offset as u32, byte);
+        }
+
+        mem.write::<u32>(dirent + 0x14, 0x50); // Attributes
+        mem.write::<u32>(dirent + 0x18, size);
+
+        dirent
+    }
+
+    // Wildcards: '?' for any character, '*' for the rest of the name
+    fn matches(pattern: &str, name: &str) -> bool
+    {
+        let mut name = name.chars();
+
+        for p in pattern.chars()
+        {
+            match p
+            {
+                '*' => return true,
+                '?' => if name.next().is_none() { return false },
+                _   => if !name.next().is_some_and(|c| c.eq_ignore_ascii_case(&p)) { return false }
+            }
+        }
+
+        name.next().is_none()
+    }
+
+    fn rename(&mut self, mem: &mut Memory, old: u32, new: u32) -> u32
+    {
+        let old = self.resolve(&Kernel::read_string(mem, old));
+        let new = self.resolve(&Kernel::read_string(mem, new));
+
+        match (old, new)
+        {
+            (Some((old, Some(_))), Some((new, Some(_)))) => fs::rename(old, new).is_ok() as u32,
+            _ => { self.last_error = ENOENT; 0 }
+        }
+    }
+
+    fn erase(&mut self, mem: &mut Memory, name: u32) -> u32
+    {
+        match self.resolve(&Kernel::read_string(mem, name))
+        {
+            Some((path, Some(_))) => fs::remove_file(path).is_ok() as u32,
+            _ => { self.last_error = ENOENT; 0 }
+        }
+    }
+
+    fn format(&mut self, mem: &mut Memory, device: u32) -> u32
+    {
+        let device = Kernel::read_string(mem, device);
+        let device = device.trim_end_matches(':').to_ascii_lowercase();
+
+        if device != "bu00" && device != "bu10"
+        {
+            return 0;
+        }
+
+        let directory = self.memory_card_root.join(device);
+        let _ = fs::remove_dir_all(&directory);
+
+        fs::create_dir_all(&directory).is_ok() as u32
+    }
+
+    // Guest memory helpers
+
+    fn read_string(mem: &mut Memory, address: u32) -> String
+    {
+        let mut string = String::new();
+
+        if address == 0
+        {
+            return string;
+        }
+
+        for offset in 0 .. 0x1000
+        {
+            match mem.read::<u8>(address.wrapping_add(offset))
+            {
+                0 => break,
+                c => string.push(c as char)
+            }
+        }
+
+        string
+    }
+
+    fn write_string(mem: &mut Memory, address: u32, string: &str)
+    {
+        for (offset, byte) in string.bytes().chain(std::iter::once(0)).enumerate()
+        {
+            mem.write::<u8>(address.wrapping_add(offset as u32), byte);
+        }
+    }
+
+    fn read_bytes(mem: &mut Memory, address: u32, length: u32) -> Vec<u8>
+    {
+        (0 .. length).map(|offset| mem.read::<u8>(address.wrapping_add(offset))).collect()
+    }
+
+    fn copy(mem: &mut Memory, destination: u32, source: u32, length: u32)
+    {
+        let bytes = Kernel::read_bytes(mem, source, length);
+
+        for (offset, byte) in bytes.iter().enumerate()
+        {
+            mem.write::<u8>(destination.wrapping_add(offset as u32), *byte);
+        }
+    }
+
+    fn fill(mem: &mut Memory, address: u32, value: u8, length: u32)
+    {
+        for offset in 0 .. length
+        {
+            mem.write::<u8>(address.wrapping_add(offset), value);
+        }
+    }
+
+    // Formats like the BIOS printf: %c %s %d %i %u %o %x %X %p %%, with the '-' and '0' flags, a width and a precision
+    // The arguments after the format are in A1-A3, then on the stack
+    fn printf(cpu: &CPU, mem: &mut Memory, format: u32) -> String
+    {
+        let registers = cpu.registers();
+        let format = Kernel::read_string(mem, format);
+
+        let mut index = 1;
+        let mut argument = |mem: &mut Memory|
+        {
+            let value = if index < 4 { registers[4 + index] } else { mem.read::<u32>(registers[29].wrapping_add(index as u32 * 4)) };
+            index += 1;
+            value
+        };
+
+        let mut output = String::new();
+        let mut characters = format.chars().peekable();
+
+        while let Some(c) = characters.next()
+        {
+            if c != '%'
+            {
+                output.push(c);
+                continue;
+            }
+
+            let mut left = false;
+            let mut zero = false;
+
+            while let Some(flag) = characters.next_if(|c| matches!(c, '-' | '0' | '+' | ' ' | '#'))
+            {
+                match flag
+                {
+                    '-' => left = true,
+                    '0' => zero = true,
+                    _   => ()
+                }
+            }
+
+            let mut width = 0;
+
+            while let Some(digit) = characters.next_if(char::is_ascii_digit)
+            {
+                width = width * 10 + digit.to_digit(10).unwrap() as usize;
+            }
+
+            let mut precision = None;
+
+            if characters.next_if_eq(&'.').is_some()
+            {
+                let mut digits = 0;
+
+                while let Some(digit) = characters.next_if(char::is_ascii_digit)
+                {
+                    digits = digits * 10 + digit.to_digit(10).unwrap() as usize;
+                }
+
+                precision = Some(digits);
+            }
+
+            while characters.next_if(|c| matches!(c, 'l' | 'h')).is_some()
+            {
+            }
+
+            let conversion = match characters.next()
+            {
+                Some(conversion) => conversion,
+                None             => break
+            };
+
+            let text = match conversion
+            {
+                'd' | 'i' => (argument(mem) as i32).to_string(),
+                'u'       => argument(mem).to_string(),
+                'o'       => format!("{:o}", argument(mem)),
+                'x'       => format!("{:x}", argument(mem)),
+                'X'       => format!("{:X}", argument(mem)),
+                'p'       => format!("{:08x}", argument(mem)),
+                'c'       => ((argument(mem) as u8) as char).to_string(),
+                's' =>
+                {
+                    let address = argument(mem);
+                    let string = Kernel::read_string(mem, address);
+
+                    match precision
+                    {
+                        Some(length) => string.chars().take(length).collect(),
+                        None         => string
+                    }
+                },
+                '%'       => String::from("%"),
+                other     => format!("%{}", other)
+            };
+
+            let padding = width.saturating_sub(text.len());
+
+            if left
+            {
+                output += &text;
+                output += &" ".repeat(padding);
+            }
+            else if zero && !matches!(conversion, 's' | 'c' | '%')
+            {
+                // The zeros go after the sign
+                let (sign, digits) = text.split_at(text.starts_with('-') as usize);
+                output += sign;
+                output += &"0".repeat(padding);
+                output += digits;
+            }
+            else
+            {
+                output += &" ".repeat(padding);
+                output += &text;
+            }
+        }
+
+        output
+    }
+
+    fn compare(a: &str, b: &str, length: usize) -> u32
+    {
+        let a = a.bytes().take(length).chain(std::iter::once(0));
+        let b = b.bytes().take(length).chain(std::iter::once(0));
+
+        a.zip(b).find(|(x, y)| x != y).map_or(0, |(x, y)| (x as i32 - y as i32) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn resolve_stays_in_the_roots()
+    {
+        let root = std::env::temp_dir().join(format!("psx-hle-{}", std::process::id()));
+        let cdrom = root.join("cdrom");
+        let memory_cards = root.join("cards");
+
+        fs::create_dir_all(cdrom.join("data")).unwrap();
+        fs::write(cdrom.join("data").join("file.bin"), b"").unwrap();
+
+        let kernel = Kernel::new(Some(cdrom.clone()), memory_cards.clone());
+        let cdrom = cdrom.canonicalize().unwrap();
+
+        assert_eq!(kernel.resolve("cdrom:\\DATA\\FILE.BIN;1"), Some((cdrom.join("data").join("file.bin"), None)));
+        assert_eq!(kernel.resolve("cdrom:"), Some((cdrom.clone(), None)));
+        assert_eq!(kernel.resolve("cdrom:\\..\\cards"), None);
+        assert_eq!(kernel.resolve("cdrom:\\DATA\\.\\FILE.BIN"), None);
+        assert_eq!(kernel.resolve("cdrom:\\DATA\\\\FILE.BIN"), None);
+
+        // The memory cards don't exist yet
+        let card = root.canonicalize().unwrap().join("cards").join("bu00").join("SAVE");
+        assert_eq!(kernel.resolve("bu00:SAVE"), Some((card, Some(0))));
+        assert_eq!(kernel.resolve("bu10:.."), None);
+        assert_eq!(kernel.resolve("bu10:"), None);
+        assert_eq!(kernel.resolve("bu10:a/b"), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&root, cdrom.join("outside")).unwrap();
+            assert_eq!(kernel.resolve("cdrom:OUTSIDE\\cards"), None);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn loaded_files_are_opened_in_the_current_directories()
+    {
+        let root = std::env::temp_dir().join(format!("psx-hle-state-{}", std::process::id()));
+
+        for directory in ["saved", "current"]
+        {
+            fs::create_dir_all(root.join(directory).join("bu00")).unwrap();
+            fs::write(root.join(directory).join("bu00").join("SAVE"), directory).unwrap();
+        }
+
+        let mut kernel = Kernel::new(None, root.join("saved"));
+        let mut file = File::open(root.join("saved").join("bu00").join("SAVE")).unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        kernel.files.insert(FIRST_FILE, OpenFile { file: Some(file), name: String::from("bu00:SAVE"), position: 0, writable: true, port: Some(0) });
+
+        let state = bincode::serialize(&kernel).unwrap();
+        let mut loaded: Kernel = bincode::deserialize(&state).unwrap();
+
+        loaded.restore(&Kernel::new(None, root.join("current"))).unwrap();
+
+        let mut contents = String::new();
+        loaded.files.get_mut(&FIRST_FILE).unwrap().file.as_mut().unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "rrent");
+
+        // The file must still be there
+        let mut loaded: Kernel = bincode::deserialize(&state).unwrap();
+        assert!(loaded.restore(&Kernel::new(None, root.join("missing"))).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
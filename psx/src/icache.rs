@@ -0,0 +1,113 @@
+// R3000A instruction cache: 4KB direct-mapped, 256 lines of 4 words
+// Documentation
+// https://problemkaputt.de/psx-spx.htm#memorycontrol
+
+use serde::{ Serialize, Deserialize };
+use serde_big_array::BigArray;
+
+const LINE_COUNT: usize = 256;
+
+// Cache control register bits (0xFFFE0130)
+pub const CONTROL_TAG_TEST: u32 = 1 << 2;
+pub const CONTROL_ENABLE: u32 = 1 << 11;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Line
+{
+    tag: u32, // Address bits 12-30
+    valid: u8, // One bit per word
+    data: [u32; 4]
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InstructionCache
+{
+    #[serde(with = "BigArray")]
+    lines: [Line; LINE_COUNT]
+}
+
+impl InstructionCache
+{
+    pub fn new() -> Self
+    {
+        InstructionCache
+        {
+            lines: [Line { tag: 0, valid: 0, data: [0; 4] }; LINE_COUNT]
+        }
+    }
+
+    // KUSEG and KSEG0 are cached, KSEG1 isn't
+    pub fn is_cached(address: u32) -> bool
+    {
+        address < 0xA000_0000
+    }
+
+    fn tag(address: u32) -> u32
+    {
+        address & 0x7FFF_F000
+    }
+
+    fn line_index(address: u32) -> usize
+    {
+        ((address >> 4) & 0xFF) as usize
+    }
+
+    fn word_index(address: u32) -> usize
+    {
+        ((address >> 2) & 3) as usize
+    }
+
+    // Returns the cached instruction, if any
+    pub fn fetch(&self, address: u32) -> Option<u32>
+    {
+        let line = &self.lines[InstructionCache::line_index(address)];
+        let word = InstructionCache::word_index(address);
+
+        if line.tag == InstructionCache::tag(address) && line.valid & (1 << word) != 0
+        {
+            Some(line.data[word])
+        }
+        else
+        {
+            None
+        }
+    }
+
+    // On a miss, the line is refilled from the missed word to its end
+    pub fn refill(&mut self, address: u32, words: &[u32])
+    {
+        let line = &mut self.lines[InstructionCache::line_index(address)];
+        let first = InstructionCache::word_index(address);
+
+        if line.tag != InstructionCache::tag(address)
+        {
+            line.tag = InstructionCache::tag(address);
+            line.valid = 0;
+        }
+
+        for (index, word) in (first .. 4).zip(words)
+        {
+            line.data[index] = *word;
+            line.valid |= 1 << index;
+        }
+    }
+
+    // Stores while the cache is isolated (used by the BIOS to flush the cache):
+    //   - in tag test mode, the line is invalidated
+    //   - otherwise, the word is written to the cached data
+    pub fn store(&mut self, address: u32, value: u32, tag_test: bool)
+    {
+        let line = &mut self.lines[InstructionCache::line_index(address)];
+        let word = InstructionCache::word_index(address);
+
+        if tag_test
+        {
+            line.tag = InstructionCache::tag(address);
+            line.valid = 0;
+        }
+        else
+        {
+            line.data[word] = value;
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use serde::{ Serialize, Deserialize };
+
 #[derive(Debug, Copy, Clone)]
 pub enum InterruptRequest
 {
@@ -14,6 +16,7 @@ pub enum InterruptRequest
     // IRQ10???
 }
 
+#[derive(Default, Serialize, Deserialize)]
 pub struct InterruptController
 {
     interrupt_status: u16,
@@ -58,10 +61,8 @@ impl InterruptController
 
     pub fn request(&mut self, request: InterruptRequest)
     {
-        error!("REQUEST");
-        error!("interrupt req {:?}", request);
-        error!("interrupt req val {:b}", request as u16);
-        error!("interrupt req val {:b}", self.interrupt_status | (1 << (request as u16)));
+        // VBlank and the timers request interrupts all the time
+        trace!("interrupt req {:?}, status {:b}", request, self.interrupt_status | (1 << (request as u16)));
 
         self.interrupt_status |= 1 << (request as u16);
     }
@@ -0,0 +1,1054 @@
+// x86-64 dynamic recompiler
+//
+// The blocks decoded by the block cache are translated to machine code that runs a whole block
+// at once: the interrupts are checked and the other components catch up before and after the block.
+// The ALU instructions, the multiplications, HI/LO, the jumps and branches, and the loads and stores
+// in RAM are native, everything else (coprocessors, I/O, unaligned accesses, exceptions...)
+// calls the interpreter's handlers.
+// The registers stay in the CPU structure, with the same load-delay slot bookkeeping as
+// the interpreter at each instruction boundary, so the block can be left after any instruction.
+// A block only runs when its worst-case time fits before the next device event, and the devices
+// catch up before each access that the block doesn't make natively: the timers and the interrupts
+// see the same cycles as with the interpreter. The instruction fetches are charged once the block
+// has run, with the same instruction cache refills.
+
+use crate::block_cache::{ AddressMap, Block };
+use crate::cpu::{ CPU, Handler };
+use crate::memory::{ Memory, CODE_PAGE_SHIFT, NOT_RAM, RAM_READ_CYCLES, RAM_WRITE_CYCLES };
+use crate::opcode::Opcode;
+
+use memmap2::{ Mmap, MmapMut };
+
+use std::mem::offset_of;
+
+// Instruction of a recompiled block, referenced by the machine code
+pub struct Entry
+{
+    pub address: u32,
+    pub handler: Handler,
+    pub opcode: Opcode,
+    pub index: u32, // In the block
+    pub delay_slot: bool
+}
+
+// Runs a block, returns the number of instructions executed
+pub type BlockFunction = unsafe extern "C" fn(cpu: *mut CPU, mem: *mut Memory) -> u32;
+
+// What a block can cost, the memory timings are only known when it runs
+#[derive(Clone, Copy)]
+pub struct Cost
+{
+    pub length: u32, // Number of instructions
+    pub loads: u32,
+    pub stores: u32
+}
+
+#[derive(Clone, Copy)]
+pub enum Compiled
+{
+    Native(BlockFunction, Cost),
+    Interpreted // Calls the BIOS functions or the HLE kernel, only the interpreter sees them
+}
+
+struct CompiledBlock
+{
+    code: Option<Mmap>,
+    cost: Cost,
+    ram_page: Option<u32>,
+    _entries: Box<[Entry]> // Kept alive for the machine code
+}
+
+// Memory seen by the native loads and stores, set before each block
+#[repr(C)]
+struct Context
+{
+    ram: *mut u8,
+    ram_offsets: *const u32, // Offset in the RAM of each 64KB page, NOT_RAM elsewhere
+    code_pages: *const bool, // The stores to the compiled code go through the memory
+    cycles: u32, // Time spent in the native loads and stores
+    fast_stores: bool // False while the stores are recorded
+}
+
+// Offsets of the CPU fields used by the machine code
+pub struct Offsets
+{
+    pub r: usize,
+    pub r_next: usize,
+    pub hi: usize,
+    pub lo: usize,
+    pub pc: usize,
+    pub next_pc: usize,
+    pub current_pc: usize,
+    pub pending_load: usize,
+    pub previous_pending_load: usize,
+    pub status: usize,
+    pub branching: usize,
+    pub in_delay_slot: usize,
+    pub jit: usize
+}
+
+// Displacements from the CPU pointer
+#[derive(Clone, Copy)]
+struct Layout
+{
+    r: i32,
+    r_next: i32,
+    hi: i32,
+    lo: i32,
+    pc: i32,
+    next_pc: i32,
+    current_pc: i32,
+    pending_register: i32,
+    pending_value: i32,
+    previous_register: i32,
+    previous_value: i32,
+    status: i32,
+    branching: i32,
+    in_delay_slot: i32,
+
+    ram: i32,
+    ram_offsets: i32,
+    code_pages: i32,
+    cycles: i32,
+    fast_stores: i32
+}
+
+pub struct JIT
+{
+    blocks: AddressMap<CompiledBlock>,
+
+    layout: Layout,
+    context: Context,
+
+    // State of the current run
+    pub budget: u32, // Instructions left to execute
+    pub stopped: bool, // Breakpoint or divergence
+    pub flush_requested: bool, // The cache was flushed, the blocks are dropped between two blocks
+
+    // State of the current block
+    pub block_address: u32,
+    pub ticked: u32, // Instructions already seen by the devices
+
+    // Registers computed by the interpreter in lockstep mode
+    pub expected_registers: [u32; 32]
+}
+
+impl JIT
+{
+    pub fn new(offsets: Offsets) -> Self
+    {
+        let context = offsets.jit + offset_of!(JIT, context);
+        let first = offset_of!((u32, u32), 0);
+        let second = offset_of!((u32, u32), 1);
+
+        JIT
+        {
+            blocks: AddressMap::default(),
+
+            layout: Layout
+            {
+                r: offsets.r as i32,
+                r_next: offsets.r_next as i32,
+                hi: offsets.hi as i32,
+                lo: offsets.lo as i32,
+                pc: offsets.pc as i32,
+                next_pc: offsets.next_pc as i32,
+                current_pc: offsets.current_pc as i32,
+                pending_register: (offsets.pending_load + first) as i32,
+                pending_value: (offsets.pending_load + second) as i32,
+                previous_register: (offsets.previous_pending_load + first) as i32,
+                previous_value: (offsets.previous_pending_load + second) as i32,
+                status: offsets.status as i32,
+                branching: offsets.branching as i32,
+                in_delay_slot: offsets.in_delay_slot as i32,
+
+                ram: (context + offset_of!(Context, ram)) as i32,
+                ram_offsets: (context + offset_of!(Context, ram_offsets)) as i32,
+                code_pages: (context + offset_of!(Context, code_pages)) as i32,
+                cycles: (context + offset_of!(Context, cycles)) as i32,
+                fast_stores: (context + offset_of!(Context, fast_stores)) as i32
+            },
+
+            context: Context
+            {
+                ram: std::ptr::null_mut(),
+                ram_offsets: std::ptr::null(),
+                code_pages: std::ptr::null(),
+                cycles: 0,
+                fast_stores: false
+            },
+
+            budget: 0,
+            stopped: false,
+            flush_requested: false,
+
+            block_address: 0,
+            ticked: 0,
+
+            expected_registers: [0; 32]
+        }
+    }
+
+    // Returns the block at this address, if already compiled
+    pub fn get(&self, address: u32) -> Option<Compiled>
+    {
+        self.blocks.get(&address).map(|block| match &block.code
+        {
+            Some(code) => Compiled::Native(unsafe { std::mem::transmute::<*const u8, BlockFunction>(code.as_ptr()) }, block.cost),
+            None       => Compiled::Interpreted
+        })
+    }
+
+    pub fn invalidate_page(&mut self, page: u32)
+    {
+        self.blocks.retain(|_, block| block.ram_page != Some(page));
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.blocks.clear();
+        self.flush_requested = false;
+    }
+
+    // The memory can move with the save states, it is given again before each block
+    pub fn set_memory(&mut self, (ram, ram_offsets, code_pages): (*mut u8, *const u32, *const bool), fast_stores: bool)
+    {
+        self.context.ram = ram;
+        self.context.ram_offsets = ram_offsets;
+        self.context.code_pages = code_pages;
+        self.context.fast_stores = fast_stores;
+    }
+
+    // Returns the time spent in the native memory accesses since the latest call
+    pub fn take_cycles(&mut self) -> u32
+    {
+        std::mem::replace(&mut self.context.cycles, 0)
+    }
+
+    pub fn insert_interpreted(&mut self, block: &Block)
+    {
+        self.blocks.insert(block.address, CompiledBlock
+        {
+            code: None,
+            cost: Cost { length: block.instructions.len() as u32, loads: 0, stores: 0 },
+            ram_page: block.ram_page,
+            _entries: Box::new([])
+        });
+    }
+
+    // In lockstep mode, each instruction is a block so that the states can be compared after it,
+    // and the native ALU instructions are checked against the interpreter
+    pub fn compile(&mut self, block: &Block, lockstep: bool) -> std::io::Result<Compiled>
+    {
+        let length = if lockstep { 1 } else { block.instructions.len() };
+
+        let mut entries = Vec::with_capacity(length);
+        let mut delay_slot = false;
+
+        for (index, (handler, bits)) in block.instructions.iter().enumerate().take(length)
+        {
+            let opcode = Opcode(*bits);
+
+            // A jump in a delay slot is left to the interpreter
+            if delay_slot && is_jump(&opcode)
+            {
+                break;
+            }
+
+            let jump = is_jump(&opcode);
+
+            entries.push(Entry
+            {
+                address: block.address.wrapping_add(index as u32 * 4),
+                handler: *handler,
+                opcode,
+                index: index as u32,
+                delay_slot
+            });
+
+            delay_slot = jump;
+        }
+
+        let entries = entries.into_boxed_slice();
+
+        let mut e = Emitter::new(self.layout);
+
+        e.prologue();
+
+        for (index, entry) in entries.iter().enumerate()
+        {
+            // Same as the start of an interpreted instruction, skipped when the previous instructions can't load
+            let previous_load = index == 0 || may_load(&entries[index - 1].opcode);
+            let older_load = index < 2 || may_load(&entries[index - 2].opcode);
+
+            if previous_load
+            {
+                e.apply_pending_load();
+            }
+            else if older_load
+            {
+                e.clear_previous_load();
+            }
+
+            e.instruction(entry, index as u32, previous_load, lockstep);
+
+            if previous_load
+            {
+                e.commit_pending_load();
+            }
+        }
+
+        e.block_end(entries.last().unwrap());
+        e.exit(entries.len() as u32);
+        e.epilogue();
+
+        let mut map = MmapMut::map_anon(e.code.len())?;
+        map.copy_from_slice(&e.code);
+        let code = map.make_exec()?;
+
+        let function = unsafe { std::mem::transmute::<*const u8, BlockFunction>(code.as_ptr()) };
+        let cost = Cost
+        {
+            length: entries.len() as u32,
+            loads: entries.iter().filter(|entry| is_load(&entry.opcode)).count() as u32,
+            stores: entries.iter().filter(|entry| is_store(&entry.opcode)).count() as u32
+        };
+
+        self.blocks.insert(block.address, CompiledBlock { code: Some(code), cost, ram_page: block.ram_page, _entries: entries });
+
+        Ok(Compiled::Native(function, cost))
+    }
+}
+
+// Jumps and branches, they have a delay slot
+fn is_jump(opcode: &Opcode) -> bool
+{
+    match opcode.instr()
+    {
+        0b000000 => matches!(opcode.sub(), 0b001000 | 0b001001), // JR, JALR
+        0b000001 ..= 0b000111 => true,
+        _ => false
+    }
+}
+
+// Loads to the registers and to COP2
+pub fn is_load(opcode: &Opcode) -> bool
+{
+    matches!(opcode.instr(), 0b100000 ..= 0b100111 | 0b110000 ..= 0b110111)
+}
+
+// Stores from the registers and from COP2
+pub fn is_store(opcode: &Opcode) -> bool
+{
+    matches!(opcode.instr(), 0b101000 ..= 0b101111 | 0b111000 ..= 0b111111)
+}
+
+// Instructions that can leave a pending load: the loads and MFC0
+fn may_load(opcode: &Opcode) -> bool
+{
+    match opcode.instr()
+    {
+        0b010000 => opcode.rs() == 0b00000,
+        0b100000 ..= 0b100110 => true,
+        _ => false
+    }
+}
+
+// Calls from the machine code
+
+// Returns 0 if the block must be left
+unsafe extern "C" fn cpu_execute(cpu: *mut CPU, mem: *mut Memory, entry: *const Entry) -> u32
+{
+    (*cpu).jit_execute(&mut *mem, &*entry) as u32
+}
+
+unsafe extern "C" fn cpu_check_begin(cpu: *mut CPU, mem: *mut Memory, entry: *const Entry)
+{
+    let entry = &*entry;
+    (*cpu).jit_check_begin(&mut *mem, entry.handler, &entry.opcode);
+}
+
+unsafe extern "C" fn cpu_check_end(cpu: *mut CPU, mem: *mut Memory, entry: *const Entry) -> u32
+{
+    let entry = &*entry;
+    (*cpu).jit_check_end(&mut *mem, entry.address) as u32
+}
+
+// Machine code generation
+//
+// RBX: CPU, R12: Memory
+// EAX, ECX, EDX: scratch
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+// Condition codes
+const OVERFLOW: u8 = 0x0;
+const EQUAL: u8 = 0x4;
+const NOT_EQUAL: u8 = 0x5;
+const LESS: u8 = 0xC;
+const GREATER_OR_EQUAL: u8 = 0xD;
+const LESS_OR_EQUAL: u8 = 0xE;
+const GREATER: u8 = 0xF;
+
+struct Emitter
+{
+    code: Vec<u8>,
+    exits: Vec<usize>, // Jumps to patch with the epilogue address
+
+    layout: Layout
+}
+
+impl Emitter
+{
+    fn new(layout: Layout) -> Self
+    {
+        Emitter
+        {
+            code: Vec::new(),
+            exits: Vec::new(),
+
+            layout
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8])
+    {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32)
+    {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn emit_u64(&mut self, value: u64)
+    {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn emit_i32(&mut self, value: i32)
+    {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn prologue(&mut self)
+    {
+        // Three pushes: the stack is 16-byte aligned for the calls
+        self.emit(&[0x53]); // push rbx
+        self.emit(&[0x41, 0x54]); // push r12
+        self.emit(&[0x41, 0x55]); // push r13
+        self.emit(&[0x48, 0x89, 0xFB]); // mov rbx, rdi
+        self.emit(&[0x49, 0x89, 0xF4]); // mov r12, rsi
+    }
+
+    fn epilogue(&mut self)
+    {
+        let exits = std::mem::take(&mut self.exits);
+        self.bind(&exits);
+
+        self.emit(&[0x41, 0x5D]); // pop r13
+        self.emit(&[0x41, 0x5C]); // pop r12
+        self.emit(&[0x5B]); // pop rbx
+        self.emit(&[0xC3]); // ret
+    }
+
+    // Jumps, the rel32 offsets are patched once the target is known
+
+    fn jump(&mut self) -> usize
+    {
+        self.emit(&[0xE9]); // jmp rel32
+        let position = self.code.len();
+        self.emit_u32(0);
+        position
+    }
+
+    fn jump_if(&mut self, condition: u8) -> usize
+    {
+        self.emit(&[0x0F, 0x80 | condition]); // jcc rel32
+        let position = self.code.len();
+        self.emit_u32(0);
+        position
+    }
+
+    // The jumps land here
+    fn bind(&mut self, jumps: &[usize])
+    {
+        let target = self.code.len();
+
+        for &jump in jumps
+        {
+            let offset = (target - (jump + 4)) as u32;
+            self.code[jump .. jump + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+    }
+
+    // Leaves the block, returning the number of instructions executed
+    fn exit(&mut self, executed: u32)
+    {
+        self.emit(&[0xB8]); // mov eax, imm32
+        self.emit_u32(executed);
+        let jump = self.jump();
+        self.exits.push(jump);
+    }
+
+    fn exit_if_zero(&mut self, executed: u32)
+    {
+        self.emit(&[0x85, 0xC0]); // test eax, eax
+        self.emit(&[0x75, 0x0A]); // jnz over the exit
+        self.exit(executed);
+    }
+
+    // function(cpu, mem[, argument])
+    fn call(&mut self, function: u64, argument: Option<u64>)
+    {
+        self.emit(&[0x48, 0x89, 0xDF]); // mov rdi, rbx
+        self.emit(&[0x4C, 0x89, 0xE6]); // mov rsi, r12
+
+        if let Some(argument) = argument
+        {
+            self.emit(&[0x48, 0xBA]); // mov rdx, imm64
+            self.emit_u64(argument);
+        }
+
+        self.emit(&[0x48, 0xB8]); // mov rax, imm64
+        self.emit_u64(function);
+        self.emit(&[0xFF, 0xD0]); // call rax
+    }
+
+    // Accesses to the CPU structure
+
+    // mov reg, [rbx + disp32]
+    fn load(&mut self, reg: u8, displacement: i32)
+    {
+        self.emit(&[0x8B, 0x83 | reg << 3]);
+        self.emit_i32(displacement);
+    }
+
+    // mov [rbx + disp32], reg
+    fn store(&mut self, reg: u8, displacement: i32)
+    {
+        self.emit(&[0x89, 0x83 | reg << 3]);
+        self.emit_i32(displacement);
+    }
+
+    // mov dword [rbx + disp32], imm32
+    fn store_immediate(&mut self, displacement: i32, value: u32)
+    {
+        self.emit(&[0xC7, 0x83]);
+        self.emit_i32(displacement);
+        self.emit_u32(value);
+    }
+
+    // mov byte [rbx + disp32], imm8
+    fn store_flag(&mut self, displacement: i32, value: bool)
+    {
+        self.emit(&[0xC6, 0x83]);
+        self.emit_i32(displacement);
+        self.emit(&[value as u8]);
+    }
+
+    // mov reg, imm32
+    fn move_immediate(&mut self, reg: u8, value: u32)
+    {
+        self.emit(&[0xB8 | reg]);
+        self.emit_u32(value);
+    }
+
+    fn r(&self, index: u32) -> i32
+    {
+        self.layout.r + index as i32 * 4
+    }
+
+    fn r_next(&self, index: u32) -> i32
+    {
+        self.layout.r_next + index as i32 * 4
+    }
+
+    fn load_register(&mut self, reg: u8, index: u32)
+    {
+        self.load(reg, self.r(index));
+    }
+
+    // Same as CPU::set_reg, the register is also updated in R as it can't be read again by this instruction
+    fn set_register(&mut self, index: u32)
+    {
+        if index != 0
+        {
+            self.store(EAX, self.r_next(index));
+            self.store(EAX, self.r(index));
+        }
+    }
+
+    // Load-delay slot bookkeeping
+
+    // The pending load becomes the previous one and is applied to R_NEXT
+    fn apply_pending_load(&mut self)
+    {
+        self.load(ECX, self.layout.pending_register);
+        self.load(EDX, self.layout.pending_value);
+        self.store(ECX, self.layout.previous_register);
+        self.store(EDX, self.layout.previous_value);
+        self.store_immediate(self.layout.pending_register, 0);
+        self.store_immediate(self.layout.pending_value, 0);
+
+        self.emit(&[0x89, 0x94, 0x8B]); // mov [rbx + rcx * 4 + disp32], edx
+        self.emit_i32(self.layout.r_next);
+        self.store_immediate(self.r_next(0), 0);
+    }
+
+    // The instruction before didn't load anything
+    fn clear_previous_load(&mut self)
+    {
+        self.store_immediate(self.layout.previous_register, 0);
+        self.store_immediate(self.layout.previous_value, 0);
+    }
+
+    // R = R_NEXT at the end of the instruction, only the previous load can differ
+    fn commit_pending_load(&mut self)
+    {
+        self.load(ECX, self.layout.previous_register);
+        self.emit(&[0x8B, 0x84, 0x8B]); // mov eax, [rbx + rcx * 4 + disp32]
+        self.emit_i32(self.layout.r_next);
+        self.emit(&[0x89, 0x84, 0x8B]); // mov [rbx + rcx * 4 + disp32], eax
+        self.emit_i32(self.layout.r);
+    }
+
+    // Registers of the CPU once the last instruction of the block is done
+    fn block_end(&mut self, last: &Entry)
+    {
+        if last.delay_slot
+        {
+            // Continue at the target of the branch
+            self.load(EAX, self.layout.next_pc);
+            self.store(EAX, self.layout.pc);
+            self.emit(&[0x83, 0xC0, 0x04]); // add eax, 4
+            self.store(EAX, self.layout.next_pc);
+        }
+        else
+        {
+            // After a jump, its delay slot is run by the interpreter
+            self.store_immediate(self.layout.pc, last.address.wrapping_add(4));
+
+            if !is_jump(&last.opcode)
+            {
+                self.store_immediate(self.layout.next_pc, last.address.wrapping_add(8));
+            }
+        }
+
+        self.store_immediate(self.layout.current_pc, last.address);
+        self.store_flag(self.layout.branching, is_jump(&last.opcode));
+        self.store_flag(self.layout.in_delay_slot, last.delay_slot);
+    }
+
+    // Runs the instruction with the interpreter's handler
+    fn execute(&mut self, entry: &Entry, index: u32)
+    {
+        self.call(cpu_execute as *const () as u64, Some(entry as *const Entry as u64));
+        self.exit_if_zero(index + 1);
+    }
+
+    // The native code jumps to the handler on the cases it doesn't deal with
+    fn with_slow_path(&mut self, entry: &Entry, index: u32, fast: impl FnOnce(&mut Self, &mut Vec<usize>))
+    {
+        let mut slow = Vec::new();
+
+        fast(self, &mut slow);
+
+        let done = self.jump();
+        self.bind(&slow);
+        self.execute(entry, index);
+        self.bind(&[done]);
+    }
+
+    // In lockstep mode, the interpreter runs the instruction on the side
+    fn checked(&mut self, entry: &Entry, index: u32, lockstep: bool, native: impl FnOnce(&mut Self))
+    {
+        let entry_pointer = entry as *const Entry as u64;
+
+        if lockstep
+        {
+            self.call(cpu_check_begin as *const () as u64, Some(entry_pointer));
+        }
+
+        native(self);
+
+        if lockstep
+        {
+            self.call(cpu_check_end as *const () as u64, Some(entry_pointer));
+            self.exit_if_zero(index + 1);
+        }
+    }
+
+    fn instruction(&mut self, entry: &Entry, index: u32, previous_load: bool, lockstep: bool)
+    {
+        let opcode = &entry.opcode;
+
+        match opcode.instr()
+        {
+            0b000000 => match opcode.sub()
+            {
+                0b000000 | 0b000010 | 0b000011 | 0b000100 | 0b000110 | 0b000111 |
+                0b010000 | 0b010010 |
+                0b100001 | 0b100011 | 0b100100 | 0b100101 | 0b100110 | 0b100111 | 0b101010 | 0b101011 =>
+                    self.checked(entry, index, lockstep, |e| e.special(opcode)),
+
+                0b010001 | 0b010011 => self.move_to_hi_lo(opcode),
+                0b011000 | 0b011001 => self.multiply(opcode),
+
+                0b001000 | 0b001001 => self.jump_register(entry, index),
+
+                // ADD, SUB
+                0b100000 | 0b100010 => self.with_slow_path(entry, index, |e, slow|
+                {
+                    e.load_register(EAX, opcode.rs());
+                    e.load_register(ECX, opcode.rt());
+                    e.emit(if opcode.sub() == 0b100000 { &[0x01, 0xC8] } else { &[0x29, 0xC8] }); // add / sub eax, ecx
+                    slow.push(e.jump_if(OVERFLOW));
+                    e.set_register(opcode.rd());
+                }),
+
+                _ => self.execute(entry, index)
+            },
+
+            0b000001 ..= 0b000111 => self.branch(entry),
+
+            // ADDI
+            0b001000 => self.with_slow_path(entry, index, |e, slow|
+            {
+                e.load_register(EAX, opcode.rs());
+                e.emit(&[0x05]); // add eax, imm32
+                e.emit_u32(opcode.imm_se());
+                slow.push(e.jump_if(OVERFLOW));
+                e.set_register(opcode.rt());
+            }),
+
+            0b001001 ..= 0b001111 => self.checked(entry, index, lockstep, |e| e.immediate(opcode)),
+
+            0b100000 | 0b100001 | 0b100011 | 0b100100 | 0b100101 => self.with_slow_path(entry, index, |e, slow| e.load_memory(opcode, previous_load, slow)),
+            0b101000 | 0b101001 | 0b101011 => self.with_slow_path(entry, index, |e, slow| e.store_memory(opcode, slow)),
+
+            _ => self.execute(entry, index)
+        }
+    }
+
+    // eax = (eax < ecx), signed or not
+    fn set_less_than(&mut self, signed: bool)
+    {
+        self.emit(&[0x39, 0xC8]); // cmp eax, ecx
+        self.emit(&[0x0F, if signed { 0x9C } else { 0x92 }, 0xC0]); // setl / setb al
+        self.emit(&[0x0F, 0xB6, 0xC0]); // movzx eax, al
+    }
+
+    // Shifts, ALU operations between registers, MFHI and MFLO
+    fn special(&mut self, opcode: &Opcode)
+    {
+        match opcode.sub()
+        {
+            // Shifts by an immediate
+            0b000000 | 0b000010 | 0b000011 =>
+            {
+                let modrm = match opcode.sub() { 0b000000 => 0xE0, 0b000010 => 0xE8, _ => 0xF8 }; // shl / shr / sar
+                self.load_register(EAX, opcode.rt());
+                self.emit(&[0xC1, modrm, opcode.imm5() as u8]);
+            },
+
+            // Shifts by a register, x86 also masks the shift to 5 bits
+            0b000100 | 0b000110 | 0b000111 =>
+            {
+                let modrm = match opcode.sub() { 0b000100 => 0xE0, 0b000110 => 0xE8, _ => 0xF8 }; // shl / shr / sar
+                self.load_register(EAX, opcode.rt());
+                self.load_register(ECX, opcode.rs());
+                self.emit(&[0xD3, modrm]);
+            },
+
+            0b010000 => self.load(EAX, self.layout.hi),
+            0b010010 => self.load(EAX, self.layout.lo),
+
+            sub =>
+            {
+                self.load_register(EAX, opcode.rs());
+                self.load_register(ECX, opcode.rt());
+
+                match sub
+                {
+                    0b100001 => self.emit(&[0x01, 0xC8]), // add eax, ecx
+                    0b100011 => self.emit(&[0x29, 0xC8]), // sub eax, ecx
+                    0b100100 => self.emit(&[0x21, 0xC8]), // and eax, ecx
+                    0b100101 => self.emit(&[0x09, 0xC8]), // or eax, ecx
+                    0b100110 => self.emit(&[0x31, 0xC8]), // xor eax, ecx
+                    0b100111 => self.emit(&[0x09, 0xC8, 0xF7, 0xD0]), // or eax, ecx; not eax
+                    0b101010 => self.set_less_than(true),
+                    _        => self.set_less_than(false)
+                }
+            }
+        }
+
+        self.set_register(opcode.rd());
+    }
+
+    // ADDIU, SLTI, SLTIU, ANDI, ORI, XORI, LUI
+    fn immediate(&mut self, opcode: &Opcode)
+    {
+        match opcode.instr()
+        {
+            0b001111 => self.move_immediate(EAX, opcode.imm() << 16),
+
+            instr =>
+            {
+                let immediate = match instr
+                {
+                    0b001001 ..= 0b001011 => opcode.imm_se(),
+                    _                     => opcode.imm()
+                };
+
+                self.load_register(EAX, opcode.rs());
+
+                match instr
+                {
+                    0b001001 => self.emit(&[0x05]), // add eax, imm32
+                    0b001100 => self.emit(&[0x25]), // and eax, imm32
+                    0b001101 => self.emit(&[0x0D]), // or eax, imm32
+                    0b001110 => self.emit(&[0x35]), // xor eax, imm32
+                    _        => self.emit(&[0xB9]) // mov ecx, imm32 (SLTI, SLTIU)
+                }
+
+                self.emit_u32(immediate);
+
+                match instr
+                {
+                    0b001010 => self.set_less_than(true),
+                    0b001011 => self.set_less_than(false),
+                    _        => ()
+                }
+            }
+        }
+
+        self.set_register(opcode.rt());
+    }
+
+    fn move_to_hi_lo(&mut self, opcode: &Opcode)
+    {
+        let destination = if opcode.sub() == 0b010001 { self.layout.hi } else { self.layout.lo };
+
+        self.load_register(EAX, opcode.rs());
+        self.store(EAX, destination);
+    }
+
+    // MULT, MULTU
+    fn multiply(&mut self, opcode: &Opcode)
+    {
+        if opcode.sub() == 0b011000
+        {
+            self.emit(&[0x48, 0x63, 0x83]); // movsxd rax, [rbx + disp32]
+            self.emit_i32(self.r(opcode.rs()));
+            self.emit(&[0x48, 0x63, 0x8B]); // movsxd rcx, [rbx + disp32]
+            self.emit_i32(self.r(opcode.rt()));
+        }
+        else
+        {
+            // The 32-bit loads clear the upper half
+            self.load_register(EAX, opcode.rs());
+            self.load_register(ECX, opcode.rt());
+        }
+
+        self.emit(&[0x48, 0x0F, 0xAF, 0xC1]); // imul rax, rcx
+        self.store(EAX, self.layout.lo);
+        self.emit(&[0x48, 0xC1, 0xE8, 0x20]); // shr rax, 32
+        self.store(EAX, self.layout.hi);
+    }
+
+    // J, JAL, BEQ, BNE, BLEZ, BGTZ, BLTZ, BGEZ and the linking variants
+    fn branch(&mut self, entry: &Entry)
+    {
+        let opcode = &entry.opcode;
+        let pc = entry.address.wrapping_add(4);
+
+        if matches!(opcode.instr(), 0b000010 | 0b000011)
+        {
+            self.store_immediate(self.layout.next_pc, (pc & 0xF000_0000) | (opcode.imm26() << 2));
+
+            if opcode.instr() == 0b000011
+            {
+                self.move_immediate(EAX, pc.wrapping_add(4));
+                self.set_register(31);
+            }
+
+            return;
+        }
+
+        self.load_register(EAX, opcode.rs());
+
+        let condition = match opcode.instr()
+        {
+            0b000100 | 0b000101 =>
+            {
+                self.emit(&[0x3B, 0x83]); // cmp eax, [rbx + disp32]
+                self.emit_i32(self.r(opcode.rt()));
+
+                if opcode.instr() == 0b000100 { EQUAL } else { NOT_EQUAL }
+            },
+
+            instr =>
+            {
+                self.emit(&[0x85, 0xC0]); // test eax, eax
+
+                match instr
+                {
+                    0b000110 => LESS_OR_EQUAL,
+                    0b000111 => GREATER,
+                    _ if opcode.rt() & 1 != 0 => GREATER_OR_EQUAL,
+                    _ => LESS
+                }
+            }
+        };
+
+        // next_pc = condition ? target : pc + 4
+        self.move_immediate(ECX, pc.wrapping_add(4));
+        self.move_immediate(EDX, pc.wrapping_add(opcode.imm_se() << 2));
+        self.emit(&[0x0F, 0x40 | condition, 0xCA]); // cmovcc ecx, edx
+        self.store(ECX, self.layout.next_pc);
+
+        // BLTZAL, BGEZAL link whatever the outcome
+        if opcode.instr() == 0b000001 && opcode.rt() & 0b11110 == 0b10000
+        {
+            self.move_immediate(EAX, pc.wrapping_add(4));
+            self.set_register(31);
+        }
+    }
+
+    // JR, JALR, the unaligned targets raise an exception in the handler
+    fn jump_register(&mut self, entry: &Entry, index: u32)
+    {
+        let opcode = &entry.opcode;
+        let link = entry.address.wrapping_add(8);
+
+        self.with_slow_path(entry, index, |e, slow|
+        {
+            e.load_register(EAX, opcode.rs());
+            e.emit(&[0xA8, 0x03]); // test al, 3
+            slow.push(e.jump_if(NOT_EQUAL));
+            e.store(EAX, e.layout.next_pc);
+
+            if opcode.sub() == 0b001001
+            {
+                e.move_immediate(EAX, link);
+                e.set_register(opcode.rd());
+            }
+        });
+    }
+
+    // EAX = offset in the RAM of the address, RDX = RAM
+    // Jumps to the slow path if the access is unaligned, with the cache isolated, out of the RAM,
+    // or for the stores, recorded or to compiled code
+    fn ram_address(&mut self, opcode: &Opcode, width: u8, store: bool, slow: &mut Vec<usize>)
+    {
+        self.load_register(EAX, opcode.rs());
+
+        if opcode.imm_se() != 0
+        {
+            self.emit(&[0x05]); // add eax, imm32
+            self.emit_u32(opcode.imm_se());
+        }
+
+        if width > 1
+        {
+            self.emit(&[0xA8, width - 1]); // test al, imm8
+            slow.push(self.jump_if(NOT_EQUAL));
+        }
+
+        self.emit(&[0xF7, 0x83]); // test dword [rbx + disp32], imm32
+        self.emit_i32(self.layout.status);
+        self.emit_u32(0x10000);
+        slow.push(self.jump_if(NOT_EQUAL));
+
+        if store
+        {
+            self.emit(&[0x80, 0xBB]); // cmp byte [rbx + disp32], 0
+            self.emit_i32(self.layout.fast_stores);
+            self.emit(&[0x00]);
+            slow.push(self.jump_if(EQUAL));
+        }
+
+        self.emit(&[0x89, 0xC1]); // mov ecx, eax
+        self.emit(&[0xC1, 0xE9, 0x10]); // shr ecx, 16
+        self.emit(&[0x48, 0x8B, 0x93]); // mov rdx, [rbx + disp32]
+        self.emit_i32(self.layout.ram_offsets);
+        self.emit(&[0x8B, 0x14, 0x8A]); // mov edx, [rdx + rcx * 4]
+        self.emit(&[0x83, 0xFA, NOT_RAM as u8]); // cmp edx, NOT_RAM
+        slow.push(self.jump_if(EQUAL));
+        self.emit(&[0x25]); // and eax, imm32
+        self.emit_u32(0xFFFF);
+        self.emit(&[0x09, 0xD0]); // or eax, edx
+
+        if store
+        {
+            self.emit(&[0x89, 0xC1]); // mov ecx, eax
+            self.emit(&[0xC1, 0xE9, CODE_PAGE_SHIFT as u8]); // shr ecx, imm8
+            self.emit(&[0x48, 0x8B, 0x93]); // mov rdx, [rbx + disp32]
+            self.emit_i32(self.layout.code_pages);
+            self.emit(&[0x80, 0x3C, 0x0A, 0x00]); // cmp byte [rdx + rcx], 0
+            slow.push(self.jump_if(NOT_EQUAL));
+        }
+
+        self.emit(&[0x48, 0x8B, 0x93]); // mov rdx, [rbx + disp32]
+        self.emit_i32(self.layout.ram);
+    }
+
+    fn add_cycles(&mut self, cycles: u32)
+    {
+        self.emit(&[0x81, 0x83]); // add dword [rbx + disp32], imm32
+        self.emit_i32(self.layout.cycles);
+        self.emit_u32(cycles);
+    }
+
+    // LB, LH, LW, LBU, LHU
+    fn load_memory(&mut self, opcode: &Opcode, previous_load: bool, slow: &mut Vec<usize>)
+    {
+        let (width, read): (u8, &[u8]) = match opcode.instr()
+        {
+            0b100000 => (1, &[0x0F, 0xBE, 0x0C, 0x02]), // movsx ecx, byte [rdx + rax]
+            0b100001 => (2, &[0x0F, 0xBF, 0x0C, 0x02]), // movsx ecx, word [rdx + rax]
+            0b100011 => (4, &[0x8B, 0x0C, 0x02]), // mov ecx, [rdx + rax]
+            0b100100 => (1, &[0x0F, 0xB6, 0x0C, 0x02]), // movzx ecx, byte [rdx + rax]
+            _        => (2, &[0x0F, 0xB7, 0x0C, 0x02]) // movzx ecx, word [rdx + rax]
+        };
+
+        let rt = opcode.rt();
+
+        self.ram_address(opcode, width, false, slow);
+        self.emit(read);
+        self.add_cycles(RAM_READ_CYCLES);
+
+        // Double delayed loads on the same register: cancel the previous load
+        if previous_load
+        {
+            self.emit(&[0x81, 0xBB]); // cmp dword [rbx + disp32], imm32
+            self.emit_i32(self.layout.previous_register);
+            self.emit_u32(rt);
+            self.emit(&[0x75, 0x0C]); // jne over the two moves
+            self.load(EDX, self.r(rt));
+            self.store(EDX, self.r_next(rt));
+        }
+
+        self.store_immediate(self.layout.pending_register, rt);
+        self.store(ECX, self.layout.pending_value);
+    }
+
+    // SB, SH, SW
+    fn store_memory(&mut self, opcode: &Opcode, slow: &mut Vec<usize>)
+    {
+        let (width, write): (u8, &[u8]) = match opcode.instr()
+        {
+            0b101000 => (1, &[0x88, 0x0C, 0x02]), // mov [rdx + rax], cl
+            0b101001 => (2, &[0x66, 0x89, 0x0C, 0x02]), // mov [rdx + rax], cx
+            _        => (4, &[0x89, 0x0C, 0x02]) // mov [rdx + rax], ecx
+        };
+
+        self.ram_address(opcode, width, true, slow);
+        self.load_register(ECX, opcode.rt());
+        self.emit(write);
+        self.add_cycles(RAM_WRITE_CYCLES);
+    }
+}
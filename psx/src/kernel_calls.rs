@@ -0,0 +1,315 @@
+// Decodes the BIOS function calls (jumps to 0xA0, 0xB0 or 0xC0 with the
+// function number in R9) to log them by name with their arguments.
+//
+// Documentation
+// https://problemkaputt.de/psx-spx.htm#biosfunctionsummary
+
+use crate::memory::Memory;
+
+#[derive(Clone, Copy)]
+enum Argument
+{
+    Hex,
+    Int,
+    Char,
+    Text // Address of a null-terminated string
+}
+
+use self::Argument::*;
+
+// Returns the name and the arguments of a function
+fn function(table: u32, function: u32) -> Option<(&'static str, &'static [Argument])>
+{
+    let description: (&'static str, &'static [Argument]) = match (table, function)
+    {
+        (0xA0, 0x00) => ("FileOpen", &[Text, Hex]),
+        (0xA0, 0x01) => ("FileSeek", &[Int, Hex, Int]),
+        (0xA0, 0x02) => ("FileRead", &[Int, Hex, Hex]),
+        (0xA0, 0x03) => ("FileWrite", &[Int, Hex, Hex]),
+        (0xA0, 0x04) => ("FileClose", &[Int]),
+        (0xA0, 0x05) => ("FileIoctl", &[Int, Hex, Hex]),
+        (0xA0, 0x06) => ("exit", &[Int]),
+        (0xA0, 0x07) => ("FileGetDeviceFlag", &[Int]),
+        (0xA0, 0x08) => ("FileGetc", &[Int]),
+        (0xA0, 0x09) => ("FilePutc", &[Char, Int]),
+        (0xA0, 0x0A) => ("todigit", &[Char]),
+        (0xA0, 0x0D) => ("strtol", &[Text, Hex, Int]),
+        (0xA0, 0x0E) => ("abs", &[Int]),
+        (0xA0, 0x0F) => ("labs", &[Int]),
+        (0xA0, 0x10) => ("atoi", &[Text]),
+        (0xA0, 0x11) => ("atol", &[Text]),
+        (0xA0, 0x13) => ("SaveState", &[Hex]),
+        (0xA0, 0x14) => ("RestoreState", &[Hex, Hex]),
+        (0xA0, 0x15) => ("strcat", &[Hex, Text]),
+        (0xA0, 0x16) => ("strncat", &[Hex, Text, Int]),
+        (0xA0, 0x17) => ("strcmp", &[Text, Text]),
+        (0xA0, 0x18) => ("strncmp", &[Text, Text, Int]),
+        (0xA0, 0x19) => ("strcpy", &[Hex, Text]),
+        (0xA0, 0x1A) => ("strncpy", &[Hex, Text, Int]),
+        (0xA0, 0x1B) => ("strlen", &[Text]),
+        (0xA0, 0x1C) => ("index", &[Text, Char]),
+        (0xA0, 0x1D) => ("rindex", &[Text, Char]),
+        (0xA0, 0x1E) => ("strchr", &[Text, Char]),
+        (0xA0, 0x1F) => ("strrchr", &[Text, Char]),
+        (0xA0, 0x20) => ("strpbrk", &[Text, Text]),
+        (0xA0, 0x21) => ("strspn", &[Text, Text]),
+        (0xA0, 0x22) => ("strcspn", &[Text, Text]),
+        (0xA0, 0x23) => ("strtok", &[Hex, Text]),
+        (0xA0, 0x24) => ("strstr", &[Text, Text]),
+        (0xA0, 0x25) => ("toupper", &[Char]),
+        (0xA0, 0x26) => ("tolower", &[Char]),
+        (0xA0, 0x27) => ("bcopy", &[Hex, Hex, Hex]),
+        (0xA0, 0x28) => ("bzero", &[Hex, Hex]),
+        (0xA0, 0x29) => ("bcmp", &[Hex, Hex, Hex]),
+        (0xA0, 0x2A) => ("memcpy", &[Hex, Hex, Hex]),
+        (0xA0, 0x2B) => ("memset", &[Hex, Hex, Hex]),
+        (0xA0, 0x2C) => ("memmove", &[Hex, Hex, Hex]),
+        (0xA0, 0x2D) => ("memcmp", &[Hex, Hex, Hex]),
+        (0xA0, 0x2E) => ("memchr", &[Hex, Char, Hex]),
+        (0xA0, 0x2F) => ("rand", &[]),
+        (0xA0, 0x30) => ("srand", &[Hex]),
+        (0xA0, 0x31) => ("qsort", &[Hex, Int, Int, Hex]),
+        (0xA0, 0x33) => ("malloc", &[Hex]),
+        (0xA0, 0x34) => ("free", &[Hex]),
+        (0xA0, 0x35) => ("lsearch", &[Hex, Hex, Hex, Hex]),
+        (0xA0, 0x36) => ("bsearch", &[Hex, Hex, Hex, Hex]),
+        (0xA0, 0x37) => ("calloc", &[Hex, Hex]),
+        (0xA0, 0x38) => ("realloc", &[Hex, Hex]),
+        (0xA0, 0x39) => ("InitHeap", &[Hex, Hex]),
+        (0xA0, 0x3A) => ("SystemErrorExit", &[Int]),
+        (0xA0, 0x3B) => ("std_in_getchar", &[]),
+        (0xA0, 0x3C) => ("std_out_putchar", &[Char]),
+        (0xA0, 0x3D) => ("std_in_gets", &[Hex]),
+        (0xA0, 0x3E) => ("std_out_puts", &[Text]),
+        (0xA0, 0x3F) => ("printf", &[Text, Hex, Hex, Hex]),
+        (0xA0, 0x40) => ("SystemErrorUnresolvedException", &[]),
+        (0xA0, 0x41) => ("LoadExeHeader", &[Text, Hex]),
+        (0xA0, 0x42) => ("LoadExeFile", &[Text, Hex]),
+        (0xA0, 0x43) => ("DoExecute", &[Hex, Hex, Hex]),
+        (0xA0, 0x44) => ("FlushCache", &[]),
+        (0xA0, 0x45) => ("init_a0_b0_c0_vectors", &[]),
+        (0xA0, 0x46) => ("GPU_dw", &[Int, Int, Int, Int]),
+        (0xA0, 0x47) => ("gpu_send_dma", &[Int, Int, Int, Int]),
+        (0xA0, 0x48) => ("SendGP1Command", &[Hex]),
+        (0xA0, 0x49) => ("GPU_cw", &[Hex]),
+        (0xA0, 0x4A) => ("GPU_cwp", &[Hex, Int]),
+        (0xA0, 0x4B) => ("send_gpu_linked_list", &[Hex]),
+        (0xA0, 0x4C) => ("gpu_abort_dma", &[]),
+        (0xA0, 0x4D) => ("GetGPUStatus", &[]),
+        (0xA0, 0x4E) => ("gpu_sync", &[]),
+        (0xA0, 0x51) => ("LoadAndExecute", &[Text, Hex, Hex]),
+        (0xA0, 0x54) => ("CdInit", &[]),
+        (0xA0, 0x55) => ("_bu_init", &[]),
+        (0xA0, 0x56) => ("CdRemove", &[]),
+        (0xA0, 0x5B) => ("dev_tty_init", &[]),
+        (0xA0, 0x5C) => ("dev_tty_open", &[Hex, Text, Hex]),
+        (0xA0, 0x5E) => ("dev_tty_ioctl", &[Hex, Hex, Hex]),
+        (0xA0, 0x5F) => ("dev_cd_open", &[Hex, Text, Hex]),
+        (0xA0, 0x60) => ("dev_cd_read", &[Hex, Hex, Hex]),
+        (0xA0, 0x61) => ("dev_cd_close", &[Hex]),
+        (0xA0, 0x62) => ("dev_cd_firstfile", &[Hex, Text, Hex]),
+        (0xA0, 0x63) => ("dev_cd_nextfile", &[Hex, Hex]),
+        (0xA0, 0x64) => ("dev_cd_chdir", &[Hex, Text]),
+        (0xA0, 0x65) => ("dev_card_open", &[Hex, Text, Hex]),
+        (0xA0, 0x66) => ("dev_card_read", &[Hex, Hex, Hex]),
+        (0xA0, 0x67) => ("dev_card_write", &[Hex, Hex, Hex]),
+        (0xA0, 0x68) => ("dev_card_close", &[Hex]),
+        (0xA0, 0x69) => ("dev_card_firstfile", &[Hex, Text, Hex]),
+        (0xA0, 0x6A) => ("dev_card_nextfile", &[Hex, Hex]),
+        (0xA0, 0x6B) => ("dev_card_erase", &[Hex, Text]),
+        (0xA0, 0x6C) => ("dev_card_undelete", &[Hex, Text]),
+        (0xA0, 0x6D) => ("dev_card_format", &[Hex]),
+        (0xA0, 0x6E) => ("dev_card_rename", &[Hex, Text, Hex, Text]),
+        (0xA0, 0x70) => ("_bu_init", &[]),
+        (0xA0, 0x71) => ("CdInit", &[]),
+        (0xA0, 0x72) => ("CdRemove", &[]),
+        (0xA0, 0x78) => ("CdAsyncSeekL", &[Hex]),
+        (0xA0, 0x7C) => ("CdAsyncGetStatus", &[Hex]),
+        (0xA0, 0x7E) => ("CdAsyncReadSector", &[Int, Hex, Hex]),
+        (0xA0, 0x81) => ("CdAsyncSetMode", &[Hex]),
+        (0xA0, 0x90) => ("CdromIoIrqFunc1", &[]),
+        (0xA0, 0x91) => ("CdromDmaIrqFunc1", &[]),
+        (0xA0, 0x92) => ("CdromIoIrqFunc2", &[]),
+        (0xA0, 0x93) => ("CdromDmaIrqFunc2", &[]),
+        (0xA0, 0x94) => ("CdromGetInt5errCode", &[Hex, Hex]),
+        (0xA0, 0x95) => ("CdInitSubFunc", &[]),
+        (0xA0, 0x96) => ("AddCDROMDevice", &[]),
+        (0xA0, 0x97) => ("AddMemCardDevice", &[]),
+        (0xA0, 0x98) => ("AddDuartTtyDevice", &[]),
+        (0xA0, 0x99) => ("AddDummyTtyDevice", &[]),
+        (0xA0, 0x9C) => ("SetConf", &[Int, Int, Hex]),
+        (0xA0, 0x9D) => ("GetConf", &[Hex, Hex, Hex]),
+        (0xA0, 0x9E) => ("SetCdromIrqAutoAbort", &[Int, Int]),
+        (0xA0, 0x9F) => ("SetMemSize", &[Int]),
+        (0xA0, 0xA0) => ("WarmBoot", &[]),
+        (0xA0, 0xA1) => ("SystemErrorBootOrDiskFailure", &[Char, Hex]),
+        (0xA0, 0xA2) => ("EnqueueCdIntr", &[]),
+        (0xA0, 0xA3) => ("DequeueCdIntr", &[]),
+        (0xA0, 0xA4) => ("CdGetLbn", &[Text]),
+        (0xA0, 0xA5) => ("CdReadSector", &[Int, Int, Hex]),
+        (0xA0, 0xA6) => ("CdGetStatus", &[]),
+        (0xA0, 0xA7) => ("bu_callback_okay", &[]),
+        (0xA0, 0xA8) => ("bu_callback_err_write", &[]),
+        (0xA0, 0xA9) => ("bu_callback_err_busy", &[]),
+        (0xA0, 0xAA) => ("bu_callback_err_eject", &[]),
+        (0xA0, 0xAB) => ("_card_info", &[Hex]),
+        (0xA0, 0xAC) => ("_card_async_load_directory", &[Hex]),
+        (0xA0, 0xAD) => ("set_card_auto_format", &[Int]),
+        (0xA0, 0xAE) => ("bu_callback_err_prev_write", &[]),
+        (0xA0, 0xAF) => ("card_write_test", &[Hex]),
+        (0xA0, 0xB2) => ("ioabort_raw", &[Hex]),
+        (0xA0, 0xB4) => ("GetSystemInfo", &[Hex]),
+
+        (0xB0, 0x00) => ("alloc_kernel_memory", &[Hex]),
+        (0xB0, 0x01) => ("free_kernel_memory", &[Hex]),
+        (0xB0, 0x02) => ("init_timer", &[Int, Hex, Hex]),
+        (0xB0, 0x03) => ("get_timer", &[Int]),
+        (0xB0, 0x04) => ("enable_timer_irq", &[Int]),
+        (0xB0, 0x05) => ("disable_timer_irq", &[Int]),
+        (0xB0, 0x06) => ("restart_timer", &[Int]),
+        (0xB0, 0x07) => ("DeliverEvent", &[Hex, Hex]),
+        (0xB0, 0x08) => ("OpenEvent", &[Hex, Hex, Hex, Hex]),
+        (0xB0, 0x09) => ("CloseEvent", &[Hex]),
+        (0xB0, 0x0A) => ("WaitEvent", &[Hex]),
+        (0xB0, 0x0B) => ("TestEvent", &[Hex]),
+        (0xB0, 0x0C) => ("EnableEvent", &[Hex]),
+        (0xB0, 0x0D) => ("DisableEvent", &[Hex]),
+        (0xB0, 0x0E) => ("OpenThread", &[Hex, Hex, Hex]),
+        (0xB0, 0x0F) => ("CloseThread", &[Hex]),
+        (0xB0, 0x10) => ("ChangeThread", &[Hex]),
+        (0xB0, 0x12) => ("InitPad", &[Hex, Hex, Hex, Hex]),
+        (0xB0, 0x13) => ("StartPad", &[]),
+        (0xB0, 0x14) => ("StopPad", &[]),
+        (0xB0, 0x15) => ("OutdatedPadInitAndStart", &[Hex, Hex, Hex, Hex]),
+        (0xB0, 0x16) => ("OutdatedPadGetButtons", &[]),
+        (0xB0, 0x17) => ("ReturnFromException", &[]),
+        (0xB0, 0x18) => ("SetDefaultExitFromException", &[]),
+        (0xB0, 0x19) => ("SetCustomExitFromException", &[Hex]),
+        (0xB0, 0x20) => ("UnDeliverEvent", &[Hex, Hex]),
+        (0xB0, 0x32) => ("FileOpen", &[Text, Hex]),
+        (0xB0, 0x33) => ("FileSeek", &[Int, Hex, Int]),
+        (0xB0, 0x34) => ("FileRead", &[Int, Hex, Hex]),
+        (0xB0, 0x35) => ("FileWrite", &[Int, Hex, Hex]),
+        (0xB0, 0x36) => ("FileClose", &[Int]),
+        (0xB0, 0x37) => ("FileIoctl", &[Int, Hex, Hex]),
+        (0xB0, 0x38) => ("exit", &[Int]),
+        (0xB0, 0x39) => ("FileGetDeviceFlag", &[Int]),
+        (0xB0, 0x3A) => ("FileGetc", &[Int]),
+        (0xB0, 0x3B) => ("FilePutc", &[Char, Int]),
+        (0xB0, 0x3C) => ("std_in_getchar", &[]),
+        (0xB0, 0x3D) => ("std_out_putchar", &[Char]),
+        (0xB0, 0x3E) => ("std_in_gets", &[Hex]),
+        (0xB0, 0x3F) => ("std_out_puts", &[Text]),
+        (0xB0, 0x40) => ("chdir", &[Text]),
+        (0xB0, 0x41) => ("FormatDevice", &[Text]),
+        (0xB0, 0x42) => ("firstfile", &[Text, Hex]),
+        (0xB0, 0x43) => ("nextfile", &[Hex]),
+        (0xB0, 0x44) => ("FileRename", &[Text, Text]),
+        (0xB0, 0x45) => ("FileDelete", &[Text]),
+        (0xB0, 0x46) => ("FileUndelete", &[Text]),
+        (0xB0, 0x47) => ("AddDevice", &[Hex]),
+        (0xB0, 0x48) => ("RemoveDevice", &[Text]),
+        (0xB0, 0x49) => ("PrintInstalledDevices", &[]),
+        (0xB0, 0x4A) => ("InitCard", &[Int]),
+        (0xB0, 0x4B) => ("StartCard", &[]),
+        (0xB0, 0x4C) => ("StopCard", &[]),
+        (0xB0, 0x4D) => ("_card_info_subfunc", &[Hex]),
+        (0xB0, 0x4E) => ("write_card_sector", &[Hex, Int, Hex]),
+        (0xB0, 0x4F) => ("read_card_sector", &[Hex, Int, Hex]),
+        (0xB0, 0x50) => ("allow_new_card", &[]),
+        (0xB0, 0x51) => ("Krom2RawAdd", &[Hex]),
+        (0xB0, 0x53) => ("Krom2Offset", &[Hex]),
+        (0xB0, 0x54) => ("GetLastError", &[]),
+        (0xB0, 0x55) => ("GetLastFileError", &[Int]),
+        (0xB0, 0x56) => ("GetC0Table", &[]),
+        (0xB0, 0x57) => ("GetB0Table", &[]),
+        (0xB0, 0x58) => ("get_bu_callback_port", &[]),
+        (0xB0, 0x59) => ("testdevice", &[Text]),
+        (0xB0, 0x5B) => ("ChangeClearPad", &[Int]),
+        (0xB0, 0x5C) => ("get_card_status", &[Int]),
+        (0xB0, 0x5D) => ("wait_card_status", &[Int]),
+
+        (0xC0, 0x00) => ("EnqueueTimerAndVblankIrqs", &[Int]),
+        (0xC0, 0x01) => ("EnqueueSyscallHandler", &[Int]),
+        (0xC0, 0x02) => ("SysEnqIntRP", &[Int, Hex]),
+        (0xC0, 0x03) => ("SysDeqIntRP", &[Int, Hex]),
+        (0xC0, 0x04) => ("get_free_EvCB_slot", &[]),
+        (0xC0, 0x05) => ("get_free_TCB_slot", &[]),
+        (0xC0, 0x06) => ("ExceptionHandler", &[]),
+        (0xC0, 0x07) => ("InstallExceptionHandlers", &[]),
+        (0xC0, 0x08) => ("SysInitMemory", &[Hex, Hex]),
+        (0xC0, 0x09) => ("SysInitKernelVariables", &[]),
+        (0xC0, 0x0A) => ("ChangeClearRCnt", &[Int, Int]),
+        (0xC0, 0x0C) => ("InitDefInt", &[Int]),
+        (0xC0, 0x0D) => ("SetIrqAutoAck", &[Int, Int]),
+        (0xC0, 0x12) => ("InstallDevices", &[Int]),
+        (0xC0, 0x13) => ("FlushStdInOutPut", &[]),
+        (0xC0, 0x15) => ("tty_cdevinput", &[Hex, Char]),
+        (0xC0, 0x16) => ("tty_cdevscan", &[]),
+        (0xC0, 0x17) => ("tty_circgetc", &[Hex]),
+        (0xC0, 0x18) => ("tty_circputc", &[Char, Hex]),
+        (0xC0, 0x19) => ("ioabort", &[Text, Text]),
+        (0xC0, 0x1A) => ("set_card_find_mode", &[Int]),
+        (0xC0, 0x1B) => ("KernelRedirect", &[Int]),
+        (0xC0, 0x1C) => ("AdjustA0Table", &[]),
+        (0xC0, 0x1D) => ("get_card_find_mode", &[]),
+
+        _ => return None
+    };
+
+    Some(description)
+}
+
+// Describes a call, e.g. `B0(3F) std_out_puts("Hello") from 80012345`
+pub fn describe(mem: &Memory, table: u32, number: u32, args: [u32; 4], ra: u32) -> String
+{
+    let (name, arguments) = function(table, number).unwrap_or(("unknown", &[Hex, Hex, Hex, Hex]));
+
+    let arguments = arguments.iter().zip(args.iter())
+        .map(|(argument, value)| match argument
+        {
+            Hex    => format!("{:08x}", value),
+            Int    => format!("{}", *value as i32),
+            Char   => format!("{:?}", (*value as u8) as char),
+            Text => format!("{:?}", read_string(mem, *value))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("{:02X}({:02X}) {}({}) from {:08x}", table, number, name, arguments, ra)
+}
+
+// Only the strings in RAM or in the BIOS can be read without side effects
+pub fn read_string(mem: &Memory, address: u32) -> String
+{
+    let mut string = String::new();
+
+    for offset in 0 .. 256
+    {
+        let address = address.wrapping_add(offset);
+
+        let byte = match mem.peek_code(address & !3)
+        {
+            Some(word) => (word >> ((address & 3) * 8)) as u8,
+            None       => break
+        };
+
+        if byte == 0
+        {
+            break;
+        }
+
+        string.push(byte as char);
+    }
+
+    string
+}
+
+// Bytes for the TTY output, see `read_string`
+pub fn read_bytes(mem: &Memory, address: u32, length: u32) -> Vec<u8>
+{
+    (0 .. length)
+        .map(|offset| address.wrapping_add(offset))
+        .map_while(|address| mem.peek_code(address & !3).map(|word| (word >> ((address & 3) * 8)) as u8))
+        .collect()
+}
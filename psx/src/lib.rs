@@ -1,18 +1,36 @@
+pub mod audio;
+pub mod lockstep;
+pub mod mdec;
+pub mod movie;
+pub mod psf;
 pub mod psx;
+pub mod rewind;
 pub mod opcode;
+pub mod tracer;
+
+pub use crate::error::Error;
 
 mod bios;
+mod block_cache;
 mod cdrom;
 mod cpu;
 mod dma;
 mod debugger;
+mod error;
 mod exefile;
 mod gpu;
+mod hle;
+mod icache;
 mod interrupt_controller;
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+mod jit;
+mod kernel_calls;
 mod memory;
 mod memory_segment;
 mod renderer;
 mod spu;
+mod timers;
+mod tty;
 
 #[macro_use]
 extern crate log;
@@ -22,3 +40,5 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate bitfield;
 extern crate glium;
+extern crate flate2;
+extern crate bincode;
@@ -0,0 +1,300 @@
+// Differential testing: runs a system next to a reference (another system or
+// a recorded trace) and stops at the first instruction where they disagree.
+// Meant to check that a CPU backend behaves exactly like the interpreter.
+// The states are compared after each instruction, the recompiler makes a block of each one.
+
+use crate::psx::PSX;
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+use crate::psx::ExecutionMode;
+
+use serde::{ Serialize, Deserialize };
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader, BufWriter, Write };
+use std::path::Path;
+
+// A store that reached the memory
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemoryWrite
+{
+    pub address: u32,
+    pub value: u32,
+    pub size: u8 // In bytes
+}
+
+// CPU state after an instruction, with the stores it made
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot
+{
+    pub pc: u32,
+    pub r: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub cop0: Vec<(u32, u32)>, // (index, value)
+    pub writes: Vec<MemoryWrite>
+}
+
+impl Snapshot
+{
+    // Takes the stores recorded since the latest capture
+    pub fn capture(psx: &mut PSX) -> Self
+    {
+        Snapshot
+        {
+            pc: psx.cpu.pc,
+            r: psx.cpu.r,
+            hi: psx.cpu.hi,
+            lo: psx.cpu.lo,
+            cop0: psx.cpu.cop0_registers(),
+            writes: psx.cpu.take_writes()
+        }
+    }
+
+    // Describes every difference with the expected state, empty if there is none
+    pub fn compare(&self, expected: &Snapshot) -> Vec<String>
+    {
+        let mut differences = Vec::new();
+
+        if self.pc != expected.pc
+        {
+            differences.push(format!("PC: {:08x}, expected {:08x}", self.pc, expected.pc));
+        }
+
+        for (index, (value, expected_value)) in self.r.iter().zip(expected.r.iter()).enumerate()
+        {
+            if value != expected_value
+            {
+                differences.push(format!("R{}: {:08x}, expected {:08x}", index, value, expected_value));
+            }
+        }
+
+        if self.hi != expected.hi
+        {
+            differences.push(format!("HI: {:08x}, expected {:08x}", self.hi, expected.hi));
+        }
+
+        if self.lo != expected.lo
+        {
+            differences.push(format!("LO: {:08x}, expected {:08x}", self.lo, expected.lo));
+        }
+
+        for ((index, value), (_, expected_value)) in self.cop0.iter().zip(expected.cop0.iter())
+        {
+            if value != expected_value
+            {
+                differences.push(format!("COP0 R{}: {:08x}, expected {:08x}", index, value, expected_value));
+            }
+        }
+
+        if self.writes != expected.writes
+        {
+            differences.push(format!("Writes: {}, expected {}", Snapshot::format_writes(&self.writes), Snapshot::format_writes(&expected.writes)));
+        }
+
+        differences
+    }
+
+    fn format_writes(writes: &[MemoryWrite]) -> String
+    {
+        if writes.is_empty()
+        {
+            return String::from("none");
+        }
+
+        writes.iter()
+            .map(|w| format!("[{:08x}] = {:0width$x}", w.address, w.value, width = w.size as usize * 2))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// First instruction after which the states differ
+#[derive(Debug)]
+pub struct Divergence
+{
+    pub instruction: u64, // Number of instructions run before this one
+    pub pc: u32,
+    pub disassembly: String,
+    pub differences: Vec<String>
+}
+
+impl fmt::Display for Divergence
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "Divergence after instruction #{} @ {:08x}: {}", self.instruction, self.pc, self.disassembly)?;
+
+        for difference in &self.differences
+        {
+            writeln!(f, "    {}", difference)?;
+        }
+
+        Ok(())
+    }
+}
+
+enum Reference
+{
+    System(Box<PSX>),
+    Trace(io::Lines<BufReader<File>>)
+}
+
+pub struct Lockstep
+{
+    reference: Reference,
+    candidate: PSX,
+    instructions: u64
+}
+
+impl Lockstep
+{
+    // Both systems must start from the same state (same BIOS, same game)
+    pub fn new(mut reference: PSX, mut candidate: PSX) -> Self
+    {
+        reference.cpu.record_writes(true);
+        Lockstep::prepare(&mut candidate);
+
+        Lockstep
+        {
+            reference: Reference::System(Box::new(reference)),
+            candidate,
+            instructions: 0
+        }
+    }
+
+    // Compares against a trace written by `record_trace`
+    pub fn from_trace(trace_path: &Path, mut candidate: PSX) -> io::Result<Self>
+    {
+        let trace = BufReader::new(File::open(trace_path)?);
+
+        Lockstep::prepare(&mut candidate);
+
+        Ok(Lockstep
+        {
+            reference: Reference::Trace(trace.lines()),
+            candidate,
+            instructions: 0
+        })
+    }
+
+    fn prepare(candidate: &mut PSX)
+    {
+        candidate.cpu.record_writes(true);
+
+        // Inside the blocks, the native instructions are also checked against the interpreter
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        {
+            if candidate.cpu.execution_mode == ExecutionMode::Recompiler
+            {
+                candidate.set_execution_mode(ExecutionMode::RecompilerLockstep);
+            }
+        }
+    }
+
+    pub fn candidate(&mut self) -> &mut PSX
+    {
+        &mut self.candidate
+    }
+
+    // Runs the next instruction of the candidate, then the reference
+    // Returns false once the reference trace is over
+    pub fn step(&mut self) -> Result<bool, Divergence>
+    {
+        let pc = self.candidate.cpu.pc;
+
+        self.candidate.run(1);
+        let state = Snapshot::capture(&mut self.candidate);
+
+        let expected = match &mut self.reference
+        {
+            Reference::System(reference) =>
+            {
+                reference.run(1);
+                Snapshot::capture(reference)
+            },
+            Reference::Trace(lines) => match Lockstep::read_snapshot(lines, self.instructions)
+            {
+                Some(snapshot) => snapshot,
+                None           => return Ok(false)
+            }
+        };
+
+        let differences = state.compare(&expected);
+
+        if !differences.is_empty()
+        {
+            let cpu = &self.candidate.cpu;
+            let disassembly = cpu.debugger.disassemble(pc, cpu, &mut self.candidate.mem);
+
+            return Err(Divergence
+            {
+                instruction: self.instructions,
+                pc,
+                disassembly: format!("{:08x} {} {}", disassembly.bits, disassembly.mnemonics, disassembly.hint),
+                differences
+            });
+        }
+
+        self.instructions += 1;
+
+        Ok(true)
+    }
+
+    // Returns None at the end of the trace
+    fn read_snapshot(lines: &mut io::Lines<BufReader<File>>, instruction: u64) -> Option<Snapshot>
+    {
+        let line = match lines.next()
+        {
+            Some(Ok(line)) => line,
+            Some(Err(error)) =>
+            {
+                error!("Cannot read the lockstep trace: {}", error);
+                return None;
+            },
+            None => return None
+        };
+
+        match serde_json::from_str(&line)
+        {
+            Ok(snapshot) => Some(snapshot),
+            Err(error) =>
+            {
+                error!("Invalid lockstep trace entry #{}: {}", instruction, error);
+                None
+            }
+        }
+    }
+
+    // Returns the number of instructions compared, less than requested if the trace is over
+    pub fn run(&mut self, instructions: u64) -> Result<u64, Divergence>
+    {
+        let mut count = 0;
+
+        while count < instructions && self.step()?
+        {
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+// Writes the state after each instruction, one JSON snapshot per line
+pub fn record_trace(psx: &mut PSX, trace_path: &Path, instructions: u64) -> io::Result<()>
+{
+    let mut trace = BufWriter::new(File::create(trace_path)?);
+
+    psx.cpu.record_writes(true);
+
+    for _ in 0 .. instructions
+    {
+        psx.run(1);
+
+        serde_json::to_writer(&mut trace, &Snapshot::capture(psx))?;
+        writeln!(trace)?;
+    }
+
+    psx.cpu.record_writes(false);
+
+    trace.flush()
+}
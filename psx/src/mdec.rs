@@ -0,0 +1,623 @@
+use serde::{ Serialize, Deserialize };
+use serde_big_array::BigArray;
+use std::collections::VecDeque;
+
+// Documentation
+//
+// https://problemkaputt.de/psx-spx.htm#macroblockdecodermdec
+
+// Maps the index of a coefficient in the run-length data (zigzag order) to its position in the 8x8 block
+const ZIGZAG: [usize; 64] =
+[
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63
+];
+
+// End of block marker (also used as padding between macroblocks)
+const END_OF_BLOCK: u16 = 0xFE00;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OutputDepth
+{
+    Bits4 = 0,
+    Bits8 = 1,
+    Bits24 = 2,
+    Bits15 = 3
+}
+
+impl OutputDepth
+{
+    fn from_bits(bits: u32) -> OutputDepth
+    {
+        match bits & 3
+        {
+            0 => OutputDepth::Bits4,
+            1 => OutputDepth::Bits8,
+            2 => OutputDepth::Bits24,
+            _ => OutputDepth::Bits15
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+enum Command
+{
+    None,
+    DecodeMacroblocks,
+    SetQuantTables(bool), // true if the chrominance table follows the luminance one
+    SetScaleTable
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MDEC
+{
+    // Current command
+
+    command: Command,
+    words_remaining: u32,
+    input: Vec<u16>, // parameters of the current command, as halfwords
+
+    output_depth: OutputDepth,
+    output_signed: bool,
+    output_bit15: bool,
+
+    // Tables
+
+    #[serde(with = "BigArray")]
+    quant_luminance: [u8; 64],
+    #[serde(with = "BigArray")]
+    quant_chrominance: [u8; 64],
+    #[serde(with = "BigArray")]
+    scale: [i16; 64],
+
+    // Decoded data waiting to be read
+    output: VecDeque<u32>,
+
+    current_block: u32,
+
+    dma_in_enabled: bool,
+    dma_out_enabled: bool
+}
+
+impl MDEC
+{
+    pub fn new() -> MDEC
+    {
+        MDEC
+        {
+            command: Command::None,
+            words_remaining: 0,
+            input: Vec::new(),
+
+            output_depth: OutputDepth::Bits4,
+            output_signed: false,
+            output_bit15: false,
+
+            quant_luminance: [0; 64],
+            quant_chrominance: [0; 64],
+            scale: [0; 64],
+
+            output: VecDeque::new(),
+
+            current_block: 4,
+
+            dma_in_enabled: false,
+            dma_out_enabled: false
+        }
+    }
+
+    // 1F801820h read: data/response
+    pub fn read_data(&mut self) -> u32
+    {
+        match self.output.pop_front()
+        {
+            Some(word) => word,
+            None =>
+            {
+                warn!("MDEC output FIFO empty");
+                0
+            }
+        }
+    }
+
+    // 1F801820h write: command/parameters
+    pub fn write_command(&mut self, value: u32)
+    {
+        if self.words_remaining == 0
+        {
+            self.start_command(value);
+        }
+        else
+        {
+            self.input.push(value as u16);
+            self.input.push((value >> 16) as u16);
+            self.words_remaining -= 1;
+        }
+
+        if self.words_remaining == 0
+        {
+            self.execute();
+        }
+    }
+
+    // 1F801824h read: status
+    pub fn status(&self) -> u32
+    {
+        let busy = self.words_remaining > 0 || !self.output.is_empty();
+
+        // The Data-In FIFO is never full (bit 30)
+        (self.output.is_empty() as u32) << 31 |
+        (busy as u32) << 29 |
+        ((self.dma_in_enabled && self.words_remaining > 0) as u32) << 28 |
+        ((self.dma_out_enabled && !self.output.is_empty()) as u32) << 27 |
+        (self.output_depth as u32) << 25 |
+        (self.output_signed as u32) << 24 |
+        (self.output_bit15 as u32) << 23 |
+        (self.current_block & 7) << 16 |
+        (self.words_remaining.wrapping_sub(1) & 0xFFFF)
+    }
+
+    // 1F801824h write: control
+    pub fn write_control(&mut self, value: u32)
+    {
+        if value & (1 << 31) != 0
+        {
+            self.reset();
+        }
+
+        self.dma_in_enabled = value & (1 << 30) != 0;
+        self.dma_out_enabled = value & (1 << 29) != 0;
+    }
+
+    // The input FIFO is never full, so the MDEC requests data as long as the DMA is enabled
+    pub fn input_ready(&self) -> bool
+    {
+        self.dma_in_enabled
+    }
+
+    // Number of output words ready to be read
+    pub fn output_len(&self) -> usize
+    {
+        self.output.len()
+    }
+
+    fn reset(&mut self)
+    {
+        self.command = Command::None;
+        self.words_remaining = 0;
+        self.input.clear();
+        self.output.clear();
+        self.current_block = 4;
+        self.output_depth = OutputDepth::Bits4;
+        self.output_signed = false;
+        self.output_bit15 = false;
+    }
+
+    fn start_command(&mut self, value: u32)
+    {
+        self.input.clear();
+
+        match value >> 29
+        {
+            1 =>
+            {
+                self.command = Command::DecodeMacroblocks;
+                self.words_remaining = value & 0xFFFF;
+                self.output_depth = OutputDepth::from_bits(value >> 27);
+                self.output_signed = value & (1 << 26) != 0;
+                self.output_bit15 = value & (1 << 25) != 0;
+            },
+
+            2 =>
+            {
+                let color = value & 1 != 0;
+                self.command = Command::SetQuantTables(color);
+                self.words_remaining = if color { 32 } else { 16 };
+            },
+
+            3 =>
+            {
+                self.command = Command::SetScaleTable;
+                self.words_remaining = 32;
+            },
+
+            _ =>
+            {
+                warn!("unsupported MDEC command {:08X}", value);
+                self.command = Command::None;
+                self.words_remaining = 0;
+            }
+        }
+    }
+
+    fn execute(&mut self)
+    {
+        match self.command
+        {
+            Command::DecodeMacroblocks => self.decode_macroblocks(),
+
+            Command::SetQuantTables(color) =>
+            {
+                for i in 0 .. 64
+                {
+                    self.quant_luminance[i] = (self.input[i / 2] >> ((i % 2) * 8)) as u8;
+                }
+
+                if color
+                {
+                    for i in 0 .. 64
+                    {
+                        self.quant_chrominance[i] = (self.input[32 + i / 2] >> ((i % 2) * 8)) as u8;
+                    }
+                }
+            },
+
+            Command::SetScaleTable =>
+            {
+                for i in 0 .. 64
+                {
+                    self.scale[i] = self.input[i] as i16;
+                }
+            },
+
+            Command::None => ()
+        }
+
+        self.command = Command::None;
+    }
+
+    fn decode_macroblocks(&mut self)
+    {
+        let input = std::mem::take(&mut self.input);
+        let mut position = 0;
+
+        loop
+        {
+            // Skip the padding between macroblocks
+
+            while position < input.len() && input[position] == END_OF_BLOCK
+            {
+                position += 1;
+            }
+
+            if position >= input.len()
+            {
+                break;
+            }
+
+            match self.output_depth
+            {
+                OutputDepth::Bits4 | OutputDepth::Bits8 =>
+                {
+                    let mut y = [0i16; 64];
+                    position = self.decode_block(&input, position, &mut y, false);
+
+                    self.current_block = 0;
+                    self.output_monochrome(&y);
+                },
+
+                OutputDepth::Bits15 | OutputDepth::Bits24 =>
+                {
+                    let mut cr = [0i16; 64];
+                    let mut cb = [0i16; 64];
+                    let mut y = [[0i16; 64]; 4];
+
+                    position = self.decode_block(&input, position, &mut cr, true);
+                    position = self.decode_block(&input, position, &mut cb, true);
+
+                    for block in y.iter_mut()
+                    {
+                        position = self.decode_block(&input, position, block, false);
+                    }
+
+                    self.current_block = 4;
+                    self.output_color(&cr, &cb, &y);
+                }
+            }
+        }
+
+        self.input = input;
+    }
+
+    // Run-length decodes a block then applies the inverse DCT.
+    // Returns the position following the block in the input.
+    fn decode_block(&self, input: &[u16], mut position: usize, block: &mut [i16; 64], chrominance: bool) -> usize
+    {
+        let quant = if chrominance { &self.quant_chrominance } else { &self.quant_luminance };
+
+        let mut coefficients = [0i32; 64];
+
+        let next = |position: &mut usize| -> u16
+        {
+            let value = input.get(*position).copied().unwrap_or(END_OF_BLOCK);
+            *position += 1;
+            value
+        };
+
+        // Skip padding
+        let mut n = next(&mut position);
+        while n == END_OF_BLOCK && position < input.len()
+        {
+            n = next(&mut position);
+        }
+
+        // The first value is the DC coefficient and holds the quantization scale
+
+        let q_scale = ((n >> 10) & 0x3F) as i32;
+        let mut k = 0usize;
+        let mut value = signed10(n) * quant[0] as i32;
+
+        loop
+        {
+            if q_scale == 0
+            {
+                value = signed10(n) * 2;
+            }
+
+            value = value.clamp(-0x400, 0x3FF);
+
+            if q_scale > 0
+            {
+                coefficients[ZIGZAG[k]] = value;
+            }
+            else
+            {
+                coefficients[k] = value;
+            }
+
+            n = next(&mut position);
+            k += ((n >> 10) & 0x3F) as usize + 1;
+
+            if k > 63
+            {
+                break;
+            }
+
+            value = (signed10(n) * quant[k] as i32 * q_scale + 4) / 8;
+        }
+
+        self.idct(&coefficients, block);
+
+        position
+    }
+
+    fn idct(&self, coefficients: &[i32; 64], block: &mut [i16; 64])
+    {
+        let mut temp = [0i64; 64];
+
+        // Columns
+
+        for x in 0 .. 8
+        {
+            for y in 0 .. 8
+            {
+                let mut sum = 0i64;
+                for z in 0 .. 8
+                {
+                    sum += coefficients[x + z * 8] as i64 * self.scale[y + z * 8] as i64;
+                }
+                temp[x + y * 8] = sum;
+            }
+        }
+
+        // Rows
+
+        for x in 0 .. 8
+        {
+            for y in 0 .. 8
+            {
+                let mut sum = 0i64;
+                for z in 0 .. 8
+                {
+                    sum += temp[z + y * 8] * self.scale[x + z * 8] as i64;
+                }
+
+                // The scale table holds the DCT basis multiplied by 2^16, for each pass
+                let value = (sum + (1 << 31)) >> 32;
+                block[x + y * 8] = value.clamp(-128, 127) as i16;
+            }
+        }
+    }
+
+    fn output_monochrome(&mut self, y: &[i16; 64])
+    {
+        let pixels: Vec<u8> = y.iter().map(|&v|
+        {
+            let v = v.clamp(-128, 127) as u8;
+            if self.output_signed { v } else { v ^ 0x80 }
+        }).collect();
+
+        match self.output_depth
+        {
+            OutputDepth::Bits8 => self.push_bytes(&pixels),
+
+            _ =>
+            {
+                let nibbles: Vec<u8> = pixels.chunks(2).map(|p| (p[0] >> 4) | (p[1] & 0xF0)).collect();
+                self.push_bytes(&nibbles);
+            }
+        }
+    }
+
+    fn output_color(&mut self, cr: &[i16; 64], cb: &[i16; 64], y: &[[i16; 64]; 4])
+    {
+        let mut pixels = [(0u8, 0u8, 0u8); 256];
+
+        for (index, block) in y.iter().enumerate()
+        {
+            let xx = (index % 2) * 8;
+            let yy = (index / 2) * 8;
+
+            for py in 0 .. 8
+            {
+                for px in 0 .. 8
+                {
+                    let chroma = (px + xx) / 2 + ((py + yy) / 2) * 8;
+                    let (r, g, b) = yuv_to_rgb(block[px + py * 8] as i32, cb[chroma] as i32, cr[chroma] as i32);
+
+                    let (r, g, b) = if self.output_signed { (r as u8, g as u8, b as u8) } else { (r as u8 ^ 0x80, g as u8 ^ 0x80, b as u8 ^ 0x80) };
+
+                    pixels[(px + xx) + (py + yy) * 16] = (r, g, b);
+                }
+            }
+        }
+
+        match self.output_depth
+        {
+            OutputDepth::Bits24 =>
+            {
+                let mut bytes = Vec::with_capacity(pixels.len() * 3);
+                for &(r, g, b) in pixels.iter()
+                {
+                    bytes.extend_from_slice(&[r, g, b]);
+                }
+                self.push_bytes(&bytes);
+            },
+
+            _ =>
+            {
+                let bit15 = (self.output_bit15 as u16) << 15;
+
+                let halfwords: Vec<u16> = pixels.iter().map(|&(r, g, b)|
+                    (r as u16 >> 3) | ((g as u16 >> 3) << 5) | ((b as u16 >> 3) << 10) | bit15
+                ).collect();
+
+                for pair in halfwords.chunks(2)
+                {
+                    self.output.push_back(pair[0] as u32 | (pair[1] as u32) << 16);
+                }
+            }
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8])
+    {
+        for word in bytes.chunks(4)
+        {
+            let mut value = 0u32;
+            for (i, byte) in word.iter().enumerate()
+            {
+                value |= (*byte as u32) << (i * 8);
+            }
+            self.output.push_back(value);
+        }
+    }
+}
+
+impl Default for MDEC
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Sign-extends the 10-bit value stored in the low bits
+fn signed10(value: u16) -> i32
+{
+    (((value & 0x3FF) << 6) as i16 >> 6) as i32
+}
+
+// Converts a signed YUV pixel to signed RGB components
+fn yuv_to_rgb(y: i32, cb: i32, cr: i32) -> (i8, i8, i8)
+{
+    let r = y + ((1.402 * cr as f32) as i32);
+    let g = y + ((-0.3437 * cb as f32 - 0.7143 * cr as f32) as i32);
+    let b = y + ((1.772 * cb as f32) as i32);
+
+    (r.clamp(-128, 127) as i8, g.clamp(-128, 127) as i8, b.clamp(-128, 127) as i8)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The table the BIOS uploads: the DCT basis scaled by 2^14.5
+    const SCALE: [u16; 64] =
+    [
+        0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82,
+        0x7D8A, 0x6A6D, 0x471C, 0x18F8, 0xE707, 0xB8E3, 0x9592, 0x8275,
+        0x7641, 0x30FB, 0xCF04, 0x89BE, 0x89BE, 0xCF04, 0x30FB, 0x7641,
+        0x6A6D, 0xE707, 0x8275, 0xB8E3, 0x471C, 0x7D8A, 0x18F8, 0x9592,
+        0x5A82, 0xA57D, 0xA57D, 0x5A82, 0x5A82, 0xA57D, 0xA57D, 0x5A82,
+        0x471C, 0x8275, 0x18F8, 0x6A6D, 0x9592, 0xE707, 0x7D8A, 0xB8E3,
+        0x30FB, 0x89BE, 0x7641, 0xCF04, 0xCF04, 0x7641, 0x89BE, 0x30FB,
+        0x18F8, 0xB8E3, 0x6A6D, 0x8275, 0x7D8A, 0x9592, 0x471C, 0xE707
+    ];
+
+    fn write_halfwords(mdec: &mut MDEC, command: u32, halfwords: &[u16])
+    {
+        mdec.write_command(command | (halfwords.len() as u32 / 2));
+
+        for pair in halfwords.chunks(2)
+        {
+            mdec.write_command(pair[0] as u32 | (pair[1] as u32) << 16);
+        }
+    }
+
+    // Decodes a monochrome block to signed 8-bit pixels, the quantization is 8 for every coefficient
+    fn decode_monochrome(block: &[u16]) -> Vec<i8>
+    {
+        let mut mdec = MDEC::new();
+
+        write_halfwords(&mut mdec, 0x4000_0000, &[0x0808; 32]);
+        write_halfwords(&mut mdec, 0x6000_0000, &SCALE);
+        write_halfwords(&mut mdec, 0x2000_0000 | 1 << 27 | 1 << 26, block);
+
+        let mut pixels = Vec::new();
+
+        while mdec.output_len() > 0
+        {
+            pixels.extend(mdec.read_data().to_le_bytes().iter().map(|&byte| byte as i8));
+        }
+
+        pixels
+    }
+
+    #[test]
+    fn decode_dc_only_block()
+    {
+        // DC 10, quantized by 8, then divided by 8 by the two IDCT passes
+        let pixels = decode_monochrome(&[1 << 10 | 10, END_OF_BLOCK]);
+
+        assert_eq!(pixels, vec![10; 64]);
+    }
+
+    #[test]
+    fn decode_uses_zigzag_order()
+    {
+        // The third coefficient in zigzag order is the first vertical frequency (row 1, column 0):
+        // the pixels only change from one row to the next
+        let pixels = decode_monochrome(&[1 << 10, 1 << 10 | 40, END_OF_BLOCK, END_OF_BLOCK]);
+
+        assert_eq!(pixels.len(), 64);
+
+        for row in pixels.chunks(8)
+        {
+            assert!(row.iter().all(|&pixel| pixel == row[0]));
+        }
+
+        assert!(pixels[0] > 0 && pixels[56] < 0);
+        assert_eq!(pixels[0], -pixels[56]);
+    }
+
+    #[test]
+    fn run_length_skips_coefficients()
+    {
+        // A run of 1 zero before the coefficient: the same block as above
+        let with_run = decode_monochrome(&[1 << 10, 1 << 10 | 40, END_OF_BLOCK, END_OF_BLOCK]);
+
+        // The second coefficient is the first horizontal frequency: the pixels only change along the row
+        let without_run = decode_monochrome(&[1 << 10, 40, END_OF_BLOCK, END_OF_BLOCK]);
+
+        assert_ne!(with_run, without_run);
+        assert!(without_run.chunks(8).all(|row| row == &without_run[0 .. 8]));
+    }
+}
@@ -1,18 +1,77 @@
-// TODO clean up ranges (in/ex)
 // TODO refactor this to be the PSX?
 
-use crate::bios::BIOS;
+use crate::bios::{ BIOS, BIOSVersion, Region };
 use crate::cdrom::CDROM;
-use crate::dma::DMA;
+use crate::dma::{ Devices, DMA };
 use crate::gpu::GPU;
 use crate::interrupt_controller::InterruptController;
+use crate::mdec::MDEC;
 use crate::memory_segment::MemorySegment;
 use crate::spu::SPU;
+use crate::timers::{ Timers, VideoStandard };
+use crate::tty::TTY;
 
 use std::cell::RefCell;
-use std::path::PathBuf;
 use std::rc::Rc;
 
+// What to do when the CPU accesses an address where nothing answers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusErrorPolicy
+{
+    Strict, // Bus error exception, like the real hardware
+    Lenient, // Log the access and carry on
+    Debugger // Stop the emulation in the debugger
+}
+
+pub(crate) const RAM_SIZE: u32 = 2 * 1024 * 1024;
+const BIOS_SIZE: u32 = 512 * 1024;
+
+// Masks converting a virtual address to a physical one, indexed by the top 3 bits
+// Documentation
+// https://problemkaputt.de/psx-spx.htm#memorymap
+const REGION_MASK: [u32; 8] =
+[
+    0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, // KUSEG: 2048MB
+    0x7FFF_FFFF, // KSEG0: 512MB
+    0x1FFF_FFFF, // KSEG1: 512MB
+    0xFFFF_FFFF, 0xFFFF_FFFF // KSEG2: 1024MB
+];
+
+// Fixed access costs in cycles, on top of the instruction itself.
+// The writes to RAM go through the write buffer.
+pub(crate) const RAM_READ_CYCLES: u32 = 5;
+pub(crate) const RAM_WRITE_CYCLES: u32 = 1;
+const SCRATCHPAD_CYCLES: u32 = 0;
+
+// Indices of the delay/size registers in the memory control registers
+const EXPANSION_1_DELAY: usize = 2;
+const EXPANSION_3_DELAY: usize = 3;
+const BIOS_DELAY: usize = 4;
+const SPU_DELAY: usize = 5;
+const CDROM_DELAY: usize = 6;
+const EXPANSION_2_DELAY: usize = 7;
+const COM_DELAY: usize = 8;
+
+// The address space is split in 64KB pages to find RAM and BIOS accesses quickly
+const PAGE_SHIFT: u32 = 16;
+const PAGE_COUNT: usize = 1 << (32 - PAGE_SHIFT);
+const PAGE_MASK: u32 = (1 << PAGE_SHIFT) - 1;
+
+// RAM pages containing decoded code are watched for writes
+pub(crate) const CODE_PAGE_SHIFT: u32 = 12;
+
+// Offset of the pages that are not in RAM, for the recompiled code
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+pub(crate) const NOT_RAM: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+enum Page
+{
+    Slow, // I/O or anything that needs the full address decoding
+    RAM(u32), // Offset of the page in the RAM
+    BIOS(u32) // Offset of the page in the BIOS, read-only
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Width
 {
@@ -79,69 +138,534 @@ pub struct Memory
     cd: CDROM,
     dma: DMA,
     pub gpu: GPU,
+    mdec: MDEC,
     ram: MemorySegment,
     scratchpad: MemorySegment,
     pub spu: SPU,
+    timers: Timers,
+    pub tty: TTY,
+
+    memory_control: [u32; 9], // Base addresses and delays of the other regions
+    access_cycles: u32, // Time spent in memory accesses since the latest check
+    ram_size: u32,
+    cache_control: u32,
+
+    pub bus_error_policy: BusErrorPolicy,
+    bus_error: bool, // Set if the latest access failed
+
+    pages: Vec<Page>,
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    ram_offsets: Vec<u32>, // Same as the RAM pages, read by the recompiled loads and stores
+
+    code_pages: Vec<bool>,
+    invalidated_code_pages: Vec<u32>,
 
     interrupt_controller: Rc<RefCell<InterruptController>>
 }
 
 impl Memory
 {
-    pub fn new(bios_path: PathBuf, display: &glium::Display, interrupt_controller: &Rc<RefCell<InterruptController>>) -> Self
+    // Without a display, nothing is rendered (e.g. for the music playback)
+    pub fn new(bios: BIOS, display: Option<&glium::Display>, interrupt_controller: &Rc<RefCell<InterruptController>>) -> Self
     {
-        Memory
+        let region = bios.region();
+        let video_standard = Memory::video_standard(region);
+
+        let mut memory = Memory
         {
-            bios: BIOS::new(bios_path),
-            cd: CDROM::new(interrupt_controller),
-            dma: DMA::new(),
-            gpu: GPU::new(display),
-            ram: MemorySegment::new(0x1F00_0000),
+            bios,
+            cd: CDROM::new(interrupt_controller, region),
+            dma: DMA::new(interrupt_controller),
+            gpu: GPU::new(display, video_standard),
+            mdec: MDEC::new(),
+            ram: MemorySegment::new(RAM_SIZE as usize),
             scratchpad: MemorySegment::new(0x400),
             spu: SPU::new(),
+            timers: Timers::new(interrupt_controller, video_standard),
+            tty: TTY::new(),
+
+            // Values set by the BIOS
+            memory_control: [0x1F00_0000, 0x1F80_2000, 0x0013_243F, 0x0000_3022, 0x0013_243F, 0x2009_31E1, 0x0002_0843, 0x0007_0777, 0x0003_1125],
+            ram_size: 0x0000_0B88,
+            cache_control: 0,
+            access_cycles: 0,
+
+            bus_error_policy: BusErrorPolicy::Strict,
+            bus_error: false,
+
+            pages: vec![Page::Slow; PAGE_COUNT],
+
+            #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+            ram_offsets: vec![NOT_RAM; PAGE_COUNT],
+
+            code_pages: vec![false; (RAM_SIZE >> CODE_PAGE_SHIFT) as usize],
+            invalidated_code_pages: Vec::new(),
+
             interrupt_controller: interrupt_controller.clone()
+        };
+
+        memory.update_pages();
+
+        memory
+    }
+
+    // Advances the components that run on their own clock.
+    // Returns the cycles during which the DMA stopped the CPU.
+    pub fn tick(&mut self, cycles: u32) -> u32
+    {
+        let mut devices = Devices
+        {
+            ram: &mut self.ram,
+            code_pages: &mut self.code_pages,
+            invalidated_code_pages: &mut self.invalidated_code_pages,
+            mdec: &mut self.mdec,
+            gpu: &mut self.gpu,
+            cdrom: &mut self.cd,
+            spu: &mut self.spu
+        };
+
+        // The CPU is stopped while the DMA holds the bus,
+        // but the other components keep running
+        let stall_cycles = self.dma.tick(cycles, &mut devices);
+
+        self.spu.tick(cycles + stall_cycles);
+        self.timers.tick(cycles + stall_cycles);
+
+        stall_cycles
+    }
+
+    // Saves the RAM and the devices. The BIOS and the settings are not part of the state.
+    pub(crate) fn save_state(&self, writer: &mut Vec<u8>) -> bincode::Result<()>
+    {
+        let registers = (self.memory_control, self.ram_size, self.cache_control, self.access_cycles);
+
+        // The devices with FIFOs are last, everything before keeps the same size
+        bincode::serialize_into(writer, &(
+            &self.ram,
+            &self.scratchpad,
+            registers,
+            &*self.interrupt_controller.borrow(),
+            &self.dma,
+            &self.gpu,
+            &self.spu,
+            &self.timers,
+            &self.cd,
+            &self.mdec))
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut &[u8]) -> bincode::Result<()>
+    {
+        type Registers = ([u32; 9], u32, u32, u32);
+
+        let (ram, scratchpad, registers, interrupt_controller, dma, gpu, spu, timers, cd, mdec):
+            (MemorySegment, MemorySegment, Registers, InterruptController, DMA, GPU, SPU, Timers, CDROM, MDEC)
+            = bincode::deserialize_from(reader)?;
+
+        self.ram = ram;
+        self.scratchpad = scratchpad;
+        (self.memory_control, self.ram_size, self.cache_control, self.access_cycles) = registers;
+        *self.interrupt_controller.borrow_mut() = interrupt_controller;
+
+        self.cd.load_state(cd);
+        self.dma.load_state(dma);
+        self.gpu.load_state(gpu).map_err(|reason| Box::new(bincode::ErrorKind::Custom(reason)))?;
+        self.mdec = mdec;
+        self.spu = spu;
+        self.timers.load_state(timers);
+
+        self.bus_error = false;
+        self.update_pages();
+
+        // The CPU drops all its compiled code
+        self.code_pages.iter_mut().for_each(|watched| *watched = false);
+        self.invalidated_code_pages.clear();
+
+        Ok(())
+    }
+
+    // Cycles the devices can be ticked at once without changing what the CPU sees,
+    // 0 while the DMA runs alongside the CPU
+    pub fn cycles_until_event(&self) -> u32
+    {
+        if self.dma.is_running()
+        {
+            return 0;
         }
+
+        self.timers.cycles_until_event()
     }
 
-    pub fn read<T: Addressable>(&mut self, address: u32) -> T
+    // True if the address reaches a device register, which must be up to date when accessed
+    pub fn is_device(&self, address: u32) -> bool
+    {
+        match self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            Page::Slow => !matches!(Memory::physical_address(address), 0x1F80_0000 ..= 0x1F80_03FF), // Scratchpad
+            _          => false
+        }
+    }
+
+    // Number of VBlanks so far
+    pub fn frame_count(&self) -> u64
+    {
+        self.timers.frames()
+    }
+
+    pub fn bios_crc(&self) -> u32
+    {
+        self.bios.crc()
+    }
+
+    // Puts the devices back in their power-on state, for a program replacing the one running
+    pub(crate) fn reset_devices(&mut self)
+    {
+        let video_standard = Memory::video_standard(self.region());
+
+        *self.interrupt_controller.borrow_mut() = InterruptController::new();
+        self.dma = DMA::new(&self.interrupt_controller);
+        self.gpu.reset(video_standard);
+        self.spu = SPU::new();
+        self.timers = Timers::new(&self.interrupt_controller, video_standard);
+    }
+
+    fn video_standard(region: Region) -> VideoStandard
+    {
+        match region
+        {
+            Region::Europe => VideoStandard::PAL,
+            _              => VideoStandard::NTSC
+        }
+    }
+
+    // Sets the VBlank rate, which is given by the region unless the program asks for another one
+    pub fn set_video_standard(&mut self, video_standard: VideoStandard)
+    {
+        self.timers.set_video_standard(video_standard);
+    }
+
+    // Converts a virtual address to a physical one
+    fn physical_address(address: u32) -> u32
+    {
+        address & REGION_MASK[(address >> 29) as usize]
+    }
+
+    // Returns the size of the RAM window and the end of the High-Z area after it,
+    // everything above is locked
+    // Documentation
+    // https://problemkaputt.de/psx-spx.htm#memorycontrol
+    fn ram_window(&self) -> (u32, u32)
+    {
+        const MB: u32 = 1024 * 1024;
+
+        match (self.ram_size >> 9) & 7
+        {
+            0 => (MB, MB),
+            1 => (4 * MB, 4 * MB),
+            2 => (MB, 2 * MB),
+            3 => (4 * MB, 8 * MB),
+            4 => (2 * MB, 2 * MB),
+            5 => (8 * MB, 8 * MB),
+            6 => (2 * MB, 4 * MB),
+            _ => (8 * MB, 8 * MB)
+        }
+    }
+
+    // Returns true if the latest read or write failed and the policy asks to report it
+    pub fn has_bus_error(&self) -> bool
+    {
+        self.bus_error
+    }
+
+    fn raise_bus_error(&mut self)
+    {
+        self.bus_error = self.bus_error_policy != BusErrorPolicy::Lenient;
+    }
+
+    // Reads an instruction without side effects, if it is in RAM or in the BIOS
+    pub fn peek_code(&self, address: u32) -> Option<u32>
+    {
+        match self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            Page::RAM(offset)  => Some(self.ram.read(offset | (address & PAGE_MASK))),
+            Page::BIOS(offset) => Some(self.bios.read(offset | (address & PAGE_MASK))),
+            Page::Slow         => None
+        }
+    }
+
+    // Charges uncached instruction fetches from RAM or the BIOS, the code itself was read before
+    pub fn charge_code_fetches(&mut self, address: u32, count: u32)
+    {
+        self.access_cycles += count * self.code_fetch_cycles(address);
+    }
+
+    // Time taken to read an instruction word from there
+    pub fn code_fetch_cycles(&self, address: u32) -> u32
+    {
+        match self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            Page::RAM(_)  => RAM_READ_CYCLES,
+            Page::BIOS(_) => self.device_access_cycles(BIOS_DELAY, Width::Word, false),
+            Page::Slow    => 0
+        }
+    }
+
+    // Longest access that doesn't reach a device register: RAM, scratchpad or BIOS
+    pub fn max_access_cycles(&self, write: bool) -> u32
+    {
+        let ram_cycles = if write { RAM_WRITE_CYCLES } else { RAM_READ_CYCLES };
+
+        ram_cycles.max(self.device_access_cycles(BIOS_DELAY, Width::Word, write))
+    }
+
+    // Returns the RAM page of an address, None if it's not in RAM
+    pub fn code_page(&self, address: u32) -> Option<u32>
+    {
+        match self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            Page::RAM(offset) => Some((offset | (address & PAGE_MASK)) >> CODE_PAGE_SHIFT),
+            _                 => None
+        }
+    }
+
+    // The next write to this page will be reported
+    pub fn watch_code_page(&mut self, page: u32)
+    {
+        self.code_pages[page as usize] = true;
+    }
+
+    pub fn has_invalidated_code_pages(&self) -> bool
+    {
+        !self.invalidated_code_pages.is_empty()
+    }
+
+    pub fn take_invalidated_code_pages(&mut self) -> Vec<u32>
+    {
+        std::mem::take(&mut self.invalidated_code_pages)
+    }
+
+    fn write_ram<T: Addressable>(&mut self, offset: u32, value: T)
+    {
+        let page = (offset >> CODE_PAGE_SHIFT) as usize;
+
+        if self.code_pages[page]
+        {
+            self.code_pages[page] = false;
+            self.invalidated_code_pages.push(page as u32);
+        }
+
+        self.ram.write(offset, value);
+    }
+
+    // Maps the RAM (with its mirrors) and the BIOS in all the regions
+    fn update_pages(&mut self)
     {
-        match address
+        let (memory_end, _) = self.ram_window();
+
+        for region in &[0x0000_0000, 0x8000_0000, 0xA000_0000]
         {
-            // KUSEG
+            for physical in (0 .. 0x0080_0000).step_by(1 << PAGE_SHIFT)
+            {
+                self.pages[((region | physical) >> PAGE_SHIFT) as usize] = if physical < memory_end
+                {
+                    Page::RAM(physical & (RAM_SIZE - 1))
+                }
+                else
+                {
+                    Page::Slow // Unconnected or locked
+                };
+            }
+
+            for physical in (0x1FC0_0000 .. 0x2000_0000).step_by(1 << PAGE_SHIFT)
+            {
+                self.pages[((region | physical) >> PAGE_SHIFT) as usize] = Page::BIOS(physical & (BIOS_SIZE - 1));
+            }
+        }
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        {
+            for (ram_offset, page) in self.ram_offsets.iter_mut().zip(&self.pages)
+            {
+                *ram_offset = match page
+                {
+                    Page::RAM(offset) => *offset,
+                    _                 => NOT_RAM
+                };
+            }
+        }
+    }
+
+    // RAM, offset of each page in the RAM and watched code pages, for the recompiled code
+    // Only valid until the next state load
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub(crate) fn jit_pointers(&mut self) -> (*mut u8, *const u32, *const bool)
+    {
+        (self.ram.as_mut_ptr(), self.ram_offsets.as_ptr(), self.code_pages.as_ptr())
+    }
+
+    // None if the BIOS dump is unknown or emulated
+    pub fn bios_version(&self) -> Option<BIOSVersion>
+    {
+        self.bios.version()
+    }
+
+    pub fn region(&self) -> Region
+    {
+        self.bios.region()
+    }
+
+    pub fn cache_control(&self) -> u32
+    {
+        self.cache_control
+    }
+
+    // Returns the cycles spent in memory accesses since the latest call
+    pub fn take_access_cycles(&mut self) -> u32
+    {
+        std::mem::replace(&mut self.access_cycles, 0)
+    }
+
+    // Access time of the devices configured through the delay/size registers
+    // Documentation
+    // https://problemkaputt.de/psx-spx.htm#memorycontrol
+    fn device_access_cycles(&self, delay_index: usize, width: Width, write: bool) -> u32
+    {
+        let delay = self.memory_control[delay_index];
+        let com = self.memory_control[COM_DELAY];
 
-            0x0000_0000 ..= 0x1EFF_FFFF =>  self.ram.read(address), //  TODO smaller range?
+        let access_time = if write { delay & 0xF } else { (delay >> 4) & 0xF };
 
-            0x1F00_0000 ..= 0x1F7F_FFFF => T::from_u32(0xFF), // fake license check
+        let mut first = 0;
+        let mut sequential = 0;
+        let mut minimum = 0;
+
+        if delay & (1 << 8) != 0 // COM0: recovery period
+        {
+            first += (com & 0xF).saturating_sub(1);
+            sequential += (com & 0xF).saturating_sub(1);
+        }
+
+        if delay & (1 << 10) != 0 // COM2: float period
+        {
+            first += (com >> 8) & 0xF;
+            sequential += (com >> 8) & 0xF;
+        }
+
+        if delay & (1 << 11) != 0 // COM3: strobe period
+        {
+            minimum = (com >> 12) & 0xF;
+        }
+
+        if first < 6
+        {
+            first += 1;
+        }
+
+        first = (first + access_time + 2).max(minimum + 6);
+        sequential = (sequential + access_time + 2).max(minimum + 2);
+
+        // Wider accesses are split according to the data bus width (8 or 16 bits)
+        let bus_width = if delay & (1 << 12) != 0 { 2 } else { 1 };
+        let transfers = (width as u32 / bus_width).max(1);
+
+        first + (transfers - 1) * sequential
+    }
+
+    fn charge_access<T: Addressable>(&mut self, physical: u32, write: bool)
+    {
+        self.access_cycles += match physical
+        {
+            0x0000_0000 ..= 0x007F_FFFF => if write { RAM_WRITE_CYCLES } else { RAM_READ_CYCLES },
+            0x1F00_0000 ..= 0x1F7F_FFFF => self.device_access_cycles(EXPANSION_1_DELAY, T::width(), write),
+            0x1F80_0000 ..= 0x1F80_03FF => SCRATCHPAD_CYCLES,
+            0x1F80_1800 ..= 0x1F80_1803 => self.device_access_cycles(CDROM_DELAY, T::width(), write),
+            0x1F80_1C00 ..= 0x1F80_1FFF => self.device_access_cycles(SPU_DELAY, T::width(), write),
+            0x1F80_2000 ..= 0x1F80_3FFF => self.device_access_cycles(EXPANSION_2_DELAY, T::width(), write),
+            0x1FA0_0000 ..= 0x1FBF_FFFF => self.device_access_cycles(EXPANSION_3_DELAY, T::width(), write),
+            0x1FC0_0000 ..= 0x1FFF_FFFF => self.device_access_cycles(BIOS_DELAY, T::width(), write),
+            _ => 0
+        };
+    }
+
+    pub fn read<T: Addressable>(&mut self, address: u32) -> T
+    {
+        self.bus_error = false;
+
+        // Fast path
+
+        match self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            Page::RAM(offset)  =>
+            {
+                self.access_cycles += RAM_READ_CYCLES;
+                return self.ram.read(offset | (address & PAGE_MASK));
+            },
+            Page::BIOS(offset) =>
+            {
+                self.access_cycles += self.device_access_cycles(BIOS_DELAY, T::width(), false);
+                return self.bios.read(offset | (address & PAGE_MASK));
+            },
+            Page::Slow         => ()
+        }
 
-            0x1F80_0000 ..= 0x1F80_03FF => self.scratchpad.read(address - 0x1F80_0000),
+        let physical = Memory::physical_address(address);
+        self.charge_access::<T>(physical, false);
 
+        match physical
+        {
+            0x0000_0000 ..= 0x007F_FFFF =>
+            {
+                let (memory_end, high_z_end) = self.ram_window();
+
+                if physical < memory_end
+                {
+                    self.ram.read(physical & (RAM_SIZE - 1))
+                }
+                else if physical < high_z_end
+                {
+                    warn!("Read {:?} from unconnected RAM @ {:08x}", T::width(), address);
+                    T::from_u32(0xFFFF_FFFF)
+                }
+                else
+                {
+                    error!("Read {:?} from locked RAM @ {:08x}", T::width(), address);
+                    self.raise_bus_error();
+                    T::from_u32(0)
+                }
+            },
+
+            0x1F00_0000 ..= 0x1F7F_FFFF => T::from_u32(0xFFFF_FFFF), // Expansion 1, nothing connected
+
+            // The scratchpad is not accessible from KSEG1
+            0x1F80_0000 ..= 0x1F80_03FF if address >> 29 != 5 => self.scratchpad.read(physical - 0x1F80_0000),
+
+            0x1F80_1000 ..= 0x1F80_1023 => T::from_u32(self.memory_control[((physical - 0x1F80_1000) >> 2) as usize]),
             0x1F80_1040 ..= 0x1F80_105F => { warn!("Ignoring IO read {:08x}", address); T::from_u32(0xFFFF) },
+            0x1F80_1060 ..= 0x1F80_1063 => T::from_u32(self.ram_size),
 
             0x1F80_1070 ..= 0x1F80_1073 => T::from_u16(self.interrupt_controller.borrow().read_status()), // TODO extract actual part
             0x1F80_1074 ..= 0x1F80_1077 => T::from_u16(self.interrupt_controller.borrow().read_mask()),
 
-            0x1F80_1080 ..= 0x1F80_10FF => self.dma.read(address - 0x1F80_1080),
-            0x1F80_1100 ..= 0x1F80_112F => { warn!("Ignoring Timer read {:08x}", address); T::from_u32(0) },
-            0x1F80_1800 ..= 0x1F80_1803 => self.cd.read(address - 0x1F80_1800),
+            0x1F80_1080 ..= 0x1F80_10FF => self.dma.read(physical - 0x1F80_1080),
+            0x1F80_1100 ..= 0x1F80_112F => self.timers.read(physical - 0x1F80_1100),
+            0x1F80_1800 ..= 0x1F80_1803 => self.cd.read(physical - 0x1F80_1800),
             0x1F80_1810 => T::from_u32(self.gpu.read()),
             0x1F80_1814 => T::from_u32(self.gpu.status()),
-            0x1F80_1C00 ..= 0x1F80_1E80 => T::from_u16(self.spu.read(address - 0x1F80_1C00)),
-
-            // TODO bios here
-
-            // KSEG0
+            0x1F80_1820 => T::from_u32(self.mdec.read_data()),
+            0x1F80_1824 => T::from_u32(self.mdec.status()),
+            0x1F80_1C00 ..= 0x1F80_1FFF => T::from_u16(self.spu.read(physical - 0x1F80_1C00)),
 
-            0x8000_0000 ..= 0x9F00_0000 =>  self.ram.read(address - 0x8000_0000), // TODO exclusive range
+            0x1F80_2000 ..= 0x1F80_3FFF => T::from_u32(0xFFFF_FFFF), // Expansion 2, nothing connected
+            0x1FA0_0000 ..= 0x1FBF_FFFF => T::from_u32(0xFFFF_FFFF), // Expansion 3, nothing connected
 
-            // KSEG1
+            0x1FC0_0000 ..= 0x1FFF_FFFF => self.bios.read(physical & (BIOS_SIZE - 1)), // Mirrored in the whole 4MB area
 
-            0xA000_0000 ..= 0xBF00_0000 => self.ram.read(address - 0xA000_0000), // TODO exclusive range
-
-            0xBFC0_0000 ..= 0xBFC8_0000 => self.bios.read(address - 0xBFC0_0000), // TODO exclusive range
+            0xFFFE_0130 => T::from_u32(self.cache_control),
 
             _ =>
             {
                 error!("Unsupported read {:?} @ {:08x}", T::width(), address);
+                self.raise_bus_error();
                 T::from_u8(0)
             }
         }
@@ -149,38 +673,195 @@ impl Memory
 
     pub fn write<T: Addressable>(&mut self, address: u32, value: T)
     {
-        match address
-        {
-            // KUSEG
+        self.bus_error = false;
 
-            0x0000_0000 ..= 0x1F00_0000 =>  self.ram.write(address, value), // TODO exclusive range
+        // Fast path
 
-            0x1F80_0000 ..= 0x1F80_03FF => self.scratchpad.write(address - 0x1F80_0000, value),
+        if let Page::RAM(offset) = self.pages[(address >> PAGE_SHIFT) as usize]
+        {
+            self.access_cycles += RAM_WRITE_CYCLES;
+            self.write_ram(offset | (address & PAGE_MASK), value);
+            return;
+        }
 
-            0x1F80_1000 ..= 0x1F80_1024 => warn!("Ignoring memory control 1 write"),
+        let physical = Memory::physical_address(address);
+        self.charge_access::<T>(physical, true);
+
+        match physical
+        {
+            0x0000_0000 ..= 0x007F_FFFF =>
+            {
+                let (memory_end, high_z_end) = self.ram_window();
+
+                if physical < memory_end
+                {
+                    self.write_ram(physical & (RAM_SIZE - 1), value);
+                }
+                else if physical < high_z_end
+                {
+                    warn!("Ignoring write {:?} to unconnected RAM @ {:08x}", T::width(), address);
+                }
+                else
+                {
+                    error!("Write {:?} to locked RAM @ {:08x}", T::width(), address);
+                    self.raise_bus_error();
+                }
+            },
+
+            0x1F00_0000 ..= 0x1F7F_FFFF => warn!("Ignoring write {:?} to Expansion 1 @ {:X}", T::width(), address),
+
+            // The scratchpad is not accessible from KSEG1
+            0x1F80_0000 ..= 0x1F80_03FF if address >> 29 != 5 => self.scratchpad.write(physical - 0x1F80_0000, value),
+
+            0x1F80_1000 ..= 0x1F80_1023 => self.memory_control[((physical - 0x1F80_1000) >> 2) as usize] = value.as_u32(),
             0x1F80_1040 ..= 0x1F80_105F => warn!("Ignoring IO write"),
-            0x1F80_1060 => warn!("Ignoring memory control 2 write"),
+            0x1F80_1060 ..= 0x1F80_1063 =>
+            {
+                self.ram_size = value.as_u32();
+                self.update_pages();
+            },
             0x1F80_1070 => self.interrupt_controller.borrow_mut().write_status(value.as_u16()),
             0x1F80_1074 => self.interrupt_controller.borrow_mut().write_mask(value.as_u16()),
-            0x1F80_1080 ..= 0x1F80_10FF => self.dma.write(address - 0x1F80_1080, value, &mut self.ram, &mut self.gpu),
-            0x1F80_1100 ..= 0x1F80_112F => warn!("Ignoring write to the timer registers: {:08x} @ {:08x}", value.as_u32(), address),
-            0x1F80_1800 ..= 0x1F80_1803 => self.cd.write(address - 0x1F80_1800, value),
-            0x1f80_1810  => self.gpu.gp0(value.as_u32()),
-            0x1f80_1814 => self.gpu.gp1(value.as_u32()),
-            0x1F80_1C00 ..= 0x1F80_1E80 => self.spu.write(address - 0x1F80_1C00, value.as_u16()),
-            0x1F80_2000 ..= 0x1F80_2042 => warn!("Ignoring write {:?} to Expansion 2 @ {:X}", T::width(), address),
+            0x1F80_1080 ..= 0x1F80_10FF => self.dma.write(physical - 0x1F80_1080, value),
+            0x1F80_1100 ..= 0x1F80_112F => self.timers.write(physical - 0x1F80_1100, value),
+            0x1F80_1800 ..= 0x1F80_1803 => self.cd.write(physical - 0x1F80_1800, value),
+            0x1F80_1810 => self.gpu.gp0(value.as_u32()),
+            0x1F80_1814 => self.gpu.gp1(value.as_u32()),
+            0x1F80_1820 => self.mdec.write_command(value.as_u32()),
+            0x1F80_1824 => self.mdec.write_control(value.as_u32()),
+            0x1F80_1C00 ..= 0x1F80_1FFF => self.spu.write(physical - 0x1F80_1C00, value.as_u16()),
+
+            0x1F80_2023 => self.tty.putchar(value.as_u8()), // DUART channel A transmit, used as a TTY by the dev kits
+            0x1F80_2000 ..= 0x1F80_3FFF => warn!("Ignoring write {:?} to Expansion 2 @ {:X}", T::width(), address),
+            0x1FA0_0000 ..= 0x1FBF_FFFF => warn!("Ignoring write {:?} to Expansion 3 @ {:X}", T::width(), address),
+
+            0x1FC0_0000 ..= 0x1FFF_FFFF => warn!("Ignoring write {:?} to the BIOS @ {:08x}", T::width(), address),
+
+            0xFFFE_0130 => self.cache_control = value.as_u32(),
 
-            // KSEG1
+            _ =>
+            {
+                error!("Unsupported write {:?} {:08X} @ {:08x}", T::width(), value.as_u32(), address);
+                self.raise_bus_error();
+            }
+        }
+    }
+}
 
-            0x8000_0000 ..= 0x9F00_0000 =>  self.ram.write(address - 0x8000_0000, value), // TODO exclusive range
+#[cfg(test)]
+mod tests
+{
+    use super::*;
 
-            // KSEG2
+    fn memory() -> Memory
+    {
+        Memory::new(BIOS::hle(), None, &Rc::new(RefCell::new(InterruptController::new())))
+    }
 
-            0xA000_0000 ..= 0xA020_0000 =>  self.ram.write(address - 0xA000_0000, value), // TODO exclusive range
+    #[test]
+    fn region_masks()
+    {
+        assert_eq!(Memory::physical_address(0x0000_1234), 0x0000_1234); // KUSEG
+        assert_eq!(Memory::physical_address(0x8000_1234), 0x0000_1234); // KSEG0
+        assert_eq!(Memory::physical_address(0xA000_1234), 0x0000_1234); // KSEG1
+        assert_eq!(Memory::physical_address(0x9FC0_0000), 0x1FC0_0000);
+        assert_eq!(Memory::physical_address(0xBF80_1810), 0x1F80_1810);
+        assert_eq!(Memory::physical_address(0xFFFE_0130), 0xFFFE_0130); // KSEG2 is not mirrored
+    }
 
-            0xFFFE_0130 ..= 0xFFFE_0130 => warn!("Ignoring write to memory control 3 {:?} {:08x}", T::width(), address),
+    #[test]
+    fn ram_mirrors()
+    {
+        let mut memory = memory();
 
-            _ => panic!("Unsupported write {:?} {:08X} @ {:08x}", T::width(), value.as_u32(), address)
+        memory.write::<u32>(0x0000_0010, 0x1234_5678);
+
+        // The BIOS sets an 8MB window, the 2MB are mirrored 4 times in each region
+        for address in [0x0020_0010, 0x0060_0010, 0x8040_0010, 0xA060_0010]
+        {
+            assert_eq!(memory.read::<u32>(address), 0x1234_5678);
         }
+
+        memory.write::<u16>(0xA020_0012, 0xABCD);
+        assert_eq!(memory.read::<u32>(0x8000_0010), 0xABCD_5678);
+    }
+
+    #[test]
+    fn ram_window()
+    {
+        let mut memory = memory();
+
+        memory.write::<u32>(0x0000_0010, 0x1234_5678);
+
+        // 2MB of RAM, then High-Z up to 4MB, then locked
+        memory.write::<u32>(0x1F80_1060, 6 << 9);
+
+        assert_eq!(memory.read::<u32>(0x8000_0010), 0x1234_5678);
+        assert_eq!(memory.read::<u32>(0x8020_0010), 0xFFFF_FFFF);
+        assert!(!memory.has_bus_error());
+        assert_eq!(memory.read::<u32>(0x8040_0010), 0);
+        assert!(memory.has_bus_error());
+    }
+
+    #[test]
+    fn page_table()
+    {
+        let mut memory = memory();
+
+        memory.write::<u32>(0x8000_1000, 0x2408_0001);
+
+        // RAM and its mirrors, BIOS, then the slow path
+        assert_eq!(memory.peek_code(0xA060_1000), Some(0x2408_0001));
+        assert_eq!(memory.peek_code(0xBFC0_0000), Some(0));
+        assert_eq!(memory.peek_code(0x1F80_1810), None);
+
+        assert_eq!(memory.code_page(0x8020_1004), Some(1));
+        assert_eq!(memory.code_page(0xBFC0_0000), None);
+
+        assert!(memory.is_device(0x1F80_1810));
+        assert!(!memory.is_device(0x1F80_0010)); // Scratchpad
+        assert!(!memory.is_device(0x8000_1000));
+
+        // The pages follow the RAM window
+        memory.write::<u32>(0x1F80_1060, 4 << 9);
+        assert_eq!(memory.peek_code(0x0020_1000), None);
+        assert_eq!(memory.peek_code(0x0000_1000), Some(0x2408_0001));
+    }
+
+    // Reads or writes once and returns the cycles charged
+    fn access_cycles<T: Addressable>(memory: &mut Memory, address: u32, write: bool) -> u32
+    {
+        memory.take_access_cycles();
+
+        if write
+        {
+            memory.write::<T>(address, T::from_u32(0));
+        }
+        else
+        {
+            memory.read::<T>(address);
+        }
+
+        memory.take_access_cycles()
+    }
+
+    #[test]
+    fn wait_states()
+    {
+        let mut memory = memory();
+
+        assert_eq!(access_cycles::<u32>(&mut memory, 0x8000_1000, false), RAM_READ_CYCLES);
+        assert_eq!(access_cycles::<u8>(&mut memory, 0xA000_1000, true), RAM_WRITE_CYCLES);
+        assert_eq!(access_cycles::<u32>(&mut memory, 0x1F80_0000, false), SCRATCHPAD_CYCLES);
+
+        // The BIOS is on an 8-bit bus: 7 cycles for the first byte, 6 for the next ones
+        assert_eq!(access_cycles::<u8>(&mut memory, 0xBFC0_0000, false), 7);
+        assert_eq!(access_cycles::<u32>(&mut memory, 0xBFC0_0000, false), 7 + 3 * 6);
+        assert_eq!(memory.code_fetch_cycles(0xBFC0_0000), 7 + 3 * 6);
+        assert_eq!(access_cycles::<u8>(&mut memory, 0x1F80_1800, false), 7);
+
+        // With a 16-bit bus
+        memory.write::<u32>(0x1F80_1010, 0x0013_343F);
+        assert_eq!(access_cycles::<u32>(&mut memory, 0xBFC0_0000, false), 7 + 6);
     }
 }
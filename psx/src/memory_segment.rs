@@ -1,5 +1,8 @@
-use crate::memory::{Addressable};
+use crate::memory::{ Addressable, Width };
 
+use serde::{ Serialize, Deserialize };
+
+#[derive(Serialize, Deserialize)]
 pub struct MemorySegment
 {
     data: Vec<u8>
@@ -23,30 +26,37 @@ impl MemorySegment
         }
     }
 
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    pub fn as_mut_ptr(&mut self) -> *mut u8
+    {
+        self.data.as_mut_ptr()
+    }
+
+    // Direct little-endian loads and stores
+    // Alignment is checked by the CPU
+
     pub fn read<T: Addressable>(&self, address: u32) -> T
     {
         let offset = address as usize;
+        let data = &self.data;
 
-        let mut value = 0u32;
-
-        for i in 0 .. T::width() as usize
+        match T::width()
         {
-            let byte = self.data[offset + i];
-            value |= (byte as u32) << (i * 8);
+            Width::Byte => T::from_u8(data[offset]),
+            Width::Half => T::from_u16(u16::from_le_bytes([data[offset], data[offset + 1]])),
+            Width::Word => T::from_u32(u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]))
         }
-
-        T::from_u32(value)
     }
 
     pub fn write<T: Addressable>(&mut self, address: u32, value: T)
     {
         let offset = address as usize;
-        let value = value.as_u32();
 
-        for i in 0 .. T::width() as usize
+        match T::width()
         {
-            let byte = value >> (i * 8);
-            self.data[offset + i] = byte as u8;
+            Width::Byte => self.data[offset] = value.as_u8(),
+            Width::Half => self.data[offset .. offset + 2].copy_from_slice(&value.as_u16().to_le_bytes()),
+            Width::Word => self.data[offset .. offset + 4].copy_from_slice(&value.as_u32().to_le_bytes())
         }
     }
 }
@@ -0,0 +1,821 @@
+use crate::mdec::MDEC;
+
+use std::fmt;
+
+// Documentation
+//
+// https://problemkaputt.de/psx-spx.htm#cdromfilevideostrstreamingandbspicturecompressionsony
+// https://problemkaputt.de/psx-spx.htm#cdromxaaudioadpcmcompression
+
+// Decodes STR movies (MDEC video and XA-ADPCM audio) from raw disc sectors,
+// without running the rest of the console.
+//
+//     let mut decoder = MovieDecoder::new();
+//     for sector in sectors
+//     {
+//         match decoder.push_sector(sector)?
+//         {
+//             Some(MovieOutput::Frame(frame)) => ...,
+//             Some(MovieOutput::Audio(audio)) => ...,
+//             None => ()
+//         }
+//     }
+
+// Raw sector: sync (12 bytes) + header (4 bytes) + subheader (8 bytes) + data
+pub const RAW_SECTOR_SIZE: usize = 2352;
+
+// Mode 2 sector without the sync and header: subheader (8 bytes) + data
+pub const MODE2_SECTOR_SIZE: usize = 2336;
+
+const VIDEO_HEADER_SIZE: usize = 0x20;
+const VIDEO_PAYLOAD_SIZE: usize = 2016;
+
+const XA_GROUP_COUNT: usize = 18;
+const XA_GROUP_SIZE: usize = 128;
+
+// Tables uploaded to the MDEC by the movie libraries
+
+// MPEG-1 intra quantization matrix in zigzag order, with a DC scale of 2
+const QUANT_TABLE: [u8; 64] =
+[
+     2, 16, 16, 19, 16, 19, 22, 22,
+    22, 22, 22, 22, 26, 24, 26, 27,
+    27, 27, 26, 26, 26, 26, 27, 27,
+    27, 29, 29, 29, 34, 34, 34, 29,
+    29, 29, 27, 27, 29, 29, 32, 32,
+    34, 34, 37, 38, 37, 35, 35, 34,
+    35, 38, 38, 40, 40, 40, 48, 48,
+    46, 46, 56, 56, 58, 69, 69, 83
+];
+
+const SCALE_TABLE: [u16; 64] =
+[
+    0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82,
+    0x7D8A, 0x6A6D, 0x471C, 0x18F8, 0xE707, 0xB8E3, 0x9592, 0x8275,
+    0x7641, 0x30FB, 0xCF04, 0x89BE, 0x89BE, 0xCF04, 0x30FB, 0x7641,
+    0x6A6D, 0xE707, 0x8275, 0xB8E3, 0x471C, 0x7D8A, 0x18F8, 0x9592,
+    0x5A82, 0xA57D, 0xA57D, 0x5A82, 0x5A82, 0xA57D, 0xA57D, 0x5A82,
+    0x471C, 0x8275, 0x18F8, 0x6A6D, 0x9592, 0xE707, 0x7D8A, 0xB8E3,
+    0x30FB, 0x89BE, 0x7641, 0xCF04, 0xCF04, 0x7641, 0x89BE, 0x30FB,
+    0x18F8, 0xB8E3, 0x6A6D, 0x8275, 0x7D8A, 0x9592, 0x471C, 0xE707
+];
+
+// AC coefficients variable-length codes (MPEG-1), without the sign bit: (code, run, level)
+const AC_CODES: [(&str, u16, u16); 111] =
+[
+    ("11", 0, 1),
+    ("011", 1, 1),
+    ("0100", 0, 2),
+    ("0101", 2, 1),
+    ("00101", 0, 3),
+    ("00111", 3, 1),
+    ("00110", 4, 1),
+    ("000110", 1, 2),
+    ("000111", 5, 1),
+    ("000101", 6, 1),
+    ("000100", 7, 1),
+    ("0000110", 0, 4),
+    ("0000100", 2, 2),
+    ("0000111", 8, 1),
+    ("0000101", 9, 1),
+    ("00100110", 0, 5),
+    ("00100001", 0, 6),
+    ("00100101", 1, 3),
+    ("00100100", 3, 2),
+    ("00100111", 10, 1),
+    ("00100011", 11, 1),
+    ("00100010", 12, 1),
+    ("00100000", 13, 1),
+    ("0000001010", 0, 7),
+    ("0000001100", 1, 4),
+    ("0000001011", 2, 3),
+    ("0000001111", 4, 2),
+    ("0000001001", 5, 2),
+    ("0000001110", 14, 1),
+    ("0000001101", 15, 1),
+    ("0000001000", 16, 1),
+    ("000000011101", 0, 8),
+    ("000000011000", 0, 9),
+    ("000000010011", 0, 10),
+    ("000000010000", 0, 11),
+    ("000000011011", 1, 5),
+    ("000000010100", 2, 4),
+    ("000000011100", 3, 3),
+    ("000000010010", 4, 3),
+    ("000000011110", 6, 2),
+    ("000000010101", 7, 2),
+    ("000000010001", 8, 2),
+    ("000000011111", 17, 1),
+    ("000000011010", 18, 1),
+    ("000000011001", 19, 1),
+    ("000000010111", 20, 1),
+    ("000000010110", 21, 1),
+    ("0000000011010", 0, 12),
+    ("0000000011001", 0, 13),
+    ("0000000011000", 0, 14),
+    ("0000000010111", 0, 15),
+    ("0000000010110", 1, 6),
+    ("0000000010101", 1, 7),
+    ("0000000010100", 2, 5),
+    ("0000000010011", 3, 4),
+    ("0000000010010", 5, 3),
+    ("0000000010001", 9, 2),
+    ("0000000010000", 10, 2),
+    ("0000000011111", 22, 1),
+    ("0000000011110", 23, 1),
+    ("0000000011101", 24, 1),
+    ("0000000011100", 25, 1),
+    ("0000000011011", 26, 1),
+    ("00000000011111", 0, 16),
+    ("00000000011110", 0, 17),
+    ("00000000011101", 0, 18),
+    ("00000000011100", 0, 19),
+    ("00000000011011", 0, 20),
+    ("00000000011010", 0, 21),
+    ("00000000011001", 0, 22),
+    ("00000000011000", 0, 23),
+    ("00000000010111", 0, 24),
+    ("00000000010110", 0, 25),
+    ("00000000010101", 0, 26),
+    ("00000000010100", 0, 27),
+    ("00000000010011", 0, 28),
+    ("00000000010010", 0, 29),
+    ("00000000010001", 0, 30),
+    ("00000000010000", 0, 31),
+    ("000000000011000", 0, 32),
+    ("000000000010111", 0, 33),
+    ("000000000010110", 0, 34),
+    ("000000000010101", 0, 35),
+    ("000000000010100", 0, 36),
+    ("000000000010011", 0, 37),
+    ("000000000010010", 0, 38),
+    ("000000000010001", 0, 39),
+    ("000000000010000", 0, 40),
+    ("000000000011111", 1, 8),
+    ("000000000011110", 1, 9),
+    ("000000000011101", 1, 10),
+    ("000000000011100", 1, 11),
+    ("000000000011011", 1, 12),
+    ("000000000011010", 1, 13),
+    ("000000000011001", 1, 14),
+    ("0000000000010011", 1, 15),
+    ("0000000000010010", 1, 16),
+    ("0000000000010001", 1, 17),
+    ("0000000000010000", 1, 18),
+    ("0000000000010100", 6, 3),
+    ("0000000000011010", 11, 2),
+    ("0000000000011001", 12, 2),
+    ("0000000000011000", 13, 2),
+    ("0000000000010111", 14, 2),
+    ("0000000000010110", 15, 2),
+    ("0000000000010101", 16, 2),
+    ("0000000000011111", 27, 1),
+    ("0000000000011110", 28, 1),
+    ("0000000000011101", 29, 1),
+    ("0000000000011100", 30, 1),
+    ("0000000000011011", 31, 1)
+];
+
+// DC size variable-length codes (version 3 frames): (code, size)
+const DC_LUMINANCE_CODES: [(&str, u32); 9] =
+[
+    ("100", 0), ("00", 1), ("01", 2), ("101", 3), ("110", 4),
+    ("1110", 5), ("11110", 6), ("111110", 7), ("1111110", 8)
+];
+
+const DC_CHROMINANCE_CODES: [(&str, u32); 9] =
+[
+    ("00", 0), ("01", 1), ("10", 2), ("110", 3), ("1110", 4),
+    ("11110", 5), ("111110", 6), ("1111110", 7), ("11111110", 8)
+];
+
+const END_OF_BLOCK: u16 = 0xFE00;
+
+// XA-ADPCM filter coefficients (positive and negative)
+const XA_FILTERS: [(i32, i32); 4] = [(0, 0), (60, 0), (115, -52), (98, -55)];
+
+#[derive(Debug)]
+pub enum MovieError
+{
+    InvalidSectorSize(usize),
+    InvalidFrameHeader,
+    UnsupportedVersion(u16),
+    CorruptBitstream(u32), // frame number
+    FrameTooLarge(u32) // frame number
+}
+
+impl fmt::Display for MovieError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            MovieError::InvalidSectorSize(size) => write!(f, "invalid sector size {}", size),
+            MovieError::InvalidFrameHeader => write!(f, "invalid frame header"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported bitstream version {}", version),
+            MovieError::CorruptBitstream(frame) => write!(f, "corrupt bitstream in frame {}", frame),
+            MovieError::FrameTooLarge(frame) => write!(f, "frame {} is too large for a single MDEC command", frame)
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// A decoded video frame, as 24-bit RGB pixels
+pub struct Frame
+{
+    pub number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+// The samples decoded from an XA audio sector
+pub struct AudioSamples
+{
+    pub file: u8,
+    pub channel: u8,
+    pub sample_rate: u32,
+    pub stereo: bool,
+    pub samples: Vec<i16> // Interleaved if stereo
+}
+
+pub enum MovieOutput
+{
+    Frame(Frame),
+    Audio(AudioSamples)
+}
+
+// Reads a frame bitstream: little-endian halfwords, most significant bit first
+struct BitReader<'a>
+{
+    data: &'a [u8],
+    position: usize // in bits
+}
+
+impl<'a> BitReader<'a>
+{
+    fn new(data: &'a [u8]) -> Self
+    {
+        BitReader { data, position: 0 }
+    }
+
+    fn bit(&self, position: usize) -> Option<u32>
+    {
+        let halfword = position / 16;
+        let offset = halfword * 2;
+
+        if offset + 1 >= self.data.len()
+        {
+            return None;
+        }
+
+        let value = self.data[offset] as u32 | (self.data[offset + 1] as u32) << 8;
+        Some((value >> (15 - position % 16)) & 1)
+    }
+
+    fn read(&mut self, count: usize) -> Option<u32>
+    {
+        let mut value = 0;
+
+        for _ in 0 .. count
+        {
+            value = (value << 1) | self.bit(self.position)?;
+            self.position += 1;
+        }
+
+        Some(value)
+    }
+
+    // Returns true and skips the code if the next bits match it
+    fn matches(&mut self, code: &str) -> bool
+    {
+        let matching = code.bytes().enumerate().all(|(i, c)| self.bit(self.position + i) == Some((c - b'0') as u32));
+
+        if matching
+        {
+            self.position += code.len();
+        }
+
+        matching
+    }
+}
+
+// Decodes the XA-ADPCM sectors, keeping track of the filter state across sectors
+struct XADecoder
+{
+    previous_samples: [(i32, i32); 2] // per channel
+}
+
+impl XADecoder
+{
+    fn new() -> Self
+    {
+        XADecoder
+        {
+            previous_samples: [(0, 0); 2]
+        }
+    }
+
+    fn decode(&mut self, coding: u8, data: &[u8], samples: &mut Vec<i16>)
+    {
+        let stereo = coding & 3 == 1;
+        let bits8 = (coding >> 4) & 3 == 1;
+
+        let units = if bits8 { 4 } else { 8 };
+
+        for group in data.chunks(XA_GROUP_SIZE).take(XA_GROUP_COUNT)
+        {
+            if group.len() < XA_GROUP_SIZE
+            {
+                break;
+            }
+
+            let mut unit_samples = vec![[0i16; 28]; units];
+
+            for (unit, decoded) in unit_samples.iter_mut().enumerate()
+            {
+                let parameters = group[4 + unit];
+                let shift = match parameters & 0xF
+                {
+                    s if s > 12 => 9,
+                    s => s
+                };
+                let (filter_pos, filter_neg) = XA_FILTERS[((parameters >> 4) & 3) as usize];
+
+                let channel = if stereo { unit % 2 } else { 0 };
+                let (mut old, mut older) = self.previous_samples[channel];
+
+                for (i, sample) in decoded.iter_mut().enumerate()
+                {
+                    let raw = if bits8
+                    {
+                        ((group[16 + i * 4 + unit] as u16) << 8) as i16
+                    }
+                    else
+                    {
+                        let byte = group[16 + i * 4 + unit / 2];
+                        let nibble = if unit % 2 == 0 { byte & 0xF } else { byte >> 4 };
+                        ((nibble as u16) << 12) as i16
+                    };
+
+                    let mut value = (raw as i32) >> shift;
+                    value += (old * filter_pos + older * filter_neg + 32) >> 6;
+                    let value = value.clamp(-0x8000, 0x7FFF);
+
+                    *sample = value as i16;
+
+                    older = old;
+                    old = value;
+                }
+
+                self.previous_samples[channel] = (old, older);
+            }
+
+            if stereo
+            {
+                for pair in unit_samples.chunks(2)
+                {
+                    for (left, right) in pair[0].iter().zip(pair[1].iter())
+                    {
+                        samples.push(*left);
+                        samples.push(*right);
+                    }
+                }
+            }
+            else
+            {
+                for unit in unit_samples.iter()
+                {
+                    samples.extend_from_slice(unit);
+                }
+            }
+        }
+    }
+}
+
+pub struct MovieDecoder
+{
+    mdec: MDEC,
+    xa: XADecoder,
+
+    // Frame being assembled from the video sectors
+    frame_number: u32,
+    frame_data: Vec<u8>,
+    frame_chunks_received: Vec<bool>
+}
+
+impl MovieDecoder
+{
+    pub fn new() -> Self
+    {
+        let mut mdec = MDEC::new();
+
+        // Upload the tables like the movie libraries do
+
+        mdec.write_command(0x4000_0001);
+        for bytes in QUANT_TABLE.chunks(4).chain(QUANT_TABLE.chunks(4))
+        {
+            mdec.write_command(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        }
+
+        mdec.write_command(0x6000_0000);
+        for pair in SCALE_TABLE.chunks(2)
+        {
+            mdec.write_command(pair[0] as u32 | (pair[1] as u32) << 16);
+        }
+
+        MovieDecoder
+        {
+            mdec,
+            xa: XADecoder::new(),
+
+            frame_number: 0,
+            frame_data: Vec::new(),
+            frame_chunks_received: Vec::new()
+        }
+    }
+
+    // Gives access to the MDEC used for decoding, e.g. to feed it custom tables
+    pub fn mdec_mut(&mut self) -> &mut MDEC
+    {
+        &mut self.mdec
+    }
+
+    // Processes a raw (2352 bytes) or Mode 2 (2336 bytes) sector.
+    // Returns a frame when its last sector is pushed, or the samples of an audio sector.
+    pub fn push_sector(&mut self, sector: &[u8]) -> Result<Option<MovieOutput>, MovieError>
+    {
+        let (subheader, data) = match sector.len()
+        {
+            RAW_SECTOR_SIZE => (&sector[16 .. 24], &sector[24 ..]),
+            MODE2_SECTOR_SIZE => (&sector[0 .. 8], &sector[8 ..]),
+            size => return Err(MovieError::InvalidSectorSize(size))
+        };
+
+        let file = subheader[0];
+        let channel = subheader[1];
+        let submode = subheader[2];
+        let coding = subheader[3];
+
+        // Audio sector
+
+        if submode & 0x04 != 0
+        {
+            let mut samples = Vec::new();
+            self.xa.decode(coding, data, &mut samples);
+
+            return Ok(Some(MovieOutput::Audio(AudioSamples
+            {
+                file,
+                channel,
+                sample_rate: if (coding >> 2) & 3 == 0 { 37800 } else { 18900 },
+                stereo: coding & 3 == 1,
+                samples
+            })));
+        }
+
+        // Video sector (flagged as either video or data depending on the encoder)
+
+        if data.len() < VIDEO_HEADER_SIZE + VIDEO_PAYLOAD_SIZE || data[0 .. 4] != [0x60, 0x01, 0x01, 0x80]
+        {
+            return Ok(None);
+        }
+
+        let halfword = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let word = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        let chunk = halfword(0x04) as usize;
+        let chunk_count = halfword(0x06) as usize;
+        let frame_number = word(0x08);
+        let width = halfword(0x10) as u32;
+        let height = halfword(0x12) as u32;
+
+        if chunk >= chunk_count
+        {
+            return Err(MovieError::InvalidFrameHeader);
+        }
+
+        // Start a new frame
+
+        if frame_number != self.frame_number || self.frame_chunks_received.len() != chunk_count
+        {
+            self.frame_number = frame_number;
+            self.frame_data = vec![0; chunk_count * VIDEO_PAYLOAD_SIZE];
+            self.frame_chunks_received = vec![false; chunk_count];
+        }
+
+        let offset = chunk * VIDEO_PAYLOAD_SIZE;
+        self.frame_data[offset .. offset + VIDEO_PAYLOAD_SIZE].copy_from_slice(&data[VIDEO_HEADER_SIZE .. VIDEO_HEADER_SIZE + VIDEO_PAYLOAD_SIZE]);
+        self.frame_chunks_received[chunk] = true;
+
+        if !self.frame_chunks_received.iter().all(|&r| r)
+        {
+            return Ok(None);
+        }
+
+        self.frame_chunks_received.clear();
+
+        let frame_data = std::mem::take(&mut self.frame_data);
+        let frame = self.decode_frame(frame_number, &frame_data, width, height)?;
+
+        Ok(Some(MovieOutput::Frame(frame)))
+    }
+
+    // Decodes a frame (v2/v3 header + bitstream) to RGB
+    pub fn decode_frame(&mut self, number: u32, data: &[u8], width: u32, height: u32) -> Result<Frame, MovieError>
+    {
+        let codes = decode_bitstream(number, data, width, height)?;
+
+        // Decode to 24-bit RGB, the command gives the number of parameter words in its low 16 bits
+
+        let words = codes.len().div_ceil(2);
+
+        if words > 0xFFFF
+        {
+            return Err(MovieError::FrameTooLarge(number));
+        }
+
+        self.mdec.write_command(0x3000_0000 | words as u32);
+
+        for pair in codes.chunks(2)
+        {
+            let high = pair.get(1).copied().unwrap_or(END_OF_BLOCK);
+            self.mdec.write_command(pair[0] as u32 | (high as u32) << 16);
+        }
+
+        // Macroblocks come out column by column, each as 16x16 pixels
+
+        let macroblocks_wide = width.div_ceil(16);
+        let macroblocks_high = height.div_ceil(16);
+
+        let mut pixels = vec![0u8; (width * height * 3) as usize];
+        let mut macroblock = [0u8; 16 * 16 * 3];
+
+        for mbx in 0 .. macroblocks_wide
+        {
+            for mby in 0 .. macroblocks_high
+            {
+                for word in macroblock.chunks_mut(4)
+                {
+                    word.copy_from_slice(&self.mdec.read_data().to_le_bytes());
+                }
+
+                for y in 0 .. 16
+                {
+                    for x in 0 .. 16
+                    {
+                        let px = mbx * 16 + x;
+                        let py = mby * 16 + y;
+
+                        if px < width && py < height
+                        {
+                            let source = ((x + y * 16) * 3) as usize;
+                            let destination = ((px + py * width) * 3) as usize;
+                            pixels[destination .. destination + 3].copy_from_slice(&macroblock[source .. source + 3]);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Frame { number, width, height, pixels })
+    }
+}
+
+impl Default for MovieDecoder
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+// Converts the variable-length coded bitstream of a frame to MDEC run-length codes
+fn decode_bitstream(number: u32, data: &[u8], width: u32, height: u32) -> Result<Vec<u16>, MovieError>
+{
+    if data.len() < 8 || u16::from_le_bytes([data[2], data[3]]) != 0x3800
+    {
+        return Err(MovieError::InvalidFrameHeader);
+    }
+
+    let quant_scale = u16::from_le_bytes([data[4], data[5]]) & 0x3F;
+    let version = u16::from_le_bytes([data[6], data[7]]);
+
+    if !(1 ..= 3).contains(&version)
+    {
+        return Err(MovieError::UnsupportedVersion(version));
+    }
+
+    let corrupt = || MovieError::CorruptBitstream(number);
+
+    let mut reader = BitReader::new(&data[8 ..]);
+    let mut codes = Vec::new();
+
+    // Version 3 DC predictors: Cr, Cb, Y
+    let mut dc_previous = [0i32; 3];
+
+    let macroblock_count = (width.div_ceil(16)) * (height.div_ceil(16));
+
+    for _ in 0 .. macroblock_count
+    {
+        // Cr, Cb, Y1, Y2, Y3, Y4
+        for block in 0 .. 6
+        {
+            // DC
+
+            let dc = if version == 3
+            {
+                let component = block.min(2);
+                let table: &[(&str, u32)] = if component == 2 { &DC_LUMINANCE_CODES } else { &DC_CHROMINANCE_CODES };
+
+                let size = table.iter().find(|(code, _)| reader.matches(code)).map(|(_, size)| *size).ok_or_else(corrupt)?;
+
+                let diff = match size
+                {
+                    0 => 0,
+                    _ =>
+                    {
+                        let bits = reader.read(size as usize).ok_or_else(corrupt)? as i32;
+                        if bits < (1 << (size - 1)) { bits - ((1 << size) - 1) } else { bits }
+                    }
+                };
+
+                // The differences are stored divided by 4
+                dc_previous[component] += diff * 4;
+                dc_previous[component] as u16
+            }
+            else
+            {
+                reader.read(10).ok_or_else(corrupt)? as u16
+            };
+
+            codes.push((quant_scale << 10) | (dc & 0x3FF));
+
+            // AC
+
+            loop
+            {
+                if reader.matches("10")
+                {
+                    codes.push(END_OF_BLOCK);
+                    break;
+                }
+
+                // Escape: the run-length code follows as is
+                if reader.matches("000001")
+                {
+                    codes.push(reader.read(16).ok_or_else(corrupt)? as u16);
+                    continue;
+                }
+
+                let (run, level) = match AC_CODES.iter().find(|(code, _, _)| reader.matches(code))
+                {
+                    Some((_, run, level)) => (*run, *level),
+                    None => return Err(corrupt())
+                };
+
+                let negative = reader.read(1).ok_or_else(corrupt)? != 0;
+                let level = if negative { (level as i16).wrapping_neg() as u16 } else { level };
+
+                codes.push((run << 10) | (level & 0x3FF));
+            }
+        }
+    }
+
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Packs the bits in little-endian halfwords, most significant bit first
+    fn pack_bits(bits: &str) -> Vec<u8>
+    {
+        let bits: Vec<u16> = bits.bytes().filter(|&c| c != b' ').map(|c| (c - b'0') as u16).collect();
+
+        bits.chunks(16)
+            .map(|chunk| chunk.iter().enumerate().fold(0u16, |halfword, (index, bit)| halfword | bit << (15 - index)))
+            .flat_map(|halfword| halfword.to_le_bytes())
+            .collect()
+    }
+
+    // Version 2 frame header: the quantization scale is 2
+    fn frame(bitstream: &str) -> Vec<u8>
+    {
+        let mut data = vec![0x00, 0x00, 0x00, 0x38, 0x02, 0x00, 0x02, 0x00];
+        data.extend(pack_bits(bitstream));
+        data
+    }
+
+    const EMPTY_BLOCK: &str = "0000000000 10";
+
+    #[test]
+    fn bitstream_to_run_length_codes()
+    {
+        // DC 5, run 1 of level -1, escaped code, end of block, then 5 empty blocks
+        let bitstream = format!("0000000101 011 1 000001 0000110000000010 10 {}", [EMPTY_BLOCK; 5].join(" "));
+
+        let codes = decode_bitstream(0, &frame(&bitstream), 16, 16).unwrap();
+
+        let mut expected = vec![2 << 10 | 5, 1 << 10 | 0x3FF, 0x0C02, END_OF_BLOCK];
+        expected.extend([2 << 10, END_OF_BLOCK].repeat(5));
+
+        assert_eq!(codes, expected);
+    }
+
+    #[test]
+    fn bitstream_errors()
+    {
+        let blocks = [EMPTY_BLOCK; 6].join(" ");
+
+        let mut bad_magic = frame(&blocks);
+        bad_magic[3] = 0x39;
+        assert!(matches!(decode_bitstream(0, &bad_magic, 16, 16), Err(MovieError::InvalidFrameHeader)));
+
+        let mut bad_version = frame(&blocks);
+        bad_version[6] = 4;
+        assert!(matches!(decode_bitstream(0, &bad_version, 16, 16), Err(MovieError::UnsupportedVersion(4))));
+
+        // The second macroblock is missing
+        assert!(matches!(decode_bitstream(7, &frame(&blocks), 32, 16), Err(MovieError::CorruptBitstream(7))));
+    }
+
+    #[test]
+    fn flat_frame_decodes_to_gray()
+    {
+        let mut decoder = MovieDecoder::new();
+
+        let frame = decoder.decode_frame(1, &frame(&[EMPTY_BLOCK; 6].join(" ")), 16, 16).unwrap();
+
+        assert_eq!((frame.number, frame.width, frame.height), (1, 16, 16));
+        assert_eq!(frame.pixels, vec![0x80; 16 * 16 * 3]);
+    }
+
+    // Mode 2 sector: subheader then data
+    fn sector(submode: u8, coding: u8, data: &[u8]) -> Vec<u8>
+    {
+        let mut sector = vec![0; MODE2_SECTOR_SIZE];
+        sector[0 .. 8].copy_from_slice(&[1, 2, submode, coding, 1, 2, submode, coding]);
+        sector[8 .. 8 + data.len()].copy_from_slice(data);
+        sector
+    }
+
+    #[test]
+    fn sector_headers()
+    {
+        let mut decoder = MovieDecoder::new();
+
+        assert!(matches!(decoder.push_sector(&[0; 2048]), Err(MovieError::InvalidSectorSize(2048))));
+
+        // Stereo 18900Hz XA audio: 18 groups of 4 stereo units of 28 samples
+        match decoder.push_sector(&sector(0x64, 0x05, &[])).unwrap()
+        {
+            Some(MovieOutput::Audio(audio)) =>
+            {
+                assert_eq!((audio.file, audio.channel, audio.sample_rate, audio.stereo), (1, 2, 18900, true));
+                assert_eq!(audio.samples, vec![0; 18 * 8 * 28]);
+            },
+            _ => panic!("audio sector expected")
+        }
+
+        // First of the two video sectors of frame 3
+        let mut header = vec![0x60, 0x01, 0x01, 0x80, 0, 0, 2, 0, 3, 0, 0, 0, 0, 0, 0, 0, 16, 0, 16, 0];
+        assert!(decoder.push_sector(&sector(0x48, 0, &header)).unwrap().is_none());
+
+        // Chunk 2 of 2
+        header[4] = 2;
+        assert!(matches!(decoder.push_sector(&sector(0x48, 0, &header)), Err(MovieError::InvalidFrameHeader)));
+
+        // Not a video sector
+        assert!(decoder.push_sector(&sector(0x08, 0, &[0; 4])).unwrap().is_none());
+    }
+
+    #[test]
+    fn xa_adpcm_filters()
+    {
+        let mut xa = XADecoder::new();
+
+        // Mono 4-bit: the first unit has shift 9 and filter 1, its samples are all 7
+        let mut group = [0u8; XA_GROUP_SIZE];
+        group[4] = 0x19;
+        for index in 0 .. 28
+        {
+            group[16 + index * 4] = 0x07;
+        }
+
+        let mut samples = Vec::new();
+        xa.decode(0x00, &group, &mut samples);
+
+        // 7 << 12 >> 9 is 56, then each sample adds 60/64 of the previous one
+        assert_eq!(samples.len(), 8 * 28);
+        assert_eq!(samples[0], 56);
+        assert_eq!(samples[1], 56 + ((56 * 60 + 32) >> 6));
+        assert!(samples[27] > samples[1]);
+
+        // The other units have filter 0, they don't carry the previous samples
+        assert!(samples[28 ..].iter().all(|&sample| sample == 0));
+    }
+}
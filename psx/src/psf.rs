@@ -0,0 +1,318 @@
+use crate::bios::Region;
+use crate::error::Error;
+use crate::exefile::ExeFile;
+use crate::timers::VideoStandard;
+
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+// Portable Sound Format: the music of a game, ripped as a PS-X EXE with the sound driver and the data.
+// A MiniPSF only holds the song data, the driver is in the "_lib" PSF it refers to.
+//
+// Documentation
+//
+// psf_format.txt, by Neill Corlett
+//
+// Layout:
+// - 0x00: "PSF" and the version, 0x01 for the PlayStation
+// - 0x04: size of the reserved area, unused on the PlayStation
+// - 0x08: size of the compressed program
+// - 0x0C: CRC32 of the compressed program
+// - 0x10: reserved area, then the zlib-compressed program
+// - then optionally "[TAG]" and "name=value" lines
+
+const MAGIC: &[u8] = b"PSF\x01";
+const HEADER_SIZE: usize = 0x10;
+const TAG_MARKER: &[u8] = b"[TAG]";
+
+// Limit of the "_lib" chain, which could otherwise loop
+const MAX_LIBRARY_DEPTH: u32 = 10;
+
+// Played when the tags don't tell, like most players do
+const DEFAULT_LENGTH: Duration = Duration::from_secs(150);
+const DEFAULT_FADE: Duration = Duration::from_secs(10);
+
+// (name, value), the names are lowercase
+type Tags = Vec<(String, String)>;
+
+pub struct PSF
+{
+    exe: ExeFile,
+    tags: Tags
+}
+
+impl PSF
+{
+    pub fn new_from_file(path: PathBuf) -> Result<Self, Error>
+    {
+        PSF::load(path, 0)
+    }
+
+    fn load(path: PathBuf, depth: u32) -> Result<Self, Error>
+    {
+        println!("Loading PSF \"{}\"", path.display());
+
+        let mut data = Vec::new();
+
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|error| Error::Io(path.clone(), error))?;
+
+        let (program, tags) = PSF::parse(&data).map_err(|reason| Error::InvalidPSF(path.clone(), reason))?;
+
+        let mut exe = ExeFile::parse_exe(&program).map_err(|reason| Error::InvalidPSF(path.clone(), reason))?;
+
+        let libraries = PSF::libraries(&tags);
+
+        if !libraries.is_empty() && depth == MAX_LIBRARY_DEPTH
+        {
+            return Err(Error::InvalidPSF(path, String::from("too many nested libraries")));
+        }
+
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+        for (index, library) in libraries.into_iter().rev()
+        {
+            let library = PSF::load(directory.join(library), depth + 1)?;
+
+            // Only the main library gives the initial registers
+            exe.add_library(library.exe, index == 1);
+        }
+
+        if depth == 0
+        {
+            exe.validate().map_err(|reason| Error::InvalidPSF(path.clone(), reason))?;
+        }
+
+        Ok(PSF
+        {
+            exe,
+            tags
+        })
+    }
+
+    // Libraries, loaded in the order "_lib", "_lib2", "_lib3"... before the program itself.
+    // Returns their indices and file names.
+    fn libraries(tags: &[(String, String)]) -> Vec<(u32, String)>
+    {
+        let mut libraries = Vec::new();
+
+        for index in 1 ..
+        {
+            let name = match index
+            {
+                1 => String::from("_lib"),
+                _ => format!("_lib{}", index)
+            };
+
+            match PSF::find_tag(tags, &name)
+            {
+                Some(library) => libraries.push((index, library.to_string())),
+                None if index == 1 => (),
+                None => break
+            }
+        }
+
+        libraries
+    }
+
+    // Returns the decompressed program and the tags
+    fn parse(data: &[u8]) -> Result<(Vec<u8>, Tags), String>
+    {
+        if !data.starts_with(MAGIC)
+        {
+            return Err(String::from("the file does not start with \"PSF\\x01\""));
+        }
+
+        if data.len() < HEADER_SIZE
+        {
+            return Err(String::from("the header is truncated"));
+        }
+
+        let reserved_size = word(data, 0x04) as usize;
+        let program_size = word(data, 0x08) as usize;
+        let crc = word(data, 0x0C);
+
+        let program_start = HEADER_SIZE + reserved_size;
+        let program_end = program_start + program_size;
+
+        let compressed = data.get(program_start .. program_end).ok_or_else(|| String::from("the program is truncated"))?;
+
+        if crc32fast::hash(compressed) != crc
+        {
+            return Err(String::from("the CRC32 of the program does not match"));
+        }
+
+        let mut program = Vec::new();
+
+        ZlibDecoder::new(compressed).read_to_end(&mut program)
+            .map_err(|error| format!("cannot decompress the program: {}", error))?;
+
+        let tags = match &data[program_end ..]
+        {
+            text if text.starts_with(TAG_MARKER) => PSF::parse_tags(&text[TAG_MARKER.len() ..]),
+            _ => Vec::new()
+        };
+
+        Ok((program, tags))
+    }
+
+    // "name=value" lines, a value on several lines repeats the name
+    fn parse_tags(text: &[u8]) -> Tags
+    {
+        let mut tags: Tags = Vec::new();
+
+        for line in String::from_utf8_lossy(text).lines()
+        {
+            let (name, value) = match line.split_once('=')
+            {
+                Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+                None => continue
+            };
+
+            match tags.iter_mut().find(|(n, _)| *n == name)
+            {
+                Some((_, previous)) =>
+                {
+                    previous.push('\n');
+                    previous.push_str(value);
+                },
+                None => tags.push((name, value.to_string()))
+            }
+        }
+
+        tags
+    }
+
+    fn find_tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str>
+    {
+        tags.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    // Tag by name, e.g. "title", "artist", "game"
+    pub fn tag(&self, name: &str) -> Option<&str>
+    {
+        PSF::find_tag(&self.tags, &name.to_lowercase())
+    }
+
+    pub fn tags(&self) -> &[(String, String)]
+    {
+        &self.tags
+    }
+
+    // Time before the fade out
+    pub fn length(&self) -> Duration
+    {
+        self.tag("length").and_then(parse_time).unwrap_or(DEFAULT_LENGTH)
+    }
+
+    pub fn fade(&self) -> Duration
+    {
+        self.tag("fade").and_then(parse_time).unwrap_or(DEFAULT_FADE)
+    }
+
+    pub(crate) fn exe(&self) -> &ExeFile
+    {
+        &self.exe
+    }
+
+    // The sound drivers are usually timed by the VBlank, which depends on the game region
+    pub(crate) fn video_standard(&self) -> Option<VideoStandard>
+    {
+        match self.tag("_refresh")
+        {
+            Some("50") => Some(VideoStandard::PAL),
+            Some("60") => Some(VideoStandard::NTSC),
+            _ => match self.exe.region()?
+            {
+                Region::Europe => Some(VideoStandard::PAL),
+                _              => Some(VideoStandard::NTSC)
+            }
+        }
+    }
+}
+
+// "[[hours:]minutes:]seconds[.decimals]", the decimal separator may also be a comma
+fn parse_time(text: &str) -> Option<Duration>
+{
+    let mut seconds = 0.0;
+
+    for part in text.split(':')
+    {
+        let value = part.trim().replace(',', ".").parse::<f64>().ok()?;
+
+        if value < 0.0
+        {
+            return None;
+        }
+
+        seconds = seconds * 60.0 + value;
+    }
+
+    Some(Duration::from_secs_f64(seconds))
+}
+
+fn word(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags
+    {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parse_time_formats()
+    {
+        assert_eq!(parse_time("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_time("1:30"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_time("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time("2.5"), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_time("0:2,5"), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_time(" 1 : 10 "), Some(Duration::from_secs(70)));
+    }
+
+    #[test]
+    fn parse_time_rejects_garbage()
+    {
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("abc"), None);
+        assert_eq!(parse_time("1:-5"), None);
+        assert_eq!(parse_time("1::2"), None);
+    }
+
+    #[test]
+    fn parse_tags_lines()
+    {
+        let parsed = PSF::parse_tags(b"title=Song\nArtist = Someone \ncomment=first\ncomment=second\nno separator\n");
+
+        assert_eq!(parsed, tags(&[("title", "Song"), ("artist", "Someone"), ("comment", "first\nsecond")]));
+    }
+
+    #[test]
+    fn libraries_in_order()
+    {
+        let parsed = tags(&[("_lib3", "c.psflib"), ("_lib", "a.psflib"), ("_lib2", "b.psflib"), ("_lib5", "e.psflib")]);
+
+        // "_lib5" is ignored, as "_lib4" is missing
+        assert_eq!(PSF::libraries(&parsed), vec![(1, String::from("a.psflib")), (2, String::from("b.psflib")), (3, String::from("c.psflib"))]);
+    }
+
+    #[test]
+    fn libraries_without_main()
+    {
+        let parsed = tags(&[("_lib2", "b.psflib")]);
+
+        assert_eq!(PSF::libraries(&parsed), vec![(2, String::from("b.psflib"))]);
+        assert!(PSF::libraries(&tags(&[("title", "Song")])).is_empty());
+    }
+}
@@ -1,64 +1,468 @@
+use crate::audio::AudioSink;
+use crate::bios::{ BIOS, SHELL_ADDRESS };
 use crate::cpu::CPU;
+use crate::error::Error;
+use crate::exefile::ExeFile;
 use crate::gpu::GPU;
+use crate::hle::Kernel;
 use crate::interrupt_controller::InterruptController;
 use crate::memory::Memory;
+use crate::psf::PSF;
+use crate::rewind::{ Rewind, RewindConfig };
+use crate::tracer::{ TraceConfig, Tracer };
+
+pub use crate::bios::{ BIOSVersion, Region };
+pub use crate::cpu::ExecutionMode;
+pub use crate::memory::BusErrorPolicy;
 
 use std::cell::RefCell;
+use std::fs;
+use std::io;
 use std::path::PathBuf;
 use std::rc::Rc;
 
+// Save states: magic, version, CRC32 of the BIOS, hash of the program, then the memory and the CPU.
+// The parts that change size (FIFOs, HLE kernel...) are at the end, so the snapshots line up for the rewind deltas.
+const STATE_MAGIC: &[u8] = b"PSXSTATE";
+const STATE_VERSION: u32 = 1;
+const STATE_HEADER_SIZE: usize = 20;
+
+// When `PSX::load_exe` starts the program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExeLoading
+{
+    AfterKernelInit, // The CPU restarts the BIOS, and the EXE replaces the shell once the kernel is initialized
+    Immediate // The EXE runs right away from a fresh CPU state, without the BIOS initialization
+}
+
 pub struct PSX
 {
     pub mem: Memory,
     pub cpu: CPU,
-    interrupt_controller: Rc<RefCell<InterruptController>>
+    interrupt_controller: Rc<RefCell<InterruptController>>,
+
+    program_hash: u32, // Of the EXE or the disc, 0 if there is none
+
+    rewind: Option<Rewind>,
+
+    audio_sink: Option<Box<dyn AudioSink>>
 }
 
 impl PSX
 {
-    pub fn new(bios_path: PathBuf, program_path: Option<PathBuf>, display: &glium::Display) -> Self
+    // Without a BIOS image, the kernel is emulated and the program can also be
+    // a directory with the contents of a disc (booted from its SYSTEM.CNF)
+    pub fn new(bios_path: Option<PathBuf>, program_path: Option<PathBuf>, display: &glium::Display) -> Result<Self, Error>
     {
-        env_logger::init();
-
-        let _interrupt_controller = Rc::new(RefCell::new(InterruptController::new()));
+        let bios = PSX::open_bios(&bios_path)?;
 
         // If the program is stored in an EXE file, we'll need
         // to hot-load it after the BIOS has been initialized
 
-        let exe_path = program_path.and_then(|path|
+        let is_exe = |path: &PathBuf| path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ["exe", "elf", "cpe"].iter().any(|e| ext.eq_ignore_ascii_case(e)));
+
+        let (exe, disc_path, program_hash) = match program_path
         {
-            path.extension()
-                .and_then(|ext| ext.to_str()) // OsStr to &str
-                .and_then(|ext|
-                    match ext
-                    {
-                        "exe" => Some(path.clone()),
-                        _ => None
-                    }
-                )
-        });
+            Some(path) if is_exe(&path) =>
+            {
+                let exe = ExeFile::new_from_file(path.clone())?;
+                PSX::check_exe_region(&exe, bios.region());
+                let hash = exe.hash();
+                (Some(exe), path.parent().map(|p| p.to_path_buf()), hash)
+            },
+            Some(path) =>
+            {
+                let path = PSX::check_disc(path, bios_path.is_none())?;
+                let hash = PSX::disc_hash(&path);
+                (None, Some(path), hash)
+            },
+            None => (None, None, 0)
+        };
+
+        let mut psx = PSX::build(bios, exe, disc_path, Some(display));
+        psx.program_hash = program_hash;
+
+        Ok(psx)
+    }
+
+    // Plays a PSF without rendering anything, the sound drivers only use
+    // the CPU, the SPU, the timers and the interrupts
+    pub fn new_psf(bios_path: Option<PathBuf>, psf: &PSF) -> Result<Self, Error>
+    {
+        let bios = PSX::open_bios(&bios_path)?;
+
+        let mut psx = PSX::build(bios, Some(psf.exe().clone()), None, None);
+        psx.program_hash = psf.exe().hash();
+
+        if let Some(video_standard) = psf.video_standard()
+        {
+            psx.mem.set_video_standard(video_standard);
+        }
+
+        Ok(psx)
+    }
+
+    fn open_bios(bios_path: &Option<PathBuf>) -> Result<BIOS, Error>
+    {
+        match bios_path
+        {
+            Some(path) => BIOS::new(path.clone()),
+            None       => Ok(BIOS::hle())
+        }
+    }
+
+    // The kernel is emulated if there is no BIOS image
+    fn build(bios: BIOS, exe: Option<ExeFile>, disc_path: Option<PathBuf>, display: Option<&glium::Display>) -> Self
+    {
+        // There may be several systems, e.g. for lockstep comparisons
+        let _ = env_logger::try_init();
+
+        let _interrupt_controller = Rc::new(RefCell::new(InterruptController::new()));
+
+        let hle = bios.is_hle();
+
+        let symbols = exe.as_ref().map(|exe| exe.symbols().to_vec()).unwrap_or_default();
+
+        let mut cpu = CPU::new(&_interrupt_controller);
+
+        if let Some(exe) = exe
+        {
+            cpu.set_pending_exe(SHELL_ADDRESS, exe);
+        }
+
+        cpu.debugger.add_symbols(&symbols);
+
+        if hle
+        {
+            // "cdrom:" files are read from the disc directory, or from the EXE's directory
+            cpu.set_hle(Some(Kernel::new(disc_path, PathBuf::from("memcards"))));
+        }
 
         PSX
         {
-            mem: Memory::new(bios_path, display, &_interrupt_controller),
-            cpu: CPU::new(&_interrupt_controller, exe_path),
-            interrupt_controller: _interrupt_controller
+            mem: Memory::new(bios, display, &_interrupt_controller),
+            cpu,
+            interrupt_controller: _interrupt_controller,
+
+            program_hash: 0,
+
+            rewind: None,
+
+            audio_sink: None
+        }
+    }
+
+    // Loads a program, replacing the one running
+    pub fn load_exe(&mut self, path: PathBuf, loading: ExeLoading) -> Result<(), Error>
+    {
+        let exe = ExeFile::new_from_file(path)?;
+
+        PSX::check_exe_region(&exe, self.region());
+
+        self.program_hash = exe.hash();
+
+        if let Some(rewind) = &mut self.rewind
+        {
+            rewind.clear();
+        }
+
+        self.cpu.debugger.clear_symbols();
+        self.cpu.debugger.add_symbols(exe.symbols());
+
+        self.mem.reset_devices();
+
+        match loading
+        {
+            ExeLoading::AfterKernelInit =>
+            {
+                self.cpu.reset();
+                self.cpu.set_pending_exe(SHELL_ADDRESS, exe);
+            },
+            ExeLoading::Immediate => self.cpu.run_exe(&exe, &mut self.mem)
+        }
+
+        Ok(())
+    }
+
+    // The licensed programs can still run on other consoles (e.g. with a modchip)
+    fn check_exe_region(exe: &ExeFile, region: Region)
+    {
+        if let Some(exe_region) = exe.region()
+        {
+            if exe_region != region
+            {
+                warn!("The EXE is for {:?} but the console is for {:?}", exe_region, region);
+            }
         }
     }
 
+    // Only the extracted contents of a disc can be used, by the HLE kernel
+    fn check_disc(path: PathBuf, hle: bool) -> Result<PathBuf, Error>
+    {
+        if !path.exists()
+        {
+            return Err(Error::Io(path, io::Error::from(io::ErrorKind::NotFound)));
+        }
+
+        if !hle
+        {
+            return Err(Error::InvalidDisc(path, String::from("the CDROM drive cannot read discs yet, only EXE files can be loaded")));
+        }
+
+        if !path.is_dir()
+        {
+            return Err(Error::InvalidDisc(path, String::from("without a BIOS, the disc must be a directory with its extracted contents")));
+        }
+
+        Ok(path)
+    }
+
+    // Identifies the extracted contents of a disc by the names and sizes of the files at its root,
+    // and by the contents of SYSTEM.CNF and of the executable it boots
+    fn disc_hash(path: &PathBuf) -> u32
+    {
+        let mut entries: Vec<(String, u64)> = fs::read_dir(path).into_iter().flatten().flatten()
+            .map(|entry| (entry.file_name().to_string_lossy().to_string(), entry.metadata().map_or(0, |m| m.len())))
+            .collect();
+
+        entries.sort();
+
+        let mut hasher = crc32fast::Hasher::new();
+
+        for (name, size) in entries
+        {
+            hasher.update(name.as_bytes());
+            hasher.update(&size.to_le_bytes());
+        }
+
+        for boot_file in Kernel::boot_files(path)
+        {
+            match fs::read(&boot_file)
+            {
+                Ok(contents) => hasher.update(&contents),
+                Err(error) => warn!("cannot read {} to identify the disc: {}", boot_file.display(), error)
+            }
+        }
+
+        hasher.finalize()
+    }
+
     pub fn load_bios()
     {
 
     }
 
+    // Snapshot of the whole machine, to be restored with `load_state`.
+    // The debugger, the trace and the other settings are not saved.
+    pub fn save_state(&self) -> Result<Vec<u8>, Error>
+    {
+        let mut state = Vec::new();
+
+        state.extend_from_slice(STATE_MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&self.mem.bios_crc().to_le_bytes());
+        state.extend_from_slice(&self.program_hash.to_le_bytes());
+
+        self.mem.save_state(&mut state)
+            .and_then(|_| self.cpu.save_state(&mut state))
+            .map_err(|error| Error::CannotSaveState(error.to_string()))?;
+
+        Ok(state)
+    }
+
+    // Only the states saved with the same BIOS and program are accepted
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Error>
+    {
+        self.apply_state(state)?;
+
+        if let Some(rewind) = &mut self.rewind
+        {
+            rewind.clear();
+        }
+
+        Ok(())
+    }
+
+    fn apply_state(&mut self, state: &[u8]) -> Result<(), Error>
+    {
+        let invalid = |reason: &str| Err(Error::InvalidState(String::from(reason)));
+
+        if state.len() < STATE_HEADER_SIZE || !state.starts_with(STATE_MAGIC)
+        {
+            return invalid("not a save state");
+        }
+
+        let word = |offset: usize| u32::from_le_bytes([state[offset], state[offset + 1], state[offset + 2], state[offset + 3]]);
+
+        if word(8) != STATE_VERSION
+        {
+            return Err(Error::InvalidState(format!("version {} is not supported, expected {}", word(8), STATE_VERSION)));
+        }
+
+        if word(12) != self.mem.bios_crc()
+        {
+            return invalid("the state was saved with another BIOS");
+        }
+
+        if word(16) != self.program_hash
+        {
+            return invalid("the state was saved with another game or program");
+        }
+
+        // Put the machine back as it was if the state turns out to be corrupted
+        let backup = self.save_state()?;
+
+        if let Err(error) = self.load_components(&state[STATE_HEADER_SIZE ..])
+        {
+            let _ = self.load_components(&backup[STATE_HEADER_SIZE ..]);
+            return Err(Error::InvalidState(error.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn load_components(&mut self, mut data: &[u8]) -> bincode::Result<()>
+    {
+        self.mem.load_state(&mut data)?;
+        self.cpu.load_state(&mut data)
+    }
+
+    // Takes a snapshot every few frames to be able to rewind, or stops
+    pub fn set_rewind(&mut self, config: Option<RewindConfig>)
+    {
+        self.rewind = config.map(Rewind::new);
+    }
+
+    // Goes back at least this number of frames, as far as the snapshots allow.
+    // Returns the number of frames actually rewound.
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, Error>
+    {
+        let mut rewind = match self.rewind.take()
+        {
+            Some(rewind) => rewind,
+            None => return Ok(0)
+        };
+
+        let now = self.mem.frame_count();
+
+        let result = match rewind.rewind(now.saturating_sub(frames as u64))
+        {
+            Some((frame, state)) => self.apply_state(state).map(|_| now.saturating_sub(frame) as u32),
+            None => Ok(0)
+        };
+
+        self.rewind = Some(rewind);
+
+        result
+    }
+
+    fn update_rewind(&mut self)
+    {
+        let frame = self.mem.frame_count();
+
+        if !self.rewind.as_ref().is_some_and(|rewind| rewind.is_due(frame))
+        {
+            return;
+        }
+
+        match self.save_state()
+        {
+            Ok(state) =>
+            {
+                if let Some(rewind) = &mut self.rewind
+                {
+                    rewind.push(frame, state);
+                }
+            },
+            Err(error) => error!("cannot take the rewind snapshot: {}", error)
+        }
+    }
+
     pub fn step(&mut self)
     {
         self.cpu.step(&mut self.mem);
+        self.update_rewind();
+        self.flush_audio();
     }
 
     pub fn run(&mut self, instructions: u32) -> bool
     {
-        self.cpu.run(instructions, &mut self.mem)
+        let result = self.cpu.run(instructions, &mut self.mem);
+        self.update_rewind();
+        self.flush_audio();
+        result
+    }
+
+    // Chooses how the CPU runs the instructions in `run`, `step` always interprets them
+    pub fn set_execution_mode(&mut self, mode: ExecutionMode)
+    {
+        self.cpu.set_execution_mode(mode);
+    }
+
+    // Chooses how accesses to unmapped addresses are handled
+    pub fn set_bus_error_policy(&mut self, policy: BusErrorPolicy)
+    {
+        self.mem.bus_error_policy = policy;
+    }
+
+    // Writes an execution trace as configured, or stops the current one
+    pub fn set_trace(&mut self, config: Option<TraceConfig>) -> std::io::Result<()>
+    {
+        let tracer = match config
+        {
+            Some(config) => Some(Tracer::new(config)?),
+            None         => None
+        };
+
+        self.cpu.set_tracer(tracer);
+
+        Ok(())
+    }
+
+    // None if the BIOS dump is unknown or emulated
+    pub fn bios_version(&self) -> Option<BIOSVersion>
+    {
+        self.mem.bios_version()
+    }
+
+    // Region of the console, given by the BIOS
+    pub fn region(&self) -> Region
+    {
+        self.mem.region()
+    }
+
+    // Prints the BIOS function calls (A0h, B0h and C0h) with their arguments
+    pub fn set_kernel_call_log(&mut self, enabled: bool)
+    {
+        self.cpu.set_kernel_call_log(enabled);
+    }
+
+    // Text written by the program with std_out_putchar/puts or to the DUART
+    pub fn tty(&self) -> &str
+    {
+        self.mem.tty.text()
+    }
+
+    pub fn clear_tty(&mut self)
+    {
+        self.mem.tty.clear();
+    }
+
+    // The sink receives all the samples generated by the SPU from now on
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>)
+    {
+        self.audio_sink = sink;
+    }
+
+    // Hands the samples generated so far to the audio sink
+    fn flush_audio(&mut self)
+    {
+        if let Some(sink) = &mut self.audio_sink
+        {
+            sink.push(self.mem.spu.output());
+        }
+
+        self.mem.spu.clear_output();
     }
 
     // TEMP
@@ -71,3 +475,93 @@ impl PSX
         &mut self.mem.gpu
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Counts in $8 and stores it in RAM forever
+    fn counting_program() -> PSX
+    {
+        let code = [0x2508_0001u32, 0xAC08_2000, 0x0800_4000, 0x0000_0000];
+
+        let mut data = vec![0; 0x800];
+        data[0 .. 8].copy_from_slice(b"PS-X EXE");
+        data[0x10 .. 0x14].copy_from_slice(&0x8001_0000u32.to_le_bytes());
+        data[0x18 .. 0x1C].copy_from_slice(&0x8001_0000u32.to_le_bytes());
+        data[0x1C .. 0x20].copy_from_slice(&0x800u32.to_le_bytes());
+        data.extend(code.iter().flat_map(|instruction| instruction.to_le_bytes()));
+        data.resize(0x1000, 0);
+
+        let exe = ExeFile::parse_exe(&data).unwrap();
+        let hash = exe.hash();
+
+        let mut psx = PSX::build(BIOS::hle(), Some(exe), None, None);
+        psx.program_hash = hash;
+        psx
+    }
+
+    #[test]
+    fn save_load_round_trip()
+    {
+        let mut psx = counting_program();
+        psx.run(200_000);
+
+        let saved = psx.save_state().unwrap();
+        psx.run(100_000);
+        let later = psx.save_state().unwrap();
+
+        // The state is restored as it was, and runs the same
+        psx.load_state(&saved).unwrap();
+        assert!(psx.save_state().unwrap() == saved);
+
+        psx.run(100_000);
+        assert!(psx.save_state().unwrap() == later);
+
+        // The program was running
+        assert_ne!(psx.mem.read::<u32>(0x2000), 0);
+    }
+
+    #[test]
+    fn invalid_states_are_rejected()
+    {
+        let mut psx = counting_program();
+        psx.run(200_000);
+
+        let saved = psx.save_state().unwrap();
+
+        let mut other_program = saved.clone();
+        other_program[16] ^= 1;
+        assert!(matches!(psx.load_state(&other_program), Err(Error::InvalidState(_))));
+
+        let mut other_version = saved.clone();
+        other_version[8] += 1;
+        assert!(matches!(psx.load_state(&other_version), Err(Error::InvalidState(_))));
+
+        // The machine is left untouched by a truncated state
+        psx.run(100_000);
+        let current = psx.save_state().unwrap();
+        assert!(matches!(psx.load_state(&saved[.. saved.len() - 100]), Err(Error::InvalidState(_))));
+        assert!(psx.save_state().unwrap() == current);
+    }
+
+    #[test]
+    fn disc_hash_follows_the_boot_executable()
+    {
+        let disc = std::env::temp_dir().join(format!("psx-disc-test-{}", std::process::id()));
+        fs::create_dir_all(&disc).unwrap();
+
+        fs::write(disc.join("SYSTEM.CNF"), "BOOT = cdrom:\\MAIN.EXE;1\r\n").unwrap();
+        fs::write(disc.join("MAIN.EXE"), [1; 16]).unwrap();
+        let hash = PSX::disc_hash(&disc);
+
+        assert_eq!(PSX::disc_hash(&disc), hash);
+
+        // Same names and sizes
+        fs::write(disc.join("MAIN.EXE"), [2; 16]).unwrap();
+        assert_ne!(PSX::disc_hash(&disc), hash);
+
+        fs::remove_dir_all(disc).unwrap();
+    }
+}
@@ -90,8 +90,42 @@ impl Renderer
         //framebuffer.blit_whole_color_to(target, &target_rect, uniforms::MagnifySamplerFilter::Linear);
         framebuffer.blit_color(&source_rect, target, &target_rect, uniforms::MagnifySamplerFilter::Linear);
 
-        // Reset the vertex buffer's content
+        self.clear_vertices();
+    }
+
+    // Replaces the render buffer with the VRAM contents (15-bit BGR pixels, 1024x512),
+    // e.g. once a save state is loaded. The triangles not rendered yet are dropped.
+    pub fn upload_vram(&mut self, vram: &[u16])
+    {
+        // The GL rows go upwards, the VRAM is mirrored vertically when rendered
+        let pixels = vram.chunks(1024).rev()
+            .flat_map(|row| row.iter())
+            .flat_map(|&pixel| [pixel, pixel >> 5, pixel >> 10].map(|component| ((component & 0x1F) << 3 | (component & 0x1F) >> 2) as u8))
+            .collect();
+
+        let image = glium::texture::RawImage2d::from_raw_rgb(pixels, (1024, 512));
+
+        let texture = match glium::texture::Texture2d::new(&self.context, image)
+        {
+            Ok(texture) => texture,
+            Err(error) =>
+            {
+                error!("cannot upload the VRAM: {:?}", error);
+                return;
+            }
+        };
 
+        let framebuffer = glium::framebuffer::SimpleFrameBuffer::new(&self.context, &self.render_buffer).unwrap();
+
+        let target_rect = glium::BlitTarget { left: 0, bottom: 0, width: 1024, height: 512 };
+        texture.as_surface().blit_whole_color_to(&framebuffer, &target_rect, uniforms::MagnifySamplerFilter::Nearest);
+
+        self.clear_vertices();
+    }
+
+    // Resets the vertex buffer's content
+    fn clear_vertices(&mut self)
+    {
         let dummy_vertex = Vertex { position: [0, 0], color: [0, 0, 0] };
 
         let mut w = self.vertex_buffer.map_write();
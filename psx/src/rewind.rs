@@ -0,0 +1,215 @@
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::collections::VecDeque;
+use std::io::{ Read, Write };
+
+// Save states taken regularly, to go back in time.
+//
+// Only the latest state is kept whole. Each older one is stored as the XOR with
+// the state that follows it, which is mostly zeros and compresses well.
+// Rewinding applies the deltas from the newest to the oldest.
+//
+// The deltas are only small if the same bytes stay at the same offsets: the save
+// states keep their variable-length parts at the end, after the RAM, VRAM and SPU RAM.
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindConfig
+{
+    pub interval: u32, // Frames between two snapshots
+    pub capacity: usize // Bytes of compressed deltas kept, the oldest ones are dropped first
+}
+
+impl Default for RewindConfig
+{
+    fn default() -> Self
+    {
+        RewindConfig
+        {
+            interval: 10,
+            capacity: 64 * 1024 * 1024
+        }
+    }
+}
+
+struct Delta
+{
+    frame: u64, // Of the older state
+    length: usize, // Of the older state, the states don't all have the same size
+    data: Vec<u8> // Compressed
+}
+
+pub struct Rewind
+{
+    config: RewindConfig,
+
+    latest: Option<(u64, Vec<u8>)>, // (frame, state)
+    deltas: VecDeque<Delta>,
+    size: usize, // Of the compressed deltas
+
+    next_frame: u64
+}
+
+impl Rewind
+{
+    pub fn new(config: RewindConfig) -> Self
+    {
+        Rewind
+        {
+            config,
+
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+
+            next_frame: 0
+        }
+    }
+
+    // True if a snapshot should be taken at this frame
+    pub fn is_due(&self, frame: u64) -> bool
+    {
+        frame >= self.next_frame
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>)
+    {
+        if let Some((previous_frame, previous)) = self.latest.take()
+        {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+
+            let data = encoder.write_all(&xor(&previous, &state)).and_then(|_| encoder.finish());
+
+            match data
+            {
+                Ok(data) =>
+                {
+                    self.size += data.len();
+                    self.deltas.push_back(Delta { frame: previous_frame, length: previous.len(), data });
+                },
+                Err(error) => error!("cannot compress the rewind snapshot: {}", error)
+            }
+
+            while self.size > self.config.capacity
+            {
+                match self.deltas.pop_front()
+                {
+                    Some(delta) => self.size -= delta.data.len(),
+                    None => break
+                }
+            }
+        }
+
+        self.latest = Some((frame, state));
+        self.next_frame = frame + self.config.interval as u64;
+    }
+
+    // Goes back to the latest snapshot taken at or before the target frame,
+    // or to the oldest one. Returns its frame and state.
+    pub fn rewind(&mut self, target_frame: u64) -> Option<(u64, &[u8])>
+    {
+        let (mut frame, mut state) = self.latest.take()?;
+
+        while frame > target_frame
+        {
+            let delta = match self.deltas.pop_back()
+            {
+                Some(delta) => delta,
+                None => break
+            };
+
+            self.size -= delta.data.len();
+
+            let mut data = Vec::new();
+
+            if let Err(error) = DeflateDecoder::new(&delta.data[..]).read_to_end(&mut data)
+            {
+                // The older snapshots can't be reached anymore
+                error!("cannot decompress the rewind snapshot: {}", error);
+                self.deltas.clear();
+                self.size = 0;
+                break;
+            }
+
+            state = xor(&state, &data);
+            state.truncate(delta.length);
+            frame = delta.frame;
+        }
+
+        self.next_frame = frame + self.config.interval as u64;
+        self.latest = Some((frame, state));
+
+        self.latest.as_ref().map(|(frame, state)| (*frame, &state[..]))
+    }
+
+    // The snapshots don't apply anymore, e.g. after loading another program or a save state
+    pub fn clear(&mut self)
+    {
+        self.latest = None;
+        self.deltas.clear();
+        self.size = 0;
+        self.next_frame = 0;
+    }
+}
+
+// The shorter buffer is padded with zeros
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8>
+{
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+
+    let mut result = long.to_vec();
+
+    for (byte, other) in result.iter_mut().zip(short)
+    {
+        *byte ^= other;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn state(length: usize, seed: u8) -> Vec<u8>
+    {
+        (0 .. length).map(|index| (index as u8).wrapping_mul(seed)).collect()
+    }
+
+    #[test]
+    fn rewind_restores_the_older_states()
+    {
+        let mut rewind = Rewind::new(RewindConfig { interval: 10, capacity: 1024 * 1024 });
+
+        // The states don't all have the same size
+        let states = [state(1000, 3), state(1200, 5), state(900, 7), state(1000, 11)];
+
+        for (index, state) in states.iter().enumerate()
+        {
+            rewind.push(index as u64 * 10, state.clone());
+        }
+
+        assert_eq!(rewind.rewind(30), Some((30, &states[3][..])));
+        assert_eq!(rewind.rewind(25), Some((20, &states[2][..])));
+        assert_eq!(rewind.rewind(0), Some((0, &states[0][..])));
+
+        // Nothing older is left
+        assert_eq!(rewind.rewind(0), Some((0, &states[0][..])));
+        assert!(rewind.deltas.is_empty());
+        assert_eq!(rewind.size, 0);
+    }
+
+    #[test]
+    fn capacity_drops_the_oldest_deltas()
+    {
+        let mut rewind = Rewind::new(RewindConfig { interval: 1, capacity: 1 });
+
+        rewind.push(0, state(100, 3));
+        rewind.push(1, state(100, 5));
+        rewind.push(2, state(100, 7));
+
+        assert!(rewind.deltas.is_empty());
+        assert_eq!(rewind.rewind(0), Some((2, &state(100, 7)[..])));
+    }
+}
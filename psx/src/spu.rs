@@ -1,13 +1,25 @@
 use bitfield::bitfield;
+use serde::{ Serialize, Deserialize };
 
 const SPU_OFFSET: u32 = 0x1F801C00;
 
+const VOICE_COUNT: usize = 24;
+
+const RAM_SIZE: usize = 512 * 1024;
+
+// The SPU outputs a sample at 44.1kHz, that is every 0x300 cycles of the 33.8688MHz system clock
+const CYCLES_PER_SAMPLE: u32 = 0x300;
+
+// ADPCM filter coefficients (positive and negative)
+const ADPCM_FILTERS: [(i32, i32); 5] = [(0, 0), (60, 0), (115, -52), (98, -55), (122, -60)];
+
 bitfield!
 {
+    #[derive(Serialize, Deserialize)]
     struct Control(u16);
 
     enabled, _: 15;
-    muted, _: 14;
+    unmuted, _: 14;
     noise_clock_frequency, _: 13, 8;
     reverb_enabled, _: 7;
     irq_enabled, _: 6;
@@ -20,6 +32,7 @@ bitfield!
 
 bitfield!
 {
+    #[derive(Serialize, Deserialize)]
     pub struct Status(u16);
     impl Debug;
     capture_buffer_half, _: 11;
@@ -31,12 +44,324 @@ bitfield!
     mode, set_mode: 5, 0; // Same as the low bits of the control register
 }
 
-pub struct SPU
+bitfield!
+{
+    #[derive(Copy, Clone, Serialize, Deserialize)]
+    struct ADSR(u32);
+
+    attack_exponential, _: 15;
+    attack_shift, _: 14, 10;
+    attack_step, _: 9, 8;
+    decay_shift, _: 7, 4;
+    sustain_level, _: 3, 0;
+    sustain_exponential, _: 31;
+    sustain_decrease, _: 30;
+    sustain_shift, _: 28, 24;
+    sustain_step, _: 23, 22;
+    release_exponential, _: 21;
+    release_shift, _: 20, 16;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+enum ADSRPhase
+{
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Voice
+{
+    // Registers
+
+    volume_left: u16,
+    volume_right: u16,
+    pitch: u16,
+    start_address: u16,
+    adsr: ADSR,
+    adsr_volume: u16,
+    repeat_address: u16,
+
+    // Internal state
+
+    current_address: u32, // in bytes
+    pitch_counter: u32, // bits 12+ index the decoded block, bits 11-4 are the interpolation factor
+
+    decoded: [i16; 28], // samples of the current ADPCM block
+    decoded_flags: u8,
+    previous_samples: (i32, i32), // for ADPCM filtering, carried over to the next block
+    interpolation_sample: i16, // last sample of the previous block, for interpolation
+
+    adsr_phase: ADSRPhase,
+    adsr_cycles: u32, // cycles to wait before the next envelope step
+
+    output: i16 // latest output, used to modulate the pitch of the next voice
+}
+
+impl Voice
+{
+    fn new() -> Voice
+    {
+        Voice
+        {
+            volume_left: 0,
+            volume_right: 0,
+            pitch: 0,
+            start_address: 0,
+            adsr: ADSR(0),
+            adsr_volume: 0,
+            repeat_address: 0,
+
+            current_address: 0,
+            pitch_counter: 0,
+
+            decoded: [0; 28],
+            decoded_flags: 0,
+            previous_samples: (0, 0),
+            interpolation_sample: 0,
+
+            adsr_phase: ADSRPhase::Off,
+            adsr_cycles: 0,
+
+            output: 0
+        }
+    }
+
+    fn read(&self, register: u32) -> u16
+    {
+        match register
+        {
+            0x0 => self.volume_left,
+            0x2 => self.volume_right,
+            0x4 => self.pitch,
+            0x6 => self.start_address,
+            0x8 => self.adsr.0 as u16,
+            0xA => (self.adsr.0 >> 16) as u16,
+            0xC => self.adsr_volume,
+            0xE => self.repeat_address,
+            _ => unreachable!()
+        }
+    }
+
+    fn write(&mut self, register: u32, val: u16)
+    {
+        match register
+        {
+            0x0 => self.volume_left = val,
+            0x2 => self.volume_right = val,
+            0x4 => self.pitch = val,
+            0x6 => self.start_address = val,
+            0x8 => self.adsr.0 = (self.adsr.0 & 0xFFFF0000) | (val as u32),
+            0xA => self.adsr.0 = (self.adsr.0 & 0x0000FFFF) | ((val as u32) << 16),
+            0xC => self.adsr_volume = val,
+            0xE => self.repeat_address = val,
+            _ => unreachable!()
+        }
+    }
+
+    fn key_on(&mut self, ram: &[u8])
+    {
+        self.current_address = (self.start_address as u32) * 8;
+        self.pitch_counter = 0;
+        self.previous_samples = (0, 0);
+        self.interpolation_sample = 0;
+
+        self.adsr_phase = ADSRPhase::Attack;
+        self.adsr_volume = 0;
+        self.adsr_cycles = 0;
+
+        self.decode_block(ram);
+    }
+
+    fn key_off(&mut self)
+    {
+        if self.adsr_phase != ADSRPhase::Off
+        {
+            self.adsr_phase = ADSRPhase::Release;
+            self.adsr_cycles = 0;
+        }
+    }
+
+    // Decodes the 28 samples of the ADPCM block at the current address.
+    //
+    // Block layout:
+    // - byte 0: shift (bits 3-0) and filter (bits 6-4)
+    // - byte 1: flags (bit 0 = loop end, bit 1 = loop repeat, bit 2 = loop start)
+    // - bytes 2-15: 28 4-bit samples
+    fn decode_block(&mut self, ram: &[u8])
+    {
+        let address = self.current_address as usize;
+
+        // A block at the end of the RAM wraps around to its start
+        let mut block = [0; 16];
+
+        for (i, byte) in block.iter_mut().enumerate()
+        {
+            *byte = ram[(address + i) & (RAM_SIZE - 1)];
+        }
+
+        let shift = match block[0] & 0xF
+        {
+            s if s > 12 => 9, // Reserved values behave like 9
+            s => s
+        };
+
+        let (filter_pos, filter_neg) = ADPCM_FILTERS[((block[0] >> 4) & 7).min(4) as usize];
+
+        self.decoded_flags = block[1];
+
+        // Loop start: remember this block as the repeat address
+        if self.decoded_flags & 4 != 0
+        {
+            self.repeat_address = (self.current_address / 8) as u16;
+        }
+
+        let (mut old, mut older) = self.previous_samples;
+
+        for i in 0 .. 28
+        {
+            let nibble = (block[2 + i / 2] >> ((i % 2) * 4)) & 0xF;
+
+            let mut sample = (((nibble as u16) << 12) as i16 as i32) >> shift;
+            sample += (old * filter_pos + older * filter_neg + 32) >> 6;
+            sample = sample.clamp(-0x8000, 0x7FFF);
+
+            self.decoded[i] = sample as i16;
+
+            older = old;
+            old = sample;
+        }
+
+        self.previous_samples = (old, older);
+    }
+
+    // Moves to the next ADPCM block, following the loop flags of the current one.
+    // Returns true if the loop end flag was hit.
+    fn next_block(&mut self, ram: &[u8]) -> bool
+    {
+        let flags = self.decoded_flags;
+
+        self.interpolation_sample = self.decoded[27];
+
+        let loop_end = flags & 1 != 0;
+
+        if loop_end
+        {
+            self.current_address = (self.repeat_address as u32) * 8;
+
+            // Without the repeat flag, the voice is silenced
+            if flags & 2 == 0
+            {
+                self.adsr_phase = ADSRPhase::Off;
+                self.adsr_volume = 0;
+            }
+        }
+        else
+        {
+            self.current_address = (self.current_address + 16) & (RAM_SIZE as u32 - 1);
+        }
+
+        self.decode_block(ram);
+
+        loop_end
+    }
+
+    // Returns the current ADPCM sample, linearly interpolated with the previous one
+    fn sample(&self) -> i16
+    {
+        let index = (self.pitch_counter >> 12) as usize;
+        let factor = ((self.pitch_counter >> 4) & 0xFF) as i32;
+
+        let current = self.decoded[index] as i32;
+        let previous = if index == 0 { self.interpolation_sample as i32 } else { self.decoded[index - 1] as i32 };
+
+        (previous + (((current - previous) * factor) >> 8)) as i16
+    }
+
+    // Advances the envelope by one sample
+    fn update_envelope(&mut self)
+    {
+        let adsr = &self.adsr;
+
+        // (exponential, decrease, shift, step)
+        let (exponential, decrease, shift, step) = match self.adsr_phase
+        {
+            ADSRPhase::Attack  => (adsr.attack_exponential(), false, adsr.attack_shift(), 7 - adsr.attack_step() as i32),
+            ADSRPhase::Decay   => (true, true, adsr.decay_shift(), -8),
+            ADSRPhase::Sustain =>
+            {
+                let step = adsr.sustain_step() as i32;
+                let step = if adsr.sustain_decrease() { -8 + step } else { 7 - step };
+                (adsr.sustain_exponential(), adsr.sustain_decrease(), adsr.sustain_shift(), step)
+            },
+            ADSRPhase::Release => (adsr.release_exponential(), true, adsr.release_shift(), -8),
+            ADSRPhase::Off     => return
+        };
+
+        if self.adsr_cycles > 0
+        {
+            self.adsr_cycles -= 1;
+            return;
+        }
+
+        let level = self.adsr_volume as i16 as i32;
+
+        let mut cycles = 1 << shift.saturating_sub(11);
+        let mut step = step << 11u32.saturating_sub(shift);
+
+        if exponential && !decrease && level > 0x6000
+        {
+            cycles *= 4;
+        }
+
+        if exponential && decrease
+        {
+            step = (step * level) >> 15;
+        }
+
+        self.adsr_cycles = cycles - 1;
+
+        let level = (level + step).clamp(0, 0x7FFF);
+        self.adsr_volume = level as u16;
+
+        // Phase transitions
+
+        match self.adsr_phase
+        {
+            ADSRPhase::Attack if level == 0x7FFF => self.adsr_phase = ADSRPhase::Decay,
+            ADSRPhase::Decay if level <= ((self.adsr.sustain_level() as i32 + 1) * 0x800) => self.adsr_phase = ADSRPhase::Sustain,
+            ADSRPhase::Release if level == 0 => self.adsr_phase = ADSRPhase::Off,
+            _ => ()
+        }
+    }
+}
+
+// Converts a volume register to a fixed volume.
+// Sweep mode (bit 15) is not emulated: the volume is left unchanged.
+fn fixed_volume(register: u16) -> i32
+{
+    if register & 0x8000 == 0
+    {
+        ((register << 1) as i16) as i32
+    }
+    else
+    {
+        0x7FFF
+    }
+}
+
+fn apply_volume(sample: i32, volume: i32) -> i32
 {
-    //data: [u8; 640], // TODO remove
+    (sample * volume) >> 15
+}
 
-    // Voice registers
-    voice_data: [u16; 0xC0], // TODO structure this
+#[derive(Serialize, Deserialize)]
+pub struct SPU
+{
+    voices: [Voice; VOICE_COUNT],
 
     // Control registers
 
@@ -45,15 +370,16 @@ pub struct SPU
     volume_reverb_left: u16,
     volume_reverb_right: u16,
 
-    voice_on: u32, // writing affects voice_status
-    voice_off: u32, // writing affects voice_status
-    channel_pitch: u32, // TODO use Voice struct
-    channel_noise: u32,
+    voice_on: u32, // writing starts the voices
+    voice_off: u32, // writing releases the voices
+    channel_pitch_modulation: u32, // voice n modulated by the output of voice n-1 (not for voice 0)
+    channel_noise: u32, // voice n outputs noise instead of ADPCM
     channel_reverb: u32,
-    pub channel_status: u32, // set when writing to voice ON/OFF
+    pub channel_status: u32, // ENDX: set when a voice hits a loop end block, cleared on key on
 
     address_irq: u16,
     address_transfer: u16,
+    current_address_transfer: u32,
 
     control: Control,
     control_transfer: u16,
@@ -65,7 +391,18 @@ pub struct SPU
     volume_extern_right: u16,
 
     // reverb registers
-    reverb_data: [u16; 0x1F] // TODO structure this
+    reverb_data: [u16; 0x20], // TODO structure this
+
+    ram: Vec<u8>,
+
+    // Noise generator
+    noise_level: u16,
+    noise_timer: i32,
+
+    cycles: u32, // cycles accumulated since the latest sample
+
+    #[serde(skip)]
+    output: Vec<i16> // interleaved stereo samples generated since the latest flush
 }
 
 impl SPU
@@ -74,9 +411,7 @@ impl SPU
     {
         SPU
         {
-            //data: [0; 640],
-
-            voice_data: [0; 0xC0],
+            voices: [Voice::new(); VOICE_COUNT],
 
             volume_main_left: 0,
             volume_main_right: 0,
@@ -84,12 +419,13 @@ impl SPU
             volume_reverb_right: 0,
             voice_on: 0,
             voice_off: 0,
-            channel_pitch: 0,
+            channel_pitch_modulation: 0,
             channel_noise: 0,
             channel_reverb: 0,
             channel_status: 0,
             address_irq: 0,
             address_transfer: 0,
+            current_address_transfer: 0,
             control: Control(0),
             control_transfer: 0,
             status: Status(0),
@@ -98,20 +434,29 @@ impl SPU
             volume_extern_left: 0,
             volume_extern_right: 0,
 
-            reverb_data: [0; 0x1F]
+            reverb_data: [0; 0x20],
+
+            ram: vec![0; RAM_SIZE],
+
+            noise_level: 0,
+            noise_timer: 0,
+
+            cycles: 0,
+
+            output: Vec::new()
         }
     }
 
     pub fn read(&self, addr: u32) -> u16
     {
-        //if addr >= 0x188 && addr <= 0x18F
-        {
-        //error!("SPU control registers read16 @ {:08X}", addr + SPU_OFFSET);
-        }
-
         match addr
         {
-            0 ..= 0x17F => self.voice_data[(addr >> 2) as usize],
+            0 ..= 0x17F => self.voices[(addr >> 4) as usize].read(addr & 0xE),
+
+            0x180 => self.volume_main_left,
+            0x182 => self.volume_main_right,
+            0x184 => self.volume_reverb_left,
+            0x186 => self.volume_reverb_right,
 
             0x188 => self.voice_on as u16,
             0x18A => (self.voice_on >> 16) as u16,
@@ -119,46 +464,59 @@ impl SPU
             0x18C => self.voice_off as u16,
             0x18E => (self.voice_off >> 16) as u16,
 
-            /*
-            0x190 => self.channel_pitch = self.channel_pitch & 0x0000FFFF | ((val as u32) << 16),
-            0x192 => self.channel_pitch = self.channel_pitch & 0xFFFF0000 | (val as u32),
+            0x190 => self.channel_pitch_modulation as u16,
+            0x192 => (self.channel_pitch_modulation >> 16) as u16,
 
-            0x194 => self.channel_noise = self.channel_noise & 0x0000FFFF | ((val as u32) << 16),
-            0x196 => self.channel_noise = self.channel_noise & 0xFFFF0000 | (val as u32),
+            0x194 => self.channel_noise as u16,
+            0x196 => (self.channel_noise >> 16) as u16,
 
-            0x198 => self.channel_reverb = self.channel_reverb & 0x0000FFFF | ((val as u32) << 16),
-            0x19A => self.channel_reverb = self.channel_reverb & 0xFFFF0000 | (val as u32),
+            0x198 => self.channel_reverb as u16,
+            0x19A => (self.channel_reverb >> 16) as u16,
 
-            0x19C => self.channel_on = self.channel_on & 0x0000FFFF | ((val as u32) << 16),
-            0x19E => self.channel_on = self.channel_on & 0xFFFF0000 | (val as u32),
-            */
-            //0x1A2 => 0, // TODO what's this?
+            0x19C => self.channel_status as u16,
+            0x19E => (self.channel_status >> 16) as u16,
+
+            0x1A2 => 0, // TODO reverb work area
+            0x1A4 => self.address_irq,
+            0x1A6 => self.address_transfer,
 
             0x1AA => self.control.0,
             0x1AC => self.control_transfer,
             0x1AE => self.status.0,
 
-            _ => panic!()
-        }
+            0x1B0 => self.volume_cd_left,
+            0x1B2 => self.volume_cd_right,
+            0x1B4 => self.volume_extern_left,
+            0x1B6 => self.volume_extern_right,
 
-        /*let offset = addr as usize;
+            // Current main volume
+            0x1B8 => self.volume_main_left,
+            0x1BA => self.volume_main_right,
 
-        let b0 = self.data[offset] as u16;
-        let b1 = self.data[offset + 1] as u16;
+            0x1C0 ..= 0x1FF => self.reverb_data[((addr - 0x1C0) >> 1) as usize],
 
-        (b1 << 8) | b0*/
+            // Current voice volume
+            0x200 ..= 0x25F =>
+            {
+                let voice = &self.voices[((addr - 0x200) >> 2) as usize];
+                if addr & 2 == 0 { voice.volume_left } else { voice.volume_right }
+            },
+
+            _ =>
+            {
+                error!("Unsupported SPU read @ {:08X}", addr + SPU_OFFSET);
+                0
+            }
+        }
     }
 
     pub fn write(&mut self, addr: u32, val: u16)
     {
-        //if addr >= 0x188 && addr <= 0x18F
-        {
-            info!("SPU write16 {:04X} @ {:08X}", val, addr + SPU_OFFSET);
-        }
+        info!("SPU write16 {:04X} @ {:08X}", val, addr + SPU_OFFSET);
 
         match addr
         {
-            0 ..= 0x17F => self.voice_data[(addr >> 2) as usize] = val,
+            0 ..= 0x17F => self.voices[(addr >> 4) as usize].write(addr & 0xE, val),
 
             0x180 => self.volume_main_left = val,
             0x182 => self.volume_main_right = val,
@@ -168,39 +526,44 @@ impl SPU
             0x188 =>
             {
                 self.voice_on = (self.voice_on & 0xFFFF0000) | (val as u32);
-                self.channel_status = (self.channel_status & 0xFFFF0000) | (val as u32);
+                self.key_on(val as u32);
             },
             0x18A =>
             {
                 self.voice_on = (self.voice_on & 0x0000FFFF) | ((val as u32) << 16);
-                self.channel_status = (self.channel_status & 0x0000FFFF) | ((val as u32) << 16);
+                self.key_on((val as u32) << 16);
             }
 
             0x18C =>
             {
                 self.voice_off = (self.voice_off & 0xFFFF0000) | (val as u32);
-                self.channel_status = (self.channel_status & 0xFFFF0000) & !(val as u32);
+                self.key_off(val as u32);
             }
             0x18E =>
             {
                 self.voice_off = (self.voice_off & 0x0000FFFF) | ((val as u32) << 16);
-                self.channel_status = (self.channel_status & 0x0000FFFF) & !((val as u32) << 16);
+                self.key_off((val as u32) << 16);
             }
 
-            // TODO fix endianness
-            0x190 => self.channel_pitch = self.channel_pitch & 0x0000FFFF | ((val as u32) << 16),
-            0x192 => self.channel_pitch = self.channel_pitch & 0xFFFF0000 | (val as u32),
+            0x190 => self.channel_pitch_modulation = (self.channel_pitch_modulation & 0xFFFF0000) | (val as u32),
+            0x192 => self.channel_pitch_modulation = (self.channel_pitch_modulation & 0x0000FFFF) | ((val as u32) << 16),
+
+            0x194 => self.channel_noise = (self.channel_noise & 0xFFFF0000) | (val as u32),
+            0x196 => self.channel_noise = (self.channel_noise & 0x0000FFFF) | ((val as u32) << 16),
 
-            0x194 => self.channel_noise = self.channel_noise & 0x0000FFFF | ((val as u32) << 16),
-            0x196 => self.channel_noise = self.channel_noise & 0xFFFF0000 | (val as u32),
+            0x198 => self.channel_reverb = (self.channel_reverb & 0xFFFF0000) | (val as u32),
+            0x19A => self.channel_reverb = (self.channel_reverb & 0x0000FFFF) | ((val as u32) << 16),
 
-            0x198 => self.channel_reverb = self.channel_reverb & 0x0000FFFF | ((val as u32) << 16),
-            0x19A => self.channel_reverb = self.channel_reverb & 0xFFFF0000 | (val as u32),
+            0x19C | 0x19E => (), // ENDX is read-only
 
             0x1A2 => {},//error!("unimplemented SPU register"),
             0x1A4 => self.address_irq = val,
-            0x1A6 => self.address_transfer = val,
-            0x1A8 => {},//error!("unimplemented SPU transfer"),
+            0x1A6 =>
+            {
+                self.address_transfer = val;
+                self.current_address_transfer = (val as u32) * 8;
+            },
+            0x1A8 => self.write_data(val),
 
             0x1AA =>
             {
@@ -216,13 +579,186 @@ impl SPU
             0x1B4 => self.volume_extern_left = val,
             0x1B6 => self.volume_extern_right = val,
 
-            0x1C0 ..= 0x1FF => self.reverb_data[((addr - 0x1C0) >> 2) as usize] = val,
+            0x1C0 ..= 0x1FF => self.reverb_data[((addr - 0x1C0) >> 1) as usize] = val,
+
+            _ => error!("Unsupported SPU write {:04X} @ {:08X}", val, addr + SPU_OFFSET)
+        }
+    }
+
+    // Writes a halfword to the SPU RAM at the current transfer address
+    pub fn write_data(&mut self, val: u16)
+    {
+        let address = self.current_address_transfer as usize;
+
+        self.ram[address] = val as u8;
+        self.ram[address + 1] = (val >> 8) as u8;
+
+        self.current_address_transfer = (self.current_address_transfer + 2) & (RAM_SIZE as u32 - 1);
+    }
+
+    // Reads a halfword from the SPU RAM at the current transfer address
+    pub fn read_data(&mut self) -> u16
+    {
+        let address = self.current_address_transfer as usize;
+
+        let val = self.ram[address] as u16 | (self.ram[address + 1] as u16) << 8;
+
+        self.current_address_transfer = (self.current_address_transfer + 2) & (RAM_SIZE as u32 - 1);
+
+        val
+    }
+
+    pub fn is_voice_active(&self, voice: usize) -> bool
+    {
+        self.voices[voice].adsr_phase != ADSRPhase::Off
+    }
+
+    pub fn output(&self) -> &[i16]
+    {
+        &self.output
+    }
+
+    pub fn clear_output(&mut self)
+    {
+        self.output.clear();
+    }
+
+    fn key_on(&mut self, voices: u32)
+    {
+        for i in 0 .. VOICE_COUNT
+        {
+            if voices & (1 << i) != 0
+            {
+                self.voices[i].key_on(&self.ram);
+                self.channel_status &= !(1 << i);
+            }
+        }
+    }
+
+    fn key_off(&mut self, voices: u32)
+    {
+        for i in 0 .. VOICE_COUNT
+        {
+            if voices & (1 << i) != 0
+            {
+                self.voices[i].key_off();
+            }
+        }
+    }
+
+    // Advances the SPU by the given number of system clock cycles
+    pub fn tick(&mut self, cycles: u32)
+    {
+        self.cycles += cycles;
+
+        while self.cycles >= CYCLES_PER_SAMPLE
+        {
+            self.cycles -= CYCLES_PER_SAMPLE;
+
+            let (left, right) = self.clock();
+            self.output.push(left);
+            self.output.push(right);
+        }
+    }
+
+    // Updates the noise generator, which is a 16-bit LFSR clocked
+    // at a frequency configured by the control register
+    fn update_noise(&mut self)
+    {
+        let frequency = self.control.noise_clock_frequency() as i32;
+        let shift = frequency >> 2;
+        let step = (frequency & 3) + 4;
+
+        self.noise_timer -= step;
+
+        let level = self.noise_level;
+        let parity = ((level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10) ^ 1) & 1;
+
+        if self.noise_timer < 0
+        {
+            self.noise_level = (level << 1) | parity;
+            self.noise_timer += 0x20000 >> shift;
 
-            _ => panic!()
+            if self.noise_timer < 0
+            {
+                self.noise_timer += 0x20000 >> shift;
+            }
         }
+    }
+
+    // Generates one stereo sample
+    fn clock(&mut self) -> (i16, i16)
+    {
+        self.update_noise();
+
+        let mut left = 0i32;
+        let mut right = 0i32;
+
+        for i in 0 .. VOICE_COUNT
+        {
+            // Pitch modulation by the previous voice's output
+
+            let mut step = self.voices[i].pitch as u32;
+
+            if i > 0 && self.channel_pitch_modulation & (1 << i) != 0
+            {
+                let factor = (self.voices[i - 1].output as i32 + 0x8000) as u32;
+                step = ((step as i16 as i32 * factor as i32) >> 15) as u32 & 0xFFFF;
+            }
+
+            let step = step.min(0x4000);
+
+            let voice = &mut self.voices[i];
+
+            if voice.adsr_phase == ADSRPhase::Off
+            {
+                voice.output = 0;
+                continue;
+            }
+
+            // Noise replaces the ADPCM samples, but the voice keeps on decoding
+            // them so that loop flags and ENDX keep working
+
+            let sample = if self.channel_noise & (1 << i) != 0
+            {
+                self.noise_level as i16
+            }
+            else
+            {
+                voice.sample()
+            };
+
+            voice.update_envelope();
+
+            let output = apply_volume(sample as i32, voice.adsr_volume as i32);
+            voice.output = output as i16;
+
+            left += apply_volume(output, fixed_volume(voice.volume_left));
+            right += apply_volume(output, fixed_volume(voice.volume_right));
+
+            // Advance in the ADPCM data
+
+            voice.pitch_counter += step;
+
+            while voice.pitch_counter >= 28 << 12
+            {
+                voice.pitch_counter -= 28 << 12;
+
+                if voice.next_block(&self.ram)
+                {
+                    self.channel_status |= 1 << i;
+                }
+            }
+        }
+
+        if !self.control.enabled() || !self.control.unmuted()
+        {
+            return (0, 0);
+        }
+
+        let left = apply_volume(left, fixed_volume(self.volume_main_left));
+        let right = apply_volume(right, fixed_volume(self.volume_main_right));
 
-        /*let offset = addr as usize;
-        self.data[offset] = val as u8;
-        self.data[offset + 1] = (val >> 8) as u8;*/
+        (left.clamp(-0x8000, 0x7FFF) as i16, right.clamp(-0x8000, 0x7FFF) as i16)
     }
 }
@@ -0,0 +1,330 @@
+use crate::interrupt_controller::{ InterruptController, InterruptRequest };
+use crate::memory::Addressable;
+
+use serde::{ Serialize, Deserialize };
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Root counters 0-2, and the video timing that drives the VBlank interrupt and the HBlank clock
+//
+// Documentation
+//
+// https://problemkaputt.de/psx-spx.htm#timers
+
+// CPU cycles per scanline and scanlines per frame (about 59.8Hz and 49.8Hz)
+const NTSC_TIMING: (u32, u32) = (2153, 263);
+const PAL_TIMING: (u32, u32) = (2168, 314);
+
+// The dot clock depends on the horizontal resolution, approximated with the 320 pixels mode
+const CYCLES_PER_DOT: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VideoStandard
+{
+    NTSC,
+    PAL
+}
+
+#[derive(Serialize, Deserialize)]
+struct Counter
+{
+    value: u16,
+    mode: u16,
+    target: u16,
+
+    divider: u32, // Cycles not counted yet with the dot clock or the system clock / 8
+    irq_done: bool // For the one-shot mode
+}
+
+impl Counter
+{
+    fn new() -> Counter
+    {
+        Counter
+        {
+            value: 0,
+            mode: 1 << 10, // No IRQ requested
+            target: 0,
+
+            divider: 0,
+            irq_done: false
+        }
+    }
+
+    fn clock_source(&self) -> u16 { (self.mode >> 8) & 3 }
+    fn reset_on_target(&self) -> bool { self.mode & (1 << 3) != 0 }
+    fn irq_on_target(&self) -> bool { self.mode & (1 << 4) != 0 }
+    fn irq_on_overflow(&self) -> bool { self.mode & (1 << 5) != 0 }
+    fn repeat(&self) -> bool { self.mode & (1 << 6) != 0 }
+    fn toggle(&self) -> bool { self.mode & (1 << 7) != 0 }
+
+    fn set_mode(&mut self, value: u16)
+    {
+        self.mode = (value & 0x3FF) | (1 << 10);
+        self.value = 0;
+        self.irq_done = false;
+    }
+
+    // Returns the mode, the "reached" flags are cleared once read
+    fn read_mode(&mut self) -> u16
+    {
+        let mode = self.mode;
+        self.mode &= !(3 << 11);
+        mode
+    }
+
+    // Converts CPU cycles to ticks of a slower clock
+    fn divide(&mut self, cycles: u32, divider: u32) -> u32
+    {
+        self.divider += cycles;
+        let ticks = self.divider / divider;
+        self.divider %= divider;
+        ticks
+    }
+
+    // Ticks until the counter reaches its target or overflows
+    fn ticks_until_event(&self) -> u32
+    {
+        let value = self.value as u32;
+        let target = self.target as u32;
+
+        let overflow = 0x1_0000 - value;
+
+        if value < target { (target - value).min(overflow) } else { overflow }
+    }
+
+    // Advances the counter, returns true if it requests an interrupt
+    fn count(&mut self, ticks: u32) -> bool
+    {
+        if ticks == 0
+        {
+            return false;
+        }
+
+        let previous = self.value as u32;
+        let target = self.target as u32;
+        let mut value = previous + ticks;
+        let mut irq = false;
+
+        if previous < target && value >= target
+        {
+            self.mode |= 1 << 11;
+            irq |= self.irq_on_target();
+
+            if self.reset_on_target()
+            {
+                value = (value - target) % target;
+            }
+        }
+
+        if value > 0xFFFF
+        {
+            self.mode |= 1 << 12;
+            irq |= self.irq_on_overflow();
+            value &= 0xFFFF;
+        }
+
+        self.value = value as u16;
+
+        if !irq || (self.irq_done && !self.repeat())
+        {
+            return false;
+        }
+
+        self.irq_done = true;
+
+        // Bit 10 is low while the IRQ is requested, it only stays there in toggle mode
+        if self.toggle()
+        {
+            self.mode ^= 1 << 10;
+            self.mode & (1 << 10) == 0
+        }
+        else
+        {
+            true
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Timers
+{
+    counters: [Counter; 3],
+
+    video_standard: VideoStandard,
+    line_cycles: u32, // Cycles spent in the current scanline
+    line: u32,
+    frames: u64, // VBlanks since the power on
+
+    #[serde(skip)]
+    interrupt_controller: Rc<RefCell<InterruptController>>
+}
+
+impl Timers
+{
+    pub fn new(interrupt_controller: &Rc<RefCell<InterruptController>>, video_standard: VideoStandard) -> Self
+    {
+        Timers
+        {
+            counters: [Counter::new(), Counter::new(), Counter::new()],
+
+            video_standard,
+            line_cycles: 0,
+            line: 0,
+            frames: 0,
+
+            interrupt_controller: interrupt_controller.clone()
+        }
+    }
+
+    // The interrupt controller is shared, it is restored separately
+    pub fn load_state(&mut self, mut state: Timers)
+    {
+        state.interrupt_controller = self.interrupt_controller.clone();
+        *self = state;
+    }
+
+    // The refresh rate is given by the console region, or by the played music
+    pub fn set_video_standard(&mut self, video_standard: VideoStandard)
+    {
+        self.video_standard = video_standard;
+    }
+
+    pub fn frames(&self) -> u64
+    {
+        self.frames
+    }
+
+    // Cycles until the next scanline, or until a counter reaches its target or overflows.
+    // The timers change nothing the CPU can see before that, unless they are read.
+    pub fn cycles_until_event(&self) -> u32
+    {
+        let (cycles_per_line, _) = match self.video_standard
+        {
+            VideoStandard::NTSC => NTSC_TIMING,
+            VideoStandard::PAL  => PAL_TIMING
+        };
+
+        let mut cycles = cycles_per_line - self.line_cycles;
+
+        for (index, counter) in self.counters.iter().enumerate()
+        {
+            let ticks = counter.ticks_until_event();
+
+            let counter_cycles = match (index, counter.clock_source())
+            {
+                (0, 1) | (0, 3) => ticks * CYCLES_PER_DOT - counter.divider,
+                (1, 1) | (1, 3) => continue, // Counts the scanlines
+                (2, 2) | (2, 3) => ticks * 8 - counter.divider,
+                _               => ticks
+            };
+
+            cycles = cycles.min(counter_cycles);
+        }
+
+        cycles
+    }
+
+    pub fn tick(&mut self, cycles: u32)
+    {
+        let (cycles_per_line, lines_per_frame) = match self.video_standard
+        {
+            VideoStandard::NTSC => NTSC_TIMING,
+            VideoStandard::PAL  => PAL_TIMING
+        };
+
+        // Video timing
+
+        let mut hblanks = 0;
+        let mut vblank = false;
+
+        self.line_cycles += cycles;
+
+        while self.line_cycles >= cycles_per_line
+        {
+            self.line_cycles -= cycles_per_line;
+            hblanks += 1;
+            self.line += 1;
+
+            if self.line == lines_per_frame
+            {
+                self.line = 0;
+                self.frames += 1;
+                vblank = true;
+            }
+        }
+
+        if vblank
+        {
+            self.interrupt_controller.borrow_mut().request(InterruptRequest::VBlank);
+        }
+
+        // Counters
+
+        for index in 0 .. 3
+        {
+            let counter = &mut self.counters[index];
+
+            let ticks = match (index, counter.clock_source())
+            {
+                (0, 1) | (0, 3) => counter.divide(cycles, CYCLES_PER_DOT),
+                (1, 1) | (1, 3) => hblanks,
+                (2, 2) | (2, 3) => counter.divide(cycles, 8),
+                _               => cycles
+            };
+
+            // The synchronization with the blanks is not emulated, except for
+            // the counter 2 modes that stop it
+            if index == 2 && counter.mode & 1 != 0 && matches!((counter.mode >> 1) & 3, 0 | 3)
+            {
+                continue;
+            }
+
+            if counter.count(ticks)
+            {
+                let request = match index
+                {
+                    0 => InterruptRequest::Timer0,
+                    1 => InterruptRequest::Timer1,
+                    _ => InterruptRequest::Timer2
+                };
+
+                self.interrupt_controller.borrow_mut().request(request);
+            }
+        }
+    }
+
+    pub fn read<T: Addressable>(&mut self, offset: u32) -> T
+    {
+        let counter = match self.counters.get_mut((offset >> 4) as usize)
+        {
+            Some(counter) => counter,
+            None => return T::from_u32(0)
+        };
+
+        match offset & 0xF
+        {
+            0x0 => T::from_u16(counter.value),
+            0x4 => T::from_u16(counter.read_mode()),
+            0x8 => T::from_u16(counter.target),
+            _   => T::from_u32(0)
+        }
+    }
+
+    pub fn write<T: Addressable>(&mut self, offset: u32, value: T)
+    {
+        let counter = match self.counters.get_mut((offset >> 4) as usize)
+        {
+            Some(counter) => counter,
+            None => return
+        };
+
+        match offset & 0xF
+        {
+            0x0 => counter.value = value.as_u16(),
+            0x4 => counter.set_mode(value.as_u16()),
+            0x8 => counter.target = value.as_u16(),
+            _   => warn!("Ignoring write to the timer registers: {:08x} @ {:x}", value.as_u32(), offset)
+        }
+    }
+}
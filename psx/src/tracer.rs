@@ -0,0 +1,312 @@
+// Execution trace: writes the CPU state before each instruction, to be diffed
+// against traces of other emulators.
+//
+// The text format has one instruction per line, with the selected fields
+// separated by spaces, all values in lowercase hexadecimal:
+//   80030000 3c1d801f R0=00000000 R1=00000000 ... HI=00000000 LO=00000000 SR=00000000 CAUSE=00000000 EPC=00000000 BADA=00000000
+//
+// The custom log format is the fixed layout of the former instruction log (custom_log_own.txt),
+// byte for byte, to be diffed with the logs written in that layout by the reference emulator:
+//   80030000 3c1d801f R0=00000000 R1=00000000 ... R31=00000000 HI 00000000 LO 00000000 S 00000000 
+// The selected fields are ignored, every line ends with a space.
+//
+// The binary format starts with a header:
+//   "PSXTRACE", version (u32), fields mask (u32)
+// followed by one record per instruction with the selected fields as little-endian u32:
+//   PC, opcode, R0-R31, HI, LO, SR, CAUSE, EPC, BADA
+
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::PathBuf;
+
+const BINARY_MAGIC: &[u8; 8] = b"PSXTRACE";
+const BINARY_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat
+{
+    Text,
+    CustomLog,
+    Binary
+}
+
+// When to start or stop tracing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceCondition
+{
+    Instruction(u32), // Once this many instructions have been run
+    Breakpoint(u32) // When the PC reaches this address
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceFields
+{
+    pub pc: bool,
+    pub opcode: bool,
+    pub gprs: bool,
+    pub hi_lo: bool,
+    pub cop0: bool
+}
+
+impl TraceFields
+{
+    pub fn all() -> Self
+    {
+        TraceFields { pc: true, opcode: true, gprs: true, hi_lo: true, cop0: true }
+    }
+
+    // Parses a comma separated list such as "pc,opcode,gprs"
+    pub fn parse(list: &str) -> Option<Self>
+    {
+        let mut fields = TraceFields { pc: false, opcode: false, gprs: false, hi_lo: false, cop0: false };
+
+        for name in list.split(',')
+        {
+            match name.trim()
+            {
+                "pc"     => fields.pc = true,
+                "opcode" => fields.opcode = true,
+                "gprs"   => fields.gprs = true,
+                "hilo"   => fields.hi_lo = true,
+                "cop0"   => fields.cop0 = true,
+                _        => return None
+            }
+        }
+
+        Some(fields)
+    }
+
+    // Written in the binary header
+    fn mask(&self) -> u32
+    {
+        (self.pc as u32) | (self.opcode as u32) << 1 | (self.gprs as u32) << 2 | (self.hi_lo as u32) << 3 | (self.cop0 as u32) << 4
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceConfig
+{
+    pub path: PathBuf,
+    pub format: TraceFormat,
+    pub fields: TraceFields,
+    pub start: Option<TraceCondition>, // None to start right away
+    pub stop: Option<TraceCondition>, // None to trace until the tracer is removed
+    pub pc_range: Option<(u32, u32)> // Only the instructions in this inclusive range are written
+}
+
+impl TraceConfig
+{
+    pub fn new(path: PathBuf) -> Self
+    {
+        TraceConfig
+        {
+            path,
+            format: TraceFormat::Text,
+            fields: TraceFields::all(),
+            start: None,
+            stop: None,
+            pc_range: None
+        }
+    }
+}
+
+// State before an instruction
+pub struct TraceEntry<'a>
+{
+    pub pc: u32,
+    pub opcode: u32,
+    pub r: &'a [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub cop0: [u32; 4] // SR, CAUSE, EPC, BADA
+}
+
+pub struct Tracer
+{
+    config: TraceConfig,
+    output: Option<BufWriter<File>>, // None once the trace is over
+    started: bool
+}
+
+impl Tracer
+{
+    pub fn new(config: TraceConfig) -> io::Result<Self>
+    {
+        let mut output = BufWriter::new(File::create(&config.path)?);
+
+        if config.format == TraceFormat::Binary
+        {
+            output.write_all(BINARY_MAGIC)?;
+            output.write_all(&BINARY_VERSION.to_le_bytes())?;
+            output.write_all(&config.fields.mask().to_le_bytes())?;
+        }
+
+        let started = config.start.is_none();
+
+        Ok(Tracer
+        {
+            config,
+            output: Some(output),
+            started
+        })
+    }
+
+    // Checks the start and stop conditions, returns true if the instruction must be written
+    #[allow(clippy::unnecessary_map_or)] // Option::is_none_or needs Rust 1.82
+    pub fn is_tracing(&mut self, counter: u32, pc: u32) -> bool
+    {
+        if self.output.is_none()
+        {
+            return false;
+        }
+
+        let matches = |condition: TraceCondition| match condition
+        {
+            TraceCondition::Instruction(count) => counter >= count,
+            TraceCondition::Breakpoint(address) => pc == address
+        };
+
+        if !self.started
+        {
+            self.started = self.config.start.map_or(true, matches);
+        }
+
+        if self.started && self.config.stop.is_some_and(matches)
+        {
+            info!("Trace stopped after {} instructions", counter);
+            self.finish();
+            return false;
+        }
+
+        self.started && self.config.pc_range.map_or(true, |(start, end)| pc >= start && pc <= end)
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry)
+    {
+        let result = match self.config.format
+        {
+            TraceFormat::Text      => self.write_text(entry),
+            TraceFormat::CustomLog => self.write_custom_log(entry),
+            TraceFormat::Binary    => self.write_binary(entry)
+        };
+
+        if let Err(error) = result
+        {
+            error!("Cannot write the trace, stopping it: {}", error);
+            self.output = None;
+        }
+    }
+
+    fn write_text(&mut self, entry: &TraceEntry) -> io::Result<()>
+    {
+        let fields = self.config.fields;
+        let mut line = String::new();
+
+        if fields.pc
+        {
+            line += &format!("{:08x} ", entry.pc);
+        }
+
+        if fields.opcode
+        {
+            line += &format!("{:08x} ", entry.opcode);
+        }
+
+        if fields.gprs
+        {
+            for (index, value) in entry.r.iter().enumerate()
+            {
+                line += &format!("R{}={:08x} ", index, value);
+            }
+        }
+
+        if fields.hi_lo
+        {
+            line += &format!("HI={:08x} LO={:08x} ", entry.hi, entry.lo);
+        }
+
+        if fields.cop0
+        {
+            line += &format!("SR={:08x} CAUSE={:08x} EPC={:08x} BADA={:08x} ", entry.cop0[0], entry.cop0[1], entry.cop0[2], entry.cop0[3]);
+        }
+
+        if let Some(output) = &mut self.output
+        {
+            writeln!(output, "{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_custom_log(&mut self, entry: &TraceEntry) -> io::Result<()>
+    {
+        let mut line = format!("{:08x} {:08x} ", entry.pc, entry.opcode);
+
+        for (index, value) in entry.r.iter().enumerate()
+        {
+            line += &format!("R{}={:08x} ", index, value);
+        }
+
+        line += &format!("HI {:08x} LO {:08x} S {:08x} ", entry.hi, entry.lo, entry.cop0[0]);
+
+        if let Some(output) = &mut self.output
+        {
+            writeln!(output, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_binary(&mut self, entry: &TraceEntry) -> io::Result<()>
+    {
+        let fields = self.config.fields;
+        let mut values = Vec::with_capacity(40);
+
+        if fields.pc
+        {
+            values.push(entry.pc);
+        }
+
+        if fields.opcode
+        {
+            values.push(entry.opcode);
+        }
+
+        if fields.gprs
+        {
+            values.extend_from_slice(entry.r);
+        }
+
+        if fields.hi_lo
+        {
+            values.push(entry.hi);
+            values.push(entry.lo);
+        }
+
+        if fields.cop0
+        {
+            values.extend_from_slice(&entry.cop0);
+        }
+
+        if let Some(output) = &mut self.output
+        {
+            for value in values
+            {
+                output.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self)
+    {
+        if let Some(mut output) = self.output.take()
+        {
+            if let Err(error) = output.flush()
+            {
+                error!("Cannot write the trace: {}", error);
+            }
+        }
+    }
+}
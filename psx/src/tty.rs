@@ -0,0 +1,68 @@
+// Text written by the programs through the BIOS (std_out_putchar...) or the
+// Expansion 2 DUART, shown on stdout and kept for the debugger.
+
+use std::io::{ self, Write };
+
+// Older text is dropped past this size
+const MAX_SIZE: usize = 64 * 1024;
+
+pub struct TTY
+{
+    text: String,
+    line: String // Current line, not printed yet
+}
+
+impl TTY
+{
+    pub fn new() -> Self
+    {
+        TTY
+        {
+            text: String::new(),
+            line: String::new()
+        }
+    }
+
+    pub fn putchar(&mut self, c: u8)
+    {
+        match c
+        {
+            b'\r' => return,
+            b'\n' =>
+            {
+                println!("{}", self.line);
+                let _ = io::stdout().flush();
+                self.line.clear();
+            },
+            _ => self.line.push(c as char)
+        }
+
+        self.text.push(c as char);
+
+        if self.text.len() > MAX_SIZE
+        {
+            // Cut after a line break
+            let excess = self.text.len() - MAX_SIZE / 2;
+            let end = self.text.match_indices('\n').map(|(i, _)| i + 1).find(|i| *i >= excess).unwrap_or(self.text.len());
+            self.text.drain(.. end);
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8])
+    {
+        for c in bytes
+        {
+            self.putchar(*c);
+        }
+    }
+
+    pub fn text(&self) -> &str
+    {
+        &self.text
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.text.clear();
+    }
+}
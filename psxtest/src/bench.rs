@@ -0,0 +1,60 @@
+use psx::lockstep::Snapshot;
+use psx::psf::PSF;
+use psx::psx::{ ExecutionMode, PSX };
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+// Instructions run between two checks of the state
+const CHUNK: u32 = 100_000;
+
+// Runs the same program headless with each execution mode, and compares their speed and final state
+pub fn run(psf_path: PathBuf, instructions: u64, bios_path: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>>
+{
+    let psf = PSF::new_from_file(psf_path)?;
+
+    let modes =
+    [
+        ("interpreter", ExecutionMode::Interpreter),
+        ("cached", ExecutionMode::CachedInterpreter),
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        ("recompiler", ExecutionMode::Recompiler)
+    ];
+
+    let mut reference: Option<(f64, Snapshot)> = None;
+
+    for (name, mode) in modes
+    {
+        let mut p = PSX::new_psf(bios_path.clone(), &psf)?;
+        p.set_execution_mode(mode);
+
+        let start = Instant::now();
+        let mut remaining = instructions;
+
+        while remaining > 0
+        {
+            let count = remaining.min(CHUNK as u64) as u32;
+            p.run(count);
+            remaining -= count as u64;
+        }
+
+        let seconds = start.elapsed().as_secs_f64();
+        let snapshot = Snapshot::capture(&mut p);
+
+        let (speedup, differences) = match &reference
+        {
+            Some((reference_seconds, expected)) => (reference_seconds / seconds, snapshot.compare(expected)),
+            None                                => (1.0, Vec::new())
+        };
+
+        println!("{:12} {:8.3} s {:8.1} MIPS  x{:.2}  {} cycles  {}", name, seconds, instructions as f64 / seconds / 1e6, speedup, p.cpu.cycles,
+            if differences.is_empty() { String::from("same state") } else { differences.join(", ") });
+
+        if reference.is_none()
+        {
+            reference = Some((seconds, snapshot));
+        }
+    }
+
+    Ok(())
+}
@@ -1,25 +1,233 @@
 extern crate psx;
 
-use psx::psx::PSX; // TODO rename to System or something
+use psx::audio::WavFileSink;
+use psx::lockstep::{ self, Lockstep };
+use psx::rewind::RewindConfig;
+use psx::tracer::{ TraceCondition, TraceConfig, TraceFields, TraceFormat };
+use psx::psx::{ BusErrorPolicy, ExecutionMode, PSX }; // TODO rename to System or something
 
 use imgui::*;
 use std::env;
 use std::path::PathBuf;
 
+mod bench;
+mod psf_play;
+mod str_dump;
 mod support;
 
+// Written with F5, restored with F9
+const STATE_PATH: &str = "state.bin";
+
 fn main()
 {
     // Check the arguments
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2
+    let mut args: Vec<String> = env::args().collect();
+
+    // Optional WAV capture of the sound output
+    let wav_path = match args.iter().position(|a| a == "--wav")
+    {
+        Some(index) if index + 1 < args.len() =>
+        {
+            let path = PathBuf::from(&args[index + 1]);
+            args.drain(index .. index + 2);
+            Some(path)
+        },
+        _ => None
+    };
+
+    // What to do on accesses to unmapped addresses
+    let bus_error_policy = match args.iter().position(|a| a == "--bus-errors")
+    {
+        Some(index) if index + 1 < args.len() =>
+        {
+            let policy = match args[index + 1].as_str()
+            {
+                "lenient"  => BusErrorPolicy::Lenient,
+                "debugger" => BusErrorPolicy::Debugger,
+                _          => BusErrorPolicy::Strict
+            };
+            args.drain(index .. index + 2);
+            policy
+        },
+        _ => BusErrorPolicy::Strict
+    };
+
+    // How the CPU runs instructions
+    let execution_mode = match args.iter().position(|a| a == "--cpu")
+    {
+        Some(index) if index + 1 < args.len() =>
+        {
+            let mode = parse_execution_mode(&args[index + 1]).unwrap_or(ExecutionMode::Interpreter);
+            args.drain(index .. index + 2);
+            mode
+        },
+        _ => ExecutionMode::Interpreter
+    };
+
+    // Execution trace
+    let trace_config = take_option(&mut args, "--trace").map(|path|
+    {
+        let mut config = TraceConfig::new(PathBuf::from(path));
+
+        if let Some(format) = take_option(&mut args, "--trace-format")
+        {
+            config.format = match format.as_str()
+            {
+                "binary"     => TraceFormat::Binary,
+                "custom-log" => TraceFormat::CustomLog,
+                _            => TraceFormat::Text
+            };
+        }
+
+        if let Some(fields) = take_option(&mut args, "--trace-fields")
+        {
+            config.fields = TraceFields::parse(&fields).unwrap_or_else(TraceFields::all);
+        }
+
+        config.start = take_option(&mut args, "--trace-start").and_then(|c| parse_trace_condition(&c));
+        config.stop = take_option(&mut args, "--trace-stop").and_then(|c| parse_trace_condition(&c));
+
+        config.pc_range = take_option(&mut args, "--trace-range").and_then(|range|
+        {
+            let (start, end) = range.split_once('-')?;
+            Some((parse_hex(start)?, parse_hex(end)?))
+        });
+
+        config
+    });
+
+    // Differential testing of the CPU against another execution mode or a recorded trace
+    let lockstep_reference = match args.iter().position(|a| a == "--lockstep")
+    {
+        Some(index) if index + 1 < args.len() =>
+        {
+            let reference = args[index + 1].clone();
+            args.drain(index .. index + 2);
+            Some(reference)
+        },
+        _ => None
+    };
+
+    // CPU states to record for later lockstep runs
+    let trace_recording = match args.iter().position(|a| a == "--record-trace")
+    {
+        Some(index) if index + 2 < args.len() =>
+        {
+            let path = PathBuf::from(&args[index + 1]);
+            let instructions = args[index + 2].parse::<u64>().unwrap_or(0);
+            args.drain(index .. index + 3);
+            Some((path, instructions))
+        },
+        _ => None
+    };
+
+    let kernel_call_log = match args.iter().position(|a| a == "--kernel-calls")
+    {
+        Some(index) =>
+        {
+            args.remove(index);
+
+            // The calls are logged at the info level, keep the user's other filters
+            let filters = env::var("RUST_LOG").unwrap_or_else(|_| "error".to_string());
+            env::set_var("RUST_LOG", filters + ",kernel_calls=info");
+            true
+        },
+        None => false
+    };
+
+    // Snapshots taken while running, to go back in time with F3
+    let rewind = match args.iter().position(|a| a == "--rewind")
+    {
+        Some(index) =>
+        {
+            args.remove(index);
+            true
+        },
+        None => false
+    };
+
+    let hle = match args.iter().position(|a| a == "--hle")
     {
-        panic!("Usage: psxtest <bios> [game]");
+        Some(index) =>
+        {
+            args.remove(index);
+            true
+        },
+        None => false
+    };
+
+    if args.len() < 2 && !hle
+    {
+        panic!("Usage: psxtest <bios>|--hle [game] [--wav output.wav] [--bus-errors strict|lenient|debugger] [--cpu interpreter|cached|recompiler] [--kernel-calls] [--rewind]\n                       [--lockstep <cpu>|<trace.jsonl>] [--record-trace <trace.jsonl> <instructions>]\n                       [--trace <file> [--trace-format text|custom-log|binary] [--trace-fields pc,opcode,gprs,hilo,cop0]\n                                       [--trace-start <count>|@<address>] [--trace-stop <count>|@<address>] [--trace-range <start>-<end>]]\n       psxtest str <movie.str> <output directory>\n       psxtest psf <music.psf|minipsf> <output.wav> [bios]\n       psxtest bench <program.psf|minipsf> <instructions> [bios]");
     }
 
-    let mut bios_path = PathBuf::new();
-    bios_path.push(&args[1]);
+    // Movie frames dump, no emulation needed
+
+    if args.get(1).is_some_and(|a| a == "str")
+    {
+        if args.len() < 4
+        {
+            panic!("Usage: psxtest str <movie.str> <output directory>");
+        }
+
+        if let Err(error) = str_dump::dump_frames(PathBuf::from(&args[2]), PathBuf::from(&args[3]))
+        {
+            println!("cannot dump movie frames: {}", error);
+        }
+
+        return;
+    }
+
+    // Music playback to a WAV file, headless and with the HLE kernel if there is no BIOS
+
+    if args.get(1).is_some_and(|a| a == "psf")
+    {
+        if args.len() < 4
+        {
+            panic!("Usage: psxtest psf <music.psf|minipsf> <output.wav> [bios]");
+        }
+
+        if let Err(error) = psf_play::play(PathBuf::from(&args[2]), PathBuf::from(&args[3]), args.get(4).map(PathBuf::from))
+        {
+            println!("cannot play PSF: {}", error);
+        }
+
+        return;
+    }
+
+    // Speed of the execution modes, headless
+
+    if args.get(1).is_some_and(|a| a == "bench")
+    {
+        let instructions = args.get(3).and_then(|count| count.parse().ok());
+
+        match instructions
+        {
+            Some(instructions) =>
+            {
+                if let Err(error) = bench::run(PathBuf::from(&args[2]), instructions, args.get(4).map(PathBuf::from))
+                {
+                    println!("cannot run the benchmark: {}", error);
+                }
+            },
+            None => panic!("Usage: psxtest bench <program.psf|minipsf> <instructions> [bios]")
+        }
+
+        return;
+    }
+
+    // Without a BIOS, the first argument is the game (an EXE, ELF or CPE file, or the directory of a disc)
+    if hle
+    {
+        args.insert(1, String::new());
+    }
+
+    let bios_path = match hle
+    {
+        true => None,
+        false => Some(PathBuf::from(&args[1]))
+    };
 
     let program_path = match args.len()
     {
@@ -36,7 +244,79 @@ fn main()
 
     let system = support::init(1600, 800, file!());
 
-    let mut p = PSX::new(bios_path, program_path, &system.display);
+    let mut p = match PSX::new(bios_path.clone(), program_path.clone(), &system.display)
+    {
+        Ok(p) => p,
+        Err(error) =>
+        {
+            println!("{}", error);
+            return;
+        }
+    };
+
+    p.set_bus_error_policy(bus_error_policy);
+    p.set_execution_mode(execution_mode);
+    p.set_kernel_call_log(kernel_call_log);
+
+    if rewind
+    {
+        p.set_rewind(Some(RewindConfig::default()));
+    }
+
+    if let Err(error) = p.set_trace(trace_config)
+    {
+        println!("cannot create trace {:?}", error);
+    }
+
+    if let Some((path, instructions)) = trace_recording
+    {
+        if let Err(error) = lockstep::record_trace(&mut p, &path, instructions)
+        {
+            println!("cannot record trace {:?}", error);
+        }
+
+        return;
+    }
+
+    if let Some(reference) = lockstep_reference
+    {
+        let lockstep = match parse_execution_mode(&reference)
+        {
+            Some(mode) => match PSX::new(bios_path, program_path, &system.display)
+            {
+                Ok(mut r) =>
+                {
+                    r.set_bus_error_policy(bus_error_policy);
+                    r.set_execution_mode(mode);
+                    Ok(Lockstep::new(r, p))
+                },
+                Err(error) =>
+                {
+                    println!("{}", error);
+                    return;
+                }
+            },
+            None => Lockstep::from_trace(&PathBuf::from(&reference), p)
+        };
+
+        match lockstep.map(|mut l| l.run(u64::MAX))
+        {
+            Ok(Ok(instructions)) => println!("No divergence in {} instructions", instructions),
+            Ok(Err(divergence)) => print!("{}", divergence),
+            Err(error) => println!("cannot open trace {:?}", error)
+        }
+
+        return;
+    }
+
+    if let Some(path) = wav_path
+    {
+        match WavFileSink::new(path)
+        {
+            Ok(sink) => p.set_audio_sink(Some(Box::new(sink))),
+            Err(error) => println!("cannot create WAV file {:?}", error)
+        }
+    }
 
     match p.cpu.debugger.load("debugger.json")
     {
@@ -76,8 +356,39 @@ fn main()
         {
             is_running = !is_running;
         }
+        // F5: Save state
+        else if ui.is_key_released(41)
+        {
+            match p.save_state().map(|state| std::fs::write(STATE_PATH, state))
+            {
+                Ok(Ok(())) => println!("State saved to \"{}\"", STATE_PATH),
+                Ok(Err(error)) => println!("cannot write \"{}\": {}", STATE_PATH, error),
+                Err(error) => println!("{}", error)
+            }
+        }
+        // F9: Load state
+        else if ui.is_key_released(45)
+        {
+            match std::fs::read(STATE_PATH)
+            {
+                Ok(state) => match p.load_state(&state)
+                {
+                    Ok(()) => println!("State loaded from \"{}\"", STATE_PATH),
+                    Err(error) => println!("{}", error)
+                },
+                Err(error) => println!("cannot read \"{}\": {}", STATE_PATH, error)
+            }
+        }
 
-        if is_running
+        // F3 (held): Rewind, one snapshot per UI frame
+        if rewind && ui.is_key_down(39)
+        {
+            if let Err(error) = p.rewind(1)
+            {
+                println!("{}", error);
+            }
+        }
+        else if is_running
         {
             is_running = p.run(1_000_000);
             //p.gpu().render(&system.display);
@@ -182,6 +493,11 @@ fn main()
 
                 let breakpoints_hit = p.cpu.debugger.get_data_breakpoints_hit().to_vec();
 
+                if let Some(address) = p.cpu.debugger.get_bus_error_hit()
+                {
+                    ui.text_colored(COLOR_ACCENT, format!("Bus error @ {:08X}", address));
+                }
+
                 for b in p.cpu.debugger.get_data_breakpoints_mut()
                 {
                     // On read / On write
@@ -310,6 +626,12 @@ fn main()
                             _ => println!("cannot convert hex \"{}\" to u32", &disasm.hint)
                         }
                     }
+
+                    if let Some(location) = &disasm.location
+                    {
+                        ui.same_line(0.0);
+                        ui.text_colored(COLOR_DIMMED, format!("<{}>", location));
+                    }
                 }
             });
 
@@ -422,8 +744,15 @@ fn main()
                     ui.text(format!("{}", i));
                     ui.next_column();
 
-                    let voice_on = if p.mem.spu.channel_status & (1 << i) != 0 {im_str!("ON")} else {im_str!("OFF")};
+                    let voice_on = if p.mem.spu.is_voice_active(i) {im_str!("ON")} else {im_str!("OFF")};
                     ui.text(voice_on);
+
+                    if p.mem.spu.channel_status & (1 << i) != 0
+                    {
+                        ui.same_line(0.0);
+                        ui.text_colored(COLOR_DIMMED, im_str!("END"));
+                    }
+
                     ui.next_column();
                 }
             });
@@ -462,6 +791,31 @@ fn main()
                 }
             });
 
+        Window::new(im_str!("TTY"))
+            .position([900.0, 0.0], Condition::FirstUseEver)
+            .size([500.0, 300.0], Condition::FirstUseEver)
+            .collapsed(true, Condition::FirstUseEver)
+            .build(ui, ||
+            {
+                if ui.small_button(im_str!("Clear"))
+                {
+                    p.clear_tty();
+                }
+
+                ui.separator();
+
+                ChildWindow::new(im_str!("tty_output")).build(ui, ||
+                {
+                    ui.text(p.tty());
+
+                    // Follow the output unless scrolled up
+                    if ui.scroll_y() >= ui.scroll_max_y()
+                    {
+                        ui.set_scroll_here_y_with_ratio(1.0);
+                    }
+                });
+            });
+
         /*if !is_running
         {
             Window::new(im_str!("Pause"))
@@ -475,3 +829,47 @@ fn main()
         }*/
     });
 }
+
+fn parse_execution_mode(name: &str) -> Option<ExecutionMode>
+{
+    match name
+    {
+        "interpreter" => Some(ExecutionMode::Interpreter),
+        "cached"      => Some(ExecutionMode::CachedInterpreter),
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        "recompiler"  => Some(ExecutionMode::Recompiler),
+
+        _ => None
+    }
+}
+
+// Removes "name value" from the arguments and returns the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String>
+{
+    let index = args.iter().position(|a| a == name)?;
+
+    if index + 1 >= args.len()
+    {
+        return None;
+    }
+
+    let value = args[index + 1].clone();
+    args.drain(index .. index + 2);
+    Some(value)
+}
+
+fn parse_hex(value: &str) -> Option<u32>
+{
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+// "@address" for a breakpoint, an instruction count otherwise
+fn parse_trace_condition(condition: &str) -> Option<TraceCondition>
+{
+    match condition.strip_prefix('@')
+    {
+        Some(address) => parse_hex(address).map(TraceCondition::Breakpoint),
+        None          => condition.parse().ok().map(TraceCondition::Instruction)
+    }
+}
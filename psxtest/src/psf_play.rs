@@ -0,0 +1,88 @@
+use psx::audio::{ AudioSink, WavFileSink, SAMPLE_RATE };
+use psx::psf::PSF;
+use psx::psx::PSX;
+
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// Applies the fade out, and counts the frames played
+struct FadeSink
+{
+    output: WavFileSink,
+    frames: Rc<Cell<u64>>,
+    fade_start: u64,
+    fade_end: u64
+}
+
+impl AudioSink for FadeSink
+{
+    fn push(&mut self, samples: &[i16])
+    {
+        let mut faded = Vec::with_capacity(samples.len());
+
+        for frame in samples.chunks_exact(2)
+        {
+            let position = self.frames.get();
+
+            if position >= self.fade_end
+            {
+                break;
+            }
+
+            let volume = match position.checked_sub(self.fade_start)
+            {
+                Some(elapsed) => 1.0 - elapsed as f32 / (self.fade_end - self.fade_start) as f32,
+                None => 1.0
+            };
+
+            faded.extend(frame.iter().map(|sample| (*sample as f32 * volume) as i16));
+            self.frames.set(position + 1);
+        }
+
+        self.output.push(&faded);
+    }
+}
+
+// Plays a PSF or MiniPSF to a WAV file, for its length and fade
+pub fn play(psf_path: PathBuf, wav_path: PathBuf, bios_path: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>>
+{
+    let psf = PSF::new_from_file(psf_path)?;
+
+    for (name, value) in psf.tags()
+    {
+        println!("{}: {}", name, value);
+    }
+
+    let mut p = PSX::new_psf(bios_path, &psf)?;
+
+    let to_frames = |seconds: f64| (seconds * SAMPLE_RATE as f64) as u64;
+
+    let frames = Rc::new(Cell::new(0));
+    let fade_start = to_frames(psf.length().as_secs_f64());
+    let fade_end = fade_start + to_frames(psf.fade().as_secs_f64());
+
+    p.set_audio_sink(Some(Box::new(FadeSink
+    {
+        output: WavFileSink::new(wav_path.clone())?,
+        frames: frames.clone(),
+        fade_start,
+        fade_end
+    })));
+
+    while frames.get() < fade_end
+    {
+        if !p.run(100_000)
+        {
+            println!("the emulation stopped after {} frames", frames.get());
+            break;
+        }
+    }
+
+    // Flushes the WAV file
+    p.set_audio_sink(None);
+
+    println!("{:.1}s written to \"{}\"", frames.get() as f64 / SAMPLE_RATE as f64, wav_path.display());
+
+    Ok(())
+}
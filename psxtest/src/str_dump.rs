@@ -0,0 +1,44 @@
+use psx::movie::{ MovieDecoder, MovieOutput, RAW_SECTOR_SIZE };
+
+use std::fs::File;
+use std::io::{ BufWriter, Read };
+use std::path::PathBuf;
+
+// Decodes the video frames of a raw STR file (2352-byte sectors) to PNG files
+pub fn dump_frames(str_path: PathBuf, output_directory: PathBuf) -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut data = Vec::new();
+    File::open(&str_path)?.read_to_end(&mut data)?;
+
+    std::fs::create_dir_all(&output_directory)?;
+
+    let mut decoder = MovieDecoder::new();
+    let mut frame_count = 0;
+
+    for sector in data.chunks_exact(RAW_SECTOR_SIZE)
+    {
+        match decoder.push_sector(sector)
+        {
+            Ok(Some(MovieOutput::Frame(frame))) =>
+            {
+                let path = output_directory.join(format!("frame_{:05}.png", frame.number));
+
+                let mut encoder = png::Encoder::new(BufWriter::new(File::create(&path)?), frame.width, frame.height);
+                encoder.set_color(png::ColorType::RGB);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()?.write_image_data(&frame.pixels)?;
+
+                frame_count += 1;
+            },
+
+            Ok(_) => (),
+
+            // Keep going, the next frames may be fine
+            Err(error) => println!("cannot decode sector: {}", error)
+        }
+    }
+
+    println!("{} frames written to \"{}\"", frame_count, output_directory.display());
+
+    Ok(())
+}